@@ -0,0 +1,215 @@
+// blockchain.rs
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::genesis::Genesis;
+use crate::signing::{self, Keypair};
+use crate::trie::{self, Hash};
+
+const TRANSACTION_DOMAIN: &str = "pi-sentinel transaction";
+const PROPOSER_DOMAIN: &str = "pi-sentinel block proposer";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Block {
+    pub height: u64,
+    pub hash: String,
+    pub parent_hash: String,
+    pub timestamp: u64,
+    // Root of the state trie after applying this block.
+    pub state_root: Hash,
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+    // Validator whose slot the block was proposed in; see consensus/stake.rs.
+    // Empty for mined blocks.
+    #[serde(default)]
+    pub proposer: String,
+    // The proposer's signature over `hash`, in hex; see consensus/signing.rs.
+    // Not part of the hash it signs.
+    #[serde(default)]
+    pub proposer_signature: String,
+    // Proof-of-work difficulty the block was mined at, and the nonce that
+    // meets it; see consensus/pow.rs. Both are zero for blocks that were not
+    // mined.
+    #[serde(default)]
+    pub difficulty: u64,
+    #[serde(default)]
+    pub nonce: u64,
+}
+
+impl Block {
+    pub fn new(height: u64, parent_hash: &str, timestamp: u64, state_root: Hash) -> Self {
+        Block::with_transactions(height, parent_hash, timestamp, state_root, Vec::new())
+    }
+
+    pub fn with_transactions(
+        height: u64,
+        parent_hash: &str,
+        timestamp: u64,
+        state_root: Hash,
+        transactions: Vec<Transaction>,
+    ) -> Self {
+        let mut block = Block {
+            height,
+            hash: String::new(),
+            parent_hash: parent_hash.to_string(),
+            timestamp,
+            state_root,
+            transactions,
+            proposer: String::new(),
+            proposer_signature: String::new(),
+            difficulty: 0,
+            nonce: 0,
+        };
+        block.hash = block.compute_hash();
+        block
+    }
+
+    // The first block, committing to the state built from `genesis.alloc`.
+    pub fn genesis(genesis: &Genesis, state_root: Hash) -> Self {
+        Block::new(genesis.block_number, "", genesis.timestamp, state_root)
+    }
+
+    // Hash of every header field except `hash` itself.
+    pub fn compute_hash(&self) -> String {
+        let mut hasher = self.header_hasher();
+        if self.difficulty > 0 {
+            hasher.update(self.nonce.to_le_bytes());
+        }
+        trie::to_hex(&hasher.finalize())
+    }
+
+    // Signs `hash` as `key`, which must own the `proposer` address, so set
+    // every other field first.
+    pub fn sign_proposal(&mut self, key: &Keypair) {
+        self.proposer_signature = key.sign(PROPOSER_DOMAIN, self.hash.as_bytes());
+    }
+
+    pub fn is_signed_by_proposer(&self) -> bool {
+        signing::verify(&self.proposer, PROPOSER_DOMAIN, self.hash.as_bytes(), &self.proposer_signature)
+    }
+
+    // The hash state over every field before the nonce, so a miner only
+    // hashes the nonce itself for each attempt. Blocks that were not mined
+    // leave out both proof-of-work fields and hash as they did before those
+    // fields existed; the same goes for the proposer of blocks that have
+    // none, and for unsigned transactions.
+    pub fn header_hasher(&self) -> Sha256 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.height.to_le_bytes());
+        hasher.update(self.parent_hash.as_bytes());
+        hasher.update(self.timestamp.to_le_bytes());
+        hasher.update(self.state_root);
+        for transaction in &self.transactions {
+            hasher.update(transaction.hash().as_bytes());
+            // Committed to as well, since signatures decide whether staking
+            // transactions take effect.
+            if !transaction.signature.is_empty() {
+                hasher.update((transaction.signature.len() as u64).to_le_bytes());
+                hasher.update(transaction.signature.as_bytes());
+            }
+        }
+        if !self.proposer.is_empty() {
+            hasher.update((self.proposer.len() as u64).to_le_bytes());
+            hasher.update(self.proposer.as_bytes());
+        }
+        if self.difficulty > 0 {
+            hasher.update(self.difficulty.to_le_bytes());
+        }
+        hasher
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transaction {
+    pub from: String,
+    pub to: String,
+    pub amount: u64,
+    pub nonce: u64,
+    // The sender's signature over `hash`, in hex; see consensus/signing.rs.
+    // Only staking transactions need one so far.
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl Transaction {
+    // Covers every field but the signature.
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.from.as_bytes());
+        hasher.update([0]);
+        hasher.update(self.to.as_bytes());
+        hasher.update([0]);
+        hasher.update(self.amount.to_le_bytes());
+        hasher.update(self.nonce.to_le_bytes());
+        trie::to_hex(&hasher.finalize())
+    }
+
+    // Signs the transaction as `key`, which must own the `from` address.
+    pub fn sign(&mut self, key: &Keypair) {
+        self.signature = key.sign(TRANSACTION_DOMAIN, self.hash().as_bytes());
+    }
+
+    pub fn is_signed_by_sender(&self) -> bool {
+        signing::verify(&self.from, TRANSACTION_DOMAIN, self.hash().as_bytes(), &self.signature)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Blockchain {
+    blocks: Vec<Block>,
+}
+
+impl Blockchain {
+    pub fn new() -> Self {
+        Blockchain { blocks: Vec::new() }
+    }
+
+    pub fn add_block(&mut self, block: Block) -> Result<(), BlockError> {
+        if block.hash != block.compute_hash() {
+            return Err(BlockError::InvalidHash);
+        }
+        if let Some(head) = self.blocks.last() {
+            if block.height != head.height + 1 {
+                return Err(BlockError::InvalidHeight);
+            }
+            if block.parent_hash != head.hash {
+                return Err(BlockError::UnknownParent);
+            }
+        }
+        self.blocks.push(block);
+        Ok(())
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn latest_block(&self) -> Option<&Block> {
+        self.blocks.last()
+    }
+
+    pub fn get_latest_blocks(&self, count: usize) -> Vec<Block> {
+        let start = self.blocks.len().saturating_sub(count);
+        self.blocks[start..].to_vec()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BlockError {
+    InvalidHash,
+    InvalidHeight,
+    UnknownParent,
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::InvalidHash => write!(f, "block hash does not match its contents"),
+            BlockError::InvalidHeight => write!(f, "block height does not follow the chain head"),
+            BlockError::UnknownParent => write!(f, "block parent is not the chain head"),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
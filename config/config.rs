@@ -2,6 +2,10 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::Path;
 
+use crate::difficulty::Retarget;
+use crate::signing::Keypair;
+use crate::stake::EPOCH_LENGTH;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub node: NodeConfig,
@@ -9,6 +13,8 @@ pub struct Config {
     pub storage: StorageConfig,
     pub network: NetworkConfig,
     pub analytics: AnalyticsConfig,
+    #[serde(default)]
+    pub rpc: RpcConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -16,6 +22,18 @@ pub struct NodeConfig {
     pub id: String,
     pub address: String,
     pub port: u16,
+    // Hex seed of the key this node proposes and votes with as a validator.
+    // Its address is the validator address; unset, the node does neither.
+    #[serde(default)]
+    pub validator_key: Option<String>,
+}
+
+impl NodeConfig {
+    // The key from `validator_key`, which `Config::validate` has checked
+    // parses.
+    pub fn validator_keypair(&self) -> Option<Keypair> {
+        self.validator_key.as_deref().and_then(Keypair::from_hex)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -23,12 +41,160 @@ pub struct ConsensusConfig {
     pub algorithm: String,
     pub block_time: u64,
     pub block_size: u64,
+    // Path to the genesis file; the built-in genesis if unset.
+    #[serde(default)]
+    pub genesis: Option<String>,
+    // Proof-of-work worker threads; 0 uses one per CPU.
+    #[serde(default)]
+    pub mining_threads: usize,
+    #[serde(default)]
+    pub hybrid: HybridConfig,
+    #[serde(default)]
+    pub bft: BftConfig,
+    #[serde(default)]
+    pub finality: FinalityConfig,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Settings for `HybridConsensus`, where mined blocks only count once staked
+// validators ratify them.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct HybridConfig {
+    // Percentage of a ratified block's weight that comes from its proof of
+    // work; the rest scales with the share of stake that approved it. 100
+    // ranks branches by work alone.
+    pub pow_weight: u8,
+    // Percentage of stake that must approve a block before it can join the
+    // canonical chain. 0 accepts blocks on work alone.
+    pub ratification_threshold: u8,
+}
+
+impl Default for HybridConfig {
+    fn default() -> Self {
+        HybridConfig {
+            pow_weight: 50,
+            ratification_threshold: 67,
+        }
+    }
+}
+
+// Round timeouts of the BFT voting protocol. Each round waits `delta_ms`
+// longer than the one before, so the timeouts eventually outlast any
+// network delay and a round can complete.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct BftConfig {
+    // How long to wait for the round's proposal.
+    pub propose_ms: u64,
+    // How long to wait for more prevotes once votes from two thirds of the
+    // stake are in but none has a quorum.
+    pub prevote_ms: u64,
+    // The same for precommits, before moving to the next round.
+    pub precommit_ms: u64,
+    pub delta_ms: u64,
+}
+
+impl Default for BftConfig {
+    fn default() -> Self {
+        BftConfig {
+            propose_ms: 3000,
+            prevote_ms: 1000,
+            precommit_ms: 1000,
+            delta_ms: 500,
+        }
+    }
+}
+
+// Settings for the finality gadget, which makes checkpoint blocks final once
+// validators holding more than two thirds of the stake sign them.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct FinalityConfig {
+    // Blocks between checkpoints; a checkpoint's height is a multiple of it.
+    pub checkpoint_interval: u64,
+}
+
+impl Default for FinalityConfig {
+    fn default() -> Self {
+        FinalityConfig { checkpoint_interval: 32 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct StorageConfig {
     pub type_: String,
     pub path: String,
+    #[serde(default)]
+    pub durability: Durability,
+    // Encrypts stored values when set. Keys are left in plaintext so the
+    // backend can keep them ordered.
+    #[serde(default)]
+    pub encryption: Option<EncryptionKey>,
+    #[serde(default)]
+    pub pruning: Pruning,
+    // Memory for recently read values in the disk backend; 0 disables the
+    // cache.
+    #[serde(default = "default_read_cache_bytes")]
+    pub read_cache_bytes: u64,
+    // Lets the disk backend answer most reads of missing keys without
+    // touching the log.
+    #[serde(default = "default_bloom_filter")]
+    pub bloom_filter: bool,
+}
+
+fn default_read_cache_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_bloom_filter() -> bool {
+    true
+}
+
+// How much chain history the node keeps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "mode")]
+pub enum Pruning {
+    // Every block and every historical state.
+    #[default]
+    Archive,
+    // Only the newest `keep_blocks` blocks and their states.
+    Pruned { keep_blocks: u64 },
+}
+
+// Secret the storage encryption key is derived from.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionKey {
+    Passphrase(String),
+    // Path to a file whose contents are the secret.
+    KeyFile(String),
+}
+
+// When disk writes are flushed to stable storage. Every write is appended to
+// the disk backend's log before it is acknowledged; this only controls when
+// that log is fsynced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "mode")]
+pub enum Durability {
+    // fsync before every write returns. Nothing acknowledged is ever lost.
+    #[default]
+    Strict,
+    // fsync at most every `interval_ms`, which must be at least 1; a crash
+    // loses at most that window.
+    GroupCommit { interval_ms: u64 },
+    // Leave flushing to the OS. Fastest, for throwaway devnets.
+    Buffered,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            type_: "local".to_string(),
+            path: "./storage".to_string(),
+            durability: Durability::default(),
+            encryption: None,
+            pruning: Pruning::default(),
+            read_cache_bytes: default_read_cache_bytes(),
+            bloom_filter: default_bloom_filter(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -37,6 +203,18 @@ pub struct NetworkConfig {
     pub peers: Vec<String>,
 }
 
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RpcConfig {
+    // Secret that callers of the admin_* methods pass as their first
+    // parameter. Those methods are disabled while it is unset.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    // Directory admin_backup writes archives to. The method is disabled
+    // while it is unset.
+    #[serde(default)]
+    pub backup_dir: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AnalyticsConfig {
     pub enabled: bool,
@@ -57,16 +235,19 @@ impl Config {
                 id: "pi-sentinel-node".to_string(),
                 address: "127.0.0.1".to_string(),
                 port: 8080,
+                validator_key: None,
             },
             consensus: ConsensusConfig {
                 algorithm: "ai-consensus".to_string(),
                 block_time: 10,
                 block_size: 1024,
+                genesis: None,
+                mining_threads: 0,
+                hybrid: HybridConfig::default(),
+                bft: BftConfig::default(),
+                finality: FinalityConfig::default(),
             },
-            storage: StorageConfig {
-                type_: "local".to_string(),
-                path: "./storage".to_string(),
-            },
+            storage: StorageConfig::default(),
             network: NetworkConfig {
                 protocol: "tcp".to_string(),
                 peers: vec!["node1.pi.network".to_string(), "node2.pi.network".to_string()],
@@ -79,6 +260,7 @@ impl Config {
                     password: "password".to_string(),
                 },
             },
+            rpc: RpcConfig::default(),
         }
     }
 
@@ -86,9 +268,39 @@ impl Config {
         let file = std::fs::File::open(path)?;
         let reader = std::io::BufReader::new(file);
         let config: Config = serde_json::from_reader(reader)?;
+        config
+            .validate()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err)))?;
         Ok(config)
     }
 
+    // Checks settings that parse on their own but cannot work together.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(key) = &self.node.validator_key {
+            if Keypair::from_hex(key).is_none() {
+                return Err("node.validator_key must be a 32-byte seed in hex".to_string());
+            }
+        }
+        if let Pruning::Pruned { keep_blocks } = self.storage.pruning {
+            // Difficulty retargeting reads the blocks before each new one,
+            // and proposer selection a seed block up to two epochs back, so
+            // they must not be pruned yet.
+            let needed = [
+                ("difficulty retargeting", Retarget::new(self.consensus.block_time, 1).history_len() as u64),
+                ("proposer selection", 2 * EPOCH_LENGTH),
+            ];
+            for (reader, blocks) in needed {
+                if keep_blocks < blocks {
+                    return Err(format!(
+                        "storage.pruning.keep_blocks is {} but {} needs the last {} blocks",
+                        keep_blocks, reader, blocks
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn save_to_file(&self, path: &Path) -> Result<(), std::io::Error> {
         let file = std::fs::File::create(path)?;
         let writer = std::io::BufWriter::new(file);
@@ -9,6 +9,9 @@ pub struct Genesis {
     pub gas_limit: u64,
     pub gas_price: u64,
     pub alloc: HashMap<String, Account>,
+    // Stake each initial validator has bonded.
+    #[serde(default)]
+    pub stakes: HashMap<String, u64>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -26,6 +29,7 @@ impl Genesis {
             gas_limit: 100000,
             gas_price: 20,
             alloc: HashMap::new(),
+            stakes: HashMap::new(),
         }
     }
 
@@ -33,6 +37,10 @@ impl Genesis {
         self.alloc.insert(address.to_string(), Account { balance, nonce: 0 });
     }
 
+    pub fn add_validator(&mut self, address: &str, stake: u64) {
+        self.stakes.insert(address.to_string(), stake);
+    }
+
     pub fn to_json(&self) -> String {
         serde_json::to_string_pretty(self).unwrap()
     }
@@ -0,0 +1,744 @@
+// bft.rs
+//
+// Byzantine fault tolerant agreement on one block per height, following
+// Tendermint ("The latest gossip on BFT consensus", Buchman, Kwon and
+// Milosevic). Each height runs in rounds of three steps:
+//
+// * Propose: the round's proposer broadcasts a block.
+// * Prevote: every validator prevotes for the proposal if it is valid and
+//   does not conflict with its lock, and for nil otherwise.
+// * Precommit: a validator that sees prevotes for the block from more than
+//   two thirds of the stake (a polka) locks on it and precommits for it.
+//
+// Precommits for a block from more than two thirds of the stake decide it;
+// they are returned as its `QuorumCertificate`. Two thirds of the stake
+// means 2f + 1 of 3f + 1 equally staked validators, so with at most f
+// Byzantine ones two conflicting quorums would need an honest validator in
+// both. A locked validator only prevotes for another block after a newer
+// polka for it, which keeps a block decided in one round from being undone
+// in a later one.
+//
+// Timeouts move a round on when its proposer or votes fail to show up, and
+// grow each round so that once the network settles a round completes.
+//
+// Validators relay every proposal they receive and announce each decided
+// block with its certificate. This stands in for Tendermint's gossip layer:
+// without it, a faulty proposer could keep a block from some validators, or
+// faulty voters could withhold precommits from them, and those validators
+// would never learn what was decided.
+//
+// `Tendermint` does no I/O: callers feed it messages and expired timeouts
+// and act on the `Output`s it returns, which keeps it testable in a
+// simulated network. Proposals and votes are signed by their sender (see
+// signing.rs), and those whose signature does not verify are dropped, so
+// neither a peer nor a certificate can speak for a validator without its
+// key.
+//
+// What a peer can make a validator keep is bounded: proposals and votes are
+// only kept for rounds up to `FUTURE_ROUNDS` past the current one, and at
+// most `PROPOSALS_PER_ROUND` proposals per round. Messages for later heights
+// are checked against the current validators before they are kept, and each
+// validator gets a fixed share of that buffer.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::Block;
+use crate::config::BftConfig;
+use crate::signing::{self, Keypair};
+use crate::stake::StakeRegistry;
+
+const PROPOSAL_DOMAIN: &str = "pi-sentinel bft proposal";
+const VOTE_DOMAIN: &str = "pi-sentinel bft vote";
+
+// Heights ahead of the current one whose messages are kept for later, so a
+// validator that falls slightly behind can still take part once it
+// catches up.
+const FUTURE_HEIGHTS: u64 = 4;
+
+// Rounds ahead of the current one whose proposals and votes are kept. A
+// validator further behind catches up through its timeouts or a commit.
+const FUTURE_ROUNDS: u32 = 8;
+
+// A proposer that signs more blocks than this in one round is faulty; the
+// rest of its proposals are dropped.
+const PROPOSALS_PER_ROUND: usize = 4;
+
+// Messages kept per validator for each later height: a proposal and two
+// votes for each round that may be kept.
+const FUTURE_MESSAGES_PER_VALIDATOR: usize = 3 * (FUTURE_ROUNDS as usize + 1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Step {
+    Propose,
+    Prevote,
+    Precommit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum VoteType {
+    Prevote,
+    Precommit,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Vote {
+    pub vote_type: VoteType,
+    pub height: u64,
+    pub round: u32,
+    // `None` votes for nil: no block this round.
+    pub block_hash: Option<String>,
+    pub validator: String,
+    pub signature: String,
+}
+
+impl Vote {
+    // A vote cast and signed by `key`.
+    pub fn signed(key: &Keypair, vote_type: VoteType, height: u64, round: u32, block_hash: Option<String>) -> Self {
+        let mut vote = Vote {
+            vote_type,
+            height,
+            round,
+            block_hash,
+            validator: key.address(),
+            signature: String::new(),
+        };
+        vote.signature = key.sign(VOTE_DOMAIN, &vote.signed_bytes());
+        vote
+    }
+
+    pub fn is_signed(&self) -> bool {
+        signing::verify(&self.validator, VOTE_DOMAIN, &self.signed_bytes(), &self.signature)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.vote_type as u8];
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.extend_from_slice(&self.round.to_be_bytes());
+        match &self.block_hash {
+            Some(block_hash) => {
+                bytes.push(1);
+                bytes.extend_from_slice(block_hash.as_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Proposal {
+    pub height: u64,
+    pub round: u32,
+    pub block: Block,
+    // The round of the polka the proposer saw for `block`, if it is
+    // re-proposing one.
+    pub valid_round: Option<u32>,
+    pub proposer: String,
+    pub signature: String,
+}
+
+impl Proposal {
+    // A proposal made and signed by `key`.
+    pub fn signed(key: &Keypair, height: u64, round: u32, block: Block, valid_round: Option<u32>) -> Self {
+        let mut proposal = Proposal {
+            height,
+            round,
+            block,
+            valid_round,
+            proposer: key.address(),
+            signature: String::new(),
+        };
+        proposal.signature = key.sign(PROPOSAL_DOMAIN, &proposal.signed_bytes());
+        proposal
+    }
+
+    // Whether the proposer signed this proposal. The block is covered by its
+    // hash, which `Tendermint` checks against its contents.
+    pub fn is_signed(&self) -> bool {
+        signing::verify(&self.proposer, PROPOSAL_DOMAIN, &self.signed_bytes(), &self.signature)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.height.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.round.to_be_bytes());
+        match self.valid_round {
+            Some(valid_round) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&valid_round.to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes.extend_from_slice(self.block.hash.as_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    Proposal(Proposal),
+    Vote(Vote),
+    // A decided block, sent on by every validator that decides it so that
+    // validators which missed some precommits can catch up.
+    Commit(Block, QuorumCertificate),
+}
+
+impl Message {
+    pub fn height(&self) -> u64 {
+        match self {
+            Message::Proposal(proposal) => proposal.height,
+            Message::Vote(vote) => vote.height,
+            Message::Commit(_, certificate) => certificate.height,
+        }
+    }
+
+    pub fn round(&self) -> u32 {
+        match self {
+            Message::Proposal(proposal) => proposal.round,
+            Message::Vote(vote) => vote.round,
+            Message::Commit(_, certificate) => certificate.round,
+        }
+    }
+
+    // The validator that signed a proposal or vote.
+    fn sender(&self) -> Option<&str> {
+        match self {
+            Message::Proposal(proposal) => Some(&proposal.proposer),
+            Message::Vote(vote) => Some(&vote.validator),
+            Message::Commit(..) => None,
+        }
+    }
+}
+
+// Precommits for `block_hash` from more than two thirds of the stake.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuorumCertificate {
+    pub height: u64,
+    pub round: u32,
+    pub block_hash: String,
+    pub precommits: Vec<Vote>,
+}
+
+impl QuorumCertificate {
+    // Whether the precommits are signed, are for this certificate's block,
+    // height and round, and come from more than two thirds of `validators`'
+    // stake.
+    pub fn verify(&self, validators: &StakeRegistry) -> bool {
+        let mut signers = HashSet::new();
+        let mut power = 0u64;
+        for vote in &self.precommits {
+            if vote.vote_type != VoteType::Precommit
+                || vote.height != self.height
+                || vote.round != self.round
+                || vote.block_hash.as_deref() != Some(self.block_hash.as_str())
+                || !vote.is_signed()
+            {
+                return false;
+            }
+            if signers.insert(vote.validator.as_str()) {
+                power = power.saturating_add(validators.stake(&vote.validator));
+            }
+        }
+        is_quorum(power, validators.total())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timeout {
+    pub step: Step,
+    pub height: u64,
+    pub round: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    // Send to every other validator.
+    Broadcast(Message),
+    // Call `handle_timeout` with this after the duration.
+    ScheduleTimeout(Timeout, Duration),
+    // `block` is final at its height; the engine has moved on to the next.
+    Decide(Block, QuorumCertificate),
+    // A validator cast two different votes in the same step.
+    Equivocation(Vote, Vote),
+}
+
+// What the engine needs from the chain it orders.
+pub trait Application {
+    // A new block to propose at `height`, or `None` to let the round time
+    // out.
+    fn propose(&mut self, height: u64) -> Option<Block>;
+    fn is_valid(&self, block: &Block) -> bool;
+    // The validators for the height after `parent`, which has just been
+    // decided and may not be stored yet.
+    fn validators(&mut self, parent: &Block) -> StakeRegistry;
+}
+
+// Rules that fire once per round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Trigger {
+    PrevoteTimeout,
+    Polka,
+    PrecommitTimeout,
+}
+
+pub struct Tendermint<A> {
+    app: A,
+    // Signs this validator's messages; `None` only follows the others.
+    key: Option<Keypair>,
+    validator: Option<String>,
+    // This height's validators, from `Application::validators`.
+    validators: StakeRegistry,
+    timeouts: BftConfig,
+    height: u64,
+    round: u32,
+    step: Step,
+    // The block this validator precommitted and the round it did so.
+    locked: Option<(u32, Block)>,
+    // The most recent block with a polka and the round of that polka.
+    valid: Option<(u32, Block)>,
+    // This height's messages, by round. A faulty proposer may propose
+    // several blocks; the first to arrive is the one prevoted on, but any
+    // of them can gather a quorum elsewhere and must be at hand then. Only
+    // the first vote of each validator per step counts.
+    proposals: BTreeMap<u32, Vec<Proposal>>,
+    votes: BTreeMap<(u32, VoteType), BTreeMap<String, Vote>>,
+    fired: HashSet<(Trigger, u32)>,
+    // A verified commit for this height from another validator.
+    commit: Option<(Block, QuorumCertificate)>,
+    validity: HashMap<String, bool>,
+    future: BTreeMap<u64, Vec<Message>>,
+}
+
+impl<A: Application> Tendermint<A> {
+    // An engine voting with `key`, whose address need not have stake, from
+    // `height` on, where `validators` vote. Call `start` before feeding it
+    // messages.
+    pub fn new(app: A, key: Option<Keypair>, validators: StakeRegistry, timeouts: BftConfig, height: u64) -> Self {
+        Tendermint {
+            app,
+            validator: key.as_ref().map(Keypair::address),
+            key,
+            validators,
+            timeouts,
+            height,
+            round: 0,
+            step: Step::Propose,
+            locked: None,
+            valid: None,
+            proposals: BTreeMap::new(),
+            votes: BTreeMap::new(),
+            fired: HashSet::new(),
+            commit: None,
+            validity: HashMap::new(),
+            future: BTreeMap::new(),
+        }
+    }
+
+    pub fn app(&self) -> &A {
+        &self.app
+    }
+
+    pub fn app_mut(&mut self) -> &mut A {
+        &mut self.app
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    pub fn step(&self) -> Step {
+        self.step
+    }
+
+    // Messages kept for later heights.
+    pub fn buffered(&self) -> usize {
+        self.future.values().map(Vec::len).sum()
+    }
+
+    pub fn start(&mut self) -> Vec<Output> {
+        let mut out = Vec::new();
+        self.start_round(0, &mut out);
+        self.run_rules(&mut out);
+        out
+    }
+
+    pub fn handle_message(&mut self, message: Message) -> Vec<Output> {
+        let mut out = Vec::new();
+        if message.height() > self.height {
+            if message.height() <= self.height + FUTURE_HEIGHTS {
+                self.buffer(message);
+            }
+            return out;
+        }
+        if message.height() == self.height && self.record(message, &mut out) {
+            self.run_rules(&mut out);
+        }
+        out
+    }
+
+    pub fn handle_timeout(&mut self, timeout: Timeout) -> Vec<Output> {
+        let mut out = Vec::new();
+        if timeout.height != self.height || timeout.round != self.round {
+            return out;
+        }
+        match timeout.step {
+            Step::Propose if self.step == Step::Propose => self.cast(VoteType::Prevote, None, &mut out),
+            Step::Prevote if self.step == Step::Prevote => self.cast(VoteType::Precommit, None, &mut out),
+            Step::Precommit => self.start_round(self.round + 1, &mut out),
+            _ => return out,
+        }
+        self.run_rules(&mut out);
+        out
+    }
+
+    fn start_round(&mut self, round: u32, out: &mut Vec<Output>) {
+        self.round = round;
+        self.step = Step::Propose;
+        if let Some(key) = &self.key {
+            if proposer(&self.validators, self.height, round) == self.validator.as_deref() {
+                let proposal = match &self.valid {
+                    Some((valid_round, block)) => Some((block.clone(), Some(*valid_round))),
+                    None => self.app.propose(self.height).map(|block| (block, None)),
+                };
+                if let Some((block, valid_round)) = proposal {
+                    let proposal = Proposal::signed(key, self.height, round, block, valid_round);
+                    self.broadcast(Message::Proposal(proposal), out);
+                }
+            }
+        }
+        // Also armed on the proposer, in case its own proposal is missing.
+        self.schedule(Step::Propose, out);
+    }
+
+    // Applies the rules until none fires. Each firing moves the step or the
+    // round forward or is a once-per-round trigger, so this terminates.
+    fn run_rules(&mut self, out: &mut Vec<Output>) {
+        loop {
+            let (height, round) = (self.height, self.round);
+            let step = self.step;
+            self.check_decision(out);
+            if self.height != height {
+                continue;
+            }
+            self.check_round_skip(out);
+            self.check_proposal(out);
+            self.check_prevotes(out);
+            self.check_precommits(out);
+            if (self.height, self.round, self.step) == (height, round, step) {
+                return;
+            }
+        }
+    }
+
+    // A proposal with precommits from a quorum decides, in any round.
+    fn check_decision(&mut self, out: &mut Vec<Output>) {
+        if let Some((block, certificate)) = self.commit.take() {
+            if self.is_valid(&block) {
+                self.decide(block, certificate, out);
+                return;
+            }
+        }
+        let rounds: Vec<u32> = self.proposals.keys().copied().collect();
+        for round in rounds {
+            let block = match self.proposal_with_quorum(round, VoteType::Precommit) {
+                Some(block) if self.is_valid(&block) => block,
+                _ => continue,
+            };
+            let precommits = self.votes[&(round, VoteType::Precommit)]
+                .values()
+                .filter(|vote| vote.block_hash.as_deref() == Some(block.hash.as_str()))
+                .cloned()
+                .collect();
+            let certificate = QuorumCertificate {
+                height: self.height,
+                round,
+                block_hash: block.hash.clone(),
+                precommits,
+            };
+            self.decide(block, certificate, out);
+            return;
+        }
+    }
+
+    fn decide(&mut self, block: Block, certificate: QuorumCertificate, out: &mut Vec<Output>) {
+        out.push(Output::Broadcast(Message::Commit(block.clone(), certificate.clone())));
+        out.push(Output::Decide(block.clone(), certificate));
+        self.next_height(&block, out);
+    }
+
+    // Messages for a later round from more than a third of the stake mean at
+    // least one honest validator is there, so this one catches up.
+    fn check_round_skip(&mut self, out: &mut Vec<Output>) {
+        let mut later = BTreeMap::<u32, HashSet<&str>>::new();
+        for proposal in self.proposals.range(self.round + 1..).flat_map(|(_, proposals)| proposals) {
+            later.entry(proposal.round).or_default().insert(&proposal.proposer);
+        }
+        for ((round, _), votes) in self.votes.range((self.round + 1, VoteType::Prevote)..) {
+            later.entry(*round).or_default().extend(votes.keys().map(String::as_str));
+        }
+        let total = self.validators.total();
+        let skip_to = later.iter().rev().find_map(|(round, senders)| {
+            let power = senders.iter().fold(0u64, |power, sender| power.saturating_add(self.validators.stake(sender)));
+            (power as u128 * 3 > total as u128).then_some(*round)
+        });
+        if let Some(round) = skip_to {
+            self.start_round(round, out);
+        }
+    }
+
+    fn check_proposal(&mut self, out: &mut Vec<Output>) {
+        if self.step != Step::Propose {
+            return;
+        }
+        let proposal = match self.proposals.get(&self.round).and_then(|proposals| proposals.first()) {
+            Some(proposal) => proposal.clone(),
+            None => return,
+        };
+        let block = &proposal.block;
+        let acceptable = match proposal.valid_round {
+            None => match &self.locked {
+                None => true,
+                Some((_, locked)) => locked.hash == block.hash,
+            },
+            // A re-proposal needs the polka it claims, seen by this
+            // validator, and may unlock an older lock.
+            Some(valid_round) if valid_round < self.round => {
+                if !self.is_quorum_for(valid_round, VoteType::Prevote, Some(&block.hash)) {
+                    return;
+                }
+                match &self.locked {
+                    None => true,
+                    Some((locked_round, locked)) => *locked_round <= valid_round || locked.hash == block.hash,
+                }
+            }
+            Some(_) => false,
+        };
+        let vote = if acceptable && self.is_valid(block) {
+            Some(block.hash.clone())
+        } else {
+            None
+        };
+        self.cast(VoteType::Prevote, vote, out);
+    }
+
+    fn check_prevotes(&mut self, out: &mut Vec<Output>) {
+        let round = self.round;
+        if self.step == Step::Prevote
+            && self.is_quorum_for_any(round, VoteType::Prevote)
+            && self.fired.insert((Trigger::PrevoteTimeout, round))
+        {
+            self.schedule(Step::Prevote, out);
+        }
+
+        if self.step >= Step::Prevote {
+            if let Some(block) = self.proposal_with_quorum(round, VoteType::Prevote) {
+                if self.is_valid(&block)
+                    && self.fired.insert((Trigger::Polka, round))
+                {
+                    if self.step == Step::Prevote {
+                        self.locked = Some((round, block.clone()));
+                        self.cast(VoteType::Precommit, Some(block.hash.clone()), out);
+                    }
+                    self.valid = Some((round, block));
+                }
+            }
+        }
+
+        if self.step == Step::Prevote && self.is_quorum_for(round, VoteType::Prevote, None) {
+            self.cast(VoteType::Precommit, None, out);
+        }
+    }
+
+    fn check_precommits(&mut self, out: &mut Vec<Output>) {
+        let round = self.round;
+        if self.is_quorum_for_any(round, VoteType::Precommit) && self.fired.insert((Trigger::PrecommitTimeout, round)) {
+            self.schedule(Step::Precommit, out);
+        }
+    }
+
+    fn next_height(&mut self, decided: &Block, out: &mut Vec<Output>) {
+        self.height += 1;
+        self.validators = self.app.validators(decided);
+        self.locked = None;
+        self.valid = None;
+        self.proposals.clear();
+        self.votes.clear();
+        self.fired.clear();
+        self.commit = None;
+        self.validity.clear();
+        self.future = self.future.split_off(&self.height);
+        self.start_round(0, out);
+        for message in self.future.remove(&self.height).unwrap_or_default() {
+            self.record(message, out);
+        }
+    }
+
+    // Keeps `message` until its height is reached. Only messages the current
+    // validators would accept are kept: the validators seldom change between
+    // heights, and one that joins later only misses the messages it sent
+    // early.
+    fn buffer(&mut self, message: Message) {
+        let height = message.height();
+        let buffered = self.future.get(&height).map(Vec::as_slice).unwrap_or_default();
+        let from_validator = |sender: &str| {
+            message.round() <= FUTURE_ROUNDS
+                && self.validators.stake(sender) > 0
+                && !buffered.contains(&message)
+                && buffered.iter().filter(|known| known.sender() == Some(sender)).count() < FUTURE_MESSAGES_PER_VALIDATOR
+        };
+        let accepted = match &message {
+            Message::Proposal(proposal) => from_validator(&proposal.proposer) && proposal.is_signed(),
+            Message::Vote(vote) => from_validator(&vote.validator) && vote.is_signed(),
+            Message::Commit(block, certificate) => {
+                !buffered.iter().any(|known| matches!(known, Message::Commit(..)))
+                    && block.hash == certificate.block_hash
+                    && certificate.verify(&self.validators)
+            }
+        };
+        if accepted {
+            self.future.entry(height).or_default().push(message);
+        }
+    }
+
+    // Adds `message` to this height's tables. Returns false if it changes
+    // nothing: a duplicate, or from a sender that may not send it.
+    fn record(&mut self, message: Message, out: &mut Vec<Output>) -> bool {
+        if !matches!(message, Message::Commit(..)) && message.round() > self.round.saturating_add(FUTURE_ROUNDS) {
+            return false;
+        }
+        match message {
+            Message::Proposal(proposal) => {
+                if proposer(&self.validators, proposal.height, proposal.round) != Some(proposal.proposer.as_str()) {
+                    return false;
+                }
+                let known = self.proposals.get(&proposal.round).map(Vec::as_slice).unwrap_or_default();
+                if known.len() >= PROPOSALS_PER_ROUND
+                    || known.iter().any(|known| known.block.hash == proposal.block.hash)
+                    || !proposal.is_signed()
+                {
+                    return false;
+                }
+                // Relayed, so validators the proposer left out still get it.
+                if self.validator.as_deref() != Some(proposal.proposer.as_str()) {
+                    out.push(Output::Broadcast(Message::Proposal(proposal.clone())));
+                }
+                self.proposals.entry(proposal.round).or_default().push(proposal);
+                true
+            }
+            Message::Commit(block, certificate) => {
+                if self.commit.is_some() || block.hash != certificate.block_hash || !certificate.verify(&self.validators) {
+                    return false;
+                }
+                self.commit = Some((block, certificate));
+                true
+            }
+            Message::Vote(vote) => {
+                if self.validators.stake(&vote.validator) == 0 {
+                    return false;
+                }
+                let first = self.votes.get(&(vote.round, vote.vote_type)).and_then(|votes| votes.get(&vote.validator));
+                match first {
+                    Some(first) if *first == vote => false,
+                    _ if !vote.is_signed() => false,
+                    Some(first) => {
+                        out.push(Output::Equivocation(first.clone(), vote));
+                        false
+                    }
+                    None => {
+                        let votes = self.votes.entry((vote.round, vote.vote_type)).or_default();
+                        votes.insert(vote.validator.clone(), vote);
+                        true
+                    }
+                }
+            }
+        }
+    }
+
+    // Casts this validator's vote for the current step, which moves it to
+    // the next step.
+    fn cast(&mut self, vote_type: VoteType, block_hash: Option<String>, out: &mut Vec<Output>) {
+        self.step = match vote_type {
+            VoteType::Prevote => Step::Prevote,
+            VoteType::Precommit => Step::Precommit,
+        };
+        if let Some(key) = &self.key {
+            let vote = Vote::signed(key, vote_type, self.height, self.round, block_hash);
+            self.broadcast(Message::Vote(vote), out);
+        }
+    }
+
+    fn broadcast(&mut self, message: Message, out: &mut Vec<Output>) {
+        out.push(Output::Broadcast(message.clone()));
+        self.record(message, out);
+    }
+
+    fn schedule(&self, step: Step, out: &mut Vec<Output>) {
+        let base = match step {
+            Step::Propose => self.timeouts.propose_ms,
+            Step::Prevote => self.timeouts.prevote_ms,
+            Step::Precommit => self.timeouts.precommit_ms,
+        };
+        let timeout = Timeout {
+            step,
+            height: self.height,
+            round: self.round,
+        };
+        let delay = base.saturating_add(self.timeouts.delta_ms.saturating_mul(self.round as u64));
+        out.push(Output::ScheduleTimeout(timeout, Duration::from_millis(delay)));
+    }
+
+    fn is_valid(&mut self, block: &Block) -> bool {
+        if let Some(valid) = self.validity.get(&block.hash) {
+            return *valid;
+        }
+        let valid = block.height == self.height && block.hash == block.compute_hash() && self.app.is_valid(block);
+        self.validity.insert(block.hash.clone(), valid);
+        valid
+    }
+
+    fn power<'a>(&self, votes: impl Iterator<Item = &'a Vote>) -> u64 {
+        votes.fold(0, |power, vote| power.saturating_add(self.validators.stake(&vote.validator)))
+    }
+
+    // The block proposed in `round` that has `vote_type` votes from a
+    // quorum, if any.
+    fn proposal_with_quorum(&self, round: u32, vote_type: VoteType) -> Option<Block> {
+        self.proposals
+            .get(&round)?
+            .iter()
+            .find(|proposal| self.is_quorum_for(round, vote_type, Some(&proposal.block.hash)))
+            .map(|proposal| proposal.block.clone())
+    }
+
+    fn is_quorum_for(&self, round: u32, vote_type: VoteType, block_hash: Option<&String>) -> bool {
+        let votes = match self.votes.get(&(round, vote_type)) {
+            Some(votes) => votes,
+            None => return false,
+        };
+        let power = self.power(votes.values().filter(|vote| vote.block_hash.as_ref() == block_hash));
+        is_quorum(power, self.validators.total())
+    }
+
+    fn is_quorum_for_any(&self, round: u32, vote_type: VoteType) -> bool {
+        match self.votes.get(&(round, vote_type)) {
+            Some(votes) => is_quorum(self.power(votes.values()), self.validators.total()),
+            None => false,
+        }
+    }
+}
+
+// The validator expected to propose in `round` of `height`, drawn by stake.
+pub fn proposer(validators: &StakeRegistry, height: u64, round: u32) -> Option<&str> {
+    validators.proposer(&height.to_string(), round as u64)
+}
+
+// More than two thirds of `total`.
+pub fn is_quorum(power: u64, total: u64) -> bool {
+    total > 0 && power as u128 * 3 > total as u128 * 2
+}
@@ -1,88 +1,283 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 use crate::blockchain::{Blockchain, Block};
+use crate::difficulty::Retarget;
+use crate::fork_choice::{BlockTree, HeaviestWork, MostAttested};
+use crate::genesis::Genesis;
+use crate::hybrid::{self, HybridChain};
 use crate::node::Node;
-use crate::storage::{Storage, StorageError};
+use crate::pow::Miner;
+use crate::rules::{self, unix_time};
+use crate::signing::Keypair;
+use crate::stake::{self, StakeRegistry};
+use crate::storage::Storage;
+
+pub use crate::rules::ConsensusError;
 
 pub trait Consensus {
     fn new(node: Arc<Node>) -> Self;
     fn start(&self);
-    fn propose_block(&self, block: Block) -> Result<(), StorageError>;
+    // Checks `block` against the consensus rules without adding it.
+    fn check_block(&self, block: &Block) -> Result<(), ConsensusError>;
+    // Checks `block` against the consensus rules and, if it passes, adds it
+    // to the chain.
+    fn propose_block(&self, block: Block) -> Result<(), ConsensusError>;
 }
 
 pub struct PoWConsensus {
     node: Arc<Node>,
     blockchain: Arc<Mutex<Blockchain>>,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Storage>,
+    // Every valid block, with the branch with the most work canonical.
+    tree: BlockTree<HeaviestWork>,
+    miner: Miner,
+    // Sets each block's difficulty, starting from `Genesis::difficulty`.
+    retarget: Retarget,
+    // Most pending transactions a mined block includes.
+    block_size: usize,
+    // Set by the chain store whenever the head changes, however the new
+    // head arrived, which makes the block being mined stale.
+    cancel: Arc<AtomicBool>,
 }
 
 impl Consensus for PoWConsensus {
     fn new(node: Arc<Node>) -> Self {
+        let config = node.get_config();
+        let storage = node.get_storage();
         PoWConsensus {
-            node,
             blockchain: node.get_blockchain(),
-            storage: node.get_storage(),
+            tree: BlockTree::new(storage.clone(), HeaviestWork),
+            cancel: storage.chain.watch_head(),
+            storage,
+            miner: Miner::new(config.consensus.mining_threads),
+            retarget: Retarget::new(config.consensus.block_time, node.get_genesis().difficulty),
+            block_size: config.consensus.block_size as usize,
+            node,
         }
     }
 
     fn start(&self) {
         thread::spawn(move || {
             loop {
-                self.mine_block();
-                thread::sleep(std::time::Duration::from_secs(10));
+                if let Err(err) = self.mine_block() {
+                    log::error!("error mining block: {}", err);
+                    thread::sleep(std::time::Duration::from_secs(10));
+                }
             }
         });
     }
 
-    fn propose_block(&self, block: Block) -> Result<(), StorageError> {
-        self.storage.lock().unwrap().add_block(block)
+    fn check_block(&self, block: &Block) -> Result<(), ConsensusError> {
+        rules::check_work(&self.storage, &self.retarget, block, unix_time())
+    }
+
+    fn propose_block(&self, block: Block) -> Result<(), ConsensusError> {
+        self.check_block(&block)?;
+        self.tree.insert(&block)?;
+        Ok(())
     }
 }
 
 impl PoWConsensus {
-    fn mine_block(&self) {
-        // Mine a new block using Proof of Work (PoW) consensus algorithm
-        //...
-        let block = Block::new(/*... */);
-        self.propose_block(block).unwrap();
+    // Mines a block on the current head and proposes it. Returns `None` if
+    // the head changed first and the search was abandoned.
+    fn mine_block(&self) -> Result<Option<Block>, ConsensusError> {
+        // Cleared before reading the head, so a head change from here on
+        // still cancels the search.
+        self.cancel.store(false, Ordering::SeqCst);
+        let parent = self.storage.latest_block()?.ok_or(ConsensusError::NoChainHead)?;
+        let transactions = self.storage.pending_transactions(self.block_size)?;
+        let template = rules::block_template(&self.storage, &self.retarget, &parent, transactions, unix_time())?;
+        let block = match self.miner.mine(&template, &self.cancel) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        self.propose_block(block.clone())?;
+        Ok(Some(block))
     }
 }
 
 pub struct PoSConsensus {
     node: Arc<Node>,
     blockchain: Arc<Mutex<Blockchain>>,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Storage>,
+    genesis: Arc<Genesis>,
+    // Every valid block, with the most attested branch canonical.
+    tree: BlockTree<MostAttested>,
+    // Slot length in seconds.
+    block_time: u64,
+    // Most pending transactions a proposed block includes.
+    block_size: usize,
+    // The key this node proposes with, from `NodeConfig::validator_key`.
+    key: Option<Keypair>,
 }
 
 impl Consensus for PoSConsensus {
     fn new(node: Arc<Node>) -> Self {
+        let config = node.get_config();
+        let storage = node.get_storage();
         PoSConsensus {
-            node,
             blockchain: node.get_blockchain(),
-            storage: node.get_storage(),
+            tree: BlockTree::new(storage.clone(), MostAttested::new(storage.clone(), node.get_genesis())),
+            storage,
+            genesis: node.get_genesis(),
+            block_time: config.consensus.block_time.max(1),
+            block_size: config.consensus.block_size as usize,
+            key: config.node.validator_keypair(),
+            node,
         }
     }
 
     fn start(&self) {
         thread::spawn(move || {
             loop {
-                self.validate_block();
-                thread::sleep(std::time::Duration::from_secs(10));
+                if let Err(err) = self.produce_block() {
+                    log::error!("error producing block: {}", err);
+                }
+                // Wake at the start of the next slot.
+                let elapsed = unix_time().saturating_sub(self.genesis.timestamp) % self.block_time;
+                thread::sleep(std::time::Duration::from_secs(self.block_time - elapsed));
             }
         });
     }
 
-    fn propose_block(&self, block: Block) -> Result<(), StorageError> {
-        self.storage.lock().unwrap().add_block(block)
+    fn check_block(&self, block: &Block) -> Result<(), ConsensusError> {
+        self.checked_stakes(block).map(|_| ())
+    }
+
+    fn propose_block(&self, block: Block) -> Result<(), ConsensusError> {
+        let stakes = self.checked_stakes(&block)?;
+        self.tree.insert_with_stakes(&block, &stakes)?;
+        Ok(())
     }
 }
 
 impl PoSConsensus {
-    fn validate_block(&self) {
-        // Validate a new block using Proof of Stake (PoS) consensus algorithm
-        //...
-        let block = Block::new(/*... */);
-        self.propose_block(block).unwrap();
+    // Checks `block` against the slot, proposer and staking rules. Returns
+    // the stake registry after it.
+    fn checked_stakes(&self, block: &Block) -> Result<StakeRegistry, ConsensusError> {
+        rules::check_proposal(&self.storage, &self.genesis, self.block_time, block, unix_time())
+    }
+
+    // Proposes a block on the current head if this node is the expected
+    // proposer for the current slot.
+    fn produce_block(&self) -> Result<Option<Block>, ConsensusError> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let parent = self.storage.latest_block()?.ok_or(ConsensusError::NoChainHead)?;
+        let now = unix_time();
+        let slot = self.slot(now);
+        if slot <= self.slot(parent.timestamp) {
+            return Ok(None);
+        }
+        let validator = key.address();
+        if rules::slot_proposer(&self.storage, &self.genesis, &parent, slot)?.as_deref() != Some(validator.as_str()) {
+            return Ok(None);
+        }
+
+        let transactions = self.storage.pending_transactions(self.block_size)?;
+        // Transactions are not executed yet, so the state carries over.
+        let mut block = Block::with_transactions(parent.height + 1, &parent.hash, now, parent.state_root, transactions);
+        block.proposer = validator;
+        block.hash = block.compute_hash();
+        block.sign_proposal(key);
+        self.propose_block(block.clone())?;
+        Ok(Some(block))
+    }
+
+    fn slot(&self, timestamp: u64) -> u64 {
+        stake::slot(self.genesis.timestamp, self.block_time, timestamp)
+    }
+}
+
+pub struct HybridConsensus {
+    node: Arc<Node>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    storage: Arc<Storage>,
+    // Holds mined blocks until staked validators ratify them.
+    chain: HybridChain,
+    miner: Miner,
+    // Most pending transactions a mined block includes.
+    block_size: usize,
+    // The key this node votes with, if it is a validator.
+    key: Option<Keypair>,
+    // Set by the chain store whenever the head changes, e.g. once votes
+    // ratify a block, which makes the block being mined stale.
+    cancel: Arc<AtomicBool>,
+}
+
+impl Consensus for HybridConsensus {
+    fn new(node: Arc<Node>) -> Self {
+        let config = node.get_config();
+        let storage = node.get_storage();
+        let retarget = Retarget::new(config.consensus.block_time, node.get_genesis().difficulty);
+        HybridConsensus {
+            blockchain: node.get_blockchain(),
+            chain: HybridChain::new(storage.clone(), node.get_genesis(), retarget, config.consensus.hybrid),
+            cancel: storage.chain.watch_head(),
+            storage,
+            miner: Miner::new(config.consensus.mining_threads),
+            block_size: config.consensus.block_size as usize,
+            key: config.node.validator_keypair(),
+            node,
+        }
+    }
+
+    fn start(&self) {
+        thread::spawn(move || {
+            loop {
+                if let Err(err) = self.mine_block() {
+                    log::error!("error mining block: {}", err);
+                    thread::sleep(std::time::Duration::from_secs(10));
+                }
+            }
+        });
+    }
+
+    fn check_block(&self, block: &Block) -> Result<(), ConsensusError> {
+        self.chain.check(block, unix_time())
     }
+
+    // Accepts `block` for ratification and, if this node is a validator,
+    // votes on it. It joins the chain once enough stake has approved it.
+    fn propose_block(&self, block: Block) -> Result<(), ConsensusError> {
+        // Judged before the import, which may already make it the head.
+        let approve = self.chain.should_approve(&block)?;
+        self.chain.import(&block, unix_time())?;
+        if let Some(key) = &self.key {
+            let signature = hybrid::sign_vote(key, &block.hash, approve);
+            self.chain.vote(&key.address(), &block.hash, approve, &signature)?;
         }
+        Ok(())
+    }
+}
+
+impl HybridConsensus {
+    // Records a vote from another validator, which must be signed with its
+    // key; see `hybrid::sign_vote`.
+    pub fn receive_vote(&self, validator: &str, block_hash: &str, approve: bool, signature: &str) -> Result<(), ConsensusError> {
+        self.chain.vote(validator, block_hash, approve, signature)?;
+        Ok(())
+    }
+
+    // Mines a block on the current head and proposes it. Returns `None` if
+    // the head changed first and the search was abandoned.
+    fn mine_block(&self) -> Result<Option<Block>, ConsensusError> {
+        // Cleared before reading the head, so a head change from here on
+        // still cancels the search.
+        self.cancel.store(false, Ordering::SeqCst);
+        let parent = self.storage.latest_block()?.ok_or(ConsensusError::NoChainHead)?;
+        let transactions = self.storage.pending_transactions(self.block_size)?;
+        let template = self.chain.template(&parent, transactions, unix_time())?;
+        let block = match self.miner.mine(&template, &self.cancel) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        self.propose_block(block.clone())?;
+        Ok(Some(block))
+    }
+}
@@ -0,0 +1,115 @@
+// difficulty.rs
+//
+// Proof-of-work difficulty retargeting. Each block's difficulty is set from
+// the solve times of the blocks before it with a linearly weighted moving
+// average (LWMA): the newest solve times weigh most, so the difficulty
+// follows hashrate changes within a few blocks while a single odd block
+// moves it only slightly. Everything is integer arithmetic on chain data,
+// so every node computes the same value.
+//
+// Miners choose their timestamps, so the algorithm never trusts one:
+// timestamps earlier than their predecessor count as one second later,
+// solve times are capped at `MAX_SOLVE_TIME_FACTOR` block times, and
+// `check_timestamp` bounds each timestamp by the median of recent blocks
+// and the local clock.
+
+use crate::blockchain::Block;
+use crate::pow::PowError;
+
+// Solve times the average covers.
+pub const RETARGET_WINDOW: usize = 60;
+// Blocks whose median timestamp a new block must exceed.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// A solve time counts as at most this many block times, and a timestamp
+// may be at most this many block times ahead of the local clock.
+const MAX_SOLVE_TIME_FACTOR: u64 = 6;
+
+pub struct Retarget {
+    // Target seconds between blocks; `ConsensusConfig::block_time`.
+    block_time: u64,
+    // Difficulty of the first mined blocks; `Genesis::difficulty`.
+    initial: u64,
+    window: usize,
+}
+
+impl Retarget {
+    pub fn new(block_time: u64, initial: u64) -> Self {
+        Retarget::with_window(block_time, initial, RETARGET_WINDOW)
+    }
+
+    pub fn with_window(block_time: u64, initial: u64, window: usize) -> Self {
+        Retarget {
+            block_time: block_time.max(1),
+            initial: initial.max(1),
+            window: window.max(1),
+        }
+    }
+
+    // Blocks `next_difficulty` looks at: the parent and those before it.
+    pub fn history_len(&self) -> usize {
+        (self.window + 1).max(MEDIAN_TIME_SPAN)
+    }
+
+    // Difficulty of the block after the last one in `history`, which holds
+    // consecutive blocks, oldest first. With fewer than two blocks there are
+    // no solve times yet and the initial difficulty applies.
+    pub fn next_difficulty(&self, history: &[Block]) -> u64 {
+        let history = &history[history.len().saturating_sub(self.window + 1)..];
+        if history.len() < 2 {
+            return self.initial;
+        }
+        let solves = (history.len() - 1) as u128;
+        let block_time = self.block_time as u128;
+
+        let mut previous = history[0].timestamp;
+        let mut weighted_solve_time = 0u128;
+        let mut difficulty_sum = 0u128;
+        for (weight, block) in (1..).zip(&history[1..]) {
+            let timestamp = block.timestamp.max(previous + 1);
+            let solve_time = (timestamp - previous).min(MAX_SOLVE_TIME_FACTOR * self.block_time);
+            previous = timestamp;
+            weighted_solve_time += weight * solve_time as u128;
+            difficulty_sum += self.difficulty_of(block) as u128;
+        }
+        // Caps the rise after a run of near-instant blocks at about ten times
+        // the average.
+        weighted_solve_time = weighted_solve_time.max(solves * solves * block_time / 20);
+
+        // The average difficulty, scaled by how far the weighted mean solve
+        // time is from the block time.
+        let next = difficulty_sum * block_time * (solves + 1) / (2 * weighted_solve_time);
+        next.clamp(1, u64::MAX as u128) as u64
+    }
+
+    // Checks that `block`, to follow `history`, is later than the median of
+    // the last `MEDIAN_TIME_SPAN` blocks and not too far past `now`.
+    pub fn check_timestamp(&self, block: &Block, history: &[Block], now: u64) -> Result<(), PowError> {
+        if let Some(median) = median_time_past(history) {
+            if block.timestamp <= median {
+                return Err(PowError::TimestampTooOld { median });
+            }
+        }
+        let limit = now.saturating_add(MAX_SOLVE_TIME_FACTOR * self.block_time);
+        if block.timestamp > limit {
+            return Err(PowError::TimestampTooNew { limit });
+        }
+        Ok(())
+    }
+
+    // Blocks that were not mined, like the genesis block, count as the
+    // initial difficulty.
+    fn difficulty_of(&self, block: &Block) -> u64 {
+        match block.difficulty {
+            0 => self.initial,
+            difficulty => difficulty,
+        }
+    }
+}
+
+// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks in `history`.
+pub fn median_time_past(history: &[Block]) -> Option<u64> {
+    let recent = &history[history.len().saturating_sub(MEDIAN_TIME_SPAN)..];
+    let mut timestamps: Vec<u64> = recent.iter().map(|block| block.timestamp).collect();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied()
+}
@@ -0,0 +1,364 @@
+// finality.rs
+//
+// A finality gadget on top of whichever consensus extends the chain. Every
+// `FinalityConfig::checkpoint_interval` blocks is a checkpoint. Validators
+// sign the canonical checkpoint at each interval, and once signatures from
+// more than two thirds of the stake, as of the checkpoint's parent, are in,
+// the checkpoint and every block before it are final. Those signatures are
+// kept as the checkpoint's `FinalityProof`, which peers and clients can
+// check against the stake registry without trusting this node.
+//
+// A final block is never replaced: fork choice ignores branches that split
+// below the latest final checkpoint, and the chain store refuses any write
+// that would replace it (`DbError::Finalized`). As with BFT decisions, two
+// conflicting checkpoints at the same height can only both be final if
+// more than a third of the stake signed both.
+//
+// An honest validator only signs checkpoints on its canonical chain above
+// the latest final one, and never two checkpoints at the same height; the
+// heights it signed are stored, so that holds across restarts. Signatures
+// arriving for a checkpoint that is not canonical here are kept; `update`
+// finalizes it should it become canonical. Votes for canonical checkpoints
+// are counted as they arrive, so `update` only looks at the checkpoints that
+// became canonical since its last call: those above the highest one it
+// examined, or above the fork point if a reorg has replaced that one.
+//
+// Votes are signed with the validator's key (see signing.rs). Only votes
+// from staked validators for checkpoints above the latest final one are
+// stored, and those for checkpoints this node has not stored yet wait in
+// memory, a bounded number of them, until their block arrives. Votes are
+// deleted once their checkpoint is final, when the proof stands for them,
+// or pruned.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bft;
+use crate::blockchain::Block;
+use crate::chain::Checkpoint;
+use crate::config::FinalityConfig;
+use crate::genesis::Genesis;
+use crate::rules::{self, ConsensusError};
+use crate::signing::{self, Keypair};
+use crate::stake::StakeRegistry;
+use crate::storage::{Storage, StorageError};
+
+const VOTE_DOMAIN: &str = "pi-sentinel checkpoint vote";
+// Most votes kept for checkpoints that are not stored yet.
+const MAX_PENDING_VOTES: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckpointVote {
+    pub height: u64,
+    pub block_hash: String,
+    pub validator: String,
+    pub signature: String,
+}
+
+impl CheckpointVote {
+    // A vote for the checkpoint `block_hash` at `height`, signed by `key`.
+    pub fn signed(key: &Keypair, height: u64, block_hash: &str) -> Self {
+        let mut vote = CheckpointVote {
+            height,
+            block_hash: block_hash.to_string(),
+            validator: key.address(),
+            signature: String::new(),
+        };
+        vote.signature = key.sign(VOTE_DOMAIN, &vote.signed_bytes());
+        vote
+    }
+
+    pub fn is_signed(&self) -> bool {
+        signing::verify(&self.validator, VOTE_DOMAIN, &self.signed_bytes(), &self.signature)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.height.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.block_hash.as_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FinalityProof {
+    pub height: u64,
+    pub block_hash: String,
+    pub votes: Vec<CheckpointVote>,
+}
+
+impl FinalityProof {
+    // Whether the votes are signed, are for this proof's checkpoint and come
+    // from more than two thirds of `validators`' stake.
+    pub fn verify(&self, validators: &StakeRegistry) -> bool {
+        let mut signers = HashSet::new();
+        let mut power = 0u64;
+        for vote in &self.votes {
+            if vote.height != self.height || vote.block_hash != self.block_hash || !vote.is_signed() {
+                return false;
+            }
+            if signers.insert(vote.validator.as_str()) {
+                power = power.saturating_add(validators.stake(&vote.validator));
+            }
+        }
+        bft::is_quorum(power, validators.total())
+    }
+}
+
+pub struct FinalityGadget {
+    storage: Arc<Storage>,
+    genesis: Arc<Genesis>,
+    config: FinalityConfig,
+    // Votes for checkpoints not stored yet, by height and validator.
+    pending: Mutex<BTreeMap<(u64, String), CheckpointVote>>,
+    // Held while signing, so two callers cannot both sign a height.
+    signing: Mutex<()>,
+    // The oldest kept height when votes were last pruned.
+    pruned_below: AtomicU64,
+    // The highest checkpoint `update` has examined.
+    examined: Mutex<Option<Checkpoint>>,
+}
+
+impl FinalityGadget {
+    pub fn new(storage: Arc<Storage>, genesis: Arc<Genesis>, config: FinalityConfig) -> Self {
+        FinalityGadget {
+            storage,
+            genesis,
+            config,
+            pending: Mutex::new(BTreeMap::new()),
+            signing: Mutex::new(()),
+            pruned_below: AtomicU64::new(0),
+            examined: Mutex::new(None),
+        }
+    }
+
+    pub fn checkpoint_interval(&self) -> u64 {
+        self.config.checkpoint_interval
+    }
+
+    // An interval of 0 turns checkpoints off. The genesis block needs no
+    // checkpoint, since every chain starts with it.
+    pub fn is_checkpoint(&self, height: u64) -> bool {
+        height > 0 && height.checked_rem(self.config.checkpoint_interval) == Some(0)
+    }
+
+    // The stakes that sign `checkpoint`: those after its parent.
+    pub fn validators(&self, checkpoint: &Block) -> Result<StakeRegistry, ConsensusError> {
+        let parent = rules::parent_of(&self.storage, checkpoint)?;
+        rules::stakes_after(&self.storage, &self.genesis, &parent)
+    }
+
+    // Whether an honest validator signs `block`: a canonical checkpoint
+    // above the latest final one.
+    pub fn should_sign(&self, block: &Block) -> Result<bool, ConsensusError> {
+        Ok(self.is_checkpoint(block.height) && self.storage.is_canonical(block)? && !self.is_settled(block.height)?)
+    }
+
+    // Whether `block` is canonical and at or below the latest final
+    // checkpoint.
+    pub fn is_final(&self, block: &Block) -> Result<bool, ConsensusError> {
+        Ok(self.is_settled(block.height)? && self.storage.is_canonical(block)?)
+    }
+
+    // Signs `block` with `key` as an honest validator would (see
+    // `should_sign`), unless `key` has no stake or has signed a checkpoint
+    // at that height before, and records the vote. The height is stored as
+    // signed before the vote is made. Returns the vote to send to the other
+    // validators.
+    pub fn sign(&self, key: &Keypair, block: &Block) -> Result<Option<CheckpointVote>, ConsensusError> {
+        let _signing = self.signing.lock().unwrap_or_else(PoisonError::into_inner);
+        let validator = key.address();
+        if !self.should_sign(block)?
+            || self.validators(block)?.stake(&validator) == 0
+            || self.storage.signed_checkpoint(&validator, block.height)?.is_some()
+        {
+            return Ok(None);
+        }
+        self.storage.put_signed_checkpoint(&validator, block.height, &block.hash)?;
+        let vote = CheckpointVote::signed(key, block.height, &block.hash);
+        self.vote(vote.clone())?;
+        Ok(Some(vote))
+    }
+
+    // Records `vote`, which may arrive before its block. Returns the proof
+    // if the vote made its checkpoint final.
+    pub fn vote(&self, vote: CheckpointVote) -> Result<Option<FinalityProof>, ConsensusError> {
+        if !self.is_checkpoint(vote.height) {
+            return Err(ConsensusError::InvalidBlock(format!("height {} is not a checkpoint", vote.height)));
+        }
+        if self.is_settled(vote.height)? {
+            return Err(ConsensusError::InvalidVote(format!("height {} is already final", vote.height)));
+        }
+        if !vote.is_signed() {
+            return Err(ConsensusError::InvalidVote(format!(
+                "bad signature from {} on checkpoint {}",
+                vote.validator, vote.block_hash
+            )));
+        }
+        let block = match self.storage.get_block(&vote.block_hash)? {
+            Some(block) => block,
+            None => {
+                self.hold(vote)?;
+                return Ok(None);
+            }
+        };
+        if block.height != vote.height {
+            return Err(ConsensusError::InvalidBlock(format!(
+                "vote for block {} at height {} is at height {}",
+                block.hash, block.height, vote.height
+            )));
+        }
+        if self.validators(&block)?.stake(&vote.validator) == 0 {
+            return Err(ConsensusError::InvalidVote(format!("{} has no stake at checkpoint {}", vote.validator, block.hash)));
+        }
+        self.storage.put_checkpoint_vote(&vote)?;
+        self.try_finalize(&block)
+    }
+
+    // Checks `proof` from a peer and keeps its votes. Returns whether it
+    // made its checkpoint final; a valid proof for a checkpoint that is not
+    // canonical here only takes effect once it is, see `update`.
+    pub fn import_proof(&self, proof: &FinalityProof) -> Result<bool, ConsensusError> {
+        let block = self
+            .storage
+            .get_block(&proof.block_hash)?
+            .ok_or_else(|| ConsensusError::MissingBlock(proof.block_hash.clone()))?;
+        if block.height != proof.height || !self.is_checkpoint(block.height) {
+            return Err(ConsensusError::InvalidBlock(format!("invalid finality proof for block {}", block.hash)));
+        }
+        let validators = self.validators(&block)?;
+        if !proof.verify(&validators) {
+            return Err(ConsensusError::InvalidBlock(format!("invalid finality proof for block {}", block.hash)));
+        }
+        if self.is_settled(block.height)? {
+            return Ok(false);
+        }
+        for vote in proof.votes.iter().filter(|vote| validators.stake(&vote.validator) > 0) {
+            self.storage.put_checkpoint_vote(vote)?;
+        }
+        Ok(self.try_finalize(&block)?.is_some())
+    }
+
+    // Finalizes the newest canonical checkpoint whose votes have reached
+    // the supermajority, e.g. after a reorg made it canonical. Stores the
+    // held votes whose blocks have arrived and prunes the votes of
+    // checkpoints below the oldest kept block on the way.
+    pub fn update(&self) -> Result<Option<FinalityProof>, ConsensusError> {
+        let interval = self.config.checkpoint_interval;
+        let head = match self.storage.latest_block()? {
+            Some(head) if interval > 0 => head,
+            _ => return Ok(None),
+        };
+        let held = std::mem::take(&mut *self.pending());
+        let mut stored = None;
+        for vote in held.into_values() {
+            match self.vote(vote) {
+                Ok(Some(proof)) => stored = Some(proof),
+                Ok(None) | Err(ConsensusError::InvalidVote(_)) | Err(ConsensusError::InvalidBlock(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let oldest = self.storage.chain.oldest().map_err(StorageError::from)?;
+        if self.pruned_below.swap(oldest, Ordering::SeqCst) < oldest {
+            self.storage.prune_checkpoint_votes(oldest)?;
+        }
+        let mut examined = self.examined.lock().unwrap_or_else(PoisonError::into_inner);
+        let lowest = self.unexamined(examined.as_ref())?.max(oldest);
+        let top = head.height - head.height % interval;
+        let mut height = top;
+        while height > 0 && height >= lowest && !self.is_settled(height)? {
+            if let Some(block) = self.storage.chain.block(height).map_err(StorageError::from)? {
+                if let Some(proof) = self.try_finalize(&block)? {
+                    stored = Some(proof);
+                    break;
+                }
+            }
+            height -= interval;
+        }
+        if let Some(block) = self.storage.chain.block(top).map_err(StorageError::from)? {
+            *examined = Some(Checkpoint {
+                height: block.height,
+                hash: block.hash,
+            });
+        }
+        Ok(stored)
+    }
+
+    // The lowest height whose canonical block `update` may not have
+    // examined: just above `examined` while it is canonical, and otherwise
+    // just above the canonical block its branch forks from.
+    fn unexamined(&self, examined: Option<&Checkpoint>) -> Result<u64, ConsensusError> {
+        let mut current = match examined {
+            Some(examined) => self.storage.get_block(&examined.hash)?,
+            None => None,
+        };
+        while let Some(block) = current {
+            if self.storage.is_canonical(&block)? {
+                return Ok(block.height + 1);
+            }
+            current = self.storage.get_block(&block.parent_hash)?;
+        }
+        Ok(0)
+    }
+
+    pub fn proof(&self, block_hash: &str) -> Result<Option<FinalityProof>, ConsensusError> {
+        Ok(self.storage.finality_proof(block_hash)?)
+    }
+
+    fn try_finalize(&self, block: &Block) -> Result<Option<FinalityProof>, ConsensusError> {
+        if self.is_settled(block.height)? || !self.storage.is_canonical(block)? {
+            return Ok(None);
+        }
+        let validators = self.validators(block)?;
+        let votes = self
+            .storage
+            .checkpoint_votes(&block.hash)?
+            .into_iter()
+            .filter(|vote| vote.height == block.height && validators.stake(&vote.validator) > 0)
+            .collect();
+        let proof = FinalityProof {
+            height: block.height,
+            block_hash: block.hash.clone(),
+            votes,
+        };
+        if !proof.verify(&validators) {
+            return Ok(None);
+        }
+        self.storage.finalize(block, &proof)?;
+        Ok(Some(proof))
+    }
+
+    // Keeps `vote` for a checkpoint not stored yet. It must come from a
+    // validator staked as of the head, be for a height at most one interval
+    // above the head, and be its validator's first at that height.
+    fn hold(&self, vote: CheckpointVote) -> Result<(), ConsensusError> {
+        let head = self.storage.latest_block()?.ok_or(ConsensusError::NoChainHead)?;
+        if vote.height > head.height.saturating_add(self.config.checkpoint_interval) {
+            return Err(ConsensusError::InvalidVote(format!(
+                "checkpoint at height {} is too far above the head at {}",
+                vote.height, head.height
+            )));
+        }
+        if rules::stakes_after(&self.storage, &self.genesis, &head)?.stake(&vote.validator) == 0 {
+            return Err(ConsensusError::InvalidVote(format!("{} has no stake", vote.validator)));
+        }
+        let mut pending = self.pending();
+        let key = (vote.height, vote.validator.clone());
+        if pending.len() >= MAX_PENDING_VOTES && !pending.contains_key(&key) {
+            return Err(ConsensusError::InvalidVote("too many votes for unknown checkpoints".to_string()));
+        }
+        pending.entry(key).or_insert(vote);
+        Ok(())
+    }
+
+    fn pending(&self) -> MutexGuard<'_, BTreeMap<(u64, String), CheckpointVote>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Whether `height` is at or below the latest final checkpoint.
+    fn is_settled(&self, height: u64) -> Result<bool, ConsensusError> {
+        Ok(self.storage.finalized()?.is_some_and(|finalized| height <= finalized.height))
+    }
+}
@@ -0,0 +1,272 @@
+// fork_choice.rs
+//
+// The block tree and fork choice. Every valid block is stored, on whichever
+// branch it extends, and the tips index (see chain.rs) leads to the end of
+// each branch. `update_head` compares every branch with the canonical
+// chain from their common ancestor up: a branch takes over when the blocks
+// it adds outweigh the canonical blocks they replace or, at equal weight,
+// outnumber them. Otherwise the current chain stays, so a tie never causes
+// a reorg.
+//
+// What a block weighs is up to the `ForkChoice` rule: its work under proof
+// of work, the stake that attested it under proof of stake, work and stake
+// together for the hybrid. A rule can also hold blocks back; a branch then
+// only counts up to its first block that is not yet eligible.
+//
+// Switching branches goes through `Storage::reorg`, which unwinds the
+// indexes and the pending pool to the common ancestor and applies the new
+// branch in one write. Branches that fork below the oldest kept block can
+// never win, since the blocks they would replace are pruned, and neither can
+// those that fork below the latest final checkpoint (see finality.rs) or more
+// than `MAX_FORK_DEPTH` blocks below the head; all of them are deleted when
+// `update_head` comes across them.
+//
+// Each `update_head` weighs every block once: branches share their blocks'
+// weights, and the canonical blocks they would replace are summed from the
+// head down once for all of them. The depth limit keeps that work bounded
+// when nothing is final and nothing is pruned, so an insert does not cost
+// more as the chain grows.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::blockchain::Block;
+use crate::genesis::Genesis;
+use crate::rules::{self, ConsensusError};
+use crate::stake::StakeRegistry;
+use crate::storage::{Reorg, Storage, StorageError};
+
+// How far below the head a branch may fork and still take over. Deeper
+// forks are deleted, so a reorg never unwinds more blocks than this.
+pub const MAX_FORK_DEPTH: u64 = 100;
+
+pub trait ForkChoice {
+    // What `block` adds to the weight of its branch.
+    fn weight(&self, block: &Block) -> Result<u128, ConsensusError>;
+
+    // Whether `block` may join the canonical chain yet.
+    fn is_eligible(&self, _block: &Block) -> Result<bool, ConsensusError> {
+        Ok(true)
+    }
+}
+
+// Proof of work: the branch with the most work.
+pub struct HeaviestWork;
+
+impl ForkChoice for HeaviestWork {
+    fn weight(&self, block: &Block) -> Result<u128, ConsensusError> {
+        Ok(block.difficulty as u128)
+    }
+}
+
+// Proof of stake: the branch whose blocks the most stake approved, counted
+// as of each block's parent. Without votes, the longest branch.
+pub struct MostAttested {
+    storage: Arc<Storage>,
+    genesis: Arc<Genesis>,
+}
+
+impl MostAttested {
+    pub fn new(storage: Arc<Storage>, genesis: Arc<Genesis>) -> Self {
+        MostAttested { storage, genesis }
+    }
+}
+
+impl ForkChoice for MostAttested {
+    fn weight(&self, block: &Block) -> Result<u128, ConsensusError> {
+        let (approving, _) = rules::attestations(&self.storage, &self.genesis, block)?;
+        Ok(approving as u128)
+    }
+}
+
+pub struct BlockTree<F> {
+    storage: Arc<Storage>,
+    fork_choice: F,
+}
+
+impl<F: ForkChoice> BlockTree<F> {
+    pub fn new(storage: Arc<Storage>, fork_choice: F) -> Self {
+        BlockTree { storage, fork_choice }
+    }
+
+    pub fn fork_choice(&self) -> &F {
+        &self.fork_choice
+    }
+
+    // Adds `block`, which has passed the consensus rules, and moves the head
+    // to the best branch. Returns the reorg if the head moved. A block that
+    // could only replace a final one is refused.
+    pub fn insert(&self, block: &Block) -> Result<Option<Reorg>, ConsensusError> {
+        self.admit(block)?;
+        self.storage.store_block(block)?;
+        self.update_head()
+    }
+
+    // `insert`, storing `stakes`, the registry after `block`, in the same
+    // write as the block.
+    pub fn insert_with_stakes(&self, block: &Block, stakes: &StakeRegistry) -> Result<Option<Reorg>, ConsensusError> {
+        self.admit(block)?;
+        self.storage.store_block_with_stakes(block, stakes)?;
+        self.update_head()
+    }
+
+    // Checks that `block` can join the tree at all.
+    fn admit(&self, block: &Block) -> Result<(), ConsensusError> {
+        if self.storage.get_block(&block.parent_hash)?.is_none() {
+            return Err(ConsensusError::InvalidBlock(format!("unknown parent {}", block.parent_hash)));
+        }
+        let head = self.storage.latest_block()?.ok_or(ConsensusError::NoChainHead)?;
+        if block.height + MAX_FORK_DEPTH <= head.height && !self.storage.is_canonical(block)? {
+            return Err(ConsensusError::InvalidBlock(format!(
+                "block {} forks more than {} blocks below the head",
+                block.hash, MAX_FORK_DEPTH
+            )));
+        }
+        if let Some(finalized) = self.storage.finalized()? {
+            if block.height <= finalized.height && !self.storage.is_canonical(block)? {
+                return Err(ConsensusError::InvalidBlock(format!(
+                    "block {} conflicts with the final block at height {}",
+                    block.hash, finalized.height
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Moves the head to the best branch, e.g. after votes changed weights.
+    pub fn update_head(&self) -> Result<Option<Reorg>, ConsensusError> {
+        update_head(&self.storage, &self.fork_choice)
+    }
+}
+
+// How far a branch beats the canonical chain: its weight, then its length,
+// each minus that of the canonical blocks it would replace.
+type Score = (i128, i64);
+
+// A branch as `update_head` finds it.
+enum Branch {
+    // The eligible part of the branch, from just above the canonical block
+    // at `ancestor`, and its weight.
+    Candidate { ancestor: u64, blocks: Vec<Block>, weight: u128 },
+    // Nothing of it is eligible yet, or it is the canonical chain itself.
+    Waiting,
+    // It cannot be traced back to a canonical block that may still be
+    // replaced, so it can never become canonical.
+    Dead,
+}
+
+// Reorgs `storage` to the best branch under `fork_choice`, if that is not
+// already the canonical chain. Branches that can no longer win are deleted
+// on the way, so each call only walks branches that still could.
+pub fn update_head(storage: &Storage, fork_choice: &impl ForkChoice) -> Result<Option<Reorg>, ConsensusError> {
+    let head = storage.latest_block()?.ok_or(ConsensusError::NoChainHead)?;
+    // Nothing at or below the latest final block may be replaced, nor
+    // anything more than `MAX_FORK_DEPTH` blocks deep.
+    let oldest = storage.chain.oldest().map_err(StorageError::from)?.max(head.height.saturating_sub(MAX_FORK_DEPTH));
+    let oldest = match storage.finalized()? {
+        Some(finalized) => oldest.max(finalized.height),
+        None => oldest,
+    };
+    // Branches share blocks, so each block is weighed once; `None` for
+    // blocks that are not eligible yet.
+    let mut weights = HashMap::new();
+    let mut candidates = Vec::new();
+    for tip in storage.tips()? {
+        match branch(storage, fork_choice, &mut weights, oldest, &tip)? {
+            Branch::Candidate { ancestor, blocks, weight } => candidates.push((ancestor, blocks, weight)),
+            Branch::Waiting => {}
+            Branch::Dead => {
+                storage.prune_branch(&tip)?;
+            }
+        }
+    }
+
+    // The weight of the canonical blocks above each height, summed once
+    // from the head down to the lowest common ancestor.
+    let lowest = match candidates.iter().map(|(ancestor, _, _)| *ancestor).min() {
+        Some(lowest) => lowest,
+        None => return Ok(None),
+    };
+    let mut replaced = vec![0u128; (head.height - lowest) as usize + 1];
+    for height in (lowest + 1..=head.height).rev() {
+        let weight = match storage.chain.block(height).map_err(StorageError::from)? {
+            Some(block) => fork_choice.weight(&block)?,
+            None => 0,
+        };
+        let above = (height - lowest) as usize;
+        replaced[above - 1] = replaced[above] + weight;
+    }
+
+    let mut best: Option<(Score, Vec<Block>)> = None;
+    for (ancestor, blocks, weight) in candidates {
+        let score = (
+            weight as i128 - replaced[(ancestor - lowest) as usize] as i128,
+            blocks.len() as i64 - (head.height - ancestor) as i64,
+        );
+        if score > (0, 0) && best.as_ref().is_none_or(|(best, _)| score > *best) {
+            best = Some((score, blocks));
+        }
+    }
+    match best {
+        Some((_, branch)) => Ok(Some(storage.reorg(&branch)?)),
+        None => Ok(None),
+    }
+}
+
+// The branch ending at `tip`, which is dead unless it leads back to a
+// canonical block at or above `oldest`.
+fn branch(
+    storage: &Storage,
+    fork_choice: &impl ForkChoice,
+    weights: &mut HashMap<String, Option<u128>>,
+    oldest: u64,
+    tip: &Block,
+) -> Result<Branch, ConsensusError> {
+    let mut blocks = Vec::new();
+    let mut current = tip.clone();
+    loop {
+        if current.height < oldest {
+            return Ok(Branch::Dead);
+        }
+        if storage.is_canonical(&current)? {
+            break;
+        }
+        let parent = match storage.get_block(&current.parent_hash)? {
+            Some(parent) => parent,
+            None => return Ok(Branch::Dead),
+        };
+        blocks.push(current);
+        current = parent;
+    }
+    blocks.reverse();
+    let mut weight = 0u128;
+    let mut eligible = 0;
+    for block in &blocks {
+        let added = match weights.get(&block.hash) {
+            Some(added) => *added,
+            None => {
+                let added = if fork_choice.is_eligible(block)? {
+                    Some(fork_choice.weight(block)?)
+                } else {
+                    None
+                };
+                weights.insert(block.hash.clone(), added);
+                added
+            }
+        };
+        match added {
+            Some(added) => weight += added,
+            None => break,
+        }
+        eligible += 1;
+    }
+    blocks.truncate(eligible);
+    if blocks.is_empty() {
+        return Ok(Branch::Waiting);
+    }
+    Ok(Branch::Candidate {
+        ancestor: current.height,
+        blocks,
+        weight,
+    })
+}
@@ -0,0 +1,212 @@
+// hybrid.rs
+//
+// Proof of work ratified by stake. Miners extend the chain as under
+// `PoWConsensus`, but a mined block only counts toward the canonical chain
+// once validators holding `HybridConfig::ratification_threshold` percent of
+// the stake, as of its parent, have approved it. Until then it is stored by
+// hash and waits for votes.
+//
+// Among branches whose blocks are all ratified, the heaviest is canonical
+// (see fork_choice.rs, with this chain as the rule). A block weighs its
+// difficulty times `pow_weight` percent, plus its difficulty times the
+// approving share of stake for the remaining percent, so a lower
+// `pow_weight` favours blocks with broad approval.
+//
+// Every vote is signed by the validator's key (see signing.rs), so nobody
+// can approve a block with stake they do not hold, and votes from
+// validators without stake are refused. Votes for a block not stored yet
+// wait in memory, a bounded number of them, until it is imported. Stored
+// votes are pruned with their block. A chain without stake ratifies
+// nothing.
+//
+// Honest validators approve only blocks that extend the canonical chain.
+// A branch mined in private is therefore rejected when it is published:
+// however much work it carries, it cannot replace the canonical chain
+// without the threshold of stake behind it.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::blockchain::{Block, Transaction};
+use crate::config::HybridConfig;
+use crate::difficulty::Retarget;
+use crate::fork_choice::{self, ForkChoice};
+use crate::genesis::Genesis;
+use crate::rules::{self, ConsensusError};
+use crate::signing::{self, Keypair};
+use crate::storage::Storage;
+
+const VOTE_DOMAIN: &str = "pi-sentinel hybrid vote";
+// Most votes kept for blocks that are not stored yet, in all and from one
+// validator.
+const MAX_PENDING_VOTES: usize = 1024;
+const MAX_PENDING_VOTES_PER_VALIDATOR: usize = 16;
+
+// `key`'s signature on its vote for the block with `block_hash`.
+pub fn sign_vote(key: &Keypair, block_hash: &str, approve: bool) -> String {
+    key.sign(VOTE_DOMAIN, &vote_message(block_hash, approve))
+}
+
+fn vote_message(block_hash: &str, approve: bool) -> Vec<u8> {
+    let mut message = block_hash.as_bytes().to_vec();
+    message.push(approve as u8);
+    message
+}
+
+pub struct HybridChain {
+    storage: Arc<Storage>,
+    genesis: Arc<Genesis>,
+    retarget: Retarget,
+    config: HybridConfig,
+    // Votes for blocks not stored yet, by block hash and validator.
+    pending: Mutex<BTreeMap<(String, String), bool>>,
+}
+
+impl HybridChain {
+    pub fn new(storage: Arc<Storage>, genesis: Arc<Genesis>, retarget: Retarget, config: HybridConfig) -> Self {
+        HybridChain {
+            storage,
+            genesis,
+            retarget,
+            config,
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // An unmined block on `parent`; see `rules::block_template`.
+    pub fn template(&self, parent: &Block, transactions: Vec<Transaction>, now: u64) -> Result<Block, ConsensusError> {
+        rules::block_template(&self.storage, &self.retarget, parent, transactions, now)
+    }
+
+    // Checks the proof of work of `block`, as under `PoWConsensus`.
+    pub fn check(&self, block: &Block, now: u64) -> Result<(), ConsensusError> {
+        rules::check_work(&self.storage, &self.retarget, block, now)
+    }
+
+    // Checks `block` and stores it to await votes, along with those that
+    // arrived before it. Returns whether it changed the head, which only
+    // happens once it is ratified, e.g. because its votes arrived first.
+    pub fn import(&self, block: &Block, now: u64) -> Result<bool, ConsensusError> {
+        self.check(block, now)?;
+        self.storage.store_block(block)?;
+        let held: Vec<(String, bool)> = {
+            let mut pending = self.pending();
+            let keys: Vec<(String, String)> = pending
+                .range((block.hash.clone(), String::new())..)
+                .take_while(|((block_hash, _), _)| *block_hash == block.hash)
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.into_iter()
+                .filter_map(|key| pending.remove(&key).map(|approve| (key.1, approve)))
+                .collect()
+        };
+        for (validator, approve) in held {
+            match self.record(block, &validator, approve) {
+                Ok(()) | Err(ConsensusError::InvalidVote(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.update_head()
+    }
+
+    // Whether an honest validator approves `block`: it builds on the
+    // canonical chain without replacing any block in it.
+    pub fn should_approve(&self, block: &Block) -> Result<bool, ConsensusError> {
+        let parent = match self.storage.get_block(&block.parent_hash)? {
+            Some(parent) => parent,
+            None => return Ok(false),
+        };
+        if !self.storage.is_canonical(&parent)? {
+            return Ok(false);
+        }
+        let head = self.storage.latest_block()?.ok_or(ConsensusError::NoChainHead)?;
+        Ok(block.height > head.height || self.storage.is_canonical(block)?)
+    }
+
+    // Records `validator`'s vote on the block with `block_hash`, which may
+    // arrive before the block itself, if `signature` is theirs (see
+    // `sign_vote`) and they have stake. Returns whether the head changed.
+    pub fn vote(&self, validator: &str, block_hash: &str, approve: bool, signature: &str) -> Result<bool, ConsensusError> {
+        if !signing::verify(validator, VOTE_DOMAIN, &vote_message(block_hash, approve), signature) {
+            return Err(ConsensusError::InvalidVote(format!("bad signature from {} on {}", validator, block_hash)));
+        }
+        let block = match self.storage.get_block(block_hash)? {
+            Some(block) => block,
+            None => {
+                self.hold(validator, block_hash, approve)?;
+                return Ok(false);
+            }
+        };
+        self.record(&block, validator, approve)?;
+        if approve {
+            self.update_head()
+        } else {
+            Ok(false)
+        }
+    }
+
+    // The stake that approved `block` and the total stake; see
+    // `rules::attestations`.
+    pub fn tally(&self, block: &Block) -> Result<(u64, u64), ConsensusError> {
+        rules::attestations(&self.storage, &self.genesis, block)
+    }
+
+    // Never true without stake, whatever the threshold.
+    pub fn is_ratified(&self, block: &Block) -> Result<bool, ConsensusError> {
+        let (approving, total) = self.tally(block)?;
+        Ok(total > 0 && approving as u128 * 100 >= self.config.ratification_threshold as u128 * total as u128)
+    }
+
+    fn update_head(&self) -> Result<bool, ConsensusError> {
+        Ok(fork_choice::update_head(&self.storage, self)?.is_some())
+    }
+
+    // Stores `validator`'s vote on `block` if it has stake as of the
+    // block's parent.
+    fn record(&self, block: &Block, validator: &str, approve: bool) -> Result<(), ConsensusError> {
+        let parent = rules::parent_of(&self.storage, block)?;
+        if rules::stakes_after(&self.storage, &self.genesis, &parent)?.stake(validator) == 0 {
+            return Err(ConsensusError::InvalidVote(format!("{} has no stake at block {}", validator, block.hash)));
+        }
+        self.storage.put_vote(validator, &block.hash, approve)?;
+        Ok(())
+    }
+
+    // Keeps a vote for a block not stored yet, from a validator staked as
+    // of the head, within the bounds on held votes.
+    fn hold(&self, validator: &str, block_hash: &str, approve: bool) -> Result<(), ConsensusError> {
+        let head = self.storage.latest_block()?.ok_or(ConsensusError::NoChainHead)?;
+        if rules::stakes_after(&self.storage, &self.genesis, &head)?.stake(validator) == 0 {
+            return Err(ConsensusError::InvalidVote(format!("{} has no stake", validator)));
+        }
+        let mut pending = self.pending();
+        let key = (block_hash.to_string(), validator.to_string());
+        if !pending.contains_key(&key)
+            && (pending.len() >= MAX_PENDING_VOTES
+                || pending.keys().filter(|(_, voter)| voter == validator).count() >= MAX_PENDING_VOTES_PER_VALIDATOR)
+        {
+            return Err(ConsensusError::InvalidVote(format!("too many votes from {} for unknown blocks", validator)));
+        }
+        pending.insert(key, approve);
+        Ok(())
+    }
+
+    fn pending(&self) -> MutexGuard<'_, BTreeMap<(String, String), bool>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Only ratified blocks are eligible, and ties keep the current chain.
+impl ForkChoice for HybridChain {
+    // The weight of `block`, in hundredths of a unit of difficulty.
+    fn weight(&self, block: &Block) -> Result<u128, ConsensusError> {
+        let pow_weight = self.config.pow_weight.min(100) as u128;
+        let (approving, total) = self.tally(block)?;
+        let (approving, total) = (approving as u128, total.max(1) as u128);
+        Ok(block.difficulty as u128 * (pow_weight * total + (100 - pow_weight) * approving) / total)
+    }
+
+    fn is_eligible(&self, block: &Block) -> Result<bool, ConsensusError> {
+        self.is_ratified(block)
+    }
+}
@@ -0,0 +1,162 @@
+// pow.rs
+//
+// Proof of work. A block mined at difficulty `d` is valid when the SHA-256
+// of its header, read as a big-endian 256-bit number, is at most
+// `(2^256 - 1) / d`, so finding one takes `d` hashes on average. The
+// difficulty is part of the hashed header, so a block cannot claim more
+// work than it was mined for. Which difficulty a block must be mined at is
+// decided by difficulty.rs.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use sha2::Digest;
+
+use crate::blockchain::Block;
+
+// Nonces a worker tries between checks for cancellation.
+const CANCEL_CHECK_INTERVAL: u64 = 256;
+
+pub type Target = [u8; 32];
+
+// The largest header hash that meets `difficulty`.
+pub fn target(difficulty: u64) -> Target {
+    let divisor = difficulty.max(1) as u128;
+    let mut target = [0; 32];
+    let mut remainder = 0u128;
+    // Long division of 2^256 - 1, eight bytes at a time.
+    for chunk in target.chunks_mut(8) {
+        let dividend = (remainder << 64) | u64::MAX as u128;
+        let quotient = (dividend / divisor) as u64;
+        remainder = dividend % divisor;
+        chunk.copy_from_slice(&quotient.to_be_bytes());
+    }
+    target
+}
+
+// The hash the proof of work is checked against. It equals the block hash
+// for mined blocks.
+pub fn work_hash(block: &Block, nonce: u64) -> [u8; 32] {
+    let mut hasher = block.header_hasher();
+    hasher.update(nonce.to_le_bytes());
+    hasher.finalize().into()
+}
+
+// Checks that `block` is intact, was mined at `difficulty` and carries
+// enough work for it.
+pub fn verify(block: &Block, difficulty: u64) -> Result<(), PowError> {
+    if block.difficulty != difficulty {
+        return Err(PowError::WrongDifficulty {
+            expected: difficulty,
+            found: block.difficulty,
+        });
+    }
+    if block.hash != block.compute_hash() {
+        return Err(PowError::InvalidHash);
+    }
+    if work_hash(block, block.nonce) > target(difficulty) {
+        return Err(PowError::InsufficientWork);
+    }
+    Ok(())
+}
+
+pub struct Miner {
+    threads: usize,
+}
+
+impl Miner {
+    // A miner searching on `threads` workers, or one per CPU if 0.
+    pub fn new(threads: usize) -> Self {
+        let threads = match threads {
+            0 => thread::available_parallelism().map(usize::from).unwrap_or(1),
+            threads => threads,
+        };
+        Miner { threads }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    // Searches for a nonce that makes `template` meet its difficulty, and
+    // returns the sealed block. Worker `i` tries nonces `i`, `i + threads`,
+    // and so on. Returns `None` if `cancel` is set first, e.g. because a
+    // competing block for the same height arrived.
+    pub fn mine(&self, template: &Block, cancel: &AtomicBool) -> Option<Block> {
+        let target = target(template.difficulty);
+        let header = template.header_hasher();
+        let found: Mutex<Option<u64>> = Mutex::new(None);
+        let done = AtomicBool::new(false);
+        let stride = self.threads as u64;
+
+        thread::scope(|scope| {
+            for first in 0..stride {
+                let (header, found, done) = (&header, &found, &done);
+                scope.spawn(move || {
+                    let mut nonce = first;
+                    let mut until_check = 0;
+                    loop {
+                        if until_check == 0 {
+                            if cancel.load(Ordering::Relaxed) || done.load(Ordering::Relaxed) {
+                                return;
+                            }
+                            until_check = CANCEL_CHECK_INTERVAL;
+                        }
+                        until_check -= 1;
+                        let mut hasher = header.clone();
+                        hasher.update(nonce.to_le_bytes());
+                        let hash: [u8; 32] = hasher.finalize().into();
+                        if hash <= target {
+                            if let Ok(mut found) = found.lock() {
+                                found.get_or_insert(nonce);
+                            }
+                            done.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                        nonce = match nonce.checked_add(stride) {
+                            Some(next) => next,
+                            // This worker's share of the nonce space is spent.
+                            None => return,
+                        };
+                    }
+                });
+            }
+        });
+
+        let nonce = found.into_inner().ok().flatten()?;
+        let mut block = template.clone();
+        block.nonce = nonce;
+        block.hash = block.compute_hash();
+        Some(block)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PowError {
+    InvalidHash,
+    WrongDifficulty { expected: u64, found: u64 },
+    InsufficientWork,
+    // The timestamp is not after the median of recent blocks.
+    TimestampTooOld { median: u64 },
+    // The timestamp is too far ahead of this node's clock.
+    TimestampTooNew { limit: u64 },
+}
+
+impl std::fmt::Display for PowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PowError::InvalidHash => write!(f, "block hash does not match its contents"),
+            PowError::WrongDifficulty { expected, found } => {
+                write!(f, "block mined at difficulty {}, expected {}", found, expected)
+            }
+            PowError::InsufficientWork => write!(f, "block hash does not meet its difficulty target"),
+            PowError::TimestampTooOld { median } => {
+                write!(f, "block timestamp is not after the median of recent blocks ({})", median)
+            }
+            PowError::TimestampTooNew { limit } => write!(f, "block timestamp is later than {}", limit),
+        }
+    }
+}
+
+impl std::error::Error for PowError {}
@@ -0,0 +1,235 @@
+// rules.rs
+//
+// Chain lookups the consensus engines share, and their error type. Nothing
+// here needs a running node, only a `Storage`, so the same code serves the
+// engines and the simulations in testing.rs. Every walk follows parent
+// hashes rather than heights, so a block on a fork is judged by its own
+// branch.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::blockchain::{Block, Transaction};
+use crate::difficulty::{self, Retarget};
+use crate::genesis::Genesis;
+use crate::pow::{self, PowError};
+use crate::stake::{self, StakeRegistry};
+use crate::storage::{Storage, StorageError};
+use crate::trie::StateTrie;
+
+// An unmined block on `parent` with `transactions`, at the difficulty
+// retargeting expects and a timestamp it accepts.
+pub fn block_template(
+    storage: &Storage,
+    retarget: &Retarget,
+    parent: &Block,
+    transactions: Vec<Transaction>,
+    now: u64,
+) -> Result<Block, ConsensusError> {
+    let history = history(storage, parent, retarget.history_len())?;
+    let earliest = difficulty::median_time_past(&history).map_or(0, |median| median + 1);
+    // Transactions are not executed yet, so the state carries over.
+    let mut block =
+        Block::with_transactions(parent.height + 1, &parent.hash, now.max(earliest), parent.state_root, transactions);
+    block.difficulty = retarget.next_difficulty(&history);
+    Ok(block)
+}
+
+// Checks the timestamp and proof of work of `block` against its branch, as
+// seen by a node whose clock reads `now`.
+pub fn check_work(storage: &Storage, retarget: &Retarget, block: &Block, now: u64) -> Result<(), ConsensusError> {
+    let parent = storage
+        .get_block(&block.parent_hash)?
+        .ok_or_else(|| ConsensusError::InvalidBlock(format!("unknown parent {}", block.parent_hash)))?;
+    let history = history(storage, &parent, retarget.history_len())?;
+    retarget.check_timestamp(block, &history, now)?;
+    pow::verify(block, retarget.next_difficulty(&history))?;
+    Ok(())
+}
+
+// Checks the proof-of-stake `block` against its branch, as seen by a node
+// whose clock reads `now`: its slot, that the slot's proposer signed it, and
+// its staking transactions. Returns the stakes after it.
+pub fn check_proposal(
+    storage: &Storage,
+    genesis: &Genesis,
+    block_time: u64,
+    block: &Block,
+    now: u64,
+) -> Result<StakeRegistry, ConsensusError> {
+    let parent = storage
+        .get_block(&block.parent_hash)?
+        .ok_or_else(|| ConsensusError::InvalidBlock(format!("unknown parent {}", block.parent_hash)))?;
+    if block.hash != block.compute_hash() {
+        return Err(ConsensusError::InvalidBlock("block hash does not match its contents".to_string()));
+    }
+    let slot = stake::slot(genesis.timestamp, block_time, block.timestamp);
+    if slot <= stake::slot(genesis.timestamp, block_time, parent.timestamp) {
+        return Err(ConsensusError::InvalidBlock(format!("slot {} is not after its parent's", slot)));
+    }
+    if slot > stake::slot(genesis.timestamp, block_time, now) {
+        return Err(ConsensusError::InvalidBlock(format!("slot {} has not started", slot)));
+    }
+    let expected = slot_proposer(storage, genesis, &parent, slot)?;
+    if expected.as_deref() != Some(block.proposer.as_str()) {
+        return Err(ConsensusError::InvalidBlock(format!(
+            "slot {} belongs to {}, not {:?}",
+            slot,
+            expected.as_deref().unwrap_or("no validator"),
+            block.proposer
+        )));
+    }
+    if !block.is_signed_by_proposer() {
+        return Err(ConsensusError::InvalidBlock(format!("block {} is not signed by {}", block.hash, block.proposer)));
+    }
+
+    let mut stakes = stakes_after(storage, genesis, &parent)?;
+    if let Some(reason) = apply_stakes(storage, &mut stakes, &parent, block)?.into_iter().next() {
+        return Err(ConsensusError::InvalidBlock(reason));
+    }
+    Ok(stakes)
+}
+
+// The validator whose turn `slot` is for a proof-of-stake block on
+// `parent`.
+pub fn slot_proposer(storage: &Storage, genesis: &Genesis, parent: &Block, slot: u64) -> Result<Option<String>, ConsensusError> {
+    let seed = ancestor(storage, parent, stake::seed_height(parent.height + 1))?;
+    let stakes = stakes_after(storage, genesis, &seed)?;
+    Ok(stakes.proposer(&seed.hash, slot).map(str::to_string))
+}
+
+// `parent` and up to `len - 1` blocks before it, oldest first.
+pub fn history(storage: &Storage, parent: &Block, len: usize) -> Result<Vec<Block>, ConsensusError> {
+    let mut history = vec![parent.clone()];
+    while history.len() < len {
+        let oldest = &history[history.len() - 1];
+        if oldest.parent_hash.is_empty() {
+            break;
+        }
+        // Starting the window later instead would give this node a
+        // different result from its peers.
+        let block = parent_of(storage, oldest)?;
+        history.push(block);
+    }
+    history.reverse();
+    Ok(history)
+}
+
+// The ancestor of `block` at `height`; the genesis block if `height` is
+// below it.
+pub fn ancestor(storage: &Storage, block: &Block, height: u64) -> Result<Block, ConsensusError> {
+    let mut current = block.clone();
+    while current.height > height && !current.parent_hash.is_empty() {
+        current = parent_of(storage, &current)?;
+    }
+    Ok(current)
+}
+
+// The stakes in effect after `block`. Registries missing from storage are
+// rebuilt from the nearest ancestor that has one, or from genesis.
+pub fn stakes_after(storage: &Storage, genesis: &Genesis, block: &Block) -> Result<StakeRegistry, ConsensusError> {
+    let mut pending = Vec::new();
+    let mut current = block.clone();
+    let mut stakes = loop {
+        if let Some(stakes) = storage.stakes(&current.hash)? {
+            break stakes;
+        }
+        if current.parent_hash.is_empty() {
+            let stakes = StakeRegistry::from_genesis(genesis);
+            storage.put_stakes(&current.hash, &stakes)?;
+            break stakes;
+        }
+        let parent = parent_of(storage, &current)?;
+        pending.push(current);
+        current = parent;
+    };
+    let mut parent = &current;
+    for block in pending.iter().rev() {
+        apply_stakes(storage, &mut stakes, parent, block)?;
+        storage.put_stakes(&block.hash, &stakes)?;
+        parent = block;
+    }
+    Ok(stakes)
+}
+
+// Applies the staking transactions of `block` to `stakes`, the registry
+// after `parent`, checking bonds against balances in the parent's state.
+// Returns why any of them were skipped.
+pub fn apply_stakes(
+    storage: &Storage,
+    stakes: &mut StakeRegistry,
+    parent: &Block,
+    block: &Block,
+) -> Result<Vec<String>, ConsensusError> {
+    let state = StateTrie::at(&storage.db, parent.state_root);
+    let rejected = stakes.apply_block(block, |address| {
+        Ok::<_, StorageError>(state.account(address)?.map_or(0, |account| account.balance))
+    })?;
+    Ok(rejected)
+}
+
+// The stake that approved `block` and the total stake, both as of its
+// parent. Votes from validators without stake count for nothing.
+pub fn attestations(storage: &Storage, genesis: &Genesis, block: &Block) -> Result<(u64, u64), ConsensusError> {
+    let parent = parent_of(storage, block)?;
+    let stakes = stakes_after(storage, genesis, &parent)?;
+    let mut approving = 0u64;
+    for (validator, stake) in stakes.validators() {
+        if storage.get_vote(validator, block)? == Some(true) {
+            approving = approving.saturating_add(stake);
+        }
+    }
+    Ok((approving, stakes.total()))
+}
+
+pub fn parent_of(storage: &Storage, block: &Block) -> Result<Block, ConsensusError> {
+    storage
+        .get_block(&block.parent_hash)?
+        .ok_or_else(|| ConsensusError::MissingBlock(block.parent_hash.clone()))
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug)]
+pub enum ConsensusError {
+    // The block breaks a consensus rule and was rejected.
+    InvalidBlock(String),
+    // A vote was rejected, e.g. because its signature does not verify.
+    InvalidVote(String),
+    // There is no block to build on; the chain has no genesis block yet.
+    NoChainHead,
+    // A block needed to check or build on the chain is not stored, e.g.
+    // because pruning keeps too few blocks.
+    MissingBlock(String),
+    StorageError(StorageError),
+}
+
+impl std::fmt::Display for ConsensusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConsensusError::InvalidBlock(err) => write!(f, "Invalid block: {}", err),
+            ConsensusError::InvalidVote(err) => write!(f, "Invalid vote: {}", err),
+            ConsensusError::NoChainHead => write!(f, "The chain has no blocks to build on"),
+            ConsensusError::MissingBlock(hash) => write!(f, "Block {} is not stored", hash),
+            ConsensusError::StorageError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ConsensusError {}
+
+impl From<StorageError> for ConsensusError {
+    fn from(err: StorageError) -> Self {
+        ConsensusError::StorageError(err)
+    }
+}
+
+impl From<PowError> for ConsensusError {
+    fn from(err: PowError) -> Self {
+        ConsensusError::InvalidBlock(err.to_string())
+    }
+}
@@ -0,0 +1,75 @@
+// signing.rs
+//
+// Ed25519 signatures for transactions and validator messages. An address is
+// the hex-encoded public key of its owner, so a signature is checked
+// against the address it claims to come from and needs no key registry.
+//
+// Every signature covers a domain string as well as the message, so one
+// signed for a transaction can never pass as a vote, or the other way
+// round.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::trie;
+
+pub struct Keypair {
+    key: SigningKey,
+}
+
+impl Keypair {
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Keypair {
+            key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    // A keypair from its 32-byte seed in hex, e.g. `NodeConfig::validator_key`.
+    pub fn from_hex(seed: &str) -> Option<Self> {
+        let seed: [u8; 32] = from_hex(seed)?.try_into().ok()?;
+        Some(Keypair::from_seed(seed))
+    }
+
+    pub fn address(&self) -> String {
+        trie::to_hex(self.key.verifying_key().as_bytes())
+    }
+
+    // The signature of `message` in `domain`, in hex.
+    pub fn sign(&self, domain: &str, message: &[u8]) -> String {
+        trie::to_hex(&self.key.sign(&signed_bytes(domain, message)).to_bytes())
+    }
+}
+
+// Whether `signature` was made over `message` in `domain` by the key whose
+// address is `address`. Malformed addresses and signatures never verify.
+pub fn verify(address: &str, domain: &str, message: &[u8], signature: &str) -> bool {
+    let key = match from_hex(address).and_then(|bytes| <[u8; 32]>::try_from(bytes).ok()) {
+        Some(bytes) => bytes,
+        None => return false,
+    };
+    let signature = match from_hex(signature).and_then(|bytes| <[u8; 64]>::try_from(bytes).ok()) {
+        Some(bytes) => Signature::from_bytes(&bytes),
+        None => return false,
+    };
+    match VerifyingKey::from_bytes(&key) {
+        Ok(key) => key.verify(&signed_bytes(domain, message), &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn signed_bytes(domain: &str, message: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(domain.len() + 1 + message.len());
+    bytes.extend_from_slice(domain.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(message);
+    bytes
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,171 @@
+// stake.rs
+//
+// Validator stakes and proof-of-stake proposer selection. The registry
+// starts from `Genesis::stakes` and changes with staking transactions: a
+// transfer to `STAKE_ADDRESS` bonds its amount to the sender, one to
+// `UNSTAKE_ADDRESS` releases up to its amount. Either only counts if the
+// sender signed it and it carries the sender's next staking nonce, and a
+// bond only if the sender's balance covers its whole stake afterwards.
+// Staking nonces count from 0 per sender and are kept in the registry, so
+// a signed transaction takes effect once on each branch.
+//
+// Time is divided into slots of `ConsensusConfig::block_time` seconds and
+// each slot has one expected proposer, drawn with probability proportional
+// to stake. The draw for a block at height `h` uses the hash of, and the
+// stakes at, the first block of the epoch before `h`'s (see `seed_height`).
+// The seed block is always at least an epoch old, so every node on the same
+// branch computes the same schedule well ahead of time, and choosing a
+// block's contents cannot change who proposes the next few blocks. Only the
+// proposer of a seed block has any say over later draws, limited to the
+// variants of one block it can try within its slot.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::blockchain::Block;
+use crate::genesis::Genesis;
+
+pub const STAKE_ADDRESS: &str = "stake";
+pub const UNSTAKE_ADDRESS: &str = "unstake";
+// Blocks per epoch.
+pub const EPOCH_LENGTH: u64 = 32;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StakeRegistry {
+    // Ordered, so the cumulative ranges the draw walks are the same on
+    // every node.
+    stakes: BTreeMap<String, u64>,
+    // The nonce each sender's next staking transaction must carry, for
+    // senders that have made one. Kept after their stake is gone, so old
+    // transactions cannot be replayed.
+    #[serde(default)]
+    nonces: BTreeMap<String, u64>,
+}
+
+impl StakeRegistry {
+    pub fn from_genesis(genesis: &Genesis) -> Self {
+        let stakes = genesis
+            .stakes
+            .iter()
+            .filter(|(_, stake)| **stake > 0)
+            .map(|(validator, stake)| (validator.clone(), *stake))
+            .collect();
+        StakeRegistry {
+            stakes,
+            nonces: BTreeMap::new(),
+        }
+    }
+
+    pub fn stake(&self, validator: &str) -> u64 {
+        self.stakes.get(validator).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.stakes.values().fold(0, |total, stake| total.saturating_add(*stake))
+    }
+
+    pub fn next_nonce(&self, sender: &str) -> u64 {
+        self.nonces.get(sender).copied().unwrap_or(0)
+    }
+
+    // Validators with any stake, by address.
+    pub fn validators(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.stakes.iter().map(|(validator, stake)| (validator.as_str(), *stake))
+    }
+
+    // Applies the staking transactions in `block`, with `balance` giving
+    // each sender's balance in the parent's state. Transactions that may not
+    // change stake are skipped, so every node ends up with the same
+    // registry, and the reasons are returned for validation to reject the
+    // block with.
+    pub fn apply_block<E>(
+        &mut self,
+        block: &Block,
+        mut balance: impl FnMut(&str) -> Result<u64, E>,
+    ) -> Result<Vec<String>, E> {
+        let mut rejected = Vec::new();
+        for transaction in &block.transactions {
+            let bonding = transaction.to == STAKE_ADDRESS;
+            if !bonding && transaction.to != UNSTAKE_ADDRESS {
+                continue;
+            }
+            if !transaction.is_signed_by_sender() {
+                rejected.push(format!(
+                    "staking transaction {} is not signed by {}",
+                    transaction.hash(),
+                    transaction.from
+                ));
+                continue;
+            }
+            let expected = self.next_nonce(&transaction.from);
+            if transaction.nonce != expected {
+                rejected.push(format!(
+                    "staking transaction {} has nonce {}, but {} is next for {}",
+                    transaction.hash(),
+                    transaction.nonce,
+                    expected,
+                    transaction.from
+                ));
+                continue;
+            }
+            if bonding {
+                let stake = self.stake(&transaction.from).saturating_add(transaction.amount);
+                let available = balance(&transaction.from)?;
+                if stake > available {
+                    rejected.push(format!(
+                        "{} cannot bond {}: its stake would be {} with a balance of {}",
+                        transaction.from, transaction.amount, stake, available
+                    ));
+                    continue;
+                }
+                self.stakes.insert(transaction.from.clone(), stake);
+            } else if let Some(stake) = self.stakes.get_mut(&transaction.from) {
+                *stake = stake.saturating_sub(transaction.amount);
+                if *stake == 0 {
+                    self.stakes.remove(&transaction.from);
+                }
+            }
+            self.nonces.insert(transaction.from.clone(), expected + 1);
+        }
+        Ok(rejected)
+    }
+
+    // The validator expected to propose in `slot`, drawn with `seed`, the
+    // hash of the seed block. `None` if nobody has stake.
+    pub fn proposer(&self, seed: &str, slot: u64) -> Option<&str> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(seed.as_bytes());
+        hasher.update(slot.to_le_bytes());
+        let digest = hasher.finalize();
+        let mut draw = [0; 16];
+        draw.copy_from_slice(&digest[..16]);
+        // 128 random bits keep the modulo bias far below anything a stake
+        // could measure.
+        let mut point = u128::from_le_bytes(draw) % total as u128;
+        for (validator, stake) in &self.stakes {
+            if point < *stake as u128 {
+                return Some(validator);
+            }
+            point -= *stake as u128;
+        }
+        None
+    }
+}
+
+// The slot a block with `timestamp` falls in, counting from the genesis
+// timestamp.
+pub fn slot(genesis_time: u64, block_time: u64, timestamp: u64) -> u64 {
+    timestamp.saturating_sub(genesis_time) / block_time.max(1)
+}
+
+// Height of the seed block for a block at `height`: the first block of the
+// previous epoch, or the genesis block during the first two epochs.
+pub fn seed_height(height: u64) -> u64 {
+    (height / EPOCH_LENGTH).saturating_sub(1) * EPOCH_LENGTH
+}
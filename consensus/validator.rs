@@ -2,8 +2,9 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 
 use crate::blockchain::{Blockchain, Block};
-use crate::consensus::{Consensus, PoWConsensus, PoSConsensus};
+use crate::consensus::{Consensus, ConsensusError, PoWConsensus, PoSConsensus};
 use crate::node::Node;
+use crate::async_storage::AsyncStorage;
 use crate::storage::{Storage, StorageError};
 
 pub trait Validator {
@@ -16,7 +17,7 @@ pub struct BlockValidator {
     node: Arc<Node>,
     consensus: Arc<dyn Consensus>,
     blockchain: Arc<Mutex<Blockchain>>,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Storage>,
 }
 
 impl Validator for BlockValidator {
@@ -40,7 +41,11 @@ impl Validator for BlockValidator {
 
     fn validate_block(&self, block: Block) -> Result<bool, StorageError> {
         // Validate the block using the consensus algorithm
-        self.consensus.propose_block(block)
+        match self.consensus.propose_block(block) {
+            Ok(()) => Ok(true),
+            Err(ConsensusError::StorageError(err)) => Err(err),
+            Err(_) => Ok(false),
+        }
     }
 }
 
@@ -51,10 +56,10 @@ impl BlockValidator {
 
         // Validate each block
         for block in blocks {
-            match self.validate_block(block.clone()) {
+            match self.validate_block(block) {
                 Ok(true) => {
-                    // Block is valid, add it to the blockchain
-                    self.storage.lock().unwrap().add_block(block).unwrap();
+                    // Block is valid; the consensus stored it in its block
+                    // tree, and fork choice decides whether it is canonical
                 }
                 Ok(false) => {
                     // Block is invalid, discard it
@@ -72,16 +77,18 @@ pub struct TransactionValidator {
     node: Arc<Node>,
     consensus: Arc<dyn Consensus>,
     blockchain: Arc<Mutex<Blockchain>>,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Storage>,
+    writer: AsyncStorage,
 }
 
 impl Validator for TransactionValidator {
     fn new(node: Arc<Node>, consensus: Arc<dyn Consensus>) -> Self {
         TransactionValidator {
-            node,
-            consensus,
             blockchain: node.get_blockchain(),
             storage: node.get_storage(),
+            writer: node.get_async_storage(),
+            node,
+            consensus,
         }
     }
 
@@ -96,7 +103,11 @@ impl Validator for TransactionValidator {
 
     fn validate_block(&self, block: Block) -> Result<bool, StorageError> {
         // Validate the transactions in the block using the consensus algorithm
-        self.consensus.propose_block(block)
+        match self.consensus.propose_block(block) {
+            Ok(()) => Ok(true),
+            Err(ConsensusError::StorageError(err)) => Err(err),
+            Err(_) => Ok(false),
+        }
     }
 }
 
@@ -110,7 +121,10 @@ impl TransactionValidator {
             match self.validate_block(transaction.clone().into()) {
                 Ok(true) => {
                     // Transaction is valid, add it to the blockchain
-                    self.storage.lock().unwrap().add_transaction(transaction).unwrap();
+                    let added = self.writer.write_blocking(move |storage| storage.add_transaction(transaction));
+                    if let Err(err) = added {
+                        log::error!("error queueing a transaction: {}", err);
+                    }
                 }
                 Ok(false) => {
                     // Transaction is invalid, discard it
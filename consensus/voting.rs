@@ -1,9 +1,18 @@
 use std::sync::{Arc, Mutex};
 use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 
+use crate::bft::{Application, Message, Output, Tendermint, Timeout};
 use crate::blockchain::{Blockchain, Block};
-use crate::consensus::{Consensus, PoWConsensus, PoSConsensus};
+use crate::config::Config;
+use crate::consensus::{Consensus, ConsensusError, PoWConsensus, PoSConsensus};
+use crate::finality::{CheckpointVote, FinalityGadget};
+use crate::genesis::Genesis;
 use crate::node::Node;
+use crate::rules;
+use crate::signing::Keypair;
+use crate::stake::StakeRegistry;
+use crate::async_storage::AsyncStorage;
 use crate::storage::{Storage, StorageError};
 
 pub trait Voting {
@@ -16,7 +25,8 @@ pub struct LeaderBasedVoting {
     node: Arc<Node>,
     consensus: Arc<dyn Consensus>,
     blockchain: Arc<Mutex<Blockchain>>,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Storage>,
+    writer: AsyncStorage,
     leaders: HashSet<Arc<Node>>,
 }
 
@@ -27,6 +37,7 @@ impl Voting for LeaderBasedVoting {
             consensus,
             blockchain: node.get_blockchain(),
             storage: node.get_storage(),
+            writer: node.get_async_storage(),
             leaders: HashSet::new(),
         }
     }
@@ -52,14 +63,14 @@ impl Voting for LeaderBasedVoting {
         // Check if the block is voted by the majority of leaders
         let mut votes = HashMap::new();
         for leader in leaders {
-            if let Ok(vote) = self.storage.lock().unwrap().get_vote(leader, block.clone()) {
+            if let Ok(Some(vote)) = self.storage.get_vote(&leader, &block) {
                 *votes.entry(vote).or_insert(0) += 1;
             }
         }
 
         // If the block is voted by the majority of leaders, add it to the blockchain
         if votes.get(&true).unwrap_or(&0) > leaders.len() / 2 {
-            self.storage.lock().unwrap().add_block(block).unwrap();
+            self.writer.write_blocking(move |storage| storage.add_block(block))?;
             return Ok(true);
         }
 
@@ -81,50 +92,256 @@ impl LeaderBasedVoting {
 
 pub struct ByzantineFaultTolerantVoting {
     node: Arc<Node>,
-    consensus: Arc<dyn Consensus>,
     blockchain: Arc<Mutex<Blockchain>>,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Storage>,
+    writer: AsyncStorage,
+    engine: Mutex<Tendermint<ChainApplication>>,
+    // Timeouts the engine asked for, by when they expire.
+    timers: Mutex<Vec<(Instant, Timeout)>>,
+    // Messages for the other validators, until the network layer takes them.
+    outbox: Mutex<Vec<Message>>,
 }
 
 impl Voting for ByzantineFaultTolerantVoting {
     fn new(node: Arc<Node>, consensus: Arc<dyn Consensus>) -> Self {
-        ByzantineFaultTolerantVoting {
-            node,
+        let config = node.get_config();
+        let storage = node.get_storage();
+        let genesis = node.get_genesis();
+        let head = storage.latest_block().ok().flatten();
+        let mut app = ChainApplication {
+            storage: storage.clone(),
+            genesis: genesis.clone(),
             consensus,
+            candidate: None,
+        };
+        let (height, validators) = match &head {
+            Some(head) => (head.height + 1, app.validators(head)),
+            None => (0, StakeRegistry::from_genesis(&genesis)),
+        };
+        let engine = Tendermint::new(app, config.node.validator_keypair(), validators, config.consensus.bft, height);
+        ByzantineFaultTolerantVoting {
             blockchain: node.get_blockchain(),
-            storage: node.get_storage(),
+            storage,
+            writer: node.get_async_storage(),
+            engine: Mutex::new(engine),
+            timers: Mutex::new(Vec::new()),
+            outbox: Mutex::new(Vec::new()),
+            node,
         }
     }
 
     fn start(&self) {
         thread::spawn(move || {
+            let outputs = self.engine.lock().unwrap().start();
+            self.apply(outputs);
             loop {
-                self.vote_on_blocks();
-                thread::sleep(std::time::Duration::from_secs(10));
+                self.fire_timeouts();
+                thread::sleep(std::time::Duration::from_millis(100));
             }
         });
     }
 
+    // Offers `block` as this node's proposal for its height, should this
+    // node be the proposer. Returns whether the block has been decided.
     fn vote(&self, block: Block) -> Result<bool, StorageError> {
-        // Check if the block is valid according to the consensus algorithm
-        if self.consensus.propose_block(block.clone()).is_err() {
-            return Ok(false);
+        let mut engine = self.engine.lock().unwrap();
+        if block.height == engine.height() {
+            engine.app_mut().candidate = Some(block.clone());
         }
-
-        // Use Byzantine Fault Tolerant algorithm to vote on the block
-        // ...
-        Ok(true)
+        Ok(self.storage.certificate(&block.hash)?.is_some())
     }
 }
 
 impl ByzantineFaultTolerantVoting {
-    fn vote_on_blocks(&self) {
-        // Get the latest blocks from the blockchain
-        let blocks = self.blockchain.lock().unwrap().get_latest_blocks(10);
+    // Handles a proposal or vote from another validator.
+    pub fn receive(&self, message: Message) {
+        let outputs = self.engine.lock().unwrap().handle_message(message);
+        self.apply(outputs);
+    }
 
-        // Vote on each block
-        for block in blocks {
-            self.vote(block.clone()).unwrap();
+    // Messages to send to every other validator.
+    pub fn take_messages(&self) -> Vec<Message> {
+        std::mem::take(&mut *self.outbox.lock().unwrap())
+    }
+
+    fn fire_timeouts(&self) {
+        let now = Instant::now();
+        let due: Vec<Timeout> = {
+            let mut timers = self.timers.lock().unwrap();
+            let (due, pending) = timers.drain(..).partition(|(at, _)| *at <= now);
+            *timers = pending;
+            due.into_iter().map(|(_, timeout)| timeout).collect()
+        };
+        for timeout in due {
+            let outputs = self.engine.lock().unwrap().handle_timeout(timeout);
+            self.apply(outputs);
+        }
+    }
+
+    // Acts on each of `outputs`. One that fails is logged and does not keep
+    // the rest, e.g. the messages that follow a decision, from going out.
+    fn apply(&self, outputs: Vec<Output>) {
+        for output in outputs {
+            match output {
+                Output::Broadcast(message) => self.outbox.lock().unwrap().push(message),
+                Output::ScheduleTimeout(timeout, after) => self.timers.lock().unwrap().push((Instant::now() + after, timeout)),
+                Output::Decide(block, certificate) => {
+                    let height = block.height;
+                    let result = self.writer.write_blocking(move |storage| {
+                        storage.put_certificate(&block.hash, &certificate)?;
+                        storage.add_block(block)
+                    });
+                    if let Err(err) = result {
+                        log::error!("error storing the block decided at height {}: {}", height, err);
+                    }
+                }
+                Output::Equivocation(first, second) => log::warn!(
+                    "validator {} voted both {:?} and {:?} at height {} round {}",
+                    first.validator, first.block_hash, second.block_hash, first.height, first.round
+                ),
+            }
+        }
+    }
+}
+
+// The chain as the BFT engine sees it: a block must extend the stored head
+// and pass the consensus rules, the validators are those staked as of the
+// previous block, and this node proposes the block last handed to `vote`.
+struct ChainApplication {
+    storage: Arc<Storage>,
+    genesis: Arc<Genesis>,
+    consensus: Arc<dyn Consensus>,
+    candidate: Option<Block>,
+}
+
+impl Application for ChainApplication {
+    fn propose(&mut self, height: u64) -> Option<Block> {
+        self.candidate.take().filter(|block| block.height == height)
+    }
+
+    fn is_valid(&self, block: &Block) -> bool {
+        let extends_head = match self.storage.latest_block() {
+            Ok(Some(head)) => block.parent_hash == head.hash,
+            Ok(None) => block.parent_hash.is_empty(),
+            Err(_) => false,
+        };
+        if !extends_head {
+            return false;
+        }
+        // The genesis block comes from the genesis file, not the rules.
+        if block.parent_hash.is_empty() {
+            return true;
+        }
+        match self.consensus.check_block(block) {
+            Ok(()) => true,
+            Err(err) => {
+                log::debug!("rejecting block {} at height {}: {}", block.hash, block.height, err);
+                false
+            }
         }
     }
+
+    fn validators(&mut self, parent: &Block) -> StakeRegistry {
+        match rules::stakes_after(&self.storage, &self.genesis, parent) {
+            Ok(validators) => validators,
+            // No validators means no quorum, so the height stalls rather
+            // than being decided by the wrong set.
+            Err(err) => {
+                log::error!("error loading the validators after block {}: {}", parent.hash, err);
+                StakeRegistry::default()
+            }
+        }
+    }
+}
+
+// Signs checkpoints for the finality gadget with this node's validator
+// key, `NodeConfig::validator_key`, on top of whichever consensus extends
+// the chain. Without a key it only collects the other validators' votes.
+pub struct FinalityVoting {
+    storage: Arc<Storage>,
+    gadget: FinalityGadget,
+    key: Option<Keypair>,
+    // Checkpoint votes for the other validators, until the network layer
+    // takes them.
+    outbox: Mutex<Vec<CheckpointVote>>,
+}
+
+impl Voting for FinalityVoting {
+    fn new(node: Arc<Node>, _consensus: Arc<dyn Consensus>) -> Self {
+        FinalityVoting::with_storage(node.get_storage(), node.get_genesis(), &node.get_config())
+    }
+
+    fn start(&self) {
+        thread::spawn(move || {
+            loop {
+                if let Err(err) = self.sign_latest_checkpoint() {
+                    log::error!("error in finality voting: {}", err);
+                }
+                thread::sleep(std::time::Duration::from_secs(1));
+            }
+        });
+    }
+
+    // Signs `block` if it is a checkpoint an honest validator signs.
+    // Returns whether the block is final.
+    fn vote(&self, block: Block) -> Result<bool, StorageError> {
+        let result = self.sign(&block).and_then(|_| self.gadget.is_final(&block));
+        match result {
+            Ok(is_final) => Ok(is_final),
+            Err(ConsensusError::StorageError(err)) => Err(err),
+            Err(_) => Ok(false),
+        }
     }
+}
+
+impl FinalityVoting {
+    // The node builds it this way, as it needs no consensus engine.
+    pub fn with_storage(storage: Arc<Storage>, genesis: Arc<Genesis>, config: &Config) -> Self {
+        FinalityVoting {
+            gadget: FinalityGadget::new(storage.clone(), genesis, config.consensus.finality),
+            storage,
+            key: config.node.validator_keypair(),
+            outbox: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn checkpoint_interval(&self) -> u64 {
+        self.gadget.checkpoint_interval()
+    }
+
+    // Handles a checkpoint vote from another validator.
+    pub fn receive(&self, vote: CheckpointVote) -> Result<(), ConsensusError> {
+        self.gadget.vote(vote)?;
+        Ok(())
+    }
+
+    // Checkpoint votes to send to every other validator.
+    pub fn take_messages(&self) -> Vec<CheckpointVote> {
+        std::mem::take(&mut *self.outbox.lock().unwrap())
+    }
+
+    fn sign(&self, block: &Block) -> Result<(), ConsensusError> {
+        if let Some(key) = &self.key {
+            if let Some(vote) = self.gadget.sign(key, block)? {
+                self.outbox.lock().unwrap().push(vote);
+            }
+        }
+        Ok(())
+    }
+
+    // Signs the newest canonical checkpoint, unless a checkpoint at its
+    // height was signed before, and picks up any checkpoint a reorg made
+    // canonical after its votes came in.
+    fn sign_latest_checkpoint(&self) -> Result<(), ConsensusError> {
+        let interval = self.gadget.checkpoint_interval();
+        let head = match self.storage.latest_block()? {
+            Some(head) if interval > 0 => head,
+            _ => return Ok(()),
+        };
+        if let Some(checkpoint) = self.storage.chain.block(head.height - head.height % interval).map_err(StorageError::from)? {
+            self.sign(&checkpoint)?;
+        }
+        self.gadget.update()?;
+        Ok(())
+    }
+}
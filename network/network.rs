@@ -4,6 +4,7 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 
 use crate::blockchain::{Block, Blockchain};
+use crate::finality::CheckpointVote;
 use crate::node::{Node, NodeId};
 use crate::smart_contract::{SmartContract, PiSentinelSmartContract};
 
@@ -12,6 +13,7 @@ pub trait Network {
     fn connect(&self, addr: SocketAddr) -> Result<(), NetworkError>;
     fn broadcast_block(&self, block: Block) -> Result<(), NetworkError>;
     fn broadcast_contract(&self, contract: Arc<dyn SmartContract>) -> Result<(), NetworkError>;
+    fn broadcast_checkpoint_vote(&self, vote: CheckpointVote) -> Result<(), NetworkError>;
     fn send_block(&self, addr: SocketAddr, block: Block) -> Result<(), NetworkError>;
     fn send_contract(&self, addr: SocketAddr, contract: Arc<dyn SmartContract>) -> Result<(), NetworkError>;
     fn start_listening(&self) -> Result<(), NetworkError>;
@@ -57,6 +59,15 @@ impl Network for PiSentinelNetwork {
         Ok(())
     }
 
+    fn broadcast_checkpoint_vote(&self, vote: CheckpointVote) -> Result<(), NetworkError> {
+        self.lock.lock().unwrap();
+        let data = serde_json::to_string(&vote)?;
+        for (_, stream) in self.connections.iter() {
+            stream.write_all(data.as_bytes())?;
+        }
+        Ok(())
+    }
+
     fn send_block(&self, addr: SocketAddr, block: Block) -> Result<(), NetworkError> {
         self.lock.lock().unwrap();
         if let Some(stream) = self.connections.get(&addr) {
@@ -6,10 +6,9 @@ use std::thread;
 use crate::blockchain::{Block, Blockchain};
 use crate::node::{Node, NodeId};
 use crate::smart_contract::{SmartContract, PiSentinelSmartContract};
-use crate::storage::{Storage, PiSentinelStorage};
 
 pub trait P2P {
-    fn new(node: Arc<Node>, storage: Arc<dyn Storage>) -> Self;
+    fn new(node: Arc<Node>) -> Self;
     fn start(&self) -> Result<(), P2PError>;
     fn connect(&self, addr: SocketAddr) -> Result<(), P2PError>;
     fn disconnect(&self, addr: SocketAddr) -> Result<(), P2PError>;
@@ -21,17 +20,15 @@ pub trait P2P {
 
 pub struct PiSentinelP2P {
     node: Arc<Node>,
-    storage: Arc<dyn Storage>,
     connections: HashMap<SocketAddr, TcpStream>,
     listeners: HashSet<TcpListener>,
     lock: Mutex<()>,
 }
 
 impl P2P for PiSentinelP2P {
-    fn new(node: Arc<Node>, storage: Arc<dyn Storage>) -> Self {
+    fn new(node: Arc<Node>) -> Self {
         PiSentinelP2P {
             node,
-            storage,
             connections: HashMap::new(),
             listeners: HashSet::new(),
             lock: Mutex::new(()),
@@ -1,31 +1,39 @@
 use std::collections::{HashMap, HashSet};
 use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+use crate::backup;
 use crate::blockchain::{Block, Blockchain};
+use crate::db::{DbError, Direction};
+use crate::storage::StorageError;
 use crate::node::{Node, NodeId};
+use crate::query::{Command, Query};
 use crate::smart_contract::{SmartContract, PiSentinelSmartContract};
-use crate::storage::{Storage, PiSentinelStorage};
+
+const ADDRESS_TRANSACTIONS_LIMIT: usize = 100;
+// Rows a `db_query` scan returns without a LIMIT, and the most it may ask
+// for.
+const DB_QUERY_DEFAULT_LIMIT: usize = 100;
+const DB_QUERY_MAX_LIMIT: usize = 1000;
 
 pub trait RPC {
-    fn new(node: Arc<Node>, storage: Arc<dyn Storage>) -> Self;
+    fn new(node: Arc<Node>) -> Self;
     fn start(&self) -> Result<(), RPCError>;
     fn call(&self, method: &str, params: Vec<String>) -> Result<String, RPCError>;
 }
 
 pub struct PiSentinelRPC {
     node: Arc<Node>,
-    storage: Arc<dyn Storage>,
     listeners: HashSet<TcpListener>,
     lock: Mutex<()>,
 }
 
 impl RPC for PiSentinelRPC {
-    fn new(node: Arc<Node>, storage: Arc<dyn Storage>) -> Self {
+    fn new(node: Arc<Node>) -> Self {
         PiSentinelRPC {
             node,
-            storage,
             listeners: HashSet::new(),
             lock: Mutex::new(()),
         }
@@ -48,14 +56,151 @@ impl RPC for PiSentinelRPC {
         match method {
             "get_block" => {
                 let block_hash = params.get(0).unwrap();
-                let block = self.storage.get_block(block_hash)?;
+                let block = self.node.get_storage().get_block(block_hash)?;
                 let data = serde_json::to_string(&block)?;
                 Ok(data)
             }
+            "get_block_by_height" => {
+                let height = parse_height(params.get(0))?;
+                let block = self.node.get_storage().chain.block(height)?;
+                let data = serde_json::to_string(&block)?;
+                Ok(data)
+            }
+            "get_account" => {
+                // Optional second parameter: the height to read the state
+                // at, defaulting to the chain head.
+                let address = params.get(0).ok_or(RPCError::InvalidParams)?;
+                let storage = self.node.get_storage();
+                let height = match params.get(1) {
+                    Some(_) => parse_height(params.get(1))?,
+                    None => storage.chain.head()?.ok_or(RPCError::InvalidParams)?,
+                };
+                let account = storage.chain.state_at(height)?.account(address)?;
+                let data = serde_json::to_string(&account.map(|account| {
+                    serde_json::json!({
+                        "balance": account.balance,
+                        "nonce": account.nonce,
+                    })
+                }))?;
+                Ok(data)
+            }
+            "get_transaction" => {
+                let hash = params.get(0).ok_or(RPCError::InvalidParams)?;
+                let found = self.node.get_storage().chain.transaction(hash)?;
+                let data = serde_json::to_string(&found.map(|(location, transaction)| {
+                    serde_json::json!({
+                        "block_hash": location.block_hash,
+                        "height": location.height,
+                        "index": location.index,
+                        "transaction": transaction,
+                    })
+                }))?;
+                Ok(data)
+            }
+            "get_address_transactions" => {
+                // Optional second parameter: the maximum number of
+                // transaction hashes to return, newest first.
+                let address = params.get(0).ok_or(RPCError::InvalidParams)?;
+                let limit = match params.get(1) {
+                    Some(limit) => limit.parse().map_err(|_| RPCError::InvalidParams)?,
+                    None => ADDRESS_TRANSACTIONS_LIMIT,
+                };
+                let hashes = self.node.get_storage().chain.address_transactions(address, Direction::Reverse, limit)?;
+                let data = serde_json::to_string(&hashes)?;
+                Ok(data)
+            }
+            "get_finality_proof" => {
+                // Optional parameter: the checkpoint's block hash,
+                // defaulting to the latest final checkpoint.
+                let storage = self.node.get_storage();
+                let block_hash = match params.get(0) {
+                    Some(block_hash) => Some(block_hash.clone()),
+                    None => storage.finalized()?.map(|checkpoint| checkpoint.hash),
+                };
+                let proof = match block_hash {
+                    Some(block_hash) => storage.finality_proof(&block_hash)?,
+                    None => None,
+                };
+                let data = serde_json::to_string(&proof)?;
+                Ok(data)
+            }
             "get_contract" => {
-                let contract_id = params.get(0).unwrap();
-                let contract = self.storage.get_contract(contract_id)?;
-                let data = serde_json::to_string(&contract)?;
+                let contract_id = params.get(0).ok_or(RPCError::InvalidParams)?;
+                let deployed = self.node.get_storage().has_contract(contract_id)?;
+                let data = serde_json::to_string(&serde_json::json!({
+                    "id": contract_id,
+                    "deployed": deployed,
+                }))?;
+                Ok(data)
+            }
+            "db_query" => {
+                let query = params.get(0).ok_or(RPCError::InvalidParams)?;
+                let mut query = Query::parse(query)?;
+                if let Command::Scan(_) = query.command {
+                    match query.limit {
+                        None => query.limit = Some(DB_QUERY_DEFAULT_LIMIT),
+                        Some(limit) if limit > DB_QUERY_MAX_LIMIT => return Err(RPCError::InvalidParams),
+                        Some(_) => {}
+                    }
+                }
+                // Run against a snapshot so long scans see one consistent
+                // state while block import keeps writing.
+                let snapshot = self.node.get_storage().db.snapshot()?;
+                let rows = snapshot.run(&query)?;
+                let rows: Vec<Vec<String>> = rows
+                    .iter()
+                    .map(|row| {
+                        row.columns
+                            .iter()
+                            .map(|column| String::from_utf8_lossy(column).into_owned())
+                            .collect()
+                    })
+                    .collect();
+                let data = serde_json::to_string(&rows)?;
+                Ok(data)
+            }
+            "admin_compact" => {
+                self.authorize(&params)?;
+                let stats = self.node.get_storage().db.compact()?;
+                let data = serde_json::to_string(&serde_json::json!({
+                    "bytes_before": stats.bytes_before,
+                    "bytes_after": stats.bytes_after,
+                    "reclaimed_bytes": stats.reclaimed_bytes(),
+                    "live_keys": stats.live_keys,
+                }))?;
+                Ok(data)
+            }
+            "admin_backup" => {
+                let params = self.authorize(&params)?;
+                let config = self.node.get_config();
+                let dir = config.rpc.backup_dir.as_ref().ok_or(RPCError::BackupsDisabled)?;
+                let name = params.get(0).ok_or(RPCError::InvalidParams)?;
+                let path = backup::archive_in(Path::new(dir), name).ok_or(RPCError::InvalidParams)?;
+                let info = self
+                    .node
+                    .get_storage()
+                    .db
+                    .backup(&path, config.storage.encryption.as_ref())?;
+                let data = serde_json::to_string(&serde_json::json!({
+                    "path": path.to_string_lossy(),
+                    "version": info.version,
+                    "encrypted": info.encrypted,
+                    "entries": info.entries,
+                    "bytes": info.bytes,
+                    "sha256": info.checksum,
+                }))?;
+                Ok(data)
+            }
+            "admin_reindex" => {
+                self.authorize(&params)?;
+                let stats = self
+                    .node
+                    .get_async_storage()
+                    .write_blocking(|storage| Ok(storage.chain.reindex()?))?;
+                let data = serde_json::to_string(&serde_json::json!({
+                    "blocks": stats.blocks,
+                    "transactions": stats.transactions,
+                }))?;
                 Ok(data)
             }
             "send_transaction" => {
@@ -68,7 +213,32 @@ impl RPC for PiSentinelRPC {
     }
 }
 
+// Compares in time that depends only on the lengths, so a caller cannot
+// find the token a byte at a time.
+fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn parse_height(param: Option<&String>) -> Result<u64, RPCError> {
+    param.and_then(|height| height.parse().ok()).ok_or(RPCError::InvalidParams)
+}
+
 impl PiSentinelRPC {
+    // Admin methods take the configured admin token as their first
+    // parameter and return the rest.
+    fn authorize<'p>(&self, params: &'p [String]) -> Result<&'p [String], RPCError> {
+        let config = self.node.get_config();
+        match (&config.rpc.admin_token, params.split_first()) {
+            (Some(token), Some((given, rest))) if tokens_match(token, given) => Ok(rest),
+            _ => Err(RPCError::Unauthorized),
+        }
+    }
+
     fn handle_incoming_connection(&self, stream: TcpStream) {
         // Handle incoming connection logic here
         //...
@@ -79,7 +249,16 @@ impl PiSentinelRPC {
 enum RPCError {
     IoError(std::io::Error),
     JsonError(serde_json::Error),
+    DbError(DbError),
+    StorageError(StorageError),
+    // A historical query for a block or state this node has pruned.
+    StatePruned(String),
     MethodNotFound,
+    InvalidParams,
+    // An admin method was called without the configured admin token.
+    Unauthorized,
+    // admin_backup was called while `rpc.backup_dir` is unset.
+    BackupsDisabled,
 }
 
 impl From<std::io::Error> for RPCError {
@@ -88,6 +267,24 @@ impl From<std::io::Error> for RPCError {
     }
 }
 
+impl From<DbError> for RPCError {
+    fn from(err: DbError) -> Self {
+        match err {
+            DbError::StatePruned { .. } => RPCError::StatePruned(err.to_string()),
+            err => RPCError::DbError(err),
+        }
+    }
+}
+
+impl From<StorageError> for RPCError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::DbError(err) => err.into(),
+            err => RPCError::StorageError(err),
+        }
+    }
+}
+
 impl From<serde_json::Error> for RPCError {
     fn from(err: serde_json::Error) -> Self {
         RPCError::JsonError(err)
@@ -4,29 +4,68 @@ use std::thread;
 
 use crate::blockchain::{Blockchain, Block};
 use crate::config::Config;
+use crate::finality::CheckpointVote;
+use crate::genesis::Genesis;
 use crate::network::{Network, Peer};
+use crate::realtime_analytics::RealtimeAnalytics;
+use crate::async_storage::AsyncStorage;
 use crate::storage::{Storage, StorageError};
+use crate::voting::{FinalityVoting, Voting};
+
+// How often the compaction thread checks the database, and how much of it
+// must be dead data before a compaction is worth the rewrite.
+const COMPACTION_INTERVAL_SECS: u64 = 600;
+const COMPACTION_GARBAGE_RATIO: f64 = 0.5;
+// How often the storage read counters are exported when analytics are on.
+const STORAGE_REPORT_INTERVAL_SECS: u64 = 15;
+// How often this node's checkpoint votes are sent to its peers.
+const CHECKPOINT_GOSSIP_INTERVAL_MILLIS: u64 = 500;
 
 pub struct Node {
     config: Arc<Config>,
+    genesis: Arc<Genesis>,
     blockchain: Arc<Mutex<Blockchain>>,
     network: Arc<Mutex<Network>>,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Storage>,
+    async_storage: AsyncStorage,
+    finality: Arc<FinalityVoting>,
 }
 
 impl Node {
-    pub fn new(config: Arc<Config>) -> Self {
-        Node {
+    pub fn new(config: Arc<Config>) -> Result<Self, NodeError> {
+        config.validate().map_err(NodeError::Config)?;
+        let genesis = match &config.consensus.genesis {
+            Some(path) => {
+                let json = std::fs::read_to_string(path)
+                    .map_err(|err| NodeError::Genesis(format!("cannot read {}: {}", path, err)))?;
+                Genesis::from_json(&json).map_err(|err| NodeError::Genesis(format!("{}: {}", path, err)))?
+            }
+            None => Genesis::new(),
+        };
+        let storage = Arc::new(Storage::open(&config.storage)?);
+        let genesis = Arc::new(genesis);
+        let finality = Arc::new(FinalityVoting::with_storage(storage.clone(), genesis.clone(), &config));
+        Ok(Node {
+            genesis,
             config,
+            finality,
             blockchain: Arc::new(Mutex::new(Blockchain::new())),
             network: Arc::new(Mutex::new(Network::new())),
-            storage: Arc::new(Mutex::new(Storage::new())),
-        }
+            async_storage: AsyncStorage::new(storage.clone()),
+            storage,
+        })
     }
 
     pub fn start(&self) {
         self.start_listening();
         self.start_syncing();
+        self.start_compacting();
+        if self.finality.checkpoint_interval() > 0 {
+            self.start_finality();
+        }
+        if self.config.analytics.enabled {
+            self.start_reporting();
+        }
     }
 
     fn start_listening(&self) {
@@ -47,6 +86,51 @@ impl Node {
         });
     }
 
+    fn start_compacting(&self) {
+        let db = self.storage.db.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(std::time::Duration::from_secs(COMPACTION_INTERVAL_SECS));
+                match db.garbage_ratio() {
+                    Ok(ratio) if ratio >= COMPACTION_GARBAGE_RATIO => match db.compact() {
+                        Ok(stats) => log::info!("compaction reclaimed {} bytes", stats.reclaimed_bytes()),
+                        Err(err) => log::error!("error compacting the database: {}", err),
+                    },
+                    Ok(_) => {}
+                    Err(err) => log::error!("error checking the database for garbage: {}", err),
+                }
+            }
+        });
+    }
+
+    // Signs checkpoints and sends the votes to the peers.
+    fn start_finality(&self) {
+        self.finality.start();
+        let finality = self.finality.clone();
+        let network = self.network.clone();
+        thread::spawn(move || {
+            loop {
+                for vote in finality.take_messages() {
+                    if let Err(err) = network.lock().unwrap().broadcast_checkpoint_vote(vote) {
+                        log::error!("error sending a checkpoint vote: {:?}", err);
+                    }
+                }
+                thread::sleep(std::time::Duration::from_millis(CHECKPOINT_GOSSIP_INTERVAL_MILLIS));
+            }
+        });
+    }
+
+    fn start_reporting(&self) {
+        let db = self.storage.db.clone();
+        let analytics = RealtimeAnalytics::new(&self.config.analytics.prometheus.url);
+        thread::spawn(move || {
+            loop {
+                analytics.report_storage(&db.read_stats());
+                thread::sleep(std::time::Duration::from_secs(STORAGE_REPORT_INTERVAL_SECS));
+            }
+        });
+    }
+
     fn handle_incoming_connection(&self, stream: TcpStream) {
         // Handle incoming connection from a peer
         // ...
@@ -57,8 +141,24 @@ impl Node {
         // ...
     }
 
+    // Goes through the storage writer, like every write of the node.
     pub fn add_block(&self, block: Block) -> Result<(), StorageError> {
-        self.storage.lock().unwrap().add_block(block)
+        self.async_storage.write_blocking(move |storage| storage.add_block(block))
+    }
+
+    // A checkpoint vote from a peer. Invalid votes are logged and dropped.
+    pub fn receive_checkpoint_vote(&self, vote: CheckpointVote) {
+        if let Err(err) = self.finality.receive(vote) {
+            log::warn!("rejected a checkpoint vote: {}", err);
+        }
+    }
+
+    pub fn get_config(&self) -> Arc<Config> {
+        self.config.clone()
+    }
+
+    pub fn get_genesis(&self) -> Arc<Genesis> {
+        self.genesis.clone()
     }
 
     pub fn get_blockchain(&self) -> Arc<Mutex<Blockchain>> {
@@ -69,7 +169,40 @@ impl Node {
         self.network.clone()
     }
 
-    pub fn get_storage(&self) -> Arc<Mutex<Storage>> {
+    pub fn get_storage(&self) -> Arc<Storage> {
         self.storage.clone()
     }
+
+    // For writes, and for reads by tasks on the tokio runtime. Other threads
+    // read through `get_storage`.
+    pub fn get_async_storage(&self) -> AsyncStorage {
+        self.async_storage.clone()
+    }
         }
+
+#[derive(Debug)]
+pub enum NodeError {
+    Storage(StorageError),
+    // Settings that cannot work together; see `Config::validate`.
+    Config(String),
+    // The configured genesis file is missing or invalid.
+    Genesis(String),
+}
+
+impl std::fmt::Display for NodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NodeError::Storage(err) => write!(f, "{}", err),
+            NodeError::Config(err) => write!(f, "Invalid config: {}", err),
+            NodeError::Genesis(err) => write!(f, "Invalid genesis file: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {}
+
+impl From<StorageError> for NodeError {
+    fn from(err: StorageError) -> Self {
+        NodeError::Storage(err)
+    }
+}
@@ -18,7 +18,7 @@ pub struct PiSentinelContractManager {
     node: Arc<Node>,
     consensus: Arc<dyn Consensus>,
     blockchain: Arc<Mutex<Blockchain>>,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Storage>,
     contracts: HashMap<String, Arc<dyn SmartContract>>,
 }
 
@@ -35,8 +35,11 @@ impl ContractManager for PiSentinelContractManager {
 
     fn deploy_contract(&self, contract: Arc<dyn SmartContract>) -> Result<(), StorageError> {
         let contract_id = contract.get_node().get_id().to_string();
-        self.contracts.insert(contract_id.clone(), contract);
-        self.storage.lock().unwrap().store_contract(contract_id, contract)?;
+        let id = contract_id.clone();
+        self.node
+            .get_async_storage()
+            .write_blocking(move |storage| storage.store_contract(&id))?;
+        self.contracts.insert(contract_id, contract);
         Ok(())
     }
 
@@ -17,7 +17,7 @@ pub struct PiSentinelSmartContract {
     node: Arc<Node>,
     consensus: Arc<dyn Consensus>,
     blockchain: Arc<Mutex<Blockchain>>,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<Storage>,
     validator: Arc<dyn Validator>,
     voting: Arc<dyn Voting>,
 }
@@ -65,7 +65,7 @@ impl PiSentinelSmartContract {
         self.blockchain.clone()
     }
 
-    fn get_storage(&self) -> Arc<Mutex<Storage>> {
+    fn get_storage(&self) -> Arc<Storage> {
         self.storage.clone()
     }
 
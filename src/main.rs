@@ -2,8 +2,22 @@ use substrate::{Node, NodeConfig};
 use ai_consensus::{AIConsensus, AIModel};
 use qrcrypto::{QRKey, QRSignature};
 use realtime_analytics::{RealtimeAnalytics, Prometheus};
+use crate::admin;
 
 fn main() {
+    // Offline storage commands run instead of the node.
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("storage") {
+        match admin::run(&args[2..]) {
+            Ok(summary) => println!("{}", summary),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     // Initialize AI-powered consensus algorithm
     let ai_model = AIModel::new("path/to/model");
     let ai_consensus = AIConsensus::new(ai_model);
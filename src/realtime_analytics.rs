@@ -1,6 +1,8 @@
 use prometheus::{Prometheus, Gauge};
 use grafana::{Grafana, Dashboard};
 
+use crate::cache::ReadStats;
+
 pub struct RealtimeAnalytics {
     prometheus: Prometheus,
     grafana: Grafana,
@@ -22,4 +24,18 @@ impl RealtimeAnalytics {
         let dashboard = self.grafana.create_dashboard("PiSentinel");
         dashboard.add_panel("Block Height", gauge);
     }
+
+    // Exports the storage read counters from `Db::read_stats`. They count
+    // from when the database was opened, so report them as they are. The
+    // node calls this periodically while analytics are enabled.
+    pub fn report_storage(&self, stats: &ReadStats) {
+        self.prometheus.gauge("storage_cache_hits").set(stats.cache_hits as f64);
+        self.prometheus.gauge("storage_cache_misses").set(stats.cache_misses as f64);
+        self.prometheus.gauge("storage_cache_hit_ratio").set(stats.cache_hit_ratio());
+        self.prometheus.gauge("storage_bloom_negatives").set(stats.bloom_negatives as f64);
+        self.prometheus
+            .gauge("storage_bloom_false_positives")
+            .set(stats.bloom_false_positives as f64);
+        self.prometheus.gauge("storage_bloom_segments").set(stats.bloom_segments as f64);
+    }
                             }
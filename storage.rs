@@ -1,24 +1,385 @@
 // Storage implementation
-pub enum StorageType {
-    Memory,
-    Disk,
-}
+//
+// The one storage layer the node, consensus, validators, voting and
+// contracts share. It runs on any `Backend` (see storage/backend.rs):
+// `open` picks the memory or disk engine from the config, and
+// `with_backend` takes any other engine. It is safe to share between
+// threads as it is; tokio tasks should go through `AsyncStorage`.
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::backend::Backend;
+use crate::bft::QuorumCertificate;
+use crate::blockchain::{Block, Transaction};
+use crate::chain::{ChainStore, Checkpoint};
+use crate::config::{Pruning, StorageConfig};
+use crate::db::{Db, DbError, Direction, KeyRange, WriteBatch};
+use crate::finality::{CheckpointVote, FinalityProof};
+use crate::migration::{self, MigrationReport};
+use crate::stake::StakeRegistry;
+
+// Transactions accepted but not yet in a block, keyed by hash.
+const PENDING_COLUMN_FAMILY: &str = "pending_transactions";
+// "<block hash>:<voter>" -> 1 for a vote for the block, 0 against. Pruned
+// with the block.
+const VOTES_COLUMN_FAMILY: &str = "votes";
+// Ids of deployed contracts. The contract objects themselves live in the
+// contract manager.
+const CONTRACTS_COLUMN_FAMILY: &str = "contracts";
+// Block hash -> the stake registry after that block, as JSON. Pruned with
+// the block.
+const STAKES_COLUMN_FAMILY: &str = "stakes";
+// Block hash -> the BFT quorum certificate that decided it, as JSON.
+const COMMITS_COLUMN_FAMILY: &str = "commits";
+// "<block hash>:<validator>" -> the validator's `CheckpointVote`, as JSON.
+const CHECKPOINT_VOTES_COLUMN_FAMILY: &str = "checkpoint_votes";
+// Block hash -> the `FinalityProof` that made the block final, as JSON.
+const FINALITY_COLUMN_FAMILY: &str = "finality";
+// "<validator>:<height>" -> hash of the checkpoint this node signed at that
+// height as the validator, so it never signs another one there.
+const SIGNED_CHECKPOINTS_COLUMN_FAMILY: &str = "signed_checkpoints";
 
 pub struct Storage {
-    pub storage_type: StorageType,
-    pub db: Db,
+    pub db: Arc<Db>,
+    // Blocks and states, kept according to `StorageConfig::pruning`.
+    pub chain: ChainStore,
 }
 
 impl Storage {
-    pub fn new(storage_type: StorageType) -> Self {
-        // Initialize storage
+    // Opens the configured database, upgrading its layout to the current
+    // schema version first.
+    pub fn open(config: &StorageConfig) -> Result<Self, StorageError> {
+        Storage::with_db(Db::from_config(config)?, config.pruning)
+    }
+
+    pub fn with_backend(backend: Box<dyn Backend>, pruning: Pruning) -> Result<Self, StorageError> {
+        Storage::with_db(Db::with_backend(backend), pruning)
+    }
+
+    // Reports the schema migrations `open` would run. The database is read
+    // through a snapshot, so nothing on disk changes, not even a torn tail
+    // that `open` would truncate.
+    pub fn plan_migrations(config: &StorageConfig) -> Result<MigrationReport, StorageError> {
+        Ok(migration::plan(Db::open_snapshot(config)?)?)
+    }
+
+    fn with_db(db: Db, pruning: Pruning) -> Result<Self, StorageError> {
+        migration::migrate(&db, false)?;
+        let db = Arc::new(db);
+        Ok(Storage {
+            chain: ChainStore::with_block_data(db.clone(), pruning, &[STAKES_COLUMN_FAMILY, VOTES_COLUMN_FAMILY]),
+            db,
+        })
+    }
+
+    // Appends `block` to the chain and drops its transactions from the
+    // pending pool. The block's state must already be committed.
+    pub fn add_block(&self, block: Block) -> Result<(), StorageError> {
+        self.chain.put_block(&block)?;
+        // A crash in between only leaves included transactions pending;
+        // `add_transaction` and `pending_transactions` skip those.
+        let mut batch = WriteBatch::new();
+        for transaction in &block.transactions {
+            batch.delete_cf(PENDING_COLUMN_FAMILY, &transaction.hash());
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    // Makes `branch`, consecutive blocks whose first parent is canonical,
+    // the canonical chain, replacing the blocks above that parent. In the
+    // same write, the replaced blocks' transactions that `branch` does not
+    // include return to the pending pool and those it does include leave
+    // it. States need no unwinding: each block names its own state root.
+    pub fn reorg(&self, branch: &[Block]) -> Result<Reorg, StorageError> {
+        let included: HashSet<String> = branch
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .map(|transaction| transaction.hash())
+            .collect();
+        let mut requeued = 0;
+        let unwound = self.chain.put_blocks_with(branch, |replaced, batch| {
+            for hash in &included {
+                batch.delete_cf(PENDING_COLUMN_FAMILY, hash);
+            }
+            for transaction in replaced.iter().flat_map(|block| &block.transactions) {
+                let hash = transaction.hash();
+                if !included.contains(&hash) {
+                    let value = serde_json::to_vec(transaction).map_err(|err| DbError::QueryError(err.to_string()))?;
+                    batch.put_cf(PENDING_COLUMN_FAMILY, &hash, value);
+                    requeued += 1;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(Reorg {
+            common_ancestor: branch.first().map(|block| block.parent_hash.clone()).unwrap_or_default(),
+            unwound,
+            applied: branch.to_vec(),
+            requeued,
+        })
+    }
+
+    // Stores `block` without adding it to the chain; see
+    // `ChainStore::store_block`.
+    pub fn store_block(&self, block: &Block) -> Result<(), StorageError> {
+        Ok(self.chain.store_block(block)?)
+    }
+
+    // Whether `block` is the canonical block at its height.
+    pub fn is_canonical(&self, block: &Block) -> Result<bool, StorageError> {
+        Ok(self.chain.block(block.height)?.map(|canonical| canonical.hash) == Some(block.hash.clone()))
+    }
+
+    // Deletes the branch ending at `tip` down to the canonical chain; see
+    // `ChainStore::prune_branch`.
+    pub fn prune_branch(&self, tip: &Block) -> Result<usize, StorageError> {
+        Ok(self.chain.prune_branch(tip)?)
+    }
+
+    // The ends of every stored branch; see `ChainStore::tips`.
+    pub fn tips(&self) -> Result<Vec<Block>, StorageError> {
+        Ok(self.chain.tips()?)
+    }
+
+    pub fn get_block(&self, block_hash: &str) -> Result<Option<Block>, StorageError> {
+        Ok(self.chain.block_by_hash(block_hash)?)
+    }
+
+    pub fn latest_block(&self) -> Result<Option<Block>, StorageError> {
+        Ok(self.chain.head_block()?)
+    }
+
+    // Queues `transaction` for a future block. Transactions already on the
+    // chain are ignored.
+    pub fn add_transaction(&self, transaction: Transaction) -> Result<(), StorageError> {
+        let hash = transaction.hash();
+        if self.chain.transaction(&hash)?.is_some() {
+            return Ok(());
+        }
+        let value = serde_json::to_vec(&transaction)?;
+        self.db.cf(PENDING_COLUMN_FAMILY)?.put(&hash, value)?;
+        Ok(())
+    }
+
+    // Up to `limit` pending transactions, ordered by hash.
+    pub fn pending_transactions(&self, limit: usize) -> Result<Vec<Transaction>, StorageError> {
+        let mut transactions = Vec::new();
+        for entry in self.db.cf(PENDING_COLUMN_FAMILY)?.iter(KeyRange::All, Direction::Forward) {
+            if transactions.len() >= limit {
+                break;
+            }
+            let (hash, value) = entry?;
+            if self.chain.transaction(&hash)?.is_none() {
+                transactions.push(serde_json::from_slice(&value)?);
+            }
+        }
+        Ok(transactions)
+    }
+
+    pub fn put_vote(&self, voter: &str, block_hash: &str, approve: bool) -> Result<(), StorageError> {
+        self.db
+            .cf(VOTES_COLUMN_FAMILY)?
+            .put(&vote_key(block_hash, voter), vec![approve as u8])?;
+        Ok(())
+    }
+
+    // How `voter` voted on `block`, if it has.
+    pub fn get_vote(&self, voter: &str, block: &Block) -> Result<Option<bool>, StorageError> {
+        match self.db.cf(VOTES_COLUMN_FAMILY)?.get(&vote_key(&block.hash, voter))? {
+            Some(value) => Ok(Some(value.first() == Some(&1))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn store_contract(&self, contract_id: &str) -> Result<(), StorageError> {
+        self.db.cf(CONTRACTS_COLUMN_FAMILY)?.put(contract_id, Vec::new())?;
+        Ok(())
+    }
+
+    pub fn has_contract(&self, contract_id: &str) -> Result<bool, StorageError> {
+        Ok(self.db.cf(CONTRACTS_COLUMN_FAMILY)?.get(contract_id)?.is_some())
+    }
+
+    // Stores `block` like `store_block`, with `stakes`, the registry in
+    // effect after it, in the same write.
+    pub fn store_block_with_stakes(&self, block: &Block, stakes: &StakeRegistry) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(stakes)?;
+        self.chain
+            .store_block_with(block, |batch| {
+                batch.put_cf(STAKES_COLUMN_FAMILY, &block.hash, value);
+            })?;
+        Ok(())
     }
 
-    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        // Put key-value pair in storage
+    // Records the validator stakes in effect after the block `block_hash`.
+    pub fn put_stakes(&self, block_hash: &str, stakes: &StakeRegistry) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(stakes)?;
+        self.db.cf(STAKES_COLUMN_FAMILY)?.put(block_hash, value)?;
+        Ok(())
     }
 
-    pub fn get(&self, key: Vec<u8>) -> Option<Vec<u8>> {
-        // Get value from storage
+    pub fn stakes(&self, block_hash: &str) -> Result<Option<StakeRegistry>, StorageError> {
+        match self.db.cf(STAKES_COLUMN_FAMILY)?.get(block_hash)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Records the precommits that decided the block `block_hash`.
+    pub fn put_certificate(&self, block_hash: &str, certificate: &QuorumCertificate) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(certificate)?;
+        self.db.cf(COMMITS_COLUMN_FAMILY)?.put(block_hash, value)?;
+        Ok(())
+    }
+
+    pub fn certificate(&self, block_hash: &str) -> Result<Option<QuorumCertificate>, StorageError> {
+        match self.db.cf(COMMITS_COLUMN_FAMILY)?.get(block_hash)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put_checkpoint_vote(&self, vote: &CheckpointVote) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(vote)?;
+        self.db
+            .cf(CHECKPOINT_VOTES_COLUMN_FAMILY)?
+            .put(&vote_key(&vote.block_hash, &vote.validator), value)?;
+        Ok(())
+    }
+
+    // Every validator's vote for the checkpoint `block_hash`, by validator.
+    pub fn checkpoint_votes(&self, block_hash: &str) -> Result<Vec<CheckpointVote>, StorageError> {
+        let prefix = KeyRange::Prefix(format!("{}:", block_hash));
+        let mut votes = Vec::new();
+        for entry in self.db.cf(CHECKPOINT_VOTES_COLUMN_FAMILY)?.iter(prefix, Direction::Forward) {
+            let (_, value) = entry?;
+            votes.push(serde_json::from_slice(&value)?);
+        }
+        Ok(votes)
+    }
+
+    // Deletes the votes for checkpoints below `height`, which can no longer
+    // become final. Returns how many were deleted.
+    pub fn prune_checkpoint_votes(&self, height: u64) -> Result<usize, StorageError> {
+        let mut batch = WriteBatch::new();
+        let mut pruned = 0;
+        for entry in self.db.cf(CHECKPOINT_VOTES_COLUMN_FAMILY)?.iter(KeyRange::All, Direction::Forward) {
+            let (key, value) = entry?;
+            let vote: CheckpointVote = serde_json::from_slice(&value)?;
+            if vote.height < height {
+                batch.delete_cf(CHECKPOINT_VOTES_COLUMN_FAMILY, &key);
+                pruned += 1;
+            }
+        }
+        self.db.write(batch)?;
+        Ok(pruned)
+    }
+
+    // Records that this node signed the checkpoint `block_hash` at `height`
+    // as `validator`.
+    pub fn put_signed_checkpoint(&self, validator: &str, height: u64, block_hash: &str) -> Result<(), StorageError> {
+        self.db
+            .cf(SIGNED_CHECKPOINTS_COLUMN_FAMILY)?
+            .put(&signed_checkpoint_key(validator, height), block_hash.as_bytes().to_vec())?;
+        Ok(())
+    }
+
+    // The checkpoint this node signed at `height` as `validator`, if any.
+    pub fn signed_checkpoint(&self, validator: &str, height: u64) -> Result<Option<String>, StorageError> {
+        Ok(self
+            .db
+            .cf(SIGNED_CHECKPOINTS_COLUMN_FAMILY)?
+            .get(&signed_checkpoint_key(validator, height))?
+            .map(|hash| String::from_utf8_lossy(&hash).into_owned()))
+    }
+
+    // Makes the canonical `block` final, keeping `proof` to serve. The
+    // proof is written first, so a crash in between leaves a proof for a
+    // block that is not final yet, never the reverse. The votes and
+    // signing records up to `block` are dropped after: the proof stands
+    // for them, and nothing at or below a final block is signed again.
+    pub fn finalize(&self, block: &Block, proof: &FinalityProof) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(proof)?;
+        self.db.cf(FINALITY_COLUMN_FAMILY)?.put(&block.hash, value)?;
+        self.chain.finalize(block)?;
+        self.prune_checkpoint_votes(block.height + 1)?;
+        let mut batch = WriteBatch::new();
+        for entry in self.db.cf(SIGNED_CHECKPOINTS_COLUMN_FAMILY)?.iter(KeyRange::All, Direction::Forward) {
+            let (key, _) = entry?;
+            let height = key.rsplit(':').next().and_then(|height| height.parse::<u64>().ok());
+            if height.is_some_and(|height| height <= block.height) {
+                batch.delete_cf(SIGNED_CHECKPOINTS_COLUMN_FAMILY, &key);
+            }
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    pub fn finality_proof(&self, block_hash: &str) -> Result<Option<FinalityProof>, StorageError> {
+        match self.db.cf(FINALITY_COLUMN_FAMILY)?.get(block_hash)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // The latest final block; see `ChainStore::finalized`.
+    pub fn finalized(&self) -> Result<Option<Checkpoint>, StorageError> {
+        Ok(self.chain.finalized()?)
+    }
+}
+
+// What `Storage::reorg` changed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Reorg {
+    // Hash of the last block both branches share.
+    pub common_ancestor: String,
+    // Canonical blocks taken off the chain, oldest first.
+    pub unwound: Vec<Block>,
+    // The blocks that replaced them, oldest first.
+    pub applied: Vec<Block>,
+    // Transactions returned to the pending pool.
+    pub requeued: usize,
+}
+
+fn vote_key(block_hash: &str, voter: &str) -> String {
+    format!("{}:{}", block_hash, voter)
+}
+
+fn signed_checkpoint_key(validator: &str, height: u64) -> String {
+    format!("{}:{:020}", validator, height)
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    DbError(DbError),
+    Corrupt(String),
+    ContractNotFound,
+    // The async storage's writer or a blocking read task has stopped.
+    Unavailable(String),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StorageError::DbError(err) => write!(f, "{}", err),
+            StorageError::Corrupt(err) => write!(f, "Corrupt storage: {}", err),
+            StorageError::ContractNotFound => write!(f, "Contract not found"),
+            StorageError::Unavailable(err) => write!(f, "Storage unavailable: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<DbError> for StorageError {
+    fn from(err: DbError) -> Self {
+        StorageError::DbError(err)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(err: serde_json::Error) -> Self {
+        StorageError::Corrupt(err.to_string())
     }
 }
@@ -0,0 +1,50 @@
+// admin.rs
+//
+// Offline storage commands, run as `pi-sentinel storage <command> ...` while
+// the node is stopped:
+//
+//   backup <config> <archive>    write an archive of the database
+//   restore <config> <archive>   replace the database with an archive
+//   verify <config> <archive>    check an archive without applying it
+//
+// `<config>` is the node's JSON config file; its storage section names the
+// database and the encryption key, which archives are sealed and opened with.
+
+use std::path::Path;
+
+use crate::backup::{self, BackupInfo};
+use crate::config::Config;
+use crate::db::{Db, DbError, DbType};
+
+pub const USAGE: &str = "usage: pi-sentinel storage <backup|restore|verify> <config> <archive>";
+
+// Runs the command in `args`, the arguments after `storage`, and returns a
+// line describing the result.
+pub fn run(args: &[String]) -> Result<String, DbError> {
+    let (command, config, archive) = match args {
+        [command, config, archive] => (command.as_str(), config, Path::new(archive)),
+        _ => return Err(DbError::QueryError(USAGE.to_string())),
+    };
+    let config = Config::load_from_file(Path::new(config))?;
+    let storage = &config.storage;
+    let info = match command {
+        "backup" => Db::open(DbType::Disk, storage)?.backup(archive, storage.encryption.as_ref())?,
+        "restore" => Db::restore(archive, storage)?,
+        "verify" => backup::verify_archive(archive, storage.encryption.as_ref())?,
+        _ => return Err(DbError::QueryError(USAGE.to_string())),
+    };
+    Ok(describe(command, archive, &info))
+}
+
+fn describe(command: &str, archive: &Path, info: &BackupInfo) -> String {
+    format!(
+        "{} {}: version {}, {} entries, {} bytes, {}, sha256 {}",
+        command,
+        archive.display(),
+        info.version,
+        info.entries,
+        info.bytes,
+        if info.encrypted { "encrypted" } else { "plaintext" },
+        info.checksum
+    )
+}
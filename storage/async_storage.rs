@@ -0,0 +1,168 @@
+// async_storage.rs
+//
+// `Storage` for tasks on the tokio runtime. Reads run on tokio's blocking
+// thread pool, so any number of them proceed concurrently without stalling
+// executor threads on disk I/O. Writes are queued to one dedicated writer
+// thread and applied in the order they were submitted; awaiting a write
+// resolves once it has been applied. The queue is bounded, so writers wait
+// rather than pile up jobs, and a job that panics fails only its own write.
+//
+// The async methods must be awaited on a tokio runtime. Threads outside the
+// runtime write through `write_blocking`, so every write of the node goes
+// through the same queue; they may read the shared `Storage` directly.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+
+use crate::blockchain::{Block, Transaction};
+use crate::chain::TxLocation;
+use crate::db::Row;
+use crate::storage::{Storage, StorageError};
+
+type WriteJob = Box<dyn FnOnce(&Storage) + Send>;
+
+// Writes queued before submitting another one waits.
+const WRITE_QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Clone)]
+pub struct AsyncStorage {
+    storage: Arc<Storage>,
+    writer: mpsc::Sender<WriteJob>,
+}
+
+impl AsyncStorage {
+    // Starts the writer thread. It stops once every clone of the handle has
+    // been dropped and the queued writes are done.
+    pub fn new(storage: Arc<Storage>) -> Self {
+        let (writer, mut jobs) = mpsc::channel::<WriteJob>(WRITE_QUEUE_CAPACITY);
+        let target = storage.clone();
+        thread::Builder::new()
+            .name("storage-writer".to_string())
+            .spawn(move || {
+                while let Some(job) = jobs.blocking_recv() {
+                    job(&target);
+                }
+            })
+            .expect("failed to start the storage writer thread");
+        AsyncStorage { storage, writer }
+    }
+
+    // The blocking interface to the same storage.
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.storage
+    }
+
+    // Runs `read` on the blocking thread pool.
+    pub async fn read<T, F>(&self, read: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Storage) -> Result<T, StorageError> + Send + 'static,
+    {
+        let storage = self.storage.clone();
+        task::spawn_blocking(move || read(&storage))
+            .await
+            .map_err(|err| StorageError::Unavailable(format!("storage read failed: {}", err)))?
+    }
+
+    // Queues `write` for the writer thread and waits for its result.
+    pub async fn write<T, F>(&self, write: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Storage) -> Result<T, StorageError> + Send + 'static,
+    {
+        let (job, result) = write_job(write);
+        self.writer.send(job).await.map_err(|_| writer_stopped())?;
+        result.await.map_err(|_| writer_stopped())?
+    }
+
+    // `write` for threads outside the tokio runtime; it panics if called
+    // from a task on it.
+    pub fn write_blocking<T, F>(&self, write: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Storage) -> Result<T, StorageError> + Send + 'static,
+    {
+        let (job, result) = write_job(write);
+        self.writer.blocking_send(job).map_err(|_| writer_stopped())?;
+        result.blocking_recv().map_err(|_| writer_stopped())?
+    }
+
+    pub async fn get_block(&self, block_hash: &str) -> Result<Option<Block>, StorageError> {
+        let block_hash = block_hash.to_string();
+        self.read(move |storage| storage.get_block(&block_hash)).await
+    }
+
+    pub async fn block_at(&self, height: u64) -> Result<Option<Block>, StorageError> {
+        self.read(move |storage| Ok(storage.chain.block(height)?)).await
+    }
+
+    pub async fn latest_block(&self) -> Result<Option<Block>, StorageError> {
+        self.read(|storage| storage.latest_block()).await
+    }
+
+    pub async fn transaction(&self, hash: &str) -> Result<Option<(TxLocation, Transaction)>, StorageError> {
+        let hash = hash.to_string();
+        self.read(move |storage| Ok(storage.chain.transaction(&hash)?)).await
+    }
+
+    pub async fn pending_transactions(&self, limit: usize) -> Result<Vec<Transaction>, StorageError> {
+        self.read(move |storage| storage.pending_transactions(limit)).await
+    }
+
+    pub async fn get_vote(&self, voter: &str, block: &Block) -> Result<Option<bool>, StorageError> {
+        let (voter, block) = (voter.to_string(), block.clone());
+        self.read(move |storage| storage.get_vote(&voter, &block)).await
+    }
+
+    // Runs `query` against a snapshot; see docs/storage_queries.md.
+    pub async fn query(&self, query: &str) -> Result<Vec<Row>, StorageError> {
+        let query = query.to_string();
+        self.read(move |storage| Ok(storage.db.snapshot()?.execute(&query)?)).await
+    }
+
+    pub async fn add_block(&self, block: Block) -> Result<(), StorageError> {
+        self.write(move |storage| storage.add_block(block)).await
+    }
+
+    pub async fn add_transaction(&self, transaction: Transaction) -> Result<(), StorageError> {
+        self.write(move |storage| storage.add_transaction(transaction)).await
+    }
+
+    pub async fn put_vote(&self, voter: &str, block_hash: &str, approve: bool) -> Result<(), StorageError> {
+        let (voter, block_hash) = (voter.to_string(), block_hash.to_string());
+        self.write(move |storage| storage.put_vote(&voter, &block_hash, approve)).await
+    }
+
+    pub async fn store_contract(&self, contract_id: &str) -> Result<(), StorageError> {
+        let contract_id = contract_id.to_string();
+        self.write(move |storage| storage.store_contract(&contract_id)).await
+    }
+}
+
+// Wraps `write` for the writer thread, which sends its result, or an error
+// if it panicked, to the returned receiver.
+fn write_job<T, F>(write: F) -> (WriteJob, oneshot::Receiver<Result<T, StorageError>>)
+where
+    T: Send + 'static,
+    F: FnOnce(&Storage) -> Result<T, StorageError> + Send + 'static,
+{
+    let (done, result) = oneshot::channel();
+    let job: WriteJob = Box::new(move |storage| {
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| write(storage)))
+            .unwrap_or_else(|_| {
+                log::error!("a storage write panicked");
+                Err(StorageError::Unavailable("storage write panicked".to_string()))
+            });
+        // The caller may have stopped waiting.
+        let _ = done.send(outcome);
+    });
+    (job, result)
+}
+
+fn writer_stopped() -> StorageError {
+    StorageError::Unavailable("storage writer stopped".to_string())
+}
@@ -0,0 +1,146 @@
+// backend.rs
+//
+// The key-value engine behind a `Db`. The memory and disk engines in db.rs
+// implement `Backend`; any other engine plugs in through `Db::with_backend`
+// and should pass the conformance suite in testing/conformance.rs.
+//
+// A backend stores raw string keys ordered by their UTF-8 bytes. Column
+// families, queries, iterators and backups are built on top of it by `Db`,
+// so an engine only needs point reads, atomic batches, ordered range reads
+// and snapshots.
+
+use crate::cache::ReadStats;
+use crate::config::EncryptionKey;
+use crate::db::{BatchOp, CompactionStats, DbError, Direction, KeyRange, WriteBatch};
+
+// Entries fetched per call when a default method walks a range.
+const SCAN_CHUNK_SIZE: usize = 256;
+
+pub trait Backend: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError>;
+
+    // Applies every operation in `batch`, in order, atomically: readers and
+    // snapshots see all of them or none, and so does the engine after a
+    // crash if it persists anything.
+    fn write(&self, batch: WriteBatch) -> Result<(), DbError>;
+
+    // Up to `limit` entries of `range` in `direction`, starting after the
+    // key `after` if it is given.
+    fn chunk(
+        &self,
+        range: &KeyRange,
+        direction: Direction,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError>;
+
+    // A read-only view of the current contents that later writes do not
+    // change.
+    fn snapshot(&self) -> Result<Box<dyn BackendSnapshot>, DbError>;
+
+    fn put(&self, key: &str, value: Vec<u8>) -> Result<(), DbError> {
+        self.write(WriteBatch::from_ops(vec![BatchOp::Put(key.to_string(), value)]))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), DbError> {
+        self.write(WriteBatch::from_ops(vec![BatchOp::Delete(key.to_string())]))
+    }
+
+    fn keys(&self, range: &KeyRange, direction: Direction, limit: usize) -> Result<Vec<String>, DbError> {
+        scan_keys(|after, count| self.chunk(range, direction, after, count), limit)
+    }
+
+    // Number of keys in `range` and the total size of their values.
+    fn stats(&self, range: &KeyRange) -> Result<(u64, u64), DbError> {
+        scan_stats(|after, count| self.chunk(range, Direction::Forward, after, count))
+    }
+
+    // Reclaims space held by dead values. Engines that never hold any only
+    // report the live key count.
+    fn compact(&self) -> Result<CompactionStats, DbError> {
+        let (live_keys, _) = self.stats(&KeyRange::All)?;
+        Ok(CompactionStats {
+            bytes_before: 0,
+            bytes_after: 0,
+            live_keys,
+        })
+    }
+
+    // Fraction of the storage footprint that `compact` would reclaim.
+    fn garbage_ratio(&self) -> Result<f64, DbError> {
+        Ok(0.0)
+    }
+
+    // Bytes acknowledged to writers but not yet on stable storage.
+    fn unsynced_bytes(&self) -> Result<u64, DbError> {
+        Ok(0)
+    }
+
+    fn sync(&self) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    // Re-encrypts everything at rest under `key`, or decrypts it if `key`
+    // is `None`.
+    fn rotate_key(&self, _key: Option<&EncryptionKey>) -> Result<(), DbError> {
+        Err(DbError::EncryptionError("this storage backend does not support encryption".to_string()))
+    }
+
+    // Cache and bloom filter counters, all zero for engines without them.
+    fn read_stats(&self) -> ReadStats {
+        ReadStats::default()
+    }
+}
+
+pub trait BackendSnapshot: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError>;
+
+    fn chunk(
+        &self,
+        range: &KeyRange,
+        direction: Direction,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError>;
+
+    fn keys(&self, range: &KeyRange, direction: Direction, limit: usize) -> Result<Vec<String>, DbError> {
+        scan_keys(|after, count| self.chunk(range, direction, after, count), limit)
+    }
+
+    fn stats(&self, range: &KeyRange) -> Result<(u64, u64), DbError> {
+        scan_stats(|after, count| self.chunk(range, Direction::Forward, after, count))
+    }
+}
+
+type Chunk = Vec<(String, Vec<u8>)>;
+
+fn scan_keys(
+    chunk: impl Fn(Option<&str>, usize) -> Result<Chunk, DbError>,
+    limit: usize,
+) -> Result<Vec<String>, DbError> {
+    let mut keys: Vec<String> = Vec::new();
+    while keys.len() < limit {
+        let count = (limit - keys.len()).min(SCAN_CHUNK_SIZE);
+        let entries = chunk(keys.last().map(String::as_str), count)?;
+        let done = entries.len() < count;
+        keys.extend(entries.into_iter().map(|(key, _)| key));
+        if done {
+            break;
+        }
+    }
+    Ok(keys)
+}
+
+fn scan_stats(chunk: impl Fn(Option<&str>, usize) -> Result<Chunk, DbError>) -> Result<(u64, u64), DbError> {
+    let (mut count, mut bytes) = (0, 0);
+    let mut after: Option<String> = None;
+    loop {
+        let entries = chunk(after.as_deref(), SCAN_CHUNK_SIZE)?;
+        count += entries.len() as u64;
+        bytes += entries.iter().map(|(_, value)| value.len() as u64).sum::<u64>();
+        match entries.last() {
+            Some((key, _)) if entries.len() == SCAN_CHUNK_SIZE => after = Some(key.clone()),
+            _ => return Ok((count, bytes)),
+        }
+    }
+}
@@ -0,0 +1,301 @@
+// backup.rs
+//
+// Portable backup archives of a `Db`. An archive lists every key of every
+// column family, independent of the backend and its on-disk layout:
+//
+//   magic "PISNTBAK" | version (u32) | header_len (u32) | header
+//   entry*:  1 (u8) | cf_len (u32) | key_len (u32) | value_len (u32) | cf | key | value
+//   trailer: 0 (u8) | entry_count (u64) | sha256 (32 bytes)
+//
+// with all integers little-endian and the SHA-256 digest covering every byte
+// before it. The header is the `Cipher` header of an encrypted archive and
+// empty otherwise; an encrypted archive seals each value with its column
+// family and key as associated data. Version 1 archives have no header and
+// are always plaintext.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::config::EncryptionKey;
+use crate::db::DbError;
+use crate::encryption::Cipher;
+
+const MAGIC: &[u8; 8] = b"PISNTBAK";
+pub const ARCHIVE_VERSION: u32 = 2;
+// Bounds the header length read from an archive.
+const MAX_HEADER_LEN: u64 = 4096;
+const TAG_END: u8 = 0;
+const TAG_ENTRY: u8 = 1;
+
+#[derive(Debug, Clone)]
+pub struct BackupInfo {
+    pub version: u32,
+    pub entries: u64,
+    pub bytes: u64,
+    pub encrypted: bool,
+    // Hex-encoded SHA-256 of the archive contents.
+    pub checksum: String,
+}
+
+// Writes `entries` as (column family, key, value) triples to an archive at
+// `path`, sealing the values with `cipher` if given. The archive is written
+// as `<path>.partial` and renamed into place once complete, so an
+// interrupted backup never looks valid.
+pub fn write_archive<I>(path: &Path, cipher: Option<&Cipher>, entries: I) -> Result<BackupInfo, DbError>
+where
+    I: IntoIterator<Item = Result<(String, String, Vec<u8>), DbError>>,
+{
+    let partial = partial_path(path);
+    let mut writer = HashingWriter {
+        inner: BufWriter::new(File::create(&partial)?),
+        hasher: Sha256::new(),
+        written: 0,
+    };
+    writer.write_all(MAGIC)?;
+    writer.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+    let header = cipher.map(Cipher::header).unwrap_or_default();
+    writer.write_all(&(header.len() as u32).to_le_bytes())?;
+    writer.write_all(&header)?;
+
+    let mut count = 0u64;
+    for entry in entries {
+        let (cf, key, value) = entry?;
+        let value = match cipher {
+            Some(cipher) => cipher.seal(&associated_data(&cf, &key), &value),
+            None => value,
+        };
+        if value.len() > u32::MAX as usize {
+            return Err(DbError::IOError(format!("value of {} is too large to back up", key)));
+        }
+        writer.write_all(&[TAG_ENTRY])?;
+        writer.write_all(&(cf.len() as u32).to_le_bytes())?;
+        writer.write_all(&(key.len() as u32).to_le_bytes())?;
+        writer.write_all(&(value.len() as u32).to_le_bytes())?;
+        writer.write_all(cf.as_bytes())?;
+        writer.write_all(key.as_bytes())?;
+        writer.write_all(&value)?;
+        count += 1;
+    }
+    writer.write_all(&[TAG_END])?;
+    writer.write_all(&count.to_le_bytes())?;
+
+    let digest = writer.hasher.finalize_reset();
+    let mut file = writer.inner.into_inner().map_err(|err| DbError::IOError(err.to_string()))?;
+    file.write_all(&digest)?;
+    file.sync_all()?;
+    fs::rename(&partial, path)?;
+
+    Ok(BackupInfo {
+        version: ARCHIVE_VERSION,
+        entries: count,
+        bytes: writer.written + digest.len() as u64,
+        encrypted: cipher.is_some(),
+        checksum: to_hex(&digest),
+    })
+}
+
+// Checks the archive at `path` end to end without applying it. The values of
+// an encrypted archive are opened with `key`, which it requires.
+pub fn verify_archive(path: &Path, key: Option<&EncryptionKey>) -> Result<BackupInfo, DbError> {
+    read_archive(path, key, |_, _, _| Ok(()))
+}
+
+// Reads the archive at `path`, passing every entry to `apply` with its value
+// decrypted. Entries are handed over before the checksum at the end has been
+// checked, so callers that must not act on a corrupt archive should run
+// `verify_archive` first.
+pub fn read_archive<F>(path: &Path, key: Option<&EncryptionKey>, mut apply: F) -> Result<BackupInfo, DbError>
+where
+    F: FnMut(String, String, Vec<u8>) -> Result<(), DbError>,
+{
+    let mut reader = HashingReader {
+        inner: BufReader::new(File::open(path)?),
+        hasher: Sha256::new(),
+        read: 0,
+    };
+
+    let mut magic = [0u8; 8];
+    read_exact(&mut reader, &mut magic)?;
+    if &magic != MAGIC {
+        return Err(corrupt("not a backup archive"));
+    }
+    let version = read_u32(&mut reader)?;
+    if version > ARCHIVE_VERSION {
+        return Err(DbError::IOError(format!(
+            "backup archive version {} is newer than the supported version {}",
+            version, ARCHIVE_VERSION
+        )));
+    }
+    let header = match version {
+        1 => Vec::new(),
+        _ => {
+            let len = read_u32(&mut reader)? as u64;
+            if len > MAX_HEADER_LEN {
+                return Err(corrupt("header too long"));
+            }
+            read_bytes(&mut reader, len)?
+        }
+    };
+    let cipher = match (header.is_empty(), key) {
+        (true, _) => None,
+        (false, Some(key)) => Some(Cipher::from_header(key, &header)?),
+        (false, None) => {
+            return Err(DbError::EncryptionError(
+                "the backup archive is encrypted but no encryption key is configured".to_string(),
+            ))
+        }
+    };
+
+    let mut count = 0u64;
+    loop {
+        let mut tag = [0u8; 1];
+        read_exact(&mut reader, &mut tag)?;
+        match tag[0] {
+            TAG_ENTRY => {
+                let cf_len = read_u32(&mut reader)? as u64;
+                let key_len = read_u32(&mut reader)? as u64;
+                let value_len = read_u32(&mut reader)? as u64;
+                let cf = read_string(&mut reader, cf_len)?;
+                let key = read_string(&mut reader, key_len)?;
+                let value = read_bytes(&mut reader, value_len)?;
+                if cf.is_empty() || cf.contains('\0') {
+                    return Err(corrupt("invalid column family name"));
+                }
+                let value = match &cipher {
+                    Some(cipher) => cipher.open(&associated_data(&cf, &key), &value)?,
+                    None => value,
+                };
+                apply(cf, key, value)?;
+                count += 1;
+            }
+            TAG_END => break,
+            _ => return Err(corrupt("unknown entry tag")),
+        }
+    }
+
+    let mut expected = [0u8; 8];
+    read_exact(&mut reader, &mut expected)?;
+    if u64::from_le_bytes(expected) != count {
+        return Err(corrupt("entry count mismatch"));
+    }
+    let digest = reader.hasher.finalize_reset();
+    let mut stored = [0u8; 32];
+    reader.inner.read_exact(&mut stored).map_err(|_| corrupt("truncated"))?;
+    if stored[..] != digest[..] {
+        return Err(corrupt("checksum mismatch"));
+    }
+    if reader.inner.read(&mut [0u8; 1])? != 0 {
+        return Err(corrupt("unexpected data after the checksum"));
+    }
+
+    Ok(BackupInfo {
+        version,
+        entries: count,
+        bytes: reader.read + stored.len() as u64,
+        encrypted: cipher.is_some(),
+        checksum: to_hex(&digest),
+    })
+}
+
+// The archive `name` inside `dir`, or `None` unless `name` is a plain file
+// name, so a remote caller cannot write outside the backup directory.
+pub fn archive_in(dir: &Path, name: &str) -> Option<PathBuf> {
+    let plain = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0']);
+    if plain {
+        Some(dir.join(name))
+    } else {
+        None
+    }
+}
+
+// `<path>.partial`, keeping the archive's own extension.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = OsString::from(path.as_os_str());
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+fn associated_data(cf: &str, key: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(cf.len() + 1 + key.len());
+    aad.extend_from_slice(cf.as_bytes());
+    aad.push(0);
+    aad.extend_from_slice(key.as_bytes());
+    aad
+}
+
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+    read: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), DbError> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => corrupt("truncated"),
+        _ => err.into(),
+    })
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, DbError> {
+    let mut buf = [0u8; 4];
+    read_exact(reader, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+// Reads through `take` rather than preallocating, so a corrupt length cannot
+// trigger a huge allocation.
+fn read_bytes<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>, DbError> {
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(corrupt("truncated"));
+    }
+    Ok(buf)
+}
+
+fn read_string<R: Read>(reader: &mut R, len: u64) -> Result<String, DbError> {
+    String::from_utf8(read_bytes(reader, len)?).map_err(|_| corrupt("key is not valid UTF-8"))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn corrupt(reason: &str) -> DbError {
+    DbError::IOError(format!("corrupt backup archive: {}", reason))
+}
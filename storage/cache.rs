@@ -0,0 +1,246 @@
+// cache.rs
+//
+// Read-path helpers for the disk backend. `ReadCache` keeps recently read
+// values in memory, bounded by their total size and evicting the least
+// recently used entry first. `SegmentedBloom` answers "definitely absent"
+// for most keys that were never written, so lookups of missing keys skip the
+// log and its lock altogether.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// About 0.1% false positives per segment at 14 bits per key with 10 hash
+// functions. Segments double in size, so a log of n keys has about
+// log2(n / BLOOM_FIRST_SEGMENT_KEYS) of them and a missing key passes one
+// of them around 1% of the time.
+const BLOOM_BITS_PER_KEY: usize = 14;
+const BLOOM_HASHES: u32 = 10;
+const BLOOM_FIRST_SEGMENT_KEYS: usize = 4096;
+const BLOOM_MAX_SEGMENT_KEYS: usize = 1 << 24;
+
+// Read counters of a backend since it was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReadStats {
+    pub cache_hits: u64,
+    // Reads of existing keys that had to go to the log.
+    pub cache_misses: u64,
+    // Reads of missing keys answered by the bloom filter alone.
+    pub bloom_negatives: u64,
+    // Reads of missing keys the bloom filter let through.
+    pub bloom_false_positives: u64,
+    // Log segments the bloom filter currently has a filter for.
+    pub bloom_segments: u64,
+}
+
+impl ReadStats {
+    pub fn cache_hit_ratio(&self) -> f64 {
+        let reads = self.cache_hits + self.cache_misses;
+        if reads == 0 {
+            return 0.0;
+        }
+        self.cache_hits as f64 / reads as f64
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ReadCounters {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    bloom_negatives: AtomicU64,
+    bloom_false_positives: AtomicU64,
+}
+
+impl ReadCounters {
+    pub(crate) fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bloom_negative(&self) {
+        self.bloom_negatives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bloom_false_positive(&self) {
+        self.bloom_false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> ReadStats {
+        ReadStats {
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            bloom_negatives: self.bloom_negatives.load(Ordering::Relaxed),
+            bloom_false_positives: self.bloom_false_positives.load(Ordering::Relaxed),
+            ..ReadStats::default()
+        }
+    }
+}
+
+pub(crate) struct ReadCache {
+    capacity: u64,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    // key -> (value, last use)
+    entries: HashMap<String, (Vec<u8>, u64)>,
+    // last use -> key, oldest first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: u64,
+}
+
+impl ReadCache {
+    // A cache holding at most `capacity` bytes of keys and values.
+    pub(crate) fn new(capacity: u64) -> Self {
+        ReadCache {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().ok()?;
+        let state = &mut *state;
+        state.tick += 1;
+        let (value, used) = state.entries.get_mut(key)?;
+        state.recency.remove(used);
+        *used = state.tick;
+        state.recency.insert(state.tick, key.to_string());
+        Some(value.clone())
+    }
+
+    pub(crate) fn insert(&self, key: &str, value: &[u8]) {
+        let size = entry_size(key, value);
+        if size > self.capacity {
+            return;
+        }
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        state.remove(key);
+        while state.bytes + size > self.capacity {
+            let oldest = match state.recency.keys().next() {
+                Some(&tick) => state.recency[&tick].clone(),
+                None => break,
+            };
+            state.remove(&oldest);
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.recency.insert(tick, key.to_string());
+        state.entries.insert(key.to_string(), (value.to_vec(), tick));
+        state.bytes += size;
+    }
+
+    pub(crate) fn remove(&self, key: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            state.remove(key);
+        }
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &str) {
+        if let Some((value, used)) = self.entries.remove(key) {
+            self.recency.remove(&used);
+            self.bytes -= entry_size(key, &value);
+        }
+    }
+}
+
+fn entry_size(key: &str, value: &[u8]) -> u64 {
+    (key.len() + value.len()) as u64
+}
+
+// Keys written to the disk log, with one filter per segment of it: the
+// keys appended while that filter had room. A full segment is never touched
+// again and the next one starts at twice its size, so the filter grows
+// without rehashing what it already holds. Compaction rebuilds it from the
+// live keys, dropping deleted ones.
+pub(crate) struct SegmentedBloom {
+    segments: Vec<BloomFilter>,
+}
+
+impl SegmentedBloom {
+    pub(crate) fn build<'a>(keys: impl Iterator<Item = &'a String>) -> Self {
+        let mut bloom = SegmentedBloom { segments: Vec::new() };
+        for key in keys {
+            bloom.insert(key);
+        }
+        bloom
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) {
+        if self.segments.last().is_none_or(|segment| segment.len == segment.capacity) {
+            let capacity = self
+                .segments
+                .last()
+                .map_or(BLOOM_FIRST_SEGMENT_KEYS, |segment| (segment.capacity * 2).min(BLOOM_MAX_SEGMENT_KEYS));
+            self.segments.push(BloomFilter::with_capacity(capacity));
+        }
+        if let Some(segment) = self.segments.last_mut() {
+            segment.insert(key_hash(key));
+        }
+    }
+
+    // False means `key` was never inserted; true means it probably was.
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        let hash = key_hash(key);
+        self.segments.iter().any(|segment| segment.may_contain(hash))
+    }
+
+    pub(crate) fn segments(&self) -> usize {
+        self.segments.len()
+    }
+}
+
+struct BloomFilter {
+    bits: Vec<u64>,
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    fn with_capacity(keys: usize) -> Self {
+        let words = (keys * BLOOM_BITS_PER_KEY).div_ceil(64);
+        BloomFilter {
+            bits: vec![0; words],
+            capacity: keys,
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, hash: u64) {
+        let bits = self.bits.len() as u64 * 64;
+        for bit in probes(hash, bits) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    fn may_contain(&self, hash: u64) -> bool {
+        let bits = self.bits.len() as u64 * 64;
+        probes(hash, bits).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+}
+
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Bit positions for a key with hash `first`, by double hashing.
+fn probes(first: u64, bits: u64) -> impl Iterator<Item = u64> {
+    // Odd, so the probes never collapse onto one bit.
+    let second = first.rotate_left(32) | 1;
+    (0..BLOOM_HASHES as u64).map(move |i| first.wrapping_add(i.wrapping_mul(second)) % bits)
+}
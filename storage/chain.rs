@@ -0,0 +1,706 @@
+// chain.rs
+//
+// Blocks, their states and the chain's secondary indexes in a `Db`:
+//
+//   blocks         block hash -> block (JSON)
+//   index_height   height (zero-padded) -> hash of the canonical block
+//   index_tx       "<tx hash>:<height>:<index>" -> `TxLocation` (JSON)
+//   index_address  "<address length>:<address>:<height>:<index>" ->
+//                  transaction hash, for every transaction the address
+//                  sent or received
+//   index_tips     hash of every stored block with no stored child -> empty
+//   chain          "head" -> head block hash, "oldest" -> oldest kept height,
+//                  "finalized" -> the latest final `Checkpoint` (JSON)
+//   state          state trie nodes (see trie.rs)
+//
+// The height, transaction and address indexes only cover the canonical
+// chain. Importing blocks updates them in the same write batch as the
+// blocks themselves; a block at or below the current head first rolls back
+// the indexes of the blocks it replaces. Replaced blocks stay readable by
+// hash, and blocks on other forks can be stored with `store_block`; the
+// tips index leads to every branch of the resulting tree, and
+// `prune_branch` deletes a branch that can no longer become canonical.
+// Once a block is
+// finalized (see finality.rs), no write may replace it or anything before
+// it; such writes fail with `DbError::Finalized`.
+//
+// In pruned mode only the newest `keep_blocks` canonical blocks are kept.
+// Older blocks and their index entries are deleted as they fall out of the
+// window, and state trie nodes no longer reachable from a kept block's state
+// root are swept in batches. Anything below the oldest kept height is
+// reported as `DbError::StatePruned`, even while its nodes await the sweep.
+// Column families given to `with_block_data`, keyed by block hash or by
+// block hash, a colon and a sub-key, lose a block's entries in the same
+// batch that prunes or sweeps the block.
+// State for a block not stored yet must be committed with `commit_state`,
+// which keeps its nodes out of sweeps until the block arrives.
+//
+// Every write that moves the head raises the flags handed out by
+// `watch_head`, whichever component made it.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{Block, Transaction};
+use crate::config::Pruning;
+use crate::db::{Db, DbError, Direction, KeyRange, WriteBatch};
+use crate::trie::{self, Hash, StateTrie, EMPTY_ROOT, STATE_COLUMN_FAMILY};
+
+pub const BLOCKS_COLUMN_FAMILY: &str = "blocks";
+pub const HEIGHT_INDEX: &str = "index_height";
+pub const TX_INDEX: &str = "index_tx";
+pub const ADDRESS_INDEX: &str = "index_address";
+pub const TIPS_INDEX: &str = "index_tips";
+pub const CHAIN_COLUMN_FAMILY: &str = "chain";
+pub const HEAD_KEY: &str = "head";
+const OLDEST_KEY: &str = "oldest";
+const FINALIZED_KEY: &str = "finalized";
+// Blocks pruned between two sweeps of the state trie.
+const SWEEP_INTERVAL_BLOCKS: u64 = 32;
+// Writes per batch when rebuilding the indexes.
+const REINDEX_BATCH_SIZE: usize = 1024;
+
+pub struct ChainStore {
+    db: Arc<Db>,
+    pruning: Pruning,
+    // Serializes block writes, state commits and the final step of a sweep.
+    write_lock: Mutex<()>,
+    // Serializes sweeps, which mark without holding `write_lock`.
+    sweep_lock: Mutex<()>,
+    // Roots committed with `commit_state` whose block is not stored yet,
+    // with the oldest kept height at the time. Sweeps keep their nodes, and
+    // forget roots whose block has not arrived within a sweep interval.
+    pending_roots: Mutex<HashMap<Hash, u64>>,
+    // Flags from `watch_head`; dropped ones are forgotten on the next
+    // head change.
+    head_watchers: Mutex<Vec<Weak<AtomicBool>>>,
+    // Column families keyed by block hash, pruned with the blocks.
+    block_data: Vec<&'static str>,
+}
+
+// Where a transaction sits in the canonical chain.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxLocation {
+    pub block_hash: String,
+    pub height: u64,
+    pub index: u32,
+}
+
+// A block by height and hash; kept even after pruning deletes the block.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub height: u64,
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SweepStats {
+    pub live_nodes: u64,
+    pub removed_nodes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReindexStats {
+    pub blocks: u64,
+    pub transactions: u64,
+}
+
+impl ChainStore {
+    pub fn new(db: Arc<Db>, pruning: Pruning) -> Self {
+        ChainStore::with_block_data(db, pruning, &[])
+    }
+
+    // A store that prunes the entries of `block_data`, column families keyed
+    // by block hash, along with their blocks.
+    pub fn with_block_data(db: Arc<Db>, pruning: Pruning, block_data: &[&'static str]) -> Self {
+        ChainStore {
+            db,
+            pruning,
+            write_lock: Mutex::new(()),
+            sweep_lock: Mutex::new(()),
+            pending_roots: Mutex::new(HashMap::new()),
+            head_watchers: Mutex::new(Vec::new()),
+            block_data: block_data.to_vec(),
+        }
+    }
+
+    // A flag that is set every time the head changes. The caller clears it;
+    // dropping it stops the updates.
+    pub fn watch_head(&self) -> Arc<AtomicBool> {
+        let flag = Arc::new(AtomicBool::new(false));
+        self.head_watchers().push(Arc::downgrade(&flag));
+        flag
+    }
+
+    // A panic elsewhere cannot leave the list half updated, so a poisoned
+    // lock is still safe to use.
+    fn head_watchers(&self) -> MutexGuard<'_, Vec<Weak<AtomicBool>>> {
+        self.head_watchers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    // Commits `state` for a block that is about to be stored. Unlike
+    // `StateTrie::commit`, this is safe while sweeps run.
+    pub fn commit_state(&self, state: &mut StateTrie) -> Result<Hash, DbError> {
+        let _writing = self.write_lock.lock().map_err(|_| lock_poisoned())?;
+        let root = state.commit()?;
+        if self.pruning != Pruning::Archive {
+            let oldest = self.oldest()?;
+            self.pending_roots.lock().map_err(|_| lock_poisoned())?.insert(root, oldest);
+        }
+        Ok(root)
+    }
+
+    // Stores `block` as the new chain head. Its state must already be
+    // committed under `block.state_root`.
+    pub fn put_block(&self, block: &Block) -> Result<(), DbError> {
+        self.put_blocks(std::slice::from_ref(block))
+    }
+
+    // Stores `block` by hash without making it canonical, e.g. a block on
+    // another fork. `put_blocks` can make it canonical later.
+    pub fn store_block(&self, block: &Block) -> Result<(), DbError> {
+        self.store_block_with(block, |_| {})
+    }
+
+    // `store_block`, with `stage` adding its own writes to the same batch.
+    // Nothing is written if the block is already stored.
+    pub fn store_block_with(&self, block: &Block, stage: impl FnOnce(&mut WriteBatch)) -> Result<(), DbError> {
+        let _writing = self.write_lock.lock().map_err(|_| lock_poisoned())?;
+        if self.block_by_hash(&block.hash)?.is_some() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        stage(&mut batch);
+        let value = serde_json::to_vec(block).map_err(|err| DbError::QueryError(err.to_string()))?;
+        batch.put_cf(BLOCKS_COLUMN_FAMILY, &block.hash, value);
+        batch.put_cf(TIPS_INDEX, &block.hash, Vec::new());
+        batch.delete_cf(TIPS_INDEX, &block.parent_hash);
+        self.db.write(batch)?;
+        self.pending_roots.lock().map_err(|_| lock_poisoned())?.remove(&block.state_root);
+        Ok(())
+    }
+
+    // Deletes the blocks of the branch ending at `tip`, down to the
+    // canonical chain, with their `block_data` entries; e.g. a fork from
+    // below the oldest kept or the final block, which can never become
+    // canonical. Returns how many blocks were deleted.
+    pub fn prune_branch(&self, tip: &Block) -> Result<usize, DbError> {
+        let _writing = self.write_lock.lock().map_err(|_| lock_poisoned())?;
+        let mut batch = WriteBatch::new();
+        let mut pruned = 0;
+        let mut current = Some(tip.clone());
+        while let Some(block) = current {
+            if self.canonical_hash(block.height)?.as_deref() == Some(block.hash.as_str()) {
+                break;
+            }
+            batch.delete_cf(BLOCKS_COLUMN_FAMILY, &block.hash);
+            batch.delete_cf(TIPS_INDEX, &block.hash);
+            self.delete_block_data(&mut batch, &block.hash)?;
+            pruned += 1;
+            current = self.block_by_hash(&block.parent_hash)?;
+        }
+        self.db.write(batch)?;
+        Ok(pruned)
+    }
+
+    // Adds the deletion of `block_hash`'s entries in every `block_data`
+    // column family to `batch`: the one under the hash itself, and those
+    // under the hash and a colon, e.g. one per voter.
+    fn delete_block_data(&self, batch: &mut WriteBatch, block_hash: &str) -> Result<(), DbError> {
+        for column_family in &self.block_data {
+            batch.delete_cf(column_family, block_hash);
+            for entry in self.db.cf(column_family)?.prefix_iter(&format!("{}:", block_hash)) {
+                let (key, _) = entry?;
+                batch.delete_cf(column_family, &key);
+            }
+        }
+        Ok(())
+    }
+
+    // Stores a run of consecutive blocks as the new canonical chain above
+    // the parent of the first one, atomically. If the first block is at or
+    // below the current head, the canonical blocks from its height up are
+    // replaced, which is how a reorg is applied.
+    pub fn put_blocks(&self, blocks: &[Block]) -> Result<(), DbError> {
+        self.put_blocks_with(blocks, |_, _| Ok(()))?;
+        Ok(())
+    }
+
+    // `put_blocks`, with `stage` adding its own writes to the same batch.
+    // `stage` gets the canonical blocks being replaced, oldest first, which
+    // are also returned. Nothing is written if it fails.
+    pub fn put_blocks_with(
+        &self,
+        blocks: &[Block],
+        stage: impl FnOnce(&[Block], &mut WriteBatch) -> Result<(), DbError>,
+    ) -> Result<Vec<Block>, DbError> {
+        let (replaced, oldest, pruned_to) = self.write_blocks(blocks, stage)?;
+        // Sweep whenever the window has moved by another interval.
+        if pruned_to / SWEEP_INTERVAL_BLOCKS > oldest / SWEEP_INTERVAL_BLOCKS {
+            self.sweep()?;
+        }
+        Ok(replaced)
+    }
+
+    // Writes the batch for `put_blocks_with` and returns the replaced blocks
+    // with the oldest kept height before and after.
+    fn write_blocks(
+        &self,
+        blocks: &[Block],
+        stage: impl FnOnce(&[Block], &mut WriteBatch) -> Result<(), DbError>,
+    ) -> Result<(Vec<Block>, u64, u64), DbError> {
+        let _writing = self.write_lock.lock().map_err(|_| lock_poisoned())?;
+        let (first, last) = match (blocks.first(), blocks.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok((Vec::new(), 0, 0)),
+        };
+        for pair in blocks.windows(2) {
+            if pair[1].height != pair[0].height + 1 || pair[1].parent_hash != pair[0].hash {
+                return Err(DbError::QueryError(format!("block {} does not follow block {}", pair[1].hash, pair[0].hash)));
+            }
+        }
+
+        if let Some(finalized) = self.finalized()? {
+            if first.height <= finalized.height {
+                return Err(DbError::Finalized { height: finalized.height });
+            }
+        }
+
+        let oldest = self.oldest()?;
+        let head = self.head_block()?;
+        let mut batch = WriteBatch::new();
+        let mut replaced = Vec::new();
+        if let Some(head) = &head {
+            // The parent of a block at `oldest` is already gone.
+            if first.height < oldest || (oldest > 0 && first.height == oldest) {
+                return Err(DbError::StatePruned {
+                    height: first.height,
+                    oldest,
+                });
+            }
+            if first.height > head.height + 1 {
+                return Err(DbError::QueryError(format!(
+                    "block at height {} does not connect to the head at height {}",
+                    first.height, head.height
+                )));
+            }
+            if first.height > 0 && self.canonical_hash(first.height - 1)?.as_deref() != Some(&first.parent_hash) {
+                return Err(DbError::QueryError(format!(
+                    "parent {} of block {} is not on the canonical chain",
+                    first.parent_hash, first.hash
+                )));
+            }
+            // Roll back the blocks being replaced.
+            for height in first.height..=head.height {
+                let block = self.canonical_block(height)?;
+                unindex_block(&mut batch, &block);
+                replaced.push(block);
+            }
+        }
+
+        for block in blocks {
+            // A block stored earlier is not written again, and may already
+            // have children.
+            if self.block_by_hash(&block.hash)?.is_none() {
+                let value = serde_json::to_vec(block).map_err(|err| DbError::QueryError(err.to_string()))?;
+                batch.put_cf(BLOCKS_COLUMN_FAMILY, &block.hash, value);
+                batch.put_cf(TIPS_INDEX, &block.hash, Vec::new());
+            }
+            batch.delete_cf(TIPS_INDEX, &block.parent_hash);
+            index_block(&mut batch, block)?;
+        }
+        stage(&replaced, &mut batch)?;
+        batch.put_cf(CHAIN_COLUMN_FAMILY, HEAD_KEY, last.hash.clone().into_bytes());
+
+        let mut pruned_to = oldest;
+        if let Pruning::Pruned { keep_blocks } = self.pruning {
+            let keep_from = (last.height + 1).saturating_sub(keep_blocks.max(1));
+            if keep_from > oldest {
+                let importing: HashMap<u64, &Block> = blocks.iter().map(|block| (block.height, block)).collect();
+                for height in oldest..keep_from {
+                    let pruned = match importing.get(&height) {
+                        Some(block) => (*block).clone(),
+                        None => self.canonical_block(height)?,
+                    };
+                    unindex_block(&mut batch, &pruned);
+                    batch.delete_cf(BLOCKS_COLUMN_FAMILY, &pruned.hash);
+                    self.delete_block_data(&mut batch, &pruned.hash)?;
+                }
+                batch.put_cf(CHAIN_COLUMN_FAMILY, OLDEST_KEY, keep_from.to_string().into_bytes());
+                pruned_to = keep_from;
+            }
+        }
+        self.db.write(batch)?;
+
+        let mut pending_roots = self.pending_roots.lock().map_err(|_| lock_poisoned())?;
+        for block in blocks {
+            pending_roots.remove(&block.state_root);
+        }
+        if head.is_none_or(|head| head.hash != last.hash) {
+            self.head_watchers().retain(|watcher| match watcher.upgrade() {
+                Some(flag) => {
+                    flag.store(true, Ordering::SeqCst);
+                    true
+                }
+                None => false,
+            });
+        }
+        Ok((replaced, oldest, pruned_to))
+    }
+
+    pub fn head(&self) -> Result<Option<u64>, DbError> {
+        Ok(self.head_block()?.map(|block| block.height))
+    }
+
+    pub fn head_block(&self) -> Result<Option<Block>, DbError> {
+        match self.db.cf(CHAIN_COLUMN_FAMILY)?.get(HEAD_KEY)? {
+            Some(hash) => {
+                let hash = String::from_utf8_lossy(&hash).into_owned();
+                self.block_by_hash(&hash)?
+                    .map(Some)
+                    .ok_or_else(|| DbError::QueryError(format!("head block {} is missing", hash)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // The latest block declared final, if any.
+    pub fn finalized(&self) -> Result<Option<Checkpoint>, DbError> {
+        match self.db.cf(CHAIN_COLUMN_FAMILY)?.get(FINALIZED_KEY)? {
+            Some(value) => serde_json::from_slice(&value)
+                .map(Some)
+                .map_err(|err| DbError::QueryError(format!("corrupt finalized checkpoint: {}", err))),
+            None => Ok(None),
+        }
+    }
+
+    // Declares the canonical `block`, and so every block before it, final.
+    // Finality only moves forward; an older block is ignored.
+    pub fn finalize(&self, block: &Block) -> Result<(), DbError> {
+        let _writing = self.write_lock.lock().map_err(|_| lock_poisoned())?;
+        if self.canonical_hash(block.height)?.as_deref() != Some(block.hash.as_str()) {
+            return Err(DbError::NotCanonical(block.hash.clone()));
+        }
+        if self.finalized()?.is_some_and(|finalized| finalized.height >= block.height) {
+            return Ok(());
+        }
+        let checkpoint = Checkpoint {
+            height: block.height,
+            hash: block.hash.clone(),
+        };
+        let value = serde_json::to_vec(&checkpoint).map_err(|err| DbError::QueryError(err.to_string()))?;
+        self.db.cf(CHAIN_COLUMN_FAMILY)?.put(FINALIZED_KEY, value)
+    }
+
+    // Lowest height whose block and state are still available.
+    pub fn oldest(&self) -> Result<u64, DbError> {
+        match self.db.cf(CHAIN_COLUMN_FAMILY)?.get(OLDEST_KEY)? {
+            Some(value) => String::from_utf8_lossy(&value)
+                .parse()
+                .map_err(|_| DbError::QueryError("corrupt oldest height".to_string())),
+            None => Ok(0),
+        }
+    }
+
+    // The canonical block at `height`.
+    pub fn block(&self, height: u64) -> Result<Option<Block>, DbError> {
+        self.check_retained(height)?;
+        match self.canonical_hash(height)? {
+            Some(hash) => self.block_by_hash(&hash),
+            None => Ok(None),
+        }
+    }
+
+    // Every stored block without a stored child: the canonical head and the
+    // ends of all other forks.
+    pub fn tips(&self) -> Result<Vec<Block>, DbError> {
+        let mut tips = Vec::new();
+        for entry in self.db.cf(TIPS_INDEX)?.iter(KeyRange::All, Direction::Forward) {
+            let (hash, _) = entry?;
+            if let Some(block) = self.block_by_hash(&hash)? {
+                tips.push(block);
+            }
+        }
+        Ok(tips)
+    }
+
+    // Any stored block, canonical or not.
+    pub fn block_by_hash(&self, hash: &str) -> Result<Option<Block>, DbError> {
+        match self.db.cf(BLOCKS_COLUMN_FAMILY)?.get(hash)? {
+            Some(value) => serde_json::from_slice(&value)
+                .map(Some)
+                .map_err(|err| DbError::QueryError(format!("corrupt block {}: {}", hash, err))),
+            None => Ok(None),
+        }
+    }
+
+    // The earliest canonical occurrence of the transaction `hash`.
+    pub fn transaction(&self, hash: &str) -> Result<Option<(TxLocation, Transaction)>, DbError> {
+        let entry = self
+            .db
+            .cf(TX_INDEX)?
+            .iter(KeyRange::Prefix(format!("{}:", hash)), Direction::Forward)
+            .next()
+            .transpose()?;
+        let location: TxLocation = match entry {
+            Some((_, value)) => serde_json::from_slice(&value)
+                .map_err(|err| DbError::QueryError(format!("corrupt index entry for {}: {}", hash, err)))?,
+            None => return Ok(None),
+        };
+        let transaction = self
+            .block_by_hash(&location.block_hash)?
+            .and_then(|block| block.transactions.get(location.index as usize).cloned())
+            .ok_or_else(|| DbError::QueryError(format!("index entry for {} points to a missing block", hash)))?;
+        Ok(Some((location, transaction)))
+    }
+
+    // Hashes of up to `limit` canonical transactions sent or received by
+    // `address`, oldest first for `Direction::Forward`.
+    pub fn address_transactions(&self, address: &str, direction: Direction, limit: usize) -> Result<Vec<String>, DbError> {
+        self.db
+            .cf(ADDRESS_INDEX)?
+            .iter(KeyRange::Prefix(address_prefix(address)), direction)
+            .take(limit)
+            .map(|entry| entry.map(|(_, hash)| String::from_utf8_lossy(&hash).into_owned()))
+            .collect()
+    }
+
+    // The state after the block at `height`.
+    pub fn state_at(&self, height: u64) -> Result<StateTrie<'_>, DbError> {
+        let block = self
+            .block(height)?
+            .ok_or_else(|| DbError::QueryError(format!("no block at height {}", height)))?;
+        Ok(StateTrie::at(&self.db, block.state_root))
+    }
+
+    // Rebuilds every index from the blocks, following parent links back
+    // from the head. The old indexes are cleared and the new ones written in
+    // batches of bounded size, with block writes waiting until it is done;
+    // if it fails partway, run it again.
+    pub fn reindex(&self) -> Result<ReindexStats, DbError> {
+        let _writing = self.write_lock.lock().map_err(|_| lock_poisoned())?;
+        let oldest = self.oldest()?;
+        let mut batch = WriteBatch::new();
+        for cf in [HEIGHT_INDEX, TX_INDEX, ADDRESS_INDEX] {
+            for entry in self.db.cf(cf)?.iter(KeyRange::All, Direction::Forward) {
+                let (key, _) = entry?;
+                batch.delete_cf(cf, &key);
+                if batch.len() >= REINDEX_BATCH_SIZE {
+                    self.db.write(std::mem::take(&mut batch))?;
+                }
+            }
+        }
+
+        let mut stats = ReindexStats::default();
+        let mut next = self.head_block()?;
+        while let Some(block) = next {
+            index_block(&mut batch, &block)?;
+            if batch.len() >= REINDEX_BATCH_SIZE {
+                self.db.write(std::mem::take(&mut batch))?;
+            }
+            stats.blocks += 1;
+            stats.transactions += block.transactions.len() as u64;
+            next = if block.height > oldest {
+                let parent = self.block_by_hash(&block.parent_hash)?.ok_or_else(|| {
+                    DbError::QueryError(format!("parent {} of block {} is missing", block.parent_hash, block.hash))
+                })?;
+                Some(parent)
+            } else {
+                None
+            };
+        }
+        self.db.write(batch)?;
+        Ok(stats)
+    }
+
+    // Deletes every state trie node not reachable from a kept block or a
+    // pending state root. Runs automatically in pruned mode; a no-op in
+    // archive mode. Marking reads a snapshot and does not block writes; only
+    // the roots added meanwhile are marked again while writes wait.
+    pub fn sweep(&self) -> Result<SweepStats, DbError> {
+        if self.pruning == Pruning::Archive {
+            return Ok(SweepStats::default());
+        }
+        let _sweeping = self.sweep_lock.lock().map_err(|_| lock_poisoned())?;
+        let (snapshot, oldest, pending) = {
+            let _writing = self.write_lock.lock().map_err(|_| lock_poisoned())?;
+            (self.db.snapshot()?, self.oldest()?, self.pending_state_roots()?)
+        };
+
+        // Replaced blocks below the window go too.
+        let mut batch = WriteBatch::new();
+        let mut roots = pending;
+        for entry in snapshot.cf(BLOCKS_COLUMN_FAMILY)?.iter(KeyRange::All, Direction::Forward) {
+            let (hash, value) = entry?;
+            let block: Block = serde_json::from_slice(&value)
+                .map_err(|err| DbError::QueryError(format!("corrupt block {}: {}", hash, err)))?;
+            if block.height < oldest {
+                batch.delete_cf(BLOCKS_COLUMN_FAMILY, &hash);
+                batch.delete_cf(TIPS_INDEX, &hash);
+                self.delete_block_data(&mut batch, &hash)?;
+            } else {
+                roots.push(block.state_root);
+            }
+        }
+        let snapshot_state = snapshot.cf(STATE_COLUMN_FAMILY)?;
+        let mut live = HashSet::new();
+        mark(&mut live, &roots, |key| snapshot_state.get(key))?;
+        // Nodes written after the snapshot are not candidates, so they
+        // survive even if nothing marked reaches them.
+        let mut dead = Vec::new();
+        for entry in snapshot_state.iter(KeyRange::All, Direction::Forward) {
+            let (key, _) = entry?;
+            if !live.contains(&key) {
+                dead.push(key);
+            }
+        }
+
+        // Blocks and state committed since the snapshot may have brought
+        // dead nodes back; mark from their roots before deleting.
+        let _writing = self.write_lock.lock().map_err(|_| lock_poisoned())?;
+        let mut roots = self.pending_state_roots()?;
+        for entry in self.db.cf(BLOCKS_COLUMN_FAMILY)?.iter(KeyRange::All, Direction::Forward) {
+            let (hash, value) = entry?;
+            let block: Block = serde_json::from_slice(&value)
+                .map_err(|err| DbError::QueryError(format!("corrupt block {}: {}", hash, err)))?;
+            if block.height >= oldest {
+                roots.push(block.state_root);
+            }
+        }
+        let state = self.db.cf(STATE_COLUMN_FAMILY)?;
+        mark(&mut live, &roots, |key| state.get(key))?;
+
+        let mut stats = SweepStats {
+            live_nodes: live.len() as u64,
+            removed_nodes: 0,
+        };
+        for key in dead.iter().filter(|key| !live.contains(*key)) {
+            batch.delete_cf(STATE_COLUMN_FAMILY, key);
+            stats.removed_nodes += 1;
+        }
+        self.db.write(batch)?;
+        Ok(stats)
+    }
+
+    // The roots committed with `commit_state` and still awaiting their
+    // block. Roots committed a whole sweep interval ago are dropped instead.
+    fn pending_state_roots(&self) -> Result<Vec<Hash>, DbError> {
+        let oldest = self.oldest()?;
+        let mut pending_roots = self.pending_roots.lock().map_err(|_| lock_poisoned())?;
+        pending_roots.retain(|_, committed_at| *committed_at + SWEEP_INTERVAL_BLOCKS > oldest);
+        Ok(pending_roots.keys().copied().collect())
+    }
+
+    fn canonical_hash(&self, height: u64) -> Result<Option<String>, DbError> {
+        Ok(self
+            .db
+            .cf(HEIGHT_INDEX)?
+            .get(&height_key(height))?
+            .map(|hash| String::from_utf8_lossy(&hash).into_owned()))
+    }
+
+    fn canonical_block(&self, height: u64) -> Result<Block, DbError> {
+        self.canonical_hash(height)?
+            .map(|hash| self.block_by_hash(&hash))
+            .transpose()?
+            .flatten()
+            .ok_or_else(|| DbError::QueryError(format!("canonical block at height {} is missing", height)))
+    }
+
+    fn check_retained(&self, height: u64) -> Result<(), DbError> {
+        let oldest = self.oldest()?;
+        if height < oldest {
+            return Err(DbError::StatePruned { height, oldest });
+        }
+        Ok(())
+    }
+}
+
+// Stages the index entries of `block` as the canonical block at its height.
+// Entries are keyed by position as well as by hash, so a transaction that
+// appears in two blocks has an entry for each.
+pub fn index_block(batch: &mut WriteBatch, block: &Block) -> Result<(), DbError> {
+    batch.put_cf(HEIGHT_INDEX, &height_key(block.height), block.hash.clone().into_bytes());
+    for (index, transaction) in block.transactions.iter().enumerate() {
+        let hash = transaction.hash();
+        let location = TxLocation {
+            block_hash: block.hash.clone(),
+            height: block.height,
+            index: index as u32,
+        };
+        let value = serde_json::to_vec(&location).map_err(|err| DbError::QueryError(err.to_string()))?;
+        batch.put_cf(TX_INDEX, &tx_key(&hash, block.height, index), value);
+        for address in addresses(transaction) {
+            batch.put_cf(ADDRESS_INDEX, &address_key(address, block.height, index), hash.clone().into_bytes());
+        }
+    }
+    Ok(())
+}
+
+fn unindex_block(batch: &mut WriteBatch, block: &Block) {
+    batch.delete_cf(HEIGHT_INDEX, &height_key(block.height));
+    for (index, transaction) in block.transactions.iter().enumerate() {
+        batch.delete_cf(TX_INDEX, &tx_key(&transaction.hash(), block.height, index));
+        for address in addresses(transaction) {
+            batch.delete_cf(ADDRESS_INDEX, &address_key(address, block.height, index));
+        }
+    }
+}
+
+fn addresses(transaction: &Transaction) -> Vec<&str> {
+    if transaction.from == transaction.to {
+        vec![&transaction.from]
+    } else {
+        vec![&transaction.from, &transaction.to]
+    }
+}
+
+// Adds the hex-encoded hashes of every trie node reachable from `roots` to
+// `live`, loading nodes with `load`. Nodes already in `live` are not
+// visited again, nor is anything below them.
+fn mark<L>(live: &mut HashSet<String>, roots: &[Hash], load: L) -> Result<(), DbError>
+where
+    L: Fn(&str) -> Result<Option<Vec<u8>>, DbError>,
+{
+    let mut pending: Vec<Hash> = roots.iter().copied().filter(|root| *root != EMPTY_ROOT).collect();
+    while let Some(hash) = pending.pop() {
+        let key = trie::to_hex(&hash);
+        if live.contains(&key) {
+            continue;
+        }
+        let encoded = load(&key)?
+            .ok_or_else(|| DbError::QueryError(format!("missing state trie node {}", key)))?;
+        pending.extend(trie::node_children(&encoded));
+        live.insert(key);
+    }
+    Ok(())
+}
+
+fn height_key(height: u64) -> String {
+    format!("{:020}", height)
+}
+
+fn tx_key(hash: &str, height: u64, index: usize) -> String {
+    format!("{}:{:020}:{:010}", hash, height, index)
+}
+
+// The length prefix keeps an address containing ':' from matching the keys
+// of another address.
+fn address_prefix(address: &str) -> String {
+    format!("{}:{}:", address.len(), address)
+}
+
+fn address_key(address: &str, height: u64, index: usize) -> String {
+    format!("{}{:020}:{:010}", address_prefix(address), height, index)
+}
+
+fn lock_poisoned() -> DbError {
+    DbError::ConnectionError("chain store lock poisoned".to_string())
+}
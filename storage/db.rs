@@ -1,7 +1,22 @@
 // db.rs
 
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use im::OrdMap;
+
+use crate::backend::{Backend, BackendSnapshot};
+use crate::backup::{self, BackupInfo};
+use crate::cache::{ReadCache, ReadCounters, ReadStats, SegmentedBloom};
+use crate::config::{Durability, EncryptionKey, StorageConfig};
+use crate::encryption::Cipher;
+use crate::query::Query;
 
 pub enum DbType {
     Memory,
@@ -9,147 +24,1876 @@ pub enum DbType {
 }
 
 pub struct Db {
-    pub db_type: DbType,
-    pub connection: Connection,
+    backend: Box<dyn Backend>,
 }
 
 impl Db {
-    pub fn new(db_type: DbType) -> Self {
+    pub fn new(db_type: DbType, path: &str) -> Result<Self, DbError> {
+        let config = StorageConfig {
+            path: path.to_string(),
+            ..StorageConfig::default()
+        };
+        Db::open(db_type, &config)
+    }
+
+    // Opens a database of `db_type` with the settings in `config`. The
+    // config's `type_` is ignored; see `from_config`.
+    pub fn open(db_type: DbType, config: &StorageConfig) -> Result<Self, DbError> {
         match db_type {
-            DbType::Memory => Db {
-                db_type,
-                connection: Connection::Memory(MemoryConnection::new()),
-            },
-            DbType::Disk => Db {
-                db_type,
-                connection: Connection::Disk(DiskConnection::new()),
-            },
+            DbType::Memory => Ok(Db::with_backend(Box::new(MemoryConnection::new()))),
+            DbType::Disk => Ok(Db::with_backend(Box::new(DiskConnection::open(config)?))),
+        }
+    }
+
+    // Wraps any engine implementing `Backend`, such as one outside this
+    // crate. Encryption and durability are up to the engine.
+    pub fn with_backend(backend: Box<dyn Backend>) -> Self {
+        Db { backend }
+    }
+
+    pub fn backend(&self) -> &dyn Backend {
+        &*self.backend
+    }
+
+    pub fn from_config(config: &StorageConfig) -> Result<Self, DbError> {
+        Db::open(config_type(config)?, config)
+    }
+
+    // A read-only view of the configured database. Unlike `from_config`, it
+    // never creates, repairs or truncates anything on disk.
+    pub fn open_snapshot(config: &StorageConfig) -> Result<Box<dyn BackendSnapshot>, DbError> {
+        match config_type(config)? {
+            DbType::Memory => MemoryConnection::new().snapshot(),
+            DbType::Disk => DiskConnection::open_snapshot(config),
         }
     }
 
+    // Returns a handle to the named column family. Column families are
+    // created on first write and need no registration.
+    pub fn cf(&self, name: &str) -> Result<ColumnFamily<'_>, DbError> {
+        Ok(ColumnFamily {
+            backend: &*self.backend,
+            view: CfView::new(Source::Live(&*self.backend), name)?,
+        })
+    }
+
+    fn default_cf(&self) -> ColumnFamily<'_> {
+        ColumnFamily {
+            backend: &*self.backend,
+            view: CfView::default(Source::Live(&*self.backend)),
+        }
+    }
+
+    // Captures the current contents of every column family. Later writes to
+    // the database are not visible through the snapshot.
+    pub fn snapshot(&self) -> Result<Snapshot, DbError> {
+        Ok(Snapshot {
+            state: self.backend.snapshot()?,
+        })
+    }
+
     pub fn execute(&self, query: &str) -> Result<Vec<Row>, DbError> {
-        self.connection.execute(query)
+        self.run(&Query::parse(query)?)
+    }
+
+    // Runs a parsed query, e.g. one whose limit the caller has bounded.
+    pub fn run(&self, query: &Query) -> Result<Vec<Row>, DbError> {
+        self.cf(query.column_family.as_deref().unwrap_or(DEFAULT_COLUMN_FAMILY))?.view.run(query)
+    }
+
+    // Writes an archive of every column family to `path`, with its values
+    // encrypted under `key` if given. The archive is taken from a snapshot,
+    // so it is consistent while the node keeps running.
+    pub fn backup(&self, path: &Path, key: Option<&EncryptionKey>) -> Result<BackupInfo, DbError> {
+        let cipher = key.map(Cipher::new).transpose()?;
+        let snapshot = self.snapshot()?;
+        let entries = snapshot.entries().map(|entry| {
+            let (raw_key, value) = entry?;
+            let (cf, key) = raw_key
+                .split_once(CF_SEPARATOR)
+                .ok_or_else(|| DbError::IOError(format!("key {:?} has no column family", raw_key)))?;
+            Ok((cf.to_string(), key.to_string(), value))
+        });
+        backup::write_archive(path, cipher.as_ref(), entries)
+    }
+
+    // Replaces the disk database at `config.path` with the contents of the
+    // archive at `archive`, which is decrypted with `config.encryption` if it
+    // is encrypted. The archive is verified in full before anything is
+    // touched, and the data is rebuilt in a staging directory that is swapped
+    // in at the end. The database must not be open. If the process dies
+    // between the two final renames, the old data is left in `<path>.old`.
+    pub fn restore(archive: &Path, config: &StorageConfig) -> Result<BackupInfo, DbError> {
+        let verified = backup::verify_archive(archive, config.encryption.as_ref())?;
+
+        let target = PathBuf::from(&config.path);
+        let staging = PathBuf::from(format!("{}.restore", config.path));
+        let previous = PathBuf::from(format!("{}.old", config.path));
+        let _ = fs::remove_dir_all(&staging);
+        let staging_config = StorageConfig {
+            path: staging.to_string_lossy().into_owned(),
+            ..config.clone()
+        };
+
+        let restored = {
+            let db = Db::open(DbType::Disk, &staging_config)?;
+            let mut batch = WriteBatch::new();
+            let info = backup::read_archive(archive, config.encryption.as_ref(), |cf, key, value| {
+                batch.put_cf(&cf, &key, value);
+                if batch.len() >= RESTORE_BATCH_SIZE {
+                    db.write(std::mem::take(&mut batch))?;
+                }
+                Ok(())
+            });
+            info.and_then(|info| {
+                db.write(batch)?;
+                db.sync()?;
+                if info.checksum != verified.checksum {
+                    return Err(DbError::IOError("backup archive changed during restore".to_string()));
+                }
+                Ok(info)
+            })
+        };
+        let restored = match restored {
+            Ok(info) => info,
+            Err(err) => {
+                let _ = fs::remove_dir_all(&staging);
+                return Err(err);
+            }
+        };
+
+        let _ = fs::remove_dir_all(&previous);
+        if target.exists() {
+            fs::rename(&target, &previous)?;
+        }
+        fs::rename(&staging, &target)?;
+        let _ = fs::remove_dir_all(&previous);
+        Ok(restored)
+    }
+
+    // Reclaims the space held by overwritten and deleted values. Reads and
+    // writes keep working while it runs.
+    pub fn compact(&self) -> Result<CompactionStats, DbError> {
+        self.backend.compact()
+    }
+
+    // Bytes acknowledged to writers but not yet fsynced, i.e. what a power
+    // loss right now could discard. Always zero in strict durability mode.
+    pub fn unsynced_bytes(&self) -> Result<u64, DbError> {
+        self.backend.unsynced_bytes()
+    }
+
+    // Forces every acknowledged write onto stable storage, whatever the
+    // durability mode.
+    pub fn sync(&self) -> Result<(), DbError> {
+        self.backend.sync()
+    }
+
+    // Fraction of the storage footprint that compaction would reclaim.
+    pub fn garbage_ratio(&self) -> Result<f64, DbError> {
+        self.backend.garbage_ratio()
+    }
+
+    // Re-encrypts every stored value under a key derived from `key`, or
+    // decrypts them all if `key` is `None`. Update `StorageConfig::encryption`
+    // to match before the database is next opened. Writes wait until the
+    // rotation finishes.
+    pub fn rotate_key(&self, key: Option<&EncryptionKey>) -> Result<(), DbError> {
+        self.backend.rotate_key(key)
+    }
+
+    // Read cache and bloom filter counters since the database was opened.
+    pub fn read_stats(&self) -> ReadStats {
+        self.backend.read_stats()
     }
 
     pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
-        self.connection.get(key)
+        self.default_cf().get(key)
     }
 
     pub fn put(&self, key: &str, value: Vec<u8>) -> Result<(), DbError> {
-        self.connection.put(key, value)
+        self.default_cf().put(key, value)
     }
 
     pub fn delete(&self, key: &str) -> Result<(), DbError> {
-        self.connection.delete(key)
+        self.default_cf().delete(key)
+    }
+
+    pub fn write(&self, batch: WriteBatch) -> Result<(), DbError> {
+        if let Some(name) = &batch.invalid {
+            return Err(DbError::InvalidColumnFamily(name.clone()));
+        }
+        self.backend.write(batch)
+    }
+
+    pub fn iter(&self, range: KeyRange, direction: Direction) -> DbIterator<'_> {
+        self.default_cf().iter(range, direction)
+    }
+
+    pub fn prefix_iter(&self, prefix: &str) -> DbIterator<'_> {
+        self.default_cf().prefix_iter(prefix)
+    }
+
+    // Names of all column families that currently hold at least one key.
+    pub fn column_families(&self) -> Result<Vec<String>, DbError> {
+        let mut names = Vec::new();
+        let mut after = None;
+        // Jump from one column family to the next instead of visiting every
+        // key: "<name>\u{1}" sorts after every key of family <name>.
+        loop {
+            let next = self.backend.chunk(&KeyRange::All, Direction::Forward, after.as_deref(), 1)?;
+            let key = match next.into_iter().next() {
+                Some((key, _)) => key,
+                None => break,
+            };
+            let name = key.split(CF_SEPARATOR).next().unwrap_or_default().to_string();
+            after = Some(format!("{}\u{1}", name));
+            names.push(name);
+        }
+        Ok(names)
+    }
+}
+
+// Column families split the keyspace into isolated namespaces such as
+// "blocks" or "votes". Every key is stored in the backend as
+// `<family><CF_SEPARATOR><key>`; since family names cannot contain the
+// separator, two families never share a key and each one is a contiguous,
+// ordered slice of the backend. The plain `Db` methods use the "default"
+// family.
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+pub(crate) const CF_SEPARATOR: char = '\0';
+
+// The backend key prefix of the family `name`. Every handle and batch goes
+// through here, so no name can reach into another family's keys.
+fn cf_prefix(name: &str) -> Result<String, DbError> {
+    if name.is_empty() || name.contains(CF_SEPARATOR) {
+        return Err(DbError::InvalidColumnFamily(name.to_string()));
     }
+    Ok(format!("{}{}", name, CF_SEPARATOR))
 }
 
-pub enum Connection {
-    Memory(MemoryConnection),
-    Disk(DiskConnection),
+pub struct ColumnFamily<'a> {
+    backend: &'a dyn Backend,
+    view: CfView<'a>,
 }
 
-impl Connection {
+impl<'a> ColumnFamily<'a> {
+    pub fn name(&self) -> &str {
+        &self.view.name
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        self.view.get(key)
+    }
+
+    pub fn put(&self, key: &str, value: Vec<u8>) -> Result<(), DbError> {
+        self.backend.put(&self.view.key(key), value)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<(), DbError> {
+        self.backend.delete(&self.view.key(key))
+    }
+
     pub fn execute(&self, query: &str) -> Result<Vec<Row>, DbError> {
-        match self {
-            Connection::Memory(conn) => conn.execute(query),
-            Connection::Disk(conn) => conn.execute(query),
+        self.view.execute(query)
+    }
+
+    pub fn iter(&self, range: KeyRange, direction: Direction) -> DbIterator<'a> {
+        self.view.iter(range, direction)
+    }
+
+    pub fn prefix_iter(&self, prefix: &str) -> DbIterator<'a> {
+        self.view.iter(KeyRange::Prefix(prefix.to_string()), Direction::Forward)
+    }
+
+    // Number of keys in the family.
+    pub fn len(&self) -> Result<u64, DbError> {
+        self.view.len()
+    }
+
+    pub fn is_empty(&self) -> Result<bool, DbError> {
+        self.view.is_empty()
+    }
+
+    // Total size of the family's values in bytes.
+    pub fn size(&self) -> Result<u64, DbError> {
+        self.view.size()
+    }
+
+    // Deletes every key in the family in one atomic batch.
+    pub fn clear(&self) -> Result<(), DbError> {
+        let mut batch = WriteBatch::new();
+        for key in self.backend.keys(&self.view.range(&KeyRange::All), Direction::Forward, usize::MAX)? {
+            batch.ops.push(BatchOp::Delete(key));
         }
+        self.backend.write(batch)
+    }
+}
+
+// A point-in-time, read-only view of the whole database. Taking a snapshot
+// is cheap for the built-in engines: their key maps are persistent trees, so
+// the snapshot shares the current map with the connection and a later write
+// only copies the path to the key it changes. On disk, values are never
+// rewritten in place, so the snapshot keeps reading them from the log
+// through its own file handle.
+pub struct Snapshot {
+    state: Box<dyn BackendSnapshot>,
+}
+
+impl Snapshot {
+    pub fn cf(&self, name: &str) -> Result<SnapshotColumnFamily<'_>, DbError> {
+        Ok(SnapshotColumnFamily {
+            view: CfView::new(Source::Snapshot(self), name)?,
+        })
+    }
+
+    fn default_cf(&self) -> SnapshotColumnFamily<'_> {
+        SnapshotColumnFamily {
+            view: CfView::default(Source::Snapshot(self)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        self.default_cf().get(key)
+    }
+
+    pub fn iter(&self, range: KeyRange, direction: Direction) -> DbIterator<'_> {
+        self.default_cf().iter(range, direction)
+    }
+
+    pub fn prefix_iter(&self, prefix: &str) -> DbIterator<'_> {
+        self.default_cf().prefix_iter(prefix)
+    }
+
+    pub fn execute(&self, query: &str) -> Result<Vec<Row>, DbError> {
+        self.run(&Query::parse(query)?)
+    }
+
+    // Runs a parsed query, e.g. one whose limit the caller has bounded.
+    pub fn run(&self, query: &Query) -> Result<Vec<Row>, DbError> {
+        self.cf(query.column_family.as_deref().unwrap_or(DEFAULT_COLUMN_FAMILY))?.view.run(query)
+    }
+
+    // Every entry of every column family, with raw `<cf>\0<key>` keys.
+    fn entries(&self) -> DbIterator<'_> {
+        DbIterator {
+            source: Source::Snapshot(self),
+            range: KeyRange::All,
+            direction: Direction::Forward,
+            cursor: None,
+            buffer: VecDeque::new(),
+            done: false,
+            strip: 0,
+        }
+    }
+
+    fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        self.state.get(key)
+    }
+}
+
+pub struct SnapshotColumnFamily<'a> {
+    view: CfView<'a>,
+}
+
+impl<'a> SnapshotColumnFamily<'a> {
+    pub fn name(&self) -> &str {
+        &self.view.name
     }
 
     pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        self.view.get(key)
+    }
+
+    pub fn execute(&self, query: &str) -> Result<Vec<Row>, DbError> {
+        self.view.execute(query)
+    }
+
+    pub fn iter(&self, range: KeyRange, direction: Direction) -> DbIterator<'a> {
+        self.view.iter(range, direction)
+    }
+
+    pub fn prefix_iter(&self, prefix: &str) -> DbIterator<'a> {
+        self.view.iter(KeyRange::Prefix(prefix.to_string()), Direction::Forward)
+    }
+
+    pub fn len(&self) -> Result<u64, DbError> {
+        self.view.len()
+    }
+
+    pub fn is_empty(&self) -> Result<bool, DbError> {
+        self.view.is_empty()
+    }
+
+    pub fn size(&self) -> Result<u64, DbError> {
+        self.view.size()
+    }
+}
+
+// Where reads come from: the live connection or a snapshot of it.
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    Live(&'a dyn Backend),
+    Snapshot(&'a Snapshot),
+}
+
+impl<'a> Source<'a> {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
         match self {
-            Connection::Memory(conn) => conn.get(key),
-            Connection::Disk(conn) => conn.get(key),
+            Source::Live(backend) => backend.get(key),
+            Source::Snapshot(snapshot) => snapshot.get_raw(key),
         }
     }
 
-    pub fn put(&self, key: &str, value: Vec<u8>) -> Result<(), DbError> {
+    fn keys(&self, range: &KeyRange, direction: Direction, limit: Option<usize>) -> Result<Vec<String>, DbError> {
         match self {
-            Connection::Memory(conn) => conn.put(key, value),
-            Connection::Disk(conn) => conn.put(key, value),
+            Source::Live(backend) => backend.keys(range, direction, limit.unwrap_or(usize::MAX)),
+            Source::Snapshot(snapshot) => snapshot.state.keys(range, direction, limit.unwrap_or(usize::MAX)),
         }
     }
 
-    pub fn delete(&self, key: &str) -> Result<(), DbError> {
+    fn chunk(
+        &self,
+        range: &KeyRange,
+        direction: Direction,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError> {
+        match self {
+            Source::Live(backend) => backend.chunk(range, direction, after, limit),
+            Source::Snapshot(snapshot) => snapshot.state.chunk(range, direction, after, limit),
+        }
+    }
+
+    fn stats(&self, range: &KeyRange) -> Result<(u64, u64), DbError> {
+        match self {
+            Source::Live(backend) => backend.stats(range),
+            Source::Snapshot(snapshot) => snapshot.state.stats(range),
+        }
+    }
+}
+
+// Read access to one column family, shared by live and snapshot handles.
+struct CfView<'a> {
+    source: Source<'a>,
+    name: String,
+    prefix: String,
+}
+
+impl<'a> CfView<'a> {
+    fn new(source: Source<'a>, name: &str) -> Result<Self, DbError> {
+        Ok(CfView {
+            source,
+            name: name.to_string(),
+            prefix: cf_prefix(name)?,
+        })
+    }
+
+    fn default(source: Source<'a>) -> Self {
+        CfView {
+            source,
+            name: DEFAULT_COLUMN_FAMILY.to_string(),
+            prefix: format!("{}{}", DEFAULT_COLUMN_FAMILY, CF_SEPARATOR),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        self.source.get(&self.key(key))
+    }
+
+    fn execute(&self, query: &str) -> Result<Vec<Row>, DbError> {
+        let query = Query::parse(query)?;
+        match &query.column_family {
+            Some(name) if *name != self.name => Err(DbError::QueryError(format!(
+                "query targets column family {} but was run on {}",
+                name, self.name
+            ))),
+            _ => self.run(&query),
+        }
+    }
+
+    fn iter(&self, range: KeyRange, direction: Direction) -> DbIterator<'a> {
+        DbIterator {
+            source: self.source,
+            range: self.range(&range),
+            direction,
+            cursor: None,
+            buffer: VecDeque::new(),
+            done: false,
+            strip: self.prefix.len(),
+        }
+    }
+
+    fn len(&self) -> Result<u64, DbError> {
+        Ok(self.source.stats(&self.range(&KeyRange::All))?.0)
+    }
+
+    fn is_empty(&self) -> Result<bool, DbError> {
+        Ok(self.source.keys(&self.range(&KeyRange::All), Direction::Forward, Some(1))?.is_empty())
+    }
+
+    fn size(&self) -> Result<u64, DbError> {
+        Ok(self.source.stats(&self.range(&KeyRange::All))?.1)
+    }
+
+    fn run(&self, query: &Query) -> Result<Vec<Row>, DbError> {
+        query.run(
+            |range, direction, limit| {
+                let keys = self.source.keys(&self.range(range), direction, Some(limit))?;
+                Ok(keys.into_iter().map(|key| key[self.prefix.len()..].to_string()).collect())
+            },
+            |range| Ok(self.source.stats(&self.range(range))?.0),
+            |key| self.get(key),
+        )
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn range(&self, range: &KeyRange) -> KeyRange {
+        match range {
+            KeyRange::All => KeyRange::Prefix(self.prefix.clone()),
+            KeyRange::Prefix(prefix) => KeyRange::Prefix(self.key(prefix)),
+            KeyRange::Range(start, end) => KeyRange::Range(self.key(start), self.key(end)),
+        }
+    }
+}
+
+// The keys an iterator or query visits. `Range` includes the start key and
+// excludes the end key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyRange {
+    All,
+    Prefix(String),
+    Range(String, String),
+}
+
+impl KeyRange {
+    pub fn contains(&self, key: &str) -> bool {
+        match self {
+            KeyRange::All => true,
+            KeyRange::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            KeyRange::Range(start, end) => key >= start.as_str() && key < end.as_str(),
+        }
+    }
+
+    fn bounds(&self) -> (Bound<String>, Bound<String>) {
         match self {
-            Connection::Memory(conn) => conn.delete(key),
-            Connection::Disk(conn) => conn.delete(key),
+            KeyRange::All => (Bound::Unbounded, Bound::Unbounded),
+            KeyRange::Prefix(prefix) => (
+                Bound::Included(prefix.clone()),
+                prefix_end(prefix).map_or(Bound::Unbounded, Bound::Excluded),
+            ),
+            KeyRange::Range(start, end) => (Bound::Included(start.clone()), Bound::Excluded(end.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Forward,
+    Reverse,
+}
+
+// Keys are ordered by their UTF-8 bytes on every backend. Iterators fetch
+// entries in small chunks and resume after the last key they returned, so
+// they never hold a connection lock between calls to `next`. On a live
+// connection, writes made while iterating are visible if they land ahead of
+// the cursor; iterate a `Snapshot` to avoid that.
+pub struct DbIterator<'a> {
+    source: Source<'a>,
+    range: KeyRange,
+    direction: Direction,
+    cursor: Option<String>,
+    buffer: VecDeque<(String, Vec<u8>)>,
+    done: bool,
+    // Length of the column family prefix removed from returned keys.
+    strip: usize,
+}
+
+const ITERATOR_CHUNK_SIZE: usize = 64;
+// Entries per write batch when restoring a backup.
+const RESTORE_BATCH_SIZE: usize = 1024;
+
+impl<'a> Iterator for DbIterator<'a> {
+    type Item = Result<(String, Vec<u8>), DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.done {
+            let chunk = self.source.chunk(
+                &self.range,
+                self.direction,
+                self.cursor.as_deref(),
+                ITERATOR_CHUNK_SIZE,
+            );
+            match chunk {
+                Ok(chunk) => {
+                    self.done = chunk.len() < ITERATOR_CHUNK_SIZE;
+                    if let Some((key, _)) = chunk.last() {
+                        self.cursor = Some(key.clone());
+                    }
+                    self.buffer.extend(chunk);
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
         }
+        let strip = self.strip;
+        self.buffer.pop_front().map(|(key, value)| Ok((key[strip..].to_string(), value)))
+    }
+}
+
+// A set of puts and deletes that is committed atomically: after a crash either
+// every operation in the batch is visible or none of them are. Operations are
+// applied in the order they were staged and may span column families. An
+// operation on an invalid column family is not staged, and `Db::write`
+// refuses the whole batch.
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+    // The first invalid column family name staged.
+    invalid: Option<String>,
+}
+
+// Keys are raw backend keys, with the column family prefix.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    Put(String, Vec<u8>),
+    Delete(String),
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::from_ops(Vec::new())
+    }
+
+    pub fn put(&mut self, key: &str, value: Vec<u8>) -> &mut Self {
+        self.put_cf(DEFAULT_COLUMN_FAMILY, key, value)
+    }
+
+    pub fn delete(&mut self, key: &str) -> &mut Self {
+        self.delete_cf(DEFAULT_COLUMN_FAMILY, key)
+    }
+
+    pub fn put_cf(&mut self, cf: &str, key: &str, value: Vec<u8>) -> &mut Self {
+        if let Some(prefix) = self.prefix(cf) {
+            self.ops.push(BatchOp::Put(prefix + key, value));
+        }
+        self
+    }
+
+    pub fn delete_cf(&mut self, cf: &str, key: &str) -> &mut Self {
+        if let Some(prefix) = self.prefix(cf) {
+            self.ops.push(BatchOp::Delete(prefix + key));
+        }
+        self
+    }
+
+    pub(crate) fn from_ops(ops: Vec<BatchOp>) -> Self {
+        WriteBatch { ops, invalid: None }
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+        self.invalid = None;
+    }
+
+    fn prefix(&mut self, cf: &str) -> Option<String> {
+        match cf_prefix(cf) {
+            Ok(prefix) => Some(prefix),
+            Err(_) => {
+                self.invalid.get_or_insert_with(|| cf.to_string());
+                None
+            }
+        }
+    }
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        WriteBatch::new()
     }
 }
 
 pub struct MemoryConnection {
-    data: HashMap<String, Vec<u8>>,
+    data: RwLock<OrdMap<String, Vec<u8>>>,
 }
 
 impl MemoryConnection {
     pub fn new() -> Self {
         MemoryConnection {
-            data: HashMap::new(),
+            data: RwLock::new(OrdMap::new()),
         }
     }
+}
 
-    pub fn execute(&self, query: &str) -> Result<Vec<Row>, DbError> {
-        // Implement in-memory query execution
-        unimplemented!()
+impl Backend for MemoryConnection {
+    fn snapshot(&self) -> Result<Box<dyn BackendSnapshot>, DbError> {
+        let data = self.data.read().map_err(|_| lock_poisoned())?;
+        Ok(Box::new(MemorySnapshot(data.clone())))
     }
 
-    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
-        Ok(self.data.get(key).cloned())
+    fn keys(&self, range: &KeyRange, direction: Direction, limit: usize) -> Result<Vec<String>, DbError> {
+        let data = self.data.read().map_err(|_| lock_poisoned())?;
+        let keys = range_entries(&data, range, direction, None, limit)
+            .into_iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+        Ok(keys)
     }
 
-    pub fn put(&self, key: &str, value: Vec<u8>) -> Result<(), DbError> {
-        self.data.insert(key.to_string(), value);
+    fn stats(&self, range: &KeyRange) -> Result<(u64, u64), DbError> {
+        let data = self.data.read().map_err(|_| lock_poisoned())?;
+        let entries = range_entries(&data, range, Direction::Forward, None, usize::MAX);
+        let bytes = entries.iter().map(|(_, value)| value.len() as u64).sum();
+        Ok((entries.len() as u64, bytes))
+    }
+
+    fn chunk(
+        &self,
+        range: &KeyRange,
+        direction: Direction,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError> {
+        let data = self.data.read().map_err(|_| lock_poisoned())?;
+        let entries = range_entries(&data, range, direction, after, limit)
+            .into_iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Ok(entries)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        let data = self.data.read().map_err(|_| lock_poisoned())?;
+        Ok(data.get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: Vec<u8>) -> Result<(), DbError> {
+        let mut data = self.data.write().map_err(|_| lock_poisoned())?;
+        data.insert(key.to_string(), value);
         Ok(())
     }
 
-    pub fn delete(&self, key: &str) -> Result<(), DbError> {
-        self.data.remove(key);
+    fn delete(&self, key: &str) -> Result<(), DbError> {
+        let mut data = self.data.write().map_err(|_| lock_poisoned())?;
+        data.remove(key);
+        Ok(())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), DbError> {
+        // Holding the write lock for the whole batch keeps readers from
+        // observing it half applied.
+        let mut data = self.data.write().map_err(|_| lock_poisoned())?;
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    data.insert(key, value);
+                }
+                BatchOp::Delete(key) => {
+                    data.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rotate_key(&self, _key: Option<&EncryptionKey>) -> Result<(), DbError> {
+        // Nothing is at rest.
         Ok(())
     }
 }
 
+// Shares every node of the map that later writes leave alone.
+struct MemorySnapshot(OrdMap<String, Vec<u8>>);
+
+impl BackendSnapshot for MemorySnapshot {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        Ok(self.0.get(key).cloned())
+    }
+
+    fn chunk(
+        &self,
+        range: &KeyRange,
+        direction: Direction,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError> {
+        Ok(range_entries(&self.0, range, direction, after, limit)
+            .into_iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn keys(&self, range: &KeyRange, direction: Direction, limit: usize) -> Result<Vec<String>, DbError> {
+        Ok(range_entries(&self.0, range, direction, None, limit)
+            .into_iter()
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn stats(&self, range: &KeyRange) -> Result<(u64, u64), DbError> {
+        let entries = range_entries(&self.0, range, Direction::Forward, None, usize::MAX);
+        let bytes = entries.iter().map(|(_, value)| value.len() as u64).sum();
+        Ok((entries.len() as u64, bytes))
+    }
+}
+
+// On-disk layout: a single append-only log file (`data.log`) inside the
+// configured directory. Every record is
+//
+//   crc32 (u32) | kind (u8) | key_len (u32) | value_len (u32) | key | value
+//
+// with all integers little-endian and the checksum covering everything after
+// the crc field. A write batch is stored as a single `RECORD_BATCH` record
+// whose value is a sequence of `kind | key_len | value_len | key | value`
+// entries, so one checksum covers the whole batch and a batch torn by a crash
+// is discarded as a unit on replay. The in-memory index maps each live key to
+// the position of its latest value and is rebuilt by replaying the log on open.
+// In an encrypted log every stored value, including those inside batches, is
+// sealed by `Cipher`; keys stay in plaintext.
+const LOG_FILE: &str = "data.log";
+const COMPACT_FILE: &str = "data.log.compact";
+const RECORD_HEADER_LEN: u64 = 13;
+const RECORD_PUT: u8 = 1;
+const RECORD_DELETE: u8 = 2;
+const RECORD_BATCH: u8 = 3;
+// First record of an encrypted log; its value is the `Cipher` header.
+const RECORD_ENCRYPTION: u8 = 4;
+const BATCH_ENTRY_HEADER_LEN: usize = 9;
+
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    offset: u64,
+    len: u32,
+}
+
+struct DiskState {
+    file: File,
+    // Shared with snapshots; a write copies only the nodes it changes.
+    index: OrdMap<String, IndexEntry>,
+    end: u64,
+    // Log offset up to which data is known to be on stable storage.
+    synced: u64,
+    durability: Durability,
+    // Set when values are stored encrypted.
+    cipher: Option<Arc<Cipher>>,
+}
+
+// The log is the disk backend's write-ahead log as well as its data file:
+// a write is appended (and, in strict mode, fsynced) before the index is
+// updated and the caller is told it succeeded.
 pub struct DiskConnection {
-    path: String,
+    path: PathBuf,
+    state: Arc<Mutex<DiskState>>,
+    // Held for the duration of a compaction so two never overlap.
+    compaction: Mutex<()>,
+    // Plaintext values of recently read keys. Filled and invalidated under
+    // the state lock, so it never holds a value older than the index.
+    cache: Option<ReadCache>,
+    // Every key written to the log since it was last rewritten, filtered
+    // per log segment. Keys are added before their record is appended, so a
+    // reader that finds a key absent here without taking the state lock is
+    // never wrong.
+    bloom: Option<RwLock<SegmentedBloom>>,
+    counters: ReadCounters,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactionStats {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub live_keys: u64,
+}
+
+impl CompactionStats {
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
 }
 
 impl DiskConnection {
-    pub fn new() -> Self {
-        DiskConnection {
-            path: "path/to/db/file".to_string(),
+    pub fn open(config: &StorageConfig) -> Result<Self, DbError> {
+        if config.durability == (Durability::GroupCommit { interval_ms: 0 }) {
+            return Err(DbError::ConnectionError("group commit interval must be at least 1 ms".to_string()));
         }
+        let path = PathBuf::from(&config.path);
+        fs::create_dir_all(&path)?;
+        // Left behind by a compaction that crashed before its final rename;
+        // the original log is still complete.
+        let _ = fs::remove_file(path.join(COMPACT_FILE));
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join(LOG_FILE))?;
+
+        let (index, mut end) = replay_log(&mut file, false)?;
+        let file_len = file.metadata()?.len();
+        if end < file_len {
+            // The tail of the log is a write torn by a crash; drop it so new
+            // records are appended after the last good one.
+            log::warn!(
+                "truncating {} bytes of incomplete data at the end of {}",
+                file_len - end,
+                path.join(LOG_FILE).display()
+            );
+            file.set_len(end)?;
+            file.sync_all()?;
+        }
+
+        let cipher = match (&config.encryption, read_header(&mut file)?) {
+            (Some(key), None) if end == 0 => {
+                let cipher = Cipher::new(key)?;
+                let record = encode_record(RECORD_ENCRYPTION, "", &cipher.header())?;
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(&record)?;
+                file.sync_all()?;
+                end = record.len() as u64;
+                Some(cipher)
+            }
+            (key, header) => log_cipher(&path, key.as_ref(), header)?,
+        };
+
+        let bloom = config.bloom_filter.then(|| RwLock::new(SegmentedBloom::build(index.keys())));
+        let state = Arc::new(Mutex::new(DiskState {
+            file,
+            index,
+            end,
+            synced: end,
+            durability: config.durability,
+            cipher: cipher.map(Arc::new),
+        }));
+        if let Durability::GroupCommit { interval_ms } = config.durability {
+            spawn_group_commit(Arc::downgrade(&state), Duration::from_millis(interval_ms));
+        }
+
+        Ok(DiskConnection {
+            path,
+            state,
+            compaction: Mutex::new(()),
+            cache: (config.read_cache_bytes > 0).then(|| ReadCache::new(config.read_cache_bytes)),
+            bloom,
+            counters: ReadCounters::default(),
+        })
     }
 
-    pub fn execute(&self, query: &str) -> Result<Vec<Row>, DbError> {
-        // Implement disk-based query execution
-        unimplemented!()
+    // A snapshot of the log at `config.path`, read without opening it for
+    // writing: a torn tail is skipped rather than truncated, and a missing
+    // log reads as empty.
+    pub fn open_snapshot(config: &StorageConfig) -> Result<Box<dyn BackendSnapshot>, DbError> {
+        let path = PathBuf::from(&config.path);
+        let mut file = match File::open(path.join(LOG_FILE)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Box::new(MemorySnapshot(OrdMap::new())))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let (index, _) = replay_log(&mut file, false)?;
+        let cipher = match read_header(&mut file)? {
+            // An empty log that `open` would seal under the configured key.
+            None if index.is_empty() => None,
+            header => log_cipher(&path, config.encryption.as_ref(), header)?,
+        };
+        Ok(Box::new(DiskSnapshot {
+            index,
+            file: Mutex::new(file),
+            cipher: cipher.map(Arc::new),
+        }))
     }
 
-    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
-        // Implement disk-based get operation
-        unimplemented!()
+    // Truncates the log at `config.path` at its first damaged record, along
+    // with every record after it, and returns the number of bytes dropped.
+    // Only needed after `open` failed with `DbError::CorruptLog`; the
+    // database must not be open.
+    pub fn repair(config: &StorageConfig) -> Result<u64, DbError> {
+        let path = PathBuf::from(&config.path).join(LOG_FILE);
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let (_, end) = replay_log(&mut file, true)?;
+        let file_len = file.metadata()?.len();
+        if end < file_len {
+            log::warn!("dropping {} bytes from offset {} of {}", file_len - end, end, path.display());
+            file.set_len(end)?;
+            file.sync_all()?;
+        }
+        Ok(file_len - end)
     }
 
-    pub fn put(&self, key: &str, value: Vec<u8>) -> Result<(), DbError> {
-        // Implement disk-based put operation
-        unimplemented!()
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
-    pub fn delete(&self, key: &str) -> Result<(), DbError> {
-        // Implement disk-based delete operation
-        unimplemented!()
+    // Moves a fully written and synced log over the current one.
+    fn replace_log(&self, new_log: &Path) -> Result<(), DbError> {
+        fs::rename(new_log, self.path.join(LOG_FILE))?;
+        // Persist the rename itself; not every platform can sync a directory.
+        if let Ok(dir) = File::open(&self.path) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, DiskState>, DbError> {
+        self.state.lock().map_err(|_| lock_poisoned())
+    }
+
+    // Called under the state lock before `keys` are appended.
+    fn remember_keys(&self, keys: &[&str]) -> Result<(), DbError> {
+        if let Some(bloom) = &self.bloom {
+            let mut bloom = bloom.write().map_err(|_| lock_poisoned())?;
+            for key in keys {
+                bloom.insert(key);
+            }
+        }
+        Ok(())
+    }
+
+    // Called under the state lock once the log has been rewritten, dropping
+    // deleted keys from the filter.
+    fn rebuild_bloom(&self, index: &OrdMap<String, IndexEntry>) -> Result<(), DbError> {
+        if let Some(bloom) = &self.bloom {
+            *bloom.write().map_err(|_| lock_poisoned())? = SegmentedBloom::build(index.keys());
+        }
+        Ok(())
+    }
+
+    fn forget(&self, key: &str) {
+        if let Some(cache) = &self.cache {
+            cache.remove(key);
+        }
     }
 }
 
+impl Backend for DiskConnection {
+    fn unsynced_bytes(&self) -> Result<u64, DbError> {
+        let state = self.lock()?;
+        Ok(state.end - state.synced)
+    }
+
+    fn sync(&self) -> Result<(), DbError> {
+        self.lock()?.sync()
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn BackendSnapshot>, DbError> {
+        let state = self.lock()?;
+        // A separate handle, so snapshot reads never move the writer's cursor.
+        let file = File::open(self.path.join(LOG_FILE))?;
+        Ok(Box::new(DiskSnapshot {
+            index: state.index.clone(),
+            file: Mutex::new(file),
+            cipher: state.cipher.clone(),
+        }))
+    }
+
+    fn keys(&self, range: &KeyRange, direction: Direction, limit: usize) -> Result<Vec<String>, DbError> {
+        let state = self.lock()?;
+        let keys = range_entries(&state.index, range, direction, None, limit)
+            .into_iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+        Ok(keys)
+    }
+
+    fn stats(&self, range: &KeyRange) -> Result<(u64, u64), DbError> {
+        let state = self.lock()?;
+        let entries = range_entries(&state.index, range, Direction::Forward, None, usize::MAX);
+        let bytes = entries
+            .iter()
+            .map(|(_, entry)| value_len(state.cipher.as_deref(), entry))
+            .sum();
+        Ok((entries.len() as u64, bytes))
+    }
+
+    fn chunk(
+        &self,
+        range: &KeyRange,
+        direction: Direction,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError> {
+        let mut state = self.lock()?;
+        let entries: Vec<(String, IndexEntry)> = range_entries(&state.index, range, direction, after, limit)
+            .into_iter()
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect();
+
+        let state = &mut *state;
+        let mut chunk = Vec::with_capacity(entries.len());
+        for (key, entry) in entries {
+            let value = read_value(&mut state.file, state.cipher.as_deref(), &key, entry)?;
+            chunk.push((key, value));
+        }
+        Ok(chunk)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        if let Some(bloom) = &self.bloom {
+            if !bloom.read().map_err(|_| lock_poisoned())?.may_contain(key) {
+                self.counters.bloom_negative();
+                return Ok(None);
+            }
+        }
+        if let Some(value) = self.cache.as_ref().and_then(|cache| cache.get(key)) {
+            self.counters.cache_hit();
+            return Ok(Some(value));
+        }
+
+        let mut state = self.lock()?;
+        let state = &mut *state;
+        match state.index.get(key) {
+            Some(entry) => {
+                let value = read_value(&mut state.file, state.cipher.as_deref(), key, *entry)?;
+                self.counters.cache_miss();
+                if let Some(cache) = &self.cache {
+                    cache.insert(key, &value);
+                }
+                Ok(Some(value))
+            }
+            None => {
+                if self.bloom.is_some() {
+                    self.counters.bloom_false_positive();
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn put(&self, key: &str, value: Vec<u8>) -> Result<(), DbError> {
+        let mut state = self.lock()?;
+        self.remember_keys(&[key])?;
+        let value = seal_value(state.cipher.as_deref(), key, value);
+        let record = encode_record(RECORD_PUT, key, &value)?;
+        let offset = state.append(&record)?;
+        state.index.insert(
+            key.to_string(),
+            IndexEntry {
+                offset: offset + RECORD_HEADER_LEN + key.len() as u64,
+                len: value.len() as u32,
+            },
+        );
+        self.forget(key);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), DbError> {
+        let mut state = self.lock()?;
+        if !state.index.contains_key(key) {
+            return Ok(());
+        }
+        let record = encode_record(RECORD_DELETE, key, &[])?;
+        state.append(&record)?;
+        state.index.remove(key);
+        self.forget(key);
+        Ok(())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), DbError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        // Sealed under the lock so a concurrent key rotation cannot leave the
+        // batch encrypted with the retired key.
+        let mut state = self.lock()?;
+        let keys: Vec<String> = batch
+            .ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Put(key, _) | BatchOp::Delete(key) => key.clone(),
+            })
+            .collect();
+        let puts: Vec<&str> = batch
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                BatchOp::Put(key, _) => Some(key.as_str()),
+                BatchOp::Delete(_) => None,
+            })
+            .collect();
+        self.remember_keys(&puts)?;
+        let mut payload = Vec::new();
+        for op in batch.ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    let value = seal_value(state.cipher.as_deref(), &key, value);
+                    encode_batch_entry(&mut payload, RECORD_PUT, &key, &value)
+                }
+                BatchOp::Delete(key) => encode_batch_entry(&mut payload, RECORD_DELETE, &key, &[]),
+            }
+        }
+        if payload.len() > u32::MAX as usize {
+            return Err(DbError::QueryError("write batch is too large".to_string()));
+        }
+
+        let record = encode_record(RECORD_BATCH, "", &payload)?;
+        let offset = state.append(&record)?;
+        apply_batch(&mut state.index, offset + RECORD_HEADER_LEN, &payload)
+            .ok_or_else(|| DbError::IOError("malformed write batch".to_string()))?;
+        for key in &keys {
+            self.forget(key);
+        }
+        Ok(())
+    }
+
+    // Rewrites the log so it holds only the latest value of every live key,
+    // dropping overwritten values and tombstones. The bulk of the copy works
+    // from a snapshot of the index without holding the connection lock;
+    // records appended in the meantime are carried over and the new log
+    // replaces the old one in a single rename.
+    fn compact(&self) -> Result<CompactionStats, DbError> {
+        let _running = self
+            .compaction
+            .try_lock()
+            .map_err(|_| DbError::ConnectionError("compaction already running".to_string()))?;
+
+        let (index, copied_end, cipher) = {
+            let state = self.lock()?;
+            (state.index.clone(), state.end, state.cipher.clone())
+        };
+
+        // Values are copied still sealed; the key does not change.
+        let compact_path = self.path.join(COMPACT_FILE);
+        let mut target = create_log(&compact_path, cipher.as_deref())?;
+        let mut source = File::open(self.path.join(LOG_FILE))?;
+        let (mut new_index, new_end) = rewrite_values(&mut target, &mut source, &index, |_, value| Ok(value))?;
+
+        let mut state = self.lock()?;
+        let mut tail = vec![0; (state.end - copied_end) as usize];
+        state.file.seek(SeekFrom::Start(copied_end))?;
+        state.file.read_exact(&mut tail)?;
+        target.seek(SeekFrom::Start(new_end))?;
+        target.write_all(&tail)?;
+        let tail_end = new_end + tail.len() as u64;
+        let end = replay_records(&mut Cursor::new(&tail[..]), new_end, tail_end, false, &mut new_index)?;
+        target.sync_all()?;
+
+        self.replace_log(&compact_path)?;
+
+        let stats = CompactionStats {
+            bytes_before: state.end,
+            bytes_after: end,
+            live_keys: new_index.len() as u64,
+        };
+        self.rebuild_bloom(&new_index)?;
+        // Open snapshots keep reading the old log through their own handles.
+        state.file = target;
+        state.index = new_index;
+        state.end = end;
+        state.synced = end;
+        Ok(stats)
+    }
+
+    // Rewrites the log with every value re-sealed under a new key derived
+    // from `key` (with a fresh salt), or in plaintext if `key` is `None`.
+    // Unlike compaction this holds the connection lock throughout, since
+    // records appended meanwhile would be sealed under the old key.
+    fn rotate_key(&self, key: Option<&EncryptionKey>) -> Result<(), DbError> {
+        let _running = self.compaction.lock().map_err(|_| lock_poisoned())?;
+        let new_cipher = key.map(Cipher::new).transpose()?;
+
+        let mut state = self.lock()?;
+        let compact_path = self.path.join(COMPACT_FILE);
+        let mut target = create_log(&compact_path, new_cipher.as_ref())?;
+        let mut source = File::open(self.path.join(LOG_FILE))?;
+        let old_cipher = state.cipher.clone();
+        let (new_index, end) = rewrite_values(&mut target, &mut source, &state.index, |key, value| {
+            let value = match &old_cipher {
+                Some(cipher) => cipher.open(key.as_bytes(), &value)?,
+                None => value,
+            };
+            Ok(seal_value(new_cipher.as_ref(), key, value))
+        })?;
+        target.sync_all()?;
+        self.replace_log(&compact_path)?;
+
+        // Cached values are plaintext, so they stay valid under the new key.
+        self.rebuild_bloom(&new_index)?;
+        state.file = target;
+        state.index = new_index;
+        state.end = end;
+        state.synced = end;
+        state.cipher = new_cipher.map(Arc::new);
+        Ok(())
+    }
+
+    fn garbage_ratio(&self) -> Result<f64, DbError> {
+        let state = self.lock()?;
+        if state.end == 0 {
+            return Ok(0.0);
+        }
+        let live: u64 = state
+            .index
+            .iter()
+            .map(|(key, entry)| RECORD_HEADER_LEN + key.len() as u64 + entry.len as u64)
+            .sum();
+        Ok(1.0 - live.min(state.end) as f64 / state.end as f64)
+    }
+
+    fn read_stats(&self) -> ReadStats {
+        let bloom_segments = match &self.bloom {
+            Some(bloom) => bloom.read().map_or(0, |bloom| bloom.segments() as u64),
+            None => 0,
+        };
+        ReadStats {
+            bloom_segments,
+            ..self.counters.stats()
+        }
+    }
+}
+
+// Values are never rewritten in place, so the snapshot keeps reading them
+// from the log it was taken on, even after a compaction replaces it.
+struct DiskSnapshot {
+    index: OrdMap<String, IndexEntry>,
+    file: Mutex<File>,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl BackendSnapshot for DiskSnapshot {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        match self.index.get(key) {
+            Some(entry) => {
+                let mut file = self.file.lock().map_err(|_| lock_poisoned())?;
+                Ok(Some(read_value(&mut file, self.cipher.as_deref(), key, *entry)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn chunk(
+        &self,
+        range: &KeyRange,
+        direction: Direction,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError> {
+        let mut file = self.file.lock().map_err(|_| lock_poisoned())?;
+        let mut chunk = Vec::new();
+        for (key, entry) in range_entries(&self.index, range, direction, after, limit) {
+            chunk.push((key.clone(), read_value(&mut file, self.cipher.as_deref(), key, *entry)?));
+        }
+        Ok(chunk)
+    }
+
+    fn keys(&self, range: &KeyRange, direction: Direction, limit: usize) -> Result<Vec<String>, DbError> {
+        Ok(range_entries(&self.index, range, direction, None, limit)
+            .into_iter()
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn stats(&self, range: &KeyRange) -> Result<(u64, u64), DbError> {
+        let entries = range_entries(&self.index, range, Direction::Forward, None, usize::MAX);
+        let bytes = entries.iter().map(|(_, entry)| value_len(self.cipher.as_deref(), entry)).sum();
+        Ok((entries.len() as u64, bytes))
+    }
+}
+
+impl DiskState {
+    // Appends a fully encoded record and returns the offset it was written at.
+    fn append(&mut self, record: &[u8]) -> Result<u64, DbError> {
+        let offset = self.end;
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut result = self.file.write_all(record);
+        if result.is_ok() && self.durability == Durability::Strict {
+            result = self.file.sync_data();
+        }
+        if let Err(err) = result {
+            // Leave the log ending at the last complete record, so a write
+            // reported as failed does not reappear after a restart.
+            let _ = self.file.set_len(offset);
+            return Err(err.into());
+        }
+        self.end += record.len() as u64;
+        if self.durability == Durability::Strict {
+            self.synced = self.end;
+        }
+        Ok(offset)
+    }
+
+    fn sync(&mut self) -> Result<(), DbError> {
+        if self.synced < self.end {
+            self.file.sync_data()?;
+            self.synced = self.end;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskConnection {
+    fn drop(&mut self) {
+        // Flush whatever group commit or the OS has not yet written out.
+        if let Ok(mut state) = self.state.lock() {
+            if let Err(err) = state.sync() {
+                log::error!("failed to sync {} on close: {}", self.path.display(), err);
+            }
+        }
+    }
+}
+
+// Fsyncs the log every `interval` while the connection is alive, so writes
+// made in between share one flush.
+fn spawn_group_commit(state: Weak<Mutex<DiskState>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => break,
+        };
+        let mut state = match state.lock() {
+            Ok(state) => state,
+            Err(_) => break,
+        };
+        if let Err(err) = state.sync() {
+            log::error!("group commit failed: {}", err);
+        }
+    });
+}
+
+// Reads the value stored for `key` at `entry`, decrypting it if the log is
+// encrypted.
+fn read_value(file: &mut File, cipher: Option<&Cipher>, key: &str, entry: IndexEntry) -> Result<Vec<u8>, DbError> {
+    let value = read_stored(file, entry)?;
+    match cipher {
+        Some(cipher) => cipher.open(key.as_bytes(), &value),
+        None => Ok(value),
+    }
+}
+
+fn read_stored(file: &mut File, entry: IndexEntry) -> Result<Vec<u8>, DbError> {
+    let mut value = vec![0; entry.len as usize];
+    file.seek(SeekFrom::Start(entry.offset))?;
+    file.read_exact(&mut value)?;
+    Ok(value)
+}
+
+fn seal_value(cipher: Option<&Cipher>, key: &str, value: Vec<u8>) -> Vec<u8> {
+    match cipher {
+        Some(cipher) => cipher.seal(key.as_bytes(), &value),
+        None => value,
+    }
+}
+
+// Length of the plaintext value behind `entry`.
+fn value_len(cipher: Option<&Cipher>, entry: &IndexEntry) -> u64 {
+    match cipher {
+        Some(_) => (entry.len as usize).saturating_sub(Cipher::OVERHEAD) as u64,
+        None => entry.len as u64,
+    }
+}
+
+// Creates an empty log at `path`, starting with the encryption header if
+// `cipher` is set.
+fn create_log(path: &Path, cipher: Option<&Cipher>) -> Result<File, DbError> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    if let Some(cipher) = cipher {
+        file.write_all(&encode_record(RECORD_ENCRYPTION, "", &cipher.header())?)?;
+    }
+    Ok(file)
+}
+
+// Appends the latest value of every key in `index`, read from `source` and
+// passed through `convert`, to `target` as put records. Returns the index of
+// the new log and its length.
+fn rewrite_values<F>(
+    target: &mut File,
+    source: &mut File,
+    index: &OrdMap<String, IndexEntry>,
+    mut convert: F,
+) -> Result<(OrdMap<String, IndexEntry>, u64), DbError>
+where
+    F: FnMut(&str, Vec<u8>) -> Result<Vec<u8>, DbError>,
+{
+    let mut new_index = OrdMap::new();
+    let mut end = target.seek(SeekFrom::End(0))?;
+    let mut writer = BufWriter::new(target);
+    for (key, entry) in index.iter() {
+        let value = convert(key, read_stored(source, *entry)?)?;
+        let record = encode_record(RECORD_PUT, key, &value)?;
+        writer.write_all(&record)?;
+        new_index.insert(
+            key.clone(),
+            IndexEntry {
+                offset: end + RECORD_HEADER_LEN + key.len() as u64,
+                len: value.len() as u32,
+            },
+        );
+        end += record.len() as u64;
+    }
+    writer.flush()?;
+    Ok((new_index, end))
+}
+
+// Returns up to `limit` entries of `map` inside `range`, in `direction` order,
+// starting after the key `after` when resuming an iteration.
+fn range_entries<'m, V>(
+    map: &'m OrdMap<String, V>,
+    range: &KeyRange,
+    direction: Direction,
+    after: Option<&str>,
+    limit: usize,
+) -> Vec<(&'m String, &'m V)> {
+    let (mut lower, mut upper) = range.bounds();
+    if let Some(after) = after {
+        match direction {
+            Direction::Forward => lower = Bound::Excluded(after.to_string()),
+            Direction::Reverse => upper = Bound::Excluded(after.to_string()),
+        }
+    }
+
+    // `OrdMap::range` panics on inverted bounds.
+    if let (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u)) = (&lower, &upper) {
+        let both_included = matches!((&lower, &upper), (Bound::Included(_), Bound::Included(_)));
+        if l > u || (l == u && !both_included) {
+            return Vec::new();
+        }
+    }
+
+    let entries = map.range((lower, upper));
+    match direction {
+        Direction::Forward => entries.take(limit).collect(),
+        Direction::Reverse => entries.rev().take(limit).collect(),
+    }
+}
+
+// The smallest string greater than every string starting with `prefix`, or
+// `None` if no such string exists.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+// Fails if `key` or `value` is too long for the record's length fields.
+fn encode_record(kind: u8, key: &str, value: &[u8]) -> Result<Vec<u8>, DbError> {
+    if key.len() > u32::MAX as usize || value.len() > u32::MAX as usize {
+        return Err(DbError::QueryError("record is too large".to_string()));
+    }
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + key.len() + value.len());
+    record.extend_from_slice(&[0; 4]);
+    record.push(kind);
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    record.extend_from_slice(key.as_bytes());
+    record.extend_from_slice(value);
+    let crc = crc32(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    Ok(record)
+}
+
+fn encode_batch_entry(payload: &mut Vec<u8>, kind: u8, key: &str, value: &[u8]) {
+    payload.push(kind);
+    payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    payload.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    payload.extend_from_slice(key.as_bytes());
+    payload.extend_from_slice(value);
+}
+
+// Applies the entries of a batch record to the index. `base` is the file
+// offset of the first payload byte. Returns `None` if the payload is malformed.
+fn apply_batch(index: &mut OrdMap<String, IndexEntry>, base: u64, payload: &[u8]) -> Option<()> {
+    let mut pos = 0;
+    while pos < payload.len() {
+        let header = payload.get(pos..pos + BATCH_ENTRY_HEADER_LEN)?;
+        let kind = header[0];
+        let key_len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let value_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        pos += BATCH_ENTRY_HEADER_LEN;
+
+        let key = std::str::from_utf8(payload.get(pos..pos + key_len)?).ok()?.to_string();
+        pos += key_len;
+        payload.get(pos..pos + value_len)?;
+
+        match kind {
+            RECORD_PUT => {
+                index.insert(
+                    key,
+                    IndexEntry {
+                        offset: base + pos as u64,
+                        len: value_len as u32,
+                    },
+                );
+            }
+            RECORD_DELETE => {
+                index.remove(&key);
+            }
+            _ => return None,
+        }
+        pos += value_len;
+    }
+    Some(())
+}
+
+// Rebuilds the index from the log. Returns the index and the offset just past
+// the last complete, checksummed record; anything after it is a torn write.
+// With `repair`, damage anywhere in the log ends the replay there instead of
+// failing it.
+fn replay_log(file: &mut File, repair: bool) -> Result<(OrdMap<String, IndexEntry>, u64), DbError> {
+    let mut index = OrdMap::new();
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(&mut *file);
+    reader.seek(SeekFrom::Start(0))?;
+    let end = replay_records(&mut reader, 0, len, repair, &mut index)?;
+    Ok((index, end))
+}
+
+// Returns the encryption header if the log starts with one.
+fn read_header(file: &mut File) -> Result<Option<Vec<u8>>, DbError> {
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    if !read_full(file, &mut header)? || header[4] != RECORD_ENCRYPTION {
+        return Ok(None);
+    }
+    let crc = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let key_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as u64;
+    let value_len = u32::from_le_bytes([header[9], header[10], header[11], header[12]]) as u64;
+    if RECORD_HEADER_LEN + key_len + value_len > len {
+        return Ok(None);
+    }
+    let mut body = vec![0u8; (key_len + value_len) as usize];
+    if !read_full(file, &mut body)? {
+        return Ok(None);
+    }
+    let mut checked = header[4..].to_vec();
+    checked.extend_from_slice(&body);
+    if crc32(&checked) != crc {
+        return Ok(None);
+    }
+    Ok(Some(body.split_off(key_len as usize)))
+}
+
+// Applies the records read from `reader` to `index`, assuming the first one
+// sits at file offset `start` and the log ends at `len`. Returns the offset
+// just past the last valid record.
+//
+// A crash can only tear the last record, so a bad record that reaches the
+// end of the log, with no valid record after it, is dropped as a torn
+// write. One that valid data follows is damage to records that were
+// acknowledged, and fails with
+// `DbError::CorruptLog` rather than quietly dropping everything after it,
+// unless `repair` is set.
+fn replay_records<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    len: u64,
+    repair: bool,
+    index: &mut OrdMap<String, IndexEntry>,
+) -> Result<u64, DbError> {
+    let mut offset = start;
+
+    loop {
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        if !read_full(reader, &mut header)? {
+            break;
+        }
+        let crc = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let kind = header[4];
+        let key_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        let value_len = u32::from_le_bytes([header[9], header[10], header[11], header[12]]) as usize;
+        let record_end = offset + RECORD_HEADER_LEN + (key_len + value_len) as u64;
+        // The lengths are not checksummed yet; never allocate past the end
+        // of the log on their word.
+        let mut valid = false;
+        if record_end <= len {
+            let mut body = vec![0u8; key_len + value_len];
+            if read_full(reader, &mut body)? {
+                let mut checked = header[4..].to_vec();
+                checked.extend_from_slice(&body);
+                valid = crc32(&checked) == crc
+                    && match std::str::from_utf8(&body[..key_len]) {
+                        Ok(key) => match kind {
+                            RECORD_PUT => {
+                                index.insert(
+                                    key.to_string(),
+                                    IndexEntry {
+                                        offset: offset + RECORD_HEADER_LEN + key_len as u64,
+                                        len: value_len as u32,
+                                    },
+                                );
+                                true
+                            }
+                            RECORD_DELETE => {
+                                index.remove(key);
+                                true
+                            }
+                            RECORD_BATCH => {
+                                let base = offset + RECORD_HEADER_LEN + key_len as u64;
+                                apply_batch(index, base, &body[key_len..]).is_some()
+                            }
+                            // Read separately on open; only valid as the first record.
+                            RECORD_ENCRYPTION => offset == 0,
+                            _ => false,
+                        },
+                        Err(_) => false,
+                    };
+            }
+        }
+        if !valid {
+            // A damaged length can reach past the end of the log just like
+            // a torn one, so it only counts as torn if no valid record
+            // starts after it.
+            if !repair && (record_end < len || record_follows(reader, start, offset + 1, len)?) {
+                return Err(DbError::CorruptLog { offset });
+            }
+            break;
+        }
+        offset = record_end;
+    }
+
+    Ok(offset)
+}
+
+// Whether a complete, checksummed record starts anywhere in `from..len`,
+// with the reader's first byte at file offset `start`. Only called on a bad
+// record, which is the last one when the log is merely torn, so the scan
+// is short unless the log is damaged.
+fn record_follows<R: Read + Seek>(reader: &mut R, start: u64, from: u64, len: u64) -> Result<bool, DbError> {
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    for at in from..len.saturating_sub(RECORD_HEADER_LEN - 1) {
+        reader.seek(SeekFrom::Start(at - start))?;
+        if !read_full(reader, &mut header)? {
+            return Ok(false);
+        }
+        let key_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as u64;
+        let value_len = u32::from_le_bytes([header[9], header[10], header[11], header[12]]) as u64;
+        if !matches!(header[4], RECORD_PUT | RECORD_DELETE | RECORD_BATCH)
+            || at + RECORD_HEADER_LEN + key_len + value_len > len
+        {
+            continue;
+        }
+        let mut checked = header[4..].to_vec();
+        checked.resize(checked.len() + (key_len + value_len) as usize, 0);
+        if read_full(reader, &mut checked[RECORD_HEADER_LEN as usize - 4..])?
+            && crc32(&checked) == u32::from_le_bytes([header[0], header[1], header[2], header[3]])
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Like `read_exact`, but reports a short read at end of file as `false`
+// instead of an error.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, DbError> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(true)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// The cipher for a log in `path` that starts with `header`, given the
+// configured key.
+fn log_cipher(path: &Path, key: Option<&EncryptionKey>, header: Option<Vec<u8>>) -> Result<Option<Cipher>, DbError> {
+    match (key, header) {
+        (Some(key), Some(header)) => Ok(Some(Cipher::from_header(key, &header).map_err(|err| match err {
+            DbError::EncryptionError(message) => DbError::EncryptionError(format!("{}: {}", path.display(), message)),
+            err => err,
+        })?)),
+        (Some(_), None) => Err(DbError::EncryptionError(format!(
+            "{} is not encrypted; open it without a key and call rotate_key to encrypt it",
+            path.display()
+        ))),
+        (None, Some(_)) => Err(DbError::EncryptionError(format!(
+            "{} is encrypted but no encryption key is configured",
+            path.display()
+        ))),
+        (None, None) => Ok(None),
+    }
+}
+
+fn config_type(config: &StorageConfig) -> Result<DbType, DbError> {
+    match config.type_.as_str() {
+        "memory" => Ok(DbType::Memory),
+        "disk" | "local" => Ok(DbType::Disk),
+        other => Err(DbError::ConnectionError(format!("unknown storage type: {}", other))),
+    }
+}
+
+fn lock_poisoned() -> DbError {
+    DbError::ConnectionError("database lock poisoned".to_string())
+}
+
 pub struct Row {
     pub columns: Vec<Vec<u8>>,
 }
 
+#[derive(Debug)]
 pub enum DbError {
     QueryError(String),
     ConnectionError(String),
     IOError(String),
+    EncryptionError(String),
+    // The node no longer keeps the block or state at `height`; the oldest
+    // one it has is at `oldest`.
+    StatePruned { height: u64, oldest: u64 },
+    // The data was written by a newer release with schema `found`; this
+    // one only understands schemas up to `supported`.
+    SchemaTooNew { found: u32, supported: u32 },
+    // The write would replace the finalized block at `height` or one
+    // before it.
+    Finalized { height: u64 },
+    // A column family name that is empty or contains the separator.
+    InvalidColumnFamily(String),
+    // The log record at `offset` is damaged and intact records follow it.
+    // `DiskConnection::repair` drops it and everything after it.
+    CorruptLog { offset: u64 },
+    // The stored schema version, which is not a number.
+    CorruptSchemaVersion(String),
+    // No migration upgrades data at schema version `from`.
+    MissingMigration { from: u32 },
+    // The block with this hash is not on the canonical chain, e.g. when it
+    // is to be finalized.
+    NotCanonical(String),
 }
 
 impl std::fmt::Display for DbError {
@@ -158,8 +1902,39 @@ impl std::fmt::Display for DbError {
             DbError::QueryError(err) => write!(f, "Query error: {}", err),
             DbError::ConnectionError(err) => write!(f, "Connection error: {}", err),
             DbError::IOError(err) => write!(f, "IO error: {}", err),
+            DbError::EncryptionError(err) => write!(f, "Encryption error: {}", err),
+            DbError::StatePruned { height, oldest } => write!(
+                f,
+                "State pruned: block {} is older than the oldest block this node keeps ({})",
+                height, oldest
+            ),
+            DbError::SchemaTooNew { found, supported } => write!(
+                f,
+                "Schema too new: the database has schema version {} but this node supports up to {}; upgrade the node",
+                found, supported
+            ),
+            DbError::Finalized { height } => write!(
+                f,
+                "Finalized: blocks up to height {} are final and cannot be replaced",
+                height
+            ),
+            DbError::InvalidColumnFamily(name) => write!(f, "Invalid column family name {:?}", name),
+            DbError::CorruptLog { offset } => write!(
+                f,
+                "Corrupt log: the record at offset {} is damaged and intact records follow it; repair the database to drop them",
+                offset
+            ),
+            DbError::CorruptSchemaVersion(version) => write!(f, "Corrupt schema version {:?}", version),
+            DbError::MissingMigration { from } => write!(f, "Missing migration: nothing upgrades schema version {}", from),
+            DbError::NotCanonical(hash) => write!(f, "Block {} is not on the canonical chain", hash),
         }
     }
 }
 
 impl std::error::Error for DbError {}
+
+impl From<std::io::Error> for DbError {
+    fn from(err: std::io::Error) -> Self {
+        DbError::IOError(err.to_string())
+    }
+}
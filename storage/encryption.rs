@@ -0,0 +1,124 @@
+// encryption.rs
+//
+// Authenticated encryption of stored values. Values are sealed with
+// XChaCha20-Poly1305 under a 256-bit key derived with Argon2id from the
+// configured passphrase or key file and a random per-database salt. A sealed
+// value is `nonce (24 bytes) | ciphertext | tag (16 bytes)`. Callers pass the
+// value's key as associated data, so a value copied under another key fails
+// authentication instead of decrypting.
+//
+// Each encrypted database carries a header of `salt | sealed check value`.
+// Opening the header with the wrong secret fails on the check value, which
+// is how a wrong key is told apart from corrupted data.
+
+use std::fs;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::config::EncryptionKey;
+use crate::db::DbError;
+
+pub const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const CHECK_VALUE: &[u8] = b"pi-sentinel storage key check";
+const CHECK_AAD: &[u8] = b"header";
+
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+    salt: [u8; SALT_LEN],
+}
+
+impl Cipher {
+    // Bytes a sealed value adds to its plaintext.
+    pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+    // Derives a key under a fresh random salt, for a new database.
+    pub fn new(key: &EncryptionKey) -> Result<Self, DbError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Cipher::with_salt(key, salt)
+    }
+
+    pub fn with_salt(key: &EncryptionKey, salt: [u8; SALT_LEN]) -> Result<Self, DbError> {
+        let secret = load_secret(key)?;
+        let mut derived = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(&secret, &salt, &mut derived)
+            .map_err(|err| encryption_error(format!("key derivation failed: {}", err)))?;
+        let aead = XChaCha20Poly1305::new_from_slice(&derived)
+            .map_err(|_| encryption_error("invalid key length".to_string()))?;
+        Ok(Cipher { aead, salt })
+    }
+
+    // Rebuilds the cipher described by `header`, failing with a wrong key
+    // error if `key` is not the secret the header was written with.
+    pub fn from_header(key: &EncryptionKey, header: &[u8]) -> Result<Self, DbError> {
+        if header.len() < SALT_LEN {
+            return Err(encryption_error("truncated encryption header".to_string()));
+        }
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&header[..SALT_LEN]);
+        let cipher = Cipher::with_salt(key, salt)?;
+        match cipher.open(CHECK_AAD, &header[SALT_LEN..]) {
+            Ok(check) if check == CHECK_VALUE => Ok(cipher),
+            _ => Err(encryption_error(
+                "wrong encryption key: the configured passphrase or key file does not match the one the data was written with"
+                    .to_string(),
+            )),
+        }
+    }
+
+    pub fn header(&self) -> Vec<u8> {
+        let mut header = self.salt.to_vec();
+        header.extend_from_slice(&self.seal(CHECK_AAD, CHECK_VALUE));
+        header
+    }
+
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .expect("XChaCha20-Poly1305 encryption does not fail");
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, DbError> {
+        if sealed.len() < Self::OVERHEAD {
+            return Err(encryption_error("sealed value is truncated".to_string()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| encryption_error("value failed authentication".to_string()))
+    }
+}
+
+fn load_secret(key: &EncryptionKey) -> Result<Vec<u8>, DbError> {
+    let secret = match key {
+        EncryptionKey::Passphrase(passphrase) => passphrase.as_bytes().to_vec(),
+        EncryptionKey::KeyFile(path) => {
+            let mut secret = fs::read(path)
+                .map_err(|err| encryption_error(format!("cannot read key file {}: {}", path, err)))?;
+            // Tolerate the trailing newline most editors add.
+            while matches!(secret.last(), Some(byte) if byte.is_ascii_whitespace()) {
+                secret.pop();
+            }
+            secret
+        }
+    };
+    if secret.is_empty() {
+        return Err(encryption_error("encryption secret is empty".to_string()));
+    }
+    Ok(secret)
+}
+
+fn encryption_error(message: String) -> DbError {
+    DbError::EncryptionError(message)
+}
@@ -0,0 +1,401 @@
+// migration.rs
+//
+// Schema versions of the database layout. The version is stored in the
+// "meta" column family; databases written before it was recorded are at
+// version 1, or at version 0 if they predate column families. `migrate` runs on startup and upgrades older layouts one
+// version at a time, each step in a single write batch that also bumps the
+// stored version, so a crash between steps resumes where it stopped. Data
+// written by a newer release is refused rather than misread.
+//
+// To change a layout, bump `SCHEMA_VERSION` and append a step to
+// `MIGRATIONS` that rewrites data from the previous version.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::RwLock;
+
+use crate::backend::{Backend, BackendSnapshot};
+use crate::blockchain::Block;
+use crate::chain::{self, ADDRESS_INDEX, BLOCKS_COLUMN_FAMILY, CHAIN_COLUMN_FAMILY, HEAD_KEY, HEIGHT_INDEX, TIPS_INDEX, TX_INDEX};
+use crate::db::{BatchOp, Db, DbError, Direction, KeyRange, WriteBatch, CF_SEPARATOR, DEFAULT_COLUMN_FAMILY};
+
+pub const SCHEMA_VERSION: u32 = 5;
+const META_COLUMN_FAMILY: &str = "meta";
+const VERSION_KEY: &str = "schema_version";
+// Where `PiSentinelStorage` kept blocks before it used the shared chain
+// layout: hash -> block, and height -> canonical hash.
+const LEGACY_BLOCKS_COLUMN_FAMILY: &str = "blocks_by_hash";
+const LEGACY_CANONICAL_COLUMN_FAMILY: &str = "canonical";
+
+struct Migration {
+    // The step upgrades data at this version to the next one.
+    from: u32,
+    description: &'static str,
+    plan: fn(&Db) -> Result<WriteBatch, DbError>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "move keys written before column families into the default family",
+        plan: default_family,
+    },
+    Migration {
+        from: 1,
+        description: "key blocks by hash and index them by height, transaction and address",
+        plan: blocks_by_hash,
+    },
+    Migration {
+        from: 2,
+        description: "index the tips of the block tree",
+        plan: index_tips,
+    },
+    Migration {
+        from: 3,
+        description: "key the transaction and address indexes by position",
+        plan: position_keyed_indexes,
+    },
+    Migration {
+        from: 4,
+        description: "move substrate blocks into the shared chain layout",
+        plan: shared_chain_layout,
+    },
+];
+
+#[derive(Debug, Clone)]
+pub struct MigrationReport {
+    // `None` for a new, empty database.
+    pub from: Option<u32>,
+    pub to: u32,
+    pub dry_run: bool,
+    pub steps: Vec<MigrationStep>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationStep {
+    pub from: u32,
+    pub to: u32,
+    pub description: String,
+    // Puts and deletes the step makes, besides the version bump.
+    pub writes: usize,
+}
+
+// The stored schema version; without one, 0 if the database holds keys
+// outside any column family, 1 if it holds other data, or `None` if it is
+// empty.
+pub fn schema_version(db: &Db) -> Result<Option<u32>, DbError> {
+    match db.cf(META_COLUMN_FAMILY)?.get(VERSION_KEY)? {
+        Some(value) => String::from_utf8_lossy(&value)
+            .parse()
+            .map(Some)
+            .map_err(|_| DbError::CorruptSchemaVersion(String::from_utf8_lossy(&value).into_owned())),
+        None if db.column_families()?.is_empty() => Ok(None),
+        None if !unscoped_keys(db)?.is_empty() => Ok(Some(0)),
+        None => Ok(Some(1)),
+    }
+}
+
+// Brings `db` up to `SCHEMA_VERSION`. With `dry_run` set, this is `plan`
+// on a snapshot of `db`, which is left untouched.
+pub fn migrate(db: &Db, dry_run: bool) -> Result<MigrationReport, DbError> {
+    if dry_run {
+        plan(db.backend().snapshot()?)
+    } else {
+        run(db, false)
+    }
+}
+
+// Reports the steps `migrate` would run on `base`. They run for real, on
+// an overlay that keeps their writes in memory on top of `base`, so the
+// report shows exactly what would change while needing memory only for
+// the changed entries.
+pub fn plan(base: Box<dyn BackendSnapshot>) -> Result<MigrationReport, DbError> {
+    let overlay = Overlay {
+        base,
+        changes: RwLock::new(BTreeMap::new()),
+    };
+    run(&Db::with_backend(Box::new(overlay)), true)
+}
+
+fn run(db: &Db, dry_run: bool) -> Result<MigrationReport, DbError> {
+    let from = schema_version(db)?;
+    if let Some(found) = from {
+        if found > SCHEMA_VERSION {
+            return Err(DbError::SchemaTooNew {
+                found,
+                supported: SCHEMA_VERSION,
+            });
+        }
+    }
+    let mut report = MigrationReport {
+        from,
+        to: SCHEMA_VERSION,
+        dry_run,
+        steps: Vec::new(),
+    };
+
+    let mut version = match from {
+        Some(version) => version,
+        // Nothing to upgrade; new data is written in the current layout.
+        None => SCHEMA_VERSION,
+    };
+    while version < SCHEMA_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or(DbError::MissingMigration { from: version })?;
+        let mut batch = (migration.plan)(db)?;
+        let writes = batch.len();
+        batch.put_cf(META_COLUMN_FAMILY, VERSION_KEY, (version + 1).to_string().into_bytes());
+        db.write(batch)?;
+        if !dry_run {
+            log::info!("migrated storage schema from version {} to {}: {}", version, version + 1, migration.description);
+        }
+        report.steps.push(MigrationStep {
+            from: version,
+            to: version + 1,
+            description: migration.description.to_string(),
+            writes,
+        });
+        version += 1;
+    }
+    if from.is_none() && !dry_run {
+        db.cf(META_COLUMN_FAMILY)?.put(VERSION_KEY, SCHEMA_VERSION.to_string().into_bytes())?;
+    }
+    Ok(report)
+}
+
+// A writable view of a snapshot for dry runs. Raw backend keys pass
+// through, so steps that read keys outside any column family see them too.
+struct Overlay {
+    base: Box<dyn BackendSnapshot>,
+    // Written keys, with `None` for deleted ones.
+    changes: RwLock<BTreeMap<String, Option<Vec<u8>>>>,
+}
+
+impl Backend for Overlay {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        match self.changes.read().map_err(|_| lock_poisoned())?.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.base.get(key),
+        }
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), DbError> {
+        let mut changes = self.changes.write().map_err(|_| lock_poisoned())?;
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Put(key, value) => changes.insert(key, Some(value)),
+                BatchOp::Delete(key) => changes.insert(key, None),
+            };
+        }
+        Ok(())
+    }
+
+    // Merges the changed keys into the snapshot's, fetching more from the
+    // snapshot while deletions leave the chunk short.
+    fn chunk(
+        &self,
+        range: &KeyRange,
+        direction: Direction,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError> {
+        let changes = self.changes.read().map_err(|_| lock_poisoned())?;
+        let ahead = |key: &str| match (direction, after) {
+            (_, None) => true,
+            (Direction::Forward, Some(after)) => key > after,
+            (Direction::Reverse, Some(after)) => key < after,
+        };
+        let mut changed: Vec<_> = changes
+            .iter()
+            .filter(|(key, _)| range.contains(key) && ahead(key))
+            .collect();
+        if direction == Direction::Reverse {
+            changed.reverse();
+        }
+        let mut changed = changed.into_iter().peekable();
+
+        let mut chunk = Vec::new();
+        let mut base = VecDeque::new();
+        let mut cursor = after.map(str::to_string);
+        let mut base_done = false;
+        while chunk.len() < limit {
+            if base.is_empty() && !base_done {
+                let fetched = self.base.chunk(range, direction, cursor.as_deref(), limit)?;
+                base_done = fetched.len() < limit;
+                if let Some((key, _)) = fetched.last() {
+                    cursor = Some(key.clone());
+                }
+                base.extend(fetched);
+            }
+            let order = match (base.front(), changed.peek()) {
+                (None, None) => break,
+                (Some(_), None) => Ordering::Greater,
+                (None, Some(_)) => Ordering::Less,
+                (Some((base_key, _)), Some((key, _))) => match direction {
+                    Direction::Forward => key.as_str().cmp(base_key),
+                    Direction::Reverse => base_key.as_str().cmp(key),
+                },
+            };
+            if order == Ordering::Greater {
+                chunk.extend(base.pop_front());
+                continue;
+            }
+            if order == Ordering::Equal {
+                base.pop_front();
+            }
+            if let Some((key, Some(value))) = changed.next() {
+                chunk.push((key.clone(), value.clone()));
+            }
+        }
+        Ok(chunk)
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn BackendSnapshot>, DbError> {
+        Err(DbError::QueryError("migration dry runs do not support snapshots".to_string()))
+    }
+}
+
+fn lock_poisoned() -> DbError {
+    DbError::ConnectionError("migration overlay lock poisoned".to_string())
+}
+
+// Keys without a column family prefix, as every key was before column
+// families.
+fn unscoped_keys(db: &Db) -> Result<Vec<String>, DbError> {
+    let keys = db.backend().keys(&KeyRange::All, Direction::Forward, usize::MAX)?;
+    Ok(keys.into_iter().filter(|key| !key.contains(CF_SEPARATOR)).collect())
+}
+
+// 0 -> 1: `Db::get`, `put` and `delete` used the raw key; they now use the
+// default column family.
+fn default_family(db: &Db) -> Result<WriteBatch, DbError> {
+    let mut ops = Vec::new();
+    for key in unscoped_keys(db)? {
+        if let Some(value) = db.backend().get(&key)? {
+            ops.push(BatchOp::Put(format!("{}{}{}", DEFAULT_COLUMN_FAMILY, CF_SEPARATOR, key), value));
+        }
+        ops.push(BatchOp::Delete(key));
+    }
+    Ok(WriteBatch::from_ops(ops))
+}
+
+// 1 -> 2: blocks were keyed by zero-padded height and the chain head was
+// stored as a height. Blocks are now keyed by hash, with the canonical
+// chain in the height index. Version 2 data carries no version either when
+// it was written before versions were recorded, so only height-keyed
+// entries are converted.
+fn blocks_by_hash(db: &Db) -> Result<WriteBatch, DbError> {
+    let mut batch = WriteBatch::new();
+    let mut head_hash = None;
+    let head_height = db
+        .cf(CHAIN_COLUMN_FAMILY)?
+        .get(HEAD_KEY)?
+        .and_then(|value| String::from_utf8_lossy(&value).parse::<u64>().ok());
+
+    for entry in db.cf(BLOCKS_COLUMN_FAMILY)?.iter(KeyRange::All, Direction::Forward) {
+        let (key, value) = entry?;
+        if key.len() != 20 || !key.bytes().all(|byte| byte.is_ascii_digit()) {
+            continue;
+        }
+        let block: Block = serde_json::from_slice(&value)
+            .map_err(|err| DbError::QueryError(format!("corrupt block at height {}: {}", key, err)))?;
+        if Some(block.height) == head_height {
+            head_hash = Some(block.hash.clone());
+        }
+        batch.delete_cf(BLOCKS_COLUMN_FAMILY, &key);
+        batch.put_cf(BLOCKS_COLUMN_FAMILY, &block.hash, value);
+        chain::index_block(&mut batch, &block)?;
+    }
+    if let Some(height) = head_height {
+        let hash = head_hash.ok_or_else(|| DbError::QueryError(format!("head block at height {} is missing", height)))?;
+        batch.put_cf(CHAIN_COLUMN_FAMILY, HEAD_KEY, hash.into_bytes());
+    }
+    Ok(batch)
+}
+
+// 2 -> 3: the tips index is new. Every stored block no other block names as
+// its parent is a tip.
+fn index_tips(db: &Db) -> Result<WriteBatch, DbError> {
+    let mut hashes = Vec::new();
+    let mut parents = HashSet::new();
+    for entry in db.cf(BLOCKS_COLUMN_FAMILY)?.iter(KeyRange::All, Direction::Forward) {
+        let (key, value) = entry?;
+        let block: Block = serde_json::from_slice(&value)
+            .map_err(|err| DbError::QueryError(format!("corrupt block {}: {}", key, err)))?;
+        parents.insert(block.parent_hash);
+        hashes.push(key);
+    }
+    let mut batch = WriteBatch::new();
+    for hash in hashes.iter().filter(|hash| !parents.contains(*hash)) {
+        batch.put_cf(TIPS_INDEX, hash, Vec::new());
+    }
+    Ok(batch)
+}
+
+// 3 -> 4: the transaction index was keyed by hash alone, so a transaction
+// in two blocks had one entry, and address index keys did not delimit the
+// address. Both indexes are rebuilt from the canonical chain.
+fn position_keyed_indexes(db: &Db) -> Result<WriteBatch, DbError> {
+    let mut batch = WriteBatch::new();
+    for cf in [TX_INDEX, ADDRESS_INDEX] {
+        for entry in db.cf(cf)?.iter(KeyRange::All, Direction::Forward) {
+            let (key, _) = entry?;
+            batch.delete_cf(cf, &key);
+        }
+    }
+    for entry in db.cf(HEIGHT_INDEX)?.iter(KeyRange::All, Direction::Forward) {
+        let (height, hash) = entry?;
+        let hash = String::from_utf8_lossy(&hash).into_owned();
+        let value = db
+            .cf(BLOCKS_COLUMN_FAMILY)?
+            .get(&hash)?
+            .ok_or_else(|| DbError::QueryError(format!("canonical block at height {} is missing", height)))?;
+        let block: Block = serde_json::from_slice(&value)
+            .map_err(|err| DbError::QueryError(format!("corrupt block {}: {}", hash, err)))?;
+        chain::index_block(&mut batch, &block)?;
+    }
+    Ok(batch)
+}
+
+// 4 -> 5: `PiSentinelStorage` kept its own block families. Its blocks join
+// the block tree, its canonical chain becomes the indexed chain, and the old
+// families are deleted.
+fn shared_chain_layout(db: &Db) -> Result<WriteBatch, DbError> {
+    let mut batch = WriteBatch::new();
+    let mut blocks = HashMap::new();
+    for entry in db.cf(LEGACY_BLOCKS_COLUMN_FAMILY)?.iter(KeyRange::All, Direction::Forward) {
+        let (hash, value) = entry?;
+        let block: Block = serde_json::from_slice(&value)
+            .map_err(|err| DbError::QueryError(format!("corrupt block {}: {}", hash, err)))?;
+        batch.delete_cf(LEGACY_BLOCKS_COLUMN_FAMILY, &hash);
+        batch.put_cf(BLOCKS_COLUMN_FAMILY, &hash, value);
+        blocks.insert(hash, block);
+    }
+    let mut head = None;
+    for entry in db.cf(LEGACY_CANONICAL_COLUMN_FAMILY)?.iter(KeyRange::All, Direction::Forward) {
+        let (height, hash) = entry?;
+        let hash = String::from_utf8_lossy(&hash).into_owned();
+        let block = blocks
+            .get(&hash)
+            .ok_or_else(|| DbError::QueryError(format!("canonical block {} at height {} is missing", hash, height)))?;
+        batch.delete_cf(LEGACY_CANONICAL_COLUMN_FAMILY, &height);
+        chain::index_block(&mut batch, block)?;
+        head = Some(hash);
+    }
+    if let Some(head) = head {
+        if db.cf(CHAIN_COLUMN_FAMILY)?.get(HEAD_KEY)?.is_some() {
+            return Err(DbError::QueryError("the database holds two chains".to_string()));
+        }
+        batch.put_cf(CHAIN_COLUMN_FAMILY, HEAD_KEY, head.into_bytes());
+    }
+    let parents: HashSet<&str> = blocks.values().map(|block| block.parent_hash.as_str()).collect();
+    for (hash, block) in &blocks {
+        if !parents.contains(hash.as_str()) {
+            batch.put_cf(TIPS_INDEX, hash, Vec::new());
+        }
+        batch.delete_cf(TIPS_INDEX, &block.parent_hash);
+    }
+    Ok(batch)
+}
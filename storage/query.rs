@@ -0,0 +1,290 @@
+// query.rs
+//
+// A small, read-only query language for inspecting a `Db` from the CLI or RPC
+// without writing Rust. The full reference lives in docs/storage_queries.md.
+//
+//   query    := command [REVERSE] [LIMIT n] [SELECT column {, column}]
+//   command  := SCAN [source] [selector] | COUNT [source] [selector]
+//             | GET [source] key
+//   source   := FROM column_family
+//   selector := PREFIX string | RANGE string string
+//   column   := KEY | VALUE | SIZE
+//
+// Keywords are case-insensitive. Strings are bare words or are quoted with
+// single or double quotes, with `\` escaping the next character. Keys are
+// visited in ascending byte order (descending with REVERSE); RANGE includes
+// the start key and excludes the end key. Without FROM, queries read the
+// "default" column family.
+
+use crate::db::{DbError, Direction, KeyRange, Row};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Key,
+    Value,
+    Size,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Scan(KeyRange),
+    Count(KeyRange),
+    Get(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub column_family: Option<String>,
+    pub command: Command,
+    pub reverse: bool,
+    pub limit: Option<usize>,
+    pub columns: Vec<Column>,
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Self, DbError> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser { tokens, pos: 0 };
+
+        let verb = parser.keyword()?;
+        let column_family = match parser.peek_keyword().as_deref() {
+            Some("FROM") => {
+                parser.pos += 1;
+                Some(parser.string("column family")?)
+            }
+            _ => None,
+        };
+        let command = match verb.as_str() {
+            "SCAN" => Command::Scan(parser.selector()?),
+            "COUNT" => Command::Count(parser.selector()?),
+            "GET" => Command::Get(parser.string("key")?),
+            other => return Err(query_error(format!("unknown command {}", other))),
+        };
+
+        let mut query = Query {
+            column_family,
+            command,
+            reverse: false,
+            limit: None,
+            columns: vec![Column::Key, Column::Value],
+        };
+        let mut selected = false;
+
+        while let Some(token) = parser.peek_keyword() {
+            parser.pos += 1;
+            match token.as_str() {
+                "REVERSE" => query.reverse = true,
+                "LIMIT" => {
+                    let limit = parser.string("limit")?;
+                    let limit = limit
+                        .parse()
+                        .map_err(|_| query_error(format!("invalid limit {}", limit)))?;
+                    query.limit = Some(limit);
+                }
+                "SELECT" => {
+                    query.columns = parser.columns()?;
+                    selected = true;
+                }
+                other => return Err(query_error(format!("unexpected {}", other))),
+            }
+        }
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(query_error(format!("unexpected {}", token.text)));
+        }
+        if let Command::Count(_) = query.command {
+            if selected {
+                return Err(query_error("COUNT does not take SELECT".to_string()));
+            }
+        }
+
+        Ok(query)
+    }
+
+    // Runs the query against a store. `keys` returns up to a limit of the
+    // keys in `range` in iteration order, `count` the number of keys in
+    // `range` and `get` loads a value; values are only read when the
+    // projection needs them.
+    pub fn run<K, C, G>(&self, keys: K, count: C, get: G) -> Result<Vec<Row>, DbError>
+    where
+        K: Fn(&KeyRange, Direction, usize) -> Result<Vec<String>, DbError>,
+        C: Fn(&KeyRange) -> Result<u64, DbError>,
+        G: Fn(&str) -> Result<Option<Vec<u8>>, DbError>,
+    {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let direction = if self.reverse {
+            Direction::Reverse
+        } else {
+            Direction::Forward
+        };
+        match &self.command {
+            Command::Count(range) => {
+                let count = match self.limit {
+                    Some(limit) => keys(range, direction, limit)?.len() as u64,
+                    None => count(range)?,
+                };
+                Ok(vec![Row {
+                    columns: vec![count.to_string().into_bytes()],
+                }])
+            }
+            Command::Get(key) => match get(key)? {
+                Some(value) if limit > 0 => Ok(vec![self.project(key, value)]),
+                _ => Ok(Vec::new()),
+            },
+            Command::Scan(range) => {
+                let needs_value = self.columns.iter().any(|c| *c != Column::Key);
+                let mut rows = Vec::new();
+                for key in keys(range, direction, limit)? {
+                    let value = if needs_value {
+                        match get(&key)? {
+                            Some(value) => value,
+                            // Deleted since the keys were listed.
+                            None => continue,
+                        }
+                    } else {
+                        Vec::new()
+                    };
+                    rows.push(self.project(&key, value));
+                }
+                Ok(rows)
+            }
+        }
+    }
+
+    fn project(&self, key: &str, value: Vec<u8>) -> Row {
+        let columns = self
+            .columns
+            .iter()
+            .map(|column| match column {
+                Column::Key => key.as_bytes().to_vec(),
+                Column::Value => value.clone(),
+                Column::Size => value.len().to_string().into_bytes(),
+            })
+            .collect();
+        Row { columns }
+    }
+}
+
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn keyword(&mut self) -> Result<String, DbError> {
+        match self.peek_keyword() {
+            Some(keyword) => {
+                self.pos += 1;
+                Ok(keyword)
+            }
+            None => Err(query_error("expected a command".to_string())),
+        }
+    }
+
+    fn peek_keyword(&self) -> Option<String> {
+        self.tokens
+            .get(self.pos)
+            .filter(|token| !token.quoted)
+            .map(|token| token.text.to_ascii_uppercase())
+    }
+
+    fn string(&mut self, what: &str) -> Result<String, DbError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.text.clone())
+            }
+            None => Err(query_error(format!("expected {}", what))),
+        }
+    }
+
+    fn selector(&mut self) -> Result<KeyRange, DbError> {
+        match self.peek_keyword().as_deref() {
+            Some("PREFIX") => {
+                self.pos += 1;
+                Ok(KeyRange::Prefix(self.string("prefix")?))
+            }
+            Some("RANGE") => {
+                self.pos += 1;
+                let start = self.string("range start")?;
+                let end = self.string("range end")?;
+                Ok(KeyRange::Range(start, end))
+            }
+            _ => Ok(KeyRange::All),
+        }
+    }
+
+    fn columns(&mut self) -> Result<Vec<Column>, DbError> {
+        let mut columns = Vec::new();
+        loop {
+            let column = match self.peek_keyword().as_deref() {
+                Some("KEY") => Column::Key,
+                Some("VALUE") => Column::Value,
+                Some("SIZE") => Column::Size,
+                _ => return Err(query_error("expected KEY, VALUE or SIZE".to_string())),
+            };
+            self.pos += 1;
+            columns.push(column);
+
+            match self.tokens.get(self.pos) {
+                Some(token) if !token.quoted && token.text == "," => self.pos += 1,
+                _ => return Ok(columns),
+            }
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, DbError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == ',' {
+            chars.next();
+            tokens.push(Token {
+                text: ",".to_string(),
+                quoted: false,
+            });
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some(escaped) => text.push(escaped),
+                        None => return Err(query_error("unterminated string".to_string())),
+                    },
+                    Some(ch) if ch == c => break,
+                    Some(ch) => text.push(ch),
+                    None => return Err(query_error("unterminated string".to_string())),
+                }
+            }
+            tokens.push(Token { text, quoted: true });
+        } else {
+            let mut text = String::new();
+            while let Some(&ch) = chars.peek() {
+                if ch.is_whitespace() || ch == ',' {
+                    break;
+                }
+                text.push(ch);
+                chars.next();
+            }
+            tokens.push(Token {
+                text,
+                quoted: false,
+            });
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn query_error(message: String) -> DbError {
+    DbError::QueryError(message)
+}
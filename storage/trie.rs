@@ -0,0 +1,531 @@
+// trie.rs
+//
+// Authenticated world state: a Merkle Patricia trie over account balances,
+// nonces and contract storage, stored in the "state" column family of a `Db`.
+// Every node is keyed by the SHA-256 of its encoding, so a root hash commits
+// to the whole state and nodes are shared between the states of successive
+// blocks. Nodes are never modified in place; writes build new nodes up to a
+// new root and old roots stay readable.
+//
+// Keys are hashed before they enter the trie, so every path is 64 nibbles
+// long, values only live in leaves and the trie stays balanced whatever
+// addresses are used. Node encodings:
+//
+//   leaf:      0 | path_len (u8) | packed path | value
+//   extension: 1 | path_len (u8) | packed path | child hash (32 bytes)
+//   branch:    2 | child bitmap (u16) | child hash for each set bit
+//
+// where a packed path holds two nibbles per byte, high nibble first.
+
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+
+use crate::db::{Db, DbError, WriteBatch};
+use crate::genesis::{Account, Genesis};
+
+pub type Hash = [u8; 32];
+
+// Root of the trie holding no keys.
+pub const EMPTY_ROOT: Hash = [0; 32];
+pub const STATE_COLUMN_FAMILY: &str = "state";
+
+const NODE_LEAF: u8 = 0;
+const NODE_EXTENSION: u8 = 1;
+const NODE_BRANCH: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Leaf(Vec<u8>, Vec<u8>),
+    Extension(Vec<u8>, Hash),
+    Branch(Box<[Option<Hash>; 16]>),
+}
+
+pub struct StateTrie<'a> {
+    db: &'a Db,
+    root: Hash,
+    // Nodes created since the last commit.
+    pending: HashMap<Hash, Vec<u8>>,
+}
+
+impl<'a> StateTrie<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        StateTrie::at(db, EMPTY_ROOT)
+    }
+
+    // Opens the state committed under `root`, e.g. a block's state root.
+    pub fn at(db: &'a Db, root: Hash) -> Self {
+        StateTrie {
+            db,
+            root,
+            pending: HashMap::new(),
+        }
+    }
+
+    // Builds and commits the genesis state from `genesis.alloc`.
+    pub fn from_genesis(db: &'a Db, genesis: &Genesis) -> Result<Self, DbError> {
+        let mut trie = StateTrie::new(db);
+        for (address, account) in &genesis.alloc {
+            trie.set_account(address, account)?;
+        }
+        trie.commit()?;
+        Ok(trie)
+    }
+
+    // Root hash of the current state, including uncommitted changes.
+    pub fn root(&self) -> Hash {
+        self.root
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        let path = key_path(key);
+        let mut node = self.root;
+        let mut rest = &path[..];
+        loop {
+            if node == EMPTY_ROOT {
+                return Ok(None);
+            }
+            match self.load(&node)? {
+                Node::Leaf(leaf_path, value) => return Ok(if leaf_path == rest { Some(value) } else { None }),
+                Node::Extension(ext_path, child) => {
+                    if !rest.starts_with(&ext_path) {
+                        return Ok(None);
+                    }
+                    rest = &rest[ext_path.len()..];
+                    node = child;
+                }
+                Node::Branch(children) => match children[rest[0] as usize] {
+                    Some(child) => {
+                        rest = &rest[1..];
+                        node = child;
+                    }
+                    None => return Ok(None),
+                },
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) -> Result<(), DbError> {
+        let path = key_path(key);
+        self.root = self.insert_at(self.root, &path, value)?;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Result<(), DbError> {
+        let path = key_path(key);
+        self.root = self.remove_at(self.root, &path)?.unwrap_or(EMPTY_ROOT);
+        Ok(())
+    }
+
+    // Writes the nodes created since the last commit in one batch and
+    // returns the new root. Nodes that were superseded before the commit
+    // are dropped rather than written.
+    // State for a block of a pruned chain goes through
+    // `ChainStore::commit_state` instead, so a sweep cannot delete it
+    // before the block is stored.
+    pub fn commit(&mut self) -> Result<Hash, DbError> {
+        let mut batch = WriteBatch::new();
+        let mut reachable = vec![self.root];
+        while let Some(hash) = reachable.pop() {
+            let encoded = match self.pending.remove(&hash) {
+                Some(encoded) => encoded,
+                // Already stored, and so is everything below it.
+                None => continue,
+            };
+            reachable.extend(node_children(&encoded));
+            batch.put_cf(STATE_COLUMN_FAMILY, &to_hex(&hash), encoded);
+        }
+        self.pending.clear();
+        self.db.write(batch)?;
+        Ok(self.root)
+    }
+
+    // Collects the nodes on the path to `key`. The proof shows the key's
+    // value if it is present, and that it is absent otherwise; check it with
+    // `verify_proof`.
+    pub fn prove(&self, key: &[u8]) -> Result<Proof, DbError> {
+        let path = key_path(key);
+        let mut nodes = Vec::new();
+        let mut node = self.root;
+        let mut rest = &path[..];
+        while node != EMPTY_ROOT {
+            let encoded = self.load_encoded(&node)?;
+            nodes.push(encoded.clone());
+            match decode_node(&encoded).ok_or_else(|| corrupt_node(&node))? {
+                Node::Leaf(..) => break,
+                Node::Extension(ext_path, child) => {
+                    if !rest.starts_with(&ext_path) {
+                        break;
+                    }
+                    rest = &rest[ext_path.len()..];
+                    node = child;
+                }
+                Node::Branch(children) => match children[rest[0] as usize] {
+                    Some(child) => {
+                        rest = &rest[1..];
+                        node = child;
+                    }
+                    None => break,
+                },
+            }
+        }
+        Ok(Proof {
+            key: key.to_vec(),
+            nodes,
+        })
+    }
+
+    pub fn account(&self, address: &str) -> Result<Option<Account>, DbError> {
+        Ok(self.get(&account_key(address))?.and_then(|value| decode_account(&value)))
+    }
+
+    pub fn set_account(&mut self, address: &str, account: &Account) -> Result<(), DbError> {
+        self.insert(&account_key(address), encode_account(account))
+    }
+
+    pub fn storage(&self, contract: &str, slot: &str) -> Result<Option<Vec<u8>>, DbError> {
+        self.get(&storage_key(contract, slot))
+    }
+
+    // An empty value clears the slot.
+    pub fn set_storage(&mut self, contract: &str, slot: &str, value: Vec<u8>) -> Result<(), DbError> {
+        if value.is_empty() {
+            self.remove(&storage_key(contract, slot))
+        } else {
+            self.insert(&storage_key(contract, slot), value)
+        }
+    }
+
+    fn insert_at(&mut self, node: Hash, path: &[u8], value: Vec<u8>) -> Result<Hash, DbError> {
+        if node == EMPTY_ROOT {
+            return Ok(self.store(Node::Leaf(path.to_vec(), value)));
+        }
+        let stored = match self.load(&node)? {
+            Node::Leaf(leaf_path, leaf_value) => {
+                if leaf_path == path {
+                    Node::Leaf(leaf_path, value)
+                } else {
+                    let common = common_prefix(&leaf_path, path);
+                    let mut children = [None; 16];
+                    children[leaf_path[common] as usize] =
+                        Some(self.store(Node::Leaf(leaf_path[common + 1..].to_vec(), leaf_value)));
+                    children[path[common] as usize] = Some(self.store(Node::Leaf(path[common + 1..].to_vec(), value)));
+                    self.split(&path[..common], Node::Branch(Box::new(children)))
+                }
+            }
+            Node::Extension(ext_path, child) => {
+                let common = common_prefix(&ext_path, path);
+                if common == ext_path.len() {
+                    let child = self.insert_at(child, &path[common..], value)?;
+                    Node::Extension(ext_path, child)
+                } else {
+                    let mut children = [None; 16];
+                    children[ext_path[common] as usize] = Some(if common + 1 == ext_path.len() {
+                        child
+                    } else {
+                        self.store(Node::Extension(ext_path[common + 1..].to_vec(), child))
+                    });
+                    children[path[common] as usize] = Some(self.store(Node::Leaf(path[common + 1..].to_vec(), value)));
+                    self.split(&path[..common], Node::Branch(Box::new(children)))
+                }
+            }
+            Node::Branch(mut children) => {
+                let index = path[0] as usize;
+                let child = children[index].unwrap_or(EMPTY_ROOT);
+                children[index] = Some(self.insert_at(child, &path[1..], value)?);
+                Node::Branch(children)
+            }
+        };
+        Ok(self.store(stored))
+    }
+
+    // Returns the new node, or `None` if nothing is left under it.
+    fn remove_at(&mut self, node: Hash, path: &[u8]) -> Result<Option<Hash>, DbError> {
+        if node == EMPTY_ROOT {
+            return Ok(None);
+        }
+        match self.load(&node)? {
+            Node::Leaf(leaf_path, _) => Ok(if leaf_path == path { None } else { Some(node) }),
+            Node::Extension(ext_path, child) => {
+                if !path.starts_with(&ext_path) {
+                    return Ok(Some(node));
+                }
+                match self.remove_at(child, &path[ext_path.len()..])? {
+                    Some(new_child) if new_child == child => Ok(Some(node)),
+                    Some(new_child) => Ok(Some(self.prepend(&ext_path, new_child)?)),
+                    None => Ok(None),
+                }
+            }
+            Node::Branch(mut children) => {
+                let index = path[0] as usize;
+                let child = match children[index] {
+                    Some(child) => child,
+                    None => return Ok(Some(node)),
+                };
+                let new_child = self.remove_at(child, &path[1..])?;
+                if new_child == Some(child) {
+                    return Ok(Some(node));
+                }
+                children[index] = new_child;
+
+                let mut remaining = children.iter().enumerate().filter_map(|(i, c)| c.map(|c| (i, c)));
+                match (remaining.next(), remaining.next()) {
+                    (None, _) => Ok(None),
+                    // A branch with one child folds into it.
+                    (Some((only, child)), None) => Ok(Some(self.prepend(&[only as u8], child)?)),
+                    _ => Ok(Some(self.store(Node::Branch(children)))),
+                }
+            }
+        }
+    }
+
+    // Wraps `node` in an extension for `prefix`, if the prefix is not empty.
+    fn split(&mut self, prefix: &[u8], node: Node) -> Node {
+        if prefix.is_empty() {
+            node
+        } else {
+            Node::Extension(prefix.to_vec(), self.store(node))
+        }
+    }
+
+    // Returns a node equivalent to `child` reached through `prefix`, merging
+    // the prefix into the child's own path where it has one.
+    fn prepend(&mut self, prefix: &[u8], child: Hash) -> Result<Hash, DbError> {
+        let node = match self.load(&child)? {
+            Node::Leaf(path, value) => Node::Leaf([prefix, &path[..]].concat(), value),
+            Node::Extension(path, grandchild) => Node::Extension([prefix, &path[..]].concat(), grandchild),
+            Node::Branch(_) => Node::Extension(prefix.to_vec(), child),
+        };
+        Ok(self.store(node))
+    }
+
+    fn store(&mut self, node: Node) -> Hash {
+        let encoded = encode_node(&node);
+        let hash = sha256(&encoded);
+        self.pending.insert(hash, encoded);
+        hash
+    }
+
+    fn load(&self, hash: &Hash) -> Result<Node, DbError> {
+        decode_node(&self.load_encoded(hash)?).ok_or_else(|| corrupt_node(hash))
+    }
+
+    fn load_encoded(&self, hash: &Hash) -> Result<Vec<u8>, DbError> {
+        if let Some(encoded) = self.pending.get(hash) {
+            return Ok(encoded.clone());
+        }
+        self.db
+            .cf(STATE_COLUMN_FAMILY)?
+            .get(&to_hex(hash))?
+            .ok_or_else(|| DbError::QueryError(format!("missing state trie node {}", to_hex(hash))))
+    }
+}
+
+// The trie nodes on the path from a state root to a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proof {
+    pub key: Vec<u8>,
+    pub nodes: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProofError {
+    // A node does not hash to the value its parent commits to.
+    HashMismatch,
+    Malformed,
+    // The proof ends before the lookup does, or continues after it.
+    WrongLength,
+}
+
+impl std::fmt::Display for ProofError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProofError::HashMismatch => write!(f, "proof node does not match its hash"),
+            ProofError::Malformed => write!(f, "malformed proof node"),
+            ProofError::WrongLength => write!(f, "proof does not end where the lookup does"),
+        }
+    }
+}
+
+impl std::error::Error for ProofError {}
+
+// Checks `proof` against `root` without access to any database. Returns the
+// value stored under the proof's key, or `None` if the proof shows the key
+// is absent.
+pub fn verify_proof(root: &Hash, proof: &Proof) -> Result<Option<Vec<u8>>, ProofError> {
+    let path = key_path(&proof.key);
+    let mut expected = *root;
+    let mut rest = &path[..];
+    let mut nodes = proof.nodes.iter();
+
+    let value = loop {
+        if expected == EMPTY_ROOT {
+            break None;
+        }
+        let encoded = nodes.next().ok_or(ProofError::WrongLength)?;
+        if sha256(encoded) != expected {
+            return Err(ProofError::HashMismatch);
+        }
+        match decode_node(encoded).ok_or(ProofError::Malformed)? {
+            Node::Leaf(leaf_path, value) => break if leaf_path == rest { Some(value) } else { None },
+            Node::Extension(ext_path, child) => {
+                if !rest.starts_with(&ext_path) {
+                    break None;
+                }
+                rest = &rest[ext_path.len()..];
+                expected = child;
+            }
+            Node::Branch(children) => {
+                let index = *rest.first().ok_or(ProofError::Malformed)? as usize;
+                match children[index] {
+                    Some(child) => {
+                        rest = &rest[1..];
+                        expected = child;
+                    }
+                    None => break None,
+                }
+            }
+        }
+    };
+
+    if nodes.next().is_some() {
+        return Err(ProofError::WrongLength);
+    }
+    Ok(value)
+}
+
+// Hashes of the nodes an encoded node points to.
+pub fn node_children(encoded: &[u8]) -> Vec<Hash> {
+    match decode_node(encoded) {
+        Some(Node::Extension(_, child)) => vec![child],
+        Some(Node::Branch(children)) => children.iter().flatten().copied().collect(),
+        _ => Vec::new(),
+    }
+}
+
+pub fn account_key(address: &str) -> Vec<u8> {
+    format!("account:{}", address).into_bytes()
+}
+
+pub fn storage_key(contract: &str, slot: &str) -> Vec<u8> {
+    format!("storage:{}:{}", contract, slot).into_bytes()
+}
+
+pub fn encode_account(account: &Account) -> Vec<u8> {
+    let mut value = account.balance.to_le_bytes().to_vec();
+    value.extend_from_slice(&account.nonce.to_le_bytes());
+    value
+}
+
+pub fn decode_account(value: &[u8]) -> Option<Account> {
+    if value.len() != 16 {
+        return None;
+    }
+    let mut balance = [0u8; 8];
+    let mut nonce = [0u8; 8];
+    balance.copy_from_slice(&value[..8]);
+    nonce.copy_from_slice(&value[8..]);
+    Some(Account {
+        balance: u64::from_le_bytes(balance),
+        nonce: u64::from_le_bytes(nonce),
+    })
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn key_path(key: &[u8]) -> Vec<u8> {
+    sha256(key).iter().flat_map(|byte| [byte >> 4, byte & 0x0f]).collect()
+}
+
+fn sha256(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn encode_node(node: &Node) -> Vec<u8> {
+    match node {
+        Node::Leaf(path, value) => {
+            let mut encoded = vec![NODE_LEAF];
+            encode_path(&mut encoded, path);
+            encoded.extend_from_slice(value);
+            encoded
+        }
+        Node::Extension(path, child) => {
+            let mut encoded = vec![NODE_EXTENSION];
+            encode_path(&mut encoded, path);
+            encoded.extend_from_slice(child);
+            encoded
+        }
+        Node::Branch(children) => {
+            let bitmap = children
+                .iter()
+                .enumerate()
+                .filter(|(_, child)| child.is_some())
+                .fold(0u16, |bitmap, (i, _)| bitmap | 1 << i);
+            let mut encoded = vec![NODE_BRANCH];
+            encoded.extend_from_slice(&bitmap.to_le_bytes());
+            for child in children.iter().flatten() {
+                encoded.extend_from_slice(child);
+            }
+            encoded
+        }
+    }
+}
+
+// Returns `None` for anything `encode_node` cannot have produced, since
+// proofs come from untrusted peers.
+fn decode_node(encoded: &[u8]) -> Option<Node> {
+    let (&kind, rest) = encoded.split_first()?;
+    match kind {
+        NODE_LEAF => {
+            let (path, rest) = decode_path(rest)?;
+            Some(Node::Leaf(path, rest.to_vec()))
+        }
+        NODE_EXTENSION => {
+            let (path, rest) = decode_path(rest)?;
+            if path.is_empty() {
+                return None;
+            }
+            Some(Node::Extension(path, rest.try_into().ok()?))
+        }
+        NODE_BRANCH => {
+            let bitmap = u16::from_le_bytes([*rest.first()?, *rest.get(1)?]);
+            let mut hashes = rest[2..].chunks_exact(32);
+            if hashes.len() != bitmap.count_ones() as usize || !hashes.remainder().is_empty() {
+                return None;
+            }
+            let mut children = [None; 16];
+            for (i, child) in children.iter_mut().enumerate() {
+                if bitmap & 1 << i != 0 {
+                    *child = Some(hashes.next()?.try_into().ok()?);
+                }
+            }
+            Some(Node::Branch(Box::new(children)))
+        }
+        _ => None,
+    }
+}
+
+fn encode_path(encoded: &mut Vec<u8>, path: &[u8]) {
+    encoded.push(path.len() as u8);
+    for pair in path.chunks(2) {
+        encoded.push(pair[0] << 4 | pair.get(1).copied().unwrap_or(0));
+    }
+}
+
+fn decode_path(encoded: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    let (&len, rest) = encoded.split_first()?;
+    let len = len as usize;
+    let packed = rest.get(..len.div_ceil(2))?;
+    let path = packed.iter().flat_map(|byte| [byte >> 4, byte & 0x0f]).take(len).collect();
+    Some((path, &rest[packed.len()..]))
+}
+
+fn corrupt_node(hash: &Hash) -> DbError {
+    DbError::QueryError(format!("corrupt state trie node {}", to_hex(hash)))
+}
@@ -1,123 +1,155 @@
-use std::collections::{HashMap, HashSet};
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::collections::HashSet;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
-
-use serde::{Serialize, Deserialize};
+use std::sync::Mutex;
 
 use crate::blockchain::{Block, Blockchain};
-use crate::smart_contract::{SmartContract, PiSentinelSmartContract};
+use crate::config::StorageConfig;
+use crate::db::DbError;
+use crate::storage::{self as shared, StorageError};
 
 pub trait Storage {
-    fn new(path: &str) -> Self;
+    fn new(path: &str) -> Result<Self, StorageError>
+    where
+        Self: Sized;
     fn store_block(&self, block: Block) -> Result<(), StorageError>;
     fn get_block(&self, block_hash: &str) -> Result<Option<Block>, StorageError>;
-    fn store_contract(&self, contract_id: &str, contract: Arc<dyn SmartContract>) -> Result<(), StorageError>;
-    fn get_contract(&self, contract_id: &str) -> Result<Option<Arc<dyn SmartContract>>, StorageError>;
+    // Contract code, from which the contract manager instantiates the
+    // contract, and its state as key-value pairs. An empty state value
+    // clears the key.
+    fn store_contract(&self, contract_id: &str, code: Vec<u8>) -> Result<(), StorageError>;
+    fn get_contract(&self, contract_id: &str) -> Result<Option<Vec<u8>>, StorageError>;
+    fn store_contract_state(&self, contract_id: &str, key: &str, value: Vec<u8>) -> Result<(), StorageError>;
+    fn get_contract_state(&self, contract_id: &str, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
     fn store_blockchain(&self, blockchain: Blockchain) -> Result<(), StorageError>;
     fn get_blockchain(&self) -> Result<Blockchain, StorageError>;
 }
 
+// The substrate view of the shared storage (storage.rs), so it runs on any
+// `Backend` and keeps blocks in the same layout as the rest of the node:
+// stored blocks in the block tree, the stored blockchain as the canonical
+// chain. Each call writes only what it changes; for `store_blockchain`,
+// that is the blocks where the new chain departs from the stored one.
+// Contract id -> code.
+const CONTRACTS_COLUMN_FAMILY: &str = "contract_code";
+// "<id length>:<contract id><key>" -> value.
+const CONTRACT_STATE_COLUMN_FAMILY: &str = "contract_state";
+
 pub struct PiSentinelStorage {
     path: String,
-    blocks: HashMap<String, Block>,
-    contracts: HashMap<String, Arc<dyn SmartContract>>,
-    blockchain: Blockchain,
-    lock: Mutex<()>,
+    storage: shared::Storage,
+    // Serializes `store_blockchain` calls, which read the stored chain
+    // before replacing it.
+    blockchain_lock: Mutex<()>,
 }
 
 impl Storage for PiSentinelStorage {
-    fn new(path: &str) -> Self {
-        PiSentinelStorage {
+    fn new(path: &str) -> Result<Self, StorageError> {
+        let config = StorageConfig {
             path: path.to_string(),
-            blocks: HashMap::new(),
-            contracts: HashMap::new(),
-            blockchain: Blockchain::new(),
-            lock: Mutex::new(()),
-        }
+            ..StorageConfig::default()
+        };
+        PiSentinelStorage::open(&config)
     }
 
     fn store_block(&self, block: Block) -> Result<(), StorageError> {
-        self.lock.lock().unwrap();
-        self.blocks.insert(block.hash.clone(), block);
-        self.write_to_disk()?;
-        Ok(())
+        self.storage.store_block(&block)
     }
 
     fn get_block(&self, block_hash: &str) -> Result<Option<Block>, StorageError> {
-        self.lock.lock().unwrap();
-        Ok(self.blocks.get(block_hash).cloned())
+        self.storage.get_block(block_hash)
     }
 
-    fn store_contract(&self, contract_id: &str, contract: Arc<dyn SmartContract>) -> Result<(), StorageError> {
-        self.lock.lock().unwrap();
-        self.contracts.insert(contract_id.to_string(), contract);
-        self.write_to_disk()?;
+    fn store_contract(&self, contract_id: &str, code: Vec<u8>) -> Result<(), StorageError> {
+        self.storage.db.cf(CONTRACTS_COLUMN_FAMILY)?.put(contract_id, code)?;
         Ok(())
     }
 
-    fn get_contract(&self, contract_id: &str) -> Result<Option<Arc<dyn SmartContract>>, StorageError> {
-        self.lock.lock().unwrap();
-        Ok(self.contracts.get(contract_id).cloned())
+    fn get_contract(&self, contract_id: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.storage.db.cf(CONTRACTS_COLUMN_FAMILY)?.get(contract_id)?)
     }
 
-    fn store_blockchain(&self, blockchain: Blockchain) -> Result<(), StorageError> {
-        self.lock.lock().unwrap();
-        self.blockchain = blockchain;
-        self.write_to_disk()?;
+    fn store_contract_state(&self, contract_id: &str, key: &str, value: Vec<u8>) -> Result<(), StorageError> {
+        let state = self.storage.db.cf(CONTRACT_STATE_COLUMN_FAMILY)?;
+        if value.is_empty() {
+            state.delete(&contract_state_key(contract_id, key))?;
+        } else {
+            state.put(&contract_state_key(contract_id, key), value)?;
+        }
         Ok(())
     }
 
-    fn get_blockchain(&self) -> Result<Blockchain, StorageError> {
-        self.lock.lock().unwrap();
-        Ok(self.blockchain.clone())
+    fn get_contract_state(&self, contract_id: &str, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.storage.db.cf(CONTRACT_STATE_COLUMN_FAMILY)?.get(&contract_state_key(contract_id, key))?)
     }
-}
-
-impl PiSentinelStorage {
-    fn write_to_disk(&self) -> Result<(), StorageError> {
-        let file = OpenOptions::new()
-           .write(true)
-           .create(true)
-           .open(self.path.clone())?;
-
-        let data = serde_json::to_string_pretty(&self)?;
-        file.write_all(data.as_bytes())?;
 
+    fn store_blockchain(&self, blockchain: Blockchain) -> Result<(), StorageError> {
+        let _storing = self.blockchain_lock.lock().unwrap();
+        let chain = &self.storage.chain;
+        let new = blockchain.blocks();
+        let tip = match new.last() {
+            Some(tip) => tip,
+            None => {
+                return Err(StorageError::DbError(DbError::QueryError(
+                    "cannot store an empty blockchain".to_string(),
+                )))
+            }
+        };
+
+        // Block hashes commit to their parents, so the chains agree up to the
+        // last height where their hashes match. Searching from the tip keeps
+        // the cost proportional to what changed.
+        let mut common = new.len();
+        while common > 0 && !self.storage.is_canonical(&new[common - 1])? {
+            common -= 1;
+        }
+        // A shorter chain is stored by writing its tip again, which drops
+        // the heights above it.
+        if common == new.len() && chain.head()?.is_some_and(|head| head > tip.height) {
+            common -= 1;
+        }
+        chain.put_blocks(&new[common..])?;
         Ok(())
     }
 
-    fn read_from_disk(&self) -> Result<(), StorageError> {
-        let file = File::open(self.path.clone())?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-
-        let data: PiSentinelStorage = serde_json::from_str(&contents)?;
-        self.blocks = data.blocks;
-        self.contracts = data.contracts;
-        self.blockchain = data.blockchain;
-
-        Ok(())
+    // Reads the whole stored chain, checking that it fits together.
+    fn get_blockchain(&self) -> Result<Blockchain, StorageError> {
+        let chain = &self.storage.chain;
+        let mut blockchain = Blockchain::new();
+        let head = match chain.head()? {
+            Some(head) => head,
+            None => return Ok(blockchain),
+        };
+        for height in chain.oldest()?..=head {
+            let block = chain
+                .block(height)?
+                .ok_or_else(|| StorageError::Corrupt(format!("canonical block at height {} is missing", height)))?;
+            blockchain
+                .add_block(block)
+                .map_err(|err| StorageError::Corrupt(format!("block at height {}: {}", height, err)))?;
+        }
+        Ok(blockchain)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum StorageError {
-    IoError(std::io::Error),
-    JsonError(serde_json::Error),
-    ContractNotFound,
-    BlockNotFound,
-}
+impl PiSentinelStorage {
+    // Opens or creates the storage described by `config`, with the engine
+    // its `type_` names. Set `config.encryption` to encrypt the stored blocks
+    // and contracts; block hashes, heights and contract ids are keys and
+    // stay readable, see docs/storage_backends.md.
+    pub fn open(config: &StorageConfig) -> Result<Self, StorageError> {
+        Ok(PiSentinelStorage {
+            path: config.path.clone(),
+            storage: shared::Storage::open(config)?,
+            blockchain_lock: Mutex::new(()),
+        })
+    }
 
-impl From<std::io::Error> for StorageError {
-    fn from(err: std::io::Error) -> Self {
-        StorageError::IoError(err)
+    pub fn path(&self) -> &str {
+        &self.path
     }
 }
 
-impl From<serde_json::Error> for StorageError {
-    fn from(err: serde_json::Error) -> Self {
-        StorageError::JsonError(err)
-    }
-    }
+// The length prefix keeps "a" + "bc" apart from "ab" + "c".
+fn contract_state_key(contract_id: &str, key: &str) -> String {
+    format!("{}:{}{}", contract_id.len(), contract_id, key)
+}
@@ -1,9 +1,18 @@
+use std::fs;
+use std::path::PathBuf;
+
 use criterion::{BenchmarkGroup, Criterion};
 use rand_core::OsRng;
 
+use crate::config::StorageConfig;
+use crate::db::{Db, DbType, WriteBatch};
 use crypto::{KeyPair, PublicKey, SecretKey};
 use math::{gcd, is_prime, lcm, next_prime, random_prime};
 
+// Keys written before each storage benchmark, and the size of their values.
+const STORAGE_KEYS: u32 = 10_000;
+const STORAGE_VALUE_LEN: usize = 256;
+
 pub fn benchmark_key_pair_generation(c: &mut Criterion) {
     let mut group = c.benchmark_group("key_pair_generation");
     let mut rng = OsRng;
@@ -87,6 +96,62 @@ pub fn benchmark_random_prime(c: &mut Criterion) {
     group.finish();
 }
 
+// Repeated reads of a small working set, as block and vote lookups do,
+// with and without the read cache.
+pub fn benchmark_storage_hot_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("storage_hot_reads");
+    for (name, cache_bytes) in [("cached", 16 * 1024 * 1024), ("uncached", 0)] {
+        let (db, dir) = storage_fixture(name, cache_bytes, true);
+        let mut height = 0;
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                height = (height + 1) % 100;
+                db.get(&format!("block:{}", height)).unwrap()
+            });
+        });
+        drop(db);
+        let _ = fs::remove_dir_all(dir);
+    }
+    group.finish();
+}
+
+// Lookups of keys that were never written, as when checking whether a
+// transaction is already known, with and without the bloom filter.
+pub fn benchmark_storage_missing_keys(c: &mut Criterion) {
+    let mut group = c.benchmark_group("storage_missing_keys");
+    for (name, bloom_filter) in [("bloom", true), ("no_bloom", false)] {
+        let (db, dir) = storage_fixture(name, 0, bloom_filter);
+        let mut n = 0u64;
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                n += 1;
+                db.get(&format!("missing:{}", n)).unwrap()
+            });
+        });
+        drop(db);
+        let _ = fs::remove_dir_all(dir);
+    }
+    group.finish();
+}
+
+fn storage_fixture(name: &str, read_cache_bytes: u64, bloom_filter: bool) -> (Db, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("pi-sentinel-bench-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let config = StorageConfig {
+        path: dir.to_str().unwrap().to_string(),
+        read_cache_bytes,
+        bloom_filter,
+        ..StorageConfig::default()
+    };
+    let db = Db::open(DbType::Disk, &config).unwrap();
+    let mut batch = WriteBatch::new();
+    for height in 0..STORAGE_KEYS {
+        batch.put(&format!("block:{}", height), vec![height as u8; STORAGE_VALUE_LEN]);
+    }
+    db.write(batch).unwrap();
+    (db, dir)
+}
+
 criterion_group!(
     benches,
     benchmark_key_pair_generation,
@@ -96,6 +161,8 @@ criterion_group!(
     benchmark_is_prime,
     benchmark_next_prime,
     benchmark_random_prime,
+    benchmark_storage_hot_reads,
+    benchmark_storage_missing_keys,
 );
 
 criterion_main!(benches);
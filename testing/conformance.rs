@@ -0,0 +1,234 @@
+// Storage backend conformance suite
+//
+// Checks the behavior every `Backend` must share with the built-in memory
+// and disk engines. Run it against a new engine with
+//
+//   conformance::check_backend(|| Box::new(MyBackend::open_empty()));
+//
+// `open` must return a fresh, empty backend on every call. Every check
+// panics with a description of the first difference it finds.
+use std::sync::Arc;
+use std::thread;
+
+use crate::backend::Backend;
+use crate::db::{BatchOp, Db, Direction, KeyRange, WriteBatch};
+
+pub fn check_backend<F: Fn() -> Box<dyn Backend>>(open: F) {
+    check_point_operations(open());
+    check_write_batches(open());
+    check_ordering(open());
+    check_ranges(open());
+    check_snapshots(open());
+    check_maintenance(open());
+    check_concurrent_batches(open());
+    check_column_families(open());
+}
+
+fn check_point_operations(backend: Box<dyn Backend>) {
+    assert_eq!(backend.get("missing").unwrap(), None, "get of a missing key");
+    backend.put("key", b"one".to_vec()).unwrap();
+    assert_eq!(backend.get("key").unwrap(), Some(b"one".to_vec()), "get after put");
+    backend.put("key", b"two".to_vec()).unwrap();
+    assert_eq!(backend.get("key").unwrap(), Some(b"two".to_vec()), "get after overwrite");
+    backend.put("empty", Vec::new()).unwrap();
+    assert_eq!(backend.get("empty").unwrap(), Some(Vec::new()), "empty values are stored");
+    backend.put("\0κλειδί", b"unicode".to_vec()).unwrap();
+    assert_eq!(backend.get("\0κλειδί").unwrap(), Some(b"unicode".to_vec()), "non-ASCII keys");
+
+    backend.delete("key").unwrap();
+    assert_eq!(backend.get("key").unwrap(), None, "get after delete");
+    backend.delete("key").unwrap();
+    backend.delete("never-written").unwrap();
+}
+
+fn check_write_batches(backend: Box<dyn Backend>) {
+    backend.put("stale", b"old".to_vec()).unwrap();
+    backend.write(WriteBatch::new()).unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put("a", b"1".to_vec())
+        .put("b", b"2".to_vec())
+        .delete("b")
+        .delete("c")
+        .put("c", b"3".to_vec())
+        .put("a", b"4".to_vec())
+        .delete("stale");
+    backend.write(batch).unwrap();
+    // Column family keys are part of the raw key.
+    assert_eq!(backend.get("default\0a").unwrap(), Some(b"4".to_vec()), "later put in a batch wins");
+    assert_eq!(backend.get("default\0b").unwrap(), None, "delete after put in a batch");
+    assert_eq!(backend.get("default\0c").unwrap(), Some(b"3".to_vec()), "put after delete in a batch");
+    assert_eq!(backend.get("stale").unwrap(), Some(b"old".to_vec()), "batch keys are prefixed");
+
+    let raw = WriteBatch::from_ops(vec![
+        BatchOp::Put("stale".to_string(), b"new".to_vec()),
+        BatchOp::Delete("default\0a".to_string()),
+    ]);
+    backend.write(raw).unwrap();
+    assert_eq!(backend.get("stale").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(backend.get("default\0a").unwrap(), None);
+}
+
+fn check_ordering(backend: Box<dyn Backend>) {
+    // Byte order, not character or numeric order.
+    let keys = ["", "B", "a", "a\0", "aa", "b", "z", "é", "\u{10000}"];
+    for key in keys.iter().rev() {
+        backend.put(key, key.as_bytes().to_vec()).unwrap();
+    }
+
+    let forward: Vec<String> = backend
+        .chunk(&KeyRange::All, Direction::Forward, None, usize::MAX)
+        .unwrap()
+        .into_iter()
+        .map(|(key, value)| {
+            assert_eq!(key.as_bytes(), &value[..], "chunk pairs keys with their values");
+            key
+        })
+        .collect();
+    assert_eq!(forward, keys, "forward order");
+    let reverse = backend.keys(&KeyRange::All, Direction::Reverse, usize::MAX).unwrap();
+    assert_eq!(reverse, keys.iter().rev().copied().collect::<Vec<_>>(), "reverse order");
+
+    // Paging with `after` visits every key once.
+    for direction in [Direction::Forward, Direction::Reverse] {
+        let mut paged = Vec::new();
+        let mut after: Option<String> = None;
+        loop {
+            let chunk = backend.chunk(&KeyRange::All, direction, after.as_deref(), 2).unwrap();
+            assert!(chunk.len() <= 2, "chunk respects its limit");
+            match chunk.last() {
+                Some((key, _)) => after = Some(key.clone()),
+                None => break,
+            }
+            paged.extend(chunk.into_iter().map(|(key, _)| key));
+        }
+        assert_eq!(paged, backend.keys(&KeyRange::All, direction, usize::MAX).unwrap(), "paging");
+    }
+    assert_eq!(backend.keys(&KeyRange::All, Direction::Forward, 3).unwrap(), keys[..3], "key limit");
+}
+
+fn check_ranges(backend: Box<dyn Backend>) {
+    for key in ["block:1", "block:2", "block:3", "block;", "blocks", "tx:1"] {
+        backend.put(key, vec![0; key.len()]).unwrap();
+    }
+    let keys = |range: KeyRange, direction| backend.keys(&range, direction, usize::MAX).unwrap();
+
+    assert_eq!(
+        keys(KeyRange::Prefix("block:".to_string()), Direction::Forward),
+        ["block:1", "block:2", "block:3"],
+        "prefix range"
+    );
+    assert_eq!(
+        keys(KeyRange::Prefix("block:".to_string()), Direction::Reverse),
+        ["block:3", "block:2", "block:1"],
+        "reverse prefix range"
+    );
+    assert!(keys(KeyRange::Prefix("none".to_string()), Direction::Forward).is_empty());
+    assert_eq!(
+        keys(KeyRange::Range("block:2".to_string(), "blocks".to_string()), Direction::Forward),
+        ["block:2", "block:3", "block;"],
+        "range includes its start and excludes its end"
+    );
+    let after = backend
+        .chunk(&KeyRange::Prefix("block:".to_string()), Direction::Reverse, Some("block:3"), 10)
+        .unwrap();
+    assert_eq!(after.len(), 2, "reverse chunk resumes after the given key");
+
+    assert_eq!(backend.stats(&KeyRange::All).unwrap(), (6, 37), "stats count keys and value bytes");
+    assert_eq!(backend.stats(&KeyRange::Prefix("block:".to_string())).unwrap(), (3, 21));
+}
+
+fn check_snapshots(backend: Box<dyn Backend>) {
+    backend.put("kept", b"1".to_vec()).unwrap();
+    backend.put("changed", b"before".to_vec()).unwrap();
+    backend.put("deleted", b"x".to_vec()).unwrap();
+    let snapshot = backend.snapshot().unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put("batched", b"y".to_vec());
+    backend.write(batch).unwrap();
+    backend.put("changed", b"after".to_vec()).unwrap();
+    backend.put("added", b"z".to_vec()).unwrap();
+    backend.delete("deleted").unwrap();
+
+    assert_eq!(snapshot.get("changed").unwrap(), Some(b"before".to_vec()), "snapshot ignores overwrites");
+    assert_eq!(snapshot.get("deleted").unwrap(), Some(b"x".to_vec()), "snapshot ignores deletes");
+    assert_eq!(snapshot.get("added").unwrap(), None, "snapshot ignores new keys");
+    assert_eq!(
+        snapshot.keys(&KeyRange::All, Direction::Forward, usize::MAX).unwrap(),
+        ["changed", "deleted", "kept"],
+        "snapshot key listing"
+    );
+    let entries = snapshot.chunk(&KeyRange::All, Direction::Reverse, Some("kept"), 10).unwrap();
+    assert_eq!(entries, vec![("deleted".to_string(), b"x".to_vec()), ("changed".to_string(), b"before".to_vec())]);
+    assert_eq!(snapshot.stats(&KeyRange::All).unwrap(), (3, 8));
+    assert_eq!(backend.get("changed").unwrap(), Some(b"after".to_vec()), "live reads see new writes");
+}
+
+fn check_maintenance(backend: Box<dyn Backend>) {
+    for round in 0..3u8 {
+        for index in 0..50u8 {
+            backend.put(&format!("key{:02}", index), vec![round; 16]).unwrap();
+        }
+    }
+    for index in 0..25u8 {
+        backend.delete(&format!("key{:02}", index)).unwrap();
+    }
+    let snapshot = backend.snapshot().unwrap();
+    let ratio = backend.garbage_ratio().unwrap();
+    assert!((0.0..=1.0).contains(&ratio), "garbage ratio {} out of range", ratio);
+
+    let stats = backend.compact().unwrap();
+    assert_eq!(stats.live_keys, 25, "compaction reports the live keys");
+    assert!(stats.bytes_after <= stats.bytes_before);
+    backend.sync().unwrap();
+    assert_eq!(backend.unsynced_bytes().unwrap(), 0, "nothing unsynced after sync");
+    assert_eq!(backend.get("key30").unwrap(), Some(vec![2; 16]), "data survives compaction");
+    assert_eq!(backend.get("key10").unwrap(), None);
+    assert_eq!(snapshot.get("key49").unwrap(), Some(vec![2; 16]), "snapshots survive compaction");
+}
+
+fn check_concurrent_batches(backend: Box<dyn Backend>) {
+    let backend: Arc<dyn Backend> = Arc::from(backend);
+    let writers: Vec<_> = (0..4)
+        .map(|writer| {
+            let backend = backend.clone();
+            thread::spawn(move || {
+                for round in 0..25 {
+                    // Both keys of a pair are always written together.
+                    let mut batch = WriteBatch::new();
+                    batch
+                        .put(&format!("{}:{}:a", writer, round), vec![writer])
+                        .put(&format!("{}:{}:b", writer, round), vec![writer]);
+                    backend.write(batch).unwrap();
+                }
+            })
+        })
+        .collect();
+    for _ in 0..20 {
+        let keys = backend.snapshot().unwrap().keys(&KeyRange::All, Direction::Forward, usize::MAX).unwrap();
+        assert_eq!(keys.len() % 2, 0, "a snapshot saw half a batch");
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(backend.stats(&KeyRange::All).unwrap().0, 200, "concurrent batches are all applied");
+}
+
+fn check_column_families(backend: Box<dyn Backend>) {
+    let db = Db::with_backend(backend);
+    db.cf("blocks").unwrap().put("1", b"block".to_vec()).unwrap();
+    db.cf("votes").unwrap().put("1", b"vote".to_vec()).unwrap();
+    db.put("1", b"default".to_vec()).unwrap();
+
+    assert_eq!(db.cf("blocks").unwrap().get("1").unwrap(), Some(b"block".to_vec()), "families are isolated");
+    assert_eq!(db.get("1").unwrap(), Some(b"default".to_vec()));
+    assert_eq!(db.column_families().unwrap(), ["blocks", "default", "votes"]);
+    let rows = db.execute("COUNT FROM votes").unwrap();
+    assert_eq!(rows[0].columns[0], b"1".to_vec(), "queries run on the backend");
+
+    db.cf("votes").unwrap().clear().unwrap();
+    assert!(db.cf("votes").unwrap().is_empty().unwrap(), "clearing a family");
+    assert_eq!(db.cf("blocks").unwrap().len().unwrap(), 1, "clearing leaves other families alone");
+}
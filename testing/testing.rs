@@ -1,4 +1,36 @@
 // Testing framework
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::admin;
+use crate::backup;
+use crate::blockchain::{Block, Blockchain, Transaction};
+use crate::async_storage::AsyncStorage;
+use crate::backend::Backend;
+use crate::chain::{ChainStore, Checkpoint, TxLocation};
+use crate::bft::{self, Application, Message, Output, Proposal, QuorumCertificate, Timeout, Tendermint, Vote, VoteType};
+use crate::config::{BftConfig, Config, Durability, EncryptionKey, FinalityConfig, HybridConfig, Pruning, StorageConfig};
+use crate::conformance;
+use crate::difficulty::{self, Retarget};
+use crate::db::{Db, DbError, DbType, DiskConnection, Direction, KeyRange, MemoryConnection, WriteBatch};
+use crate::finality::{CheckpointVote, FinalityGadget};
+use crate::fork_choice::{BlockTree, ForkChoice, HeaviestWork, MostAttested, MAX_FORK_DEPTH};
+use crate::genesis::{Account, Genesis};
+use crate::hybrid::{self, HybridChain};
+use crate::migration::{self, SCHEMA_VERSION};
+use crate::pow::{self, Miner, PowError};
+use crate::rules::{self, ConsensusError};
+use crate::signing::Keypair;
+use crate::stake::{self, StakeRegistry, EPOCH_LENGTH, STAKE_ADDRESS, UNSTAKE_ADDRESS};
+use crate::storage::{Storage, StorageError};
+use crate::substrate::storage::{PiSentinelStorage, Storage as _};
+use crate::trie::{self, ProofError, StateTrie, EMPTY_ROOT};
+
 pub fn test_consensus() {
     // Test consensus algorithm
 }
@@ -10,3 +42,2321 @@ pub fn test_smart_contracts() {
 pub fn test_network() {
     // Test network communication
 }
+
+pub fn test_storage_write_batch() {
+    for db_type in [DbType::Memory, DbType::Disk] {
+        let dir = temp_dir("write_batch");
+        let db = Db::new(db_type, dir.to_str().unwrap()).unwrap();
+        db.put("stale", b"old".to_vec()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put("header", b"h".to_vec())
+            .put("tx:0", b"t0".to_vec())
+            .delete("stale")
+            .put("header", b"h2".to_vec());
+        db.write(batch).unwrap();
+
+        assert_eq!(db.get("header").unwrap(), Some(b"h2".to_vec()));
+        assert_eq!(db.get("tx:0").unwrap(), Some(b"t0".to_vec()));
+        assert_eq!(db.get("stale").unwrap(), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+pub fn test_storage_write_batch_crash_recovery() {
+    let dir = temp_dir("write_batch_crash");
+    let path = dir.to_str().unwrap();
+    {
+        let db = Db::new(DbType::Disk, path).unwrap();
+        db.put("height", b"1".to_vec()).unwrap();
+    }
+    let committed_len = fs::metadata(dir.join("data.log")).unwrap().len();
+    {
+        let db = Db::new(DbType::Disk, path).unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put("height", b"2".to_vec())
+            .put("block:2", vec![7; 64])
+            .put("receipt:2", vec![9; 64]);
+        db.write(batch).unwrap();
+    }
+    let full_len = fs::metadata(dir.join("data.log")).unwrap().len();
+
+    // Cut the batch record off at every possible point, as a crash halfway
+    // through the write would, and check that none of it survives.
+    for torn_len in committed_len..full_len {
+        let backup = fs::read(dir.join("data.log")).unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(dir.join("data.log"))
+            .unwrap()
+            .set_len(torn_len)
+            .unwrap();
+
+        let db = Db::new(DbType::Disk, path).unwrap();
+        assert_eq!(db.get("height").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get("block:2").unwrap(), None);
+        assert_eq!(db.get("receipt:2").unwrap(), None);
+        drop(db);
+        fs::write(dir.join("data.log"), backup).unwrap();
+    }
+
+    let db = Db::new(DbType::Disk, path).unwrap();
+    assert_eq!(db.get("height").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(db.get("receipt:2").unwrap(), Some(vec![9; 64]));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+pub fn test_storage_log_recovery() {
+    let dir = temp_dir("log_recovery");
+    let path = dir.to_str().unwrap();
+    let log = dir.join("data.log");
+    {
+        let db = Db::new(DbType::Disk, path).unwrap();
+        db.put("a", b"1".to_vec()).unwrap();
+        db.put("b", b"2".to_vec()).unwrap();
+        db.delete("a").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("c", b"3".to_vec()).put("b", b"22".to_vec());
+        db.write(batch).unwrap();
+    }
+    // Everything acknowledged is back after a restart, and so is what is
+    // written after it.
+    {
+        let db = Db::new(DbType::Disk, path).unwrap();
+        assert_eq!(db.get("a").unwrap(), None);
+        assert_eq!(db.get("b").unwrap(), Some(b"22".to_vec()));
+        assert_eq!(db.get("c").unwrap(), Some(b"3".to_vec()));
+        db.put("d", b"4".to_vec()).unwrap();
+    }
+    let committed_len = fs::metadata(&log).unwrap().len();
+    {
+        let db = Db::new(DbType::Disk, path).unwrap();
+        assert_eq!(db.get("d").unwrap(), Some(b"4".to_vec()));
+        db.put("e", vec![5; 32]).unwrap();
+    }
+
+    // A record torn anywhere is dropped, and the log takes new writes after
+    // the last good one.
+    let full = fs::read(&log).unwrap();
+    for torn_len in committed_len + 1..full.len() as u64 {
+        fs::write(&log, &full[..torn_len as usize]).unwrap();
+        {
+            let db = Db::new(DbType::Disk, path).unwrap();
+            assert_eq!(db.get("e").unwrap(), None);
+            db.put("f", b"6".to_vec()).unwrap();
+        }
+        let db = Db::new(DbType::Disk, path).unwrap();
+        assert_eq!(db.get("d").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(db.get("f").unwrap(), Some(b"6".to_vec()));
+    }
+
+    // A torn header claiming huge lengths is dropped without allocating
+    // them.
+    let mut huge = full.clone();
+    huge.extend_from_slice(&[0, 0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    fs::write(&log, &huge).unwrap();
+    {
+        let db = Db::new(DbType::Disk, path).unwrap();
+        assert_eq!(db.get("e").unwrap(), Some(vec![5; 32]));
+    }
+    assert_eq!(fs::metadata(&log).unwrap().len(), full.len() as u64);
+
+    // Damage with intact records after it fails the open instead of
+    // silently dropping them, until the log is repaired. That includes a
+    // damaged length pointing past the end of the log, like a torn one.
+    let length = |at: usize| u32::from_le_bytes(full[at..at + 4].try_into().unwrap()) as usize;
+    let second = 13 + length(5) + length(9);
+    for byte in [second + 8, second + 12] {
+        let mut overlong = full.clone();
+        overlong[byte] ^= 0xff;
+        fs::write(&log, &overlong).unwrap();
+        match Db::new(DbType::Disk, path) {
+            Err(DbError::CorruptLog { offset }) => assert_eq!(offset, second as u64),
+            other => panic!("expected a corrupt log, got {:?}", other.err()),
+        }
+        assert_eq!(fs::metadata(&log).unwrap().len(), full.len() as u64);
+    }
+    let mut corrupt = full.clone();
+    corrupt[14] ^= 0xff;
+    fs::write(&log, &corrupt).unwrap();
+    match Db::new(DbType::Disk, path) {
+        Err(DbError::CorruptLog { offset }) => assert_eq!(offset, 0),
+        other => panic!("expected a corrupt log, got {:?}", other.err()),
+    }
+    let config = StorageConfig {
+        path: path.to_string(),
+        ..StorageConfig::default()
+    };
+    assert_eq!(DiskConnection::repair(&config).unwrap(), full.len() as u64);
+    let db = Db::new(DbType::Disk, path).unwrap();
+    assert_eq!(db.get("d").unwrap(), None);
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+pub fn test_storage_query() {
+    for db_type in [DbType::Memory, DbType::Disk] {
+        let dir = temp_dir("query");
+        let db = Db::new(db_type, dir.to_str().unwrap()).unwrap();
+        for (key, value) in [("block:1", "a"), ("block:2", "bb"), ("block:3", "ccc"), ("vote:1", "v")] {
+            db.put(key, value.as_bytes().to_vec()).unwrap();
+        }
+
+        let rows = db.execute("SCAN PREFIX block: REVERSE LIMIT 2 SELECT key, size").unwrap();
+        let columns: Vec<Vec<Vec<u8>>> = rows.into_iter().map(|row| row.columns).collect();
+        assert_eq!(
+            columns,
+            vec![
+                vec![b"block:3".to_vec(), b"3".to_vec()],
+                vec![b"block:2".to_vec(), b"2".to_vec()],
+            ]
+        );
+
+        let rows = db.execute("count range 'block:2' 'vote:1'").unwrap();
+        assert_eq!(rows[0].columns, vec![b"2".to_vec()]);
+
+        let rows = db.execute("GET \"vote:1\" SELECT value").unwrap();
+        assert_eq!(rows[0].columns, vec![b"v".to_vec()]);
+
+        assert!(db.execute("SCAN PREFIX").is_err());
+        assert!(db.execute("DROP everything").is_err());
+        assert!(db.execute("COUNT SELECT key, value").is_err());
+        assert_eq!(db.execute("COUNT LIMIT 1").unwrap()[0].columns[0], b"1".to_vec());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+pub fn test_storage_iterators() {
+    for db_type in [DbType::Memory, DbType::Disk] {
+        let dir = temp_dir("iterators");
+        let db = Db::new(db_type, dir.to_str().unwrap()).unwrap();
+        // Enough keys to span several iterator chunks.
+        for height in 0..200u32 {
+            db.put(&format!("height:{:08}", height), height.to_le_bytes().to_vec()).unwrap();
+        }
+        db.put("heighu", vec![]).unwrap();
+        db.put("heigh", vec![]).unwrap();
+
+        let keys: Vec<String> = db.prefix_iter("height:").map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys.len(), 200);
+        assert_eq!(keys[0], "height:00000000");
+        assert_eq!(keys[199], "height:00000199");
+
+        let reversed: Vec<String> = db
+            .iter(KeyRange::Prefix("height:".to_string()), Direction::Reverse)
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(reversed, keys.iter().rev().cloned().collect::<Vec<_>>());
+
+        let range: Vec<String> = db
+            .iter(
+                KeyRange::Range("height:00000010".to_string(), "height:00000013".to_string()),
+                Direction::Reverse,
+            )
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(range, vec!["height:00000012", "height:00000011", "height:00000010"]);
+
+        let all: Vec<String> = db.iter(KeyRange::All, Direction::Forward).map(|entry| entry.unwrap().0).collect();
+        assert_eq!(all.len(), 202);
+        assert_eq!(all[0], "heigh");
+        assert_eq!(all[201], "heighu");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+pub fn test_storage_column_families() {
+    for db_type in [DbType::Memory, DbType::Disk] {
+        let dir = temp_dir("column_families");
+        let db = Db::new(db_type, dir.to_str().unwrap()).unwrap();
+        db.put("0x01", b"default".to_vec()).unwrap();
+        db.cf("blocks").unwrap().put("0x01", b"block".to_vec()).unwrap();
+        db.cf("blocks").unwrap().put("0x02", b"block2".to_vec()).unwrap();
+        db.cf("votes").unwrap().put("0x01", b"vote".to_vec()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put_cf("blocks", "0x03", b"block3".to_vec()).delete_cf("votes", "0x01");
+        db.write(batch).unwrap();
+
+        assert_eq!(db.get("0x01").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(db.cf("blocks").unwrap().get("0x01").unwrap(), Some(b"block".to_vec()));
+        assert_eq!(db.cf("votes").unwrap().get("0x01").unwrap(), None);
+        assert_eq!(db.cf("blocks").unwrap().len().unwrap(), 3);
+        assert_eq!(db.cf("blocks").unwrap().size().unwrap(), 17);
+        assert!(db.cf("votes").unwrap().is_empty().unwrap());
+        assert_eq!(db.column_families().unwrap(), vec!["blocks", "default"]);
+
+        let keys: Vec<String> = db.cf("blocks").unwrap().prefix_iter("").map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, vec!["0x01", "0x02", "0x03"]);
+        let rows = db.execute("COUNT FROM blocks").unwrap();
+        assert_eq!(rows[0].columns, vec![b"3".to_vec()]);
+
+        db.cf("blocks").unwrap().clear().unwrap();
+        assert!(db.cf("blocks").unwrap().is_empty().unwrap());
+        assert_eq!(db.get("0x01").unwrap(), Some(b"default".to_vec()));
+
+        // A name with the separator could reach into another family, so it
+        // is refused everywhere, batches included.
+        assert!(matches!(db.cf("votes\0"), Err(DbError::InvalidColumnFamily(_))));
+        assert!(db.cf("").is_err());
+        let mut batch = WriteBatch::new();
+        batch.put("0x01", b"overwritten".to_vec()).put_cf("votes\0blocks", "0x01", b"bad".to_vec());
+        assert!(matches!(db.write(batch), Err(DbError::InvalidColumnFamily(_))));
+        assert_eq!(db.get("0x01").unwrap(), Some(b"default".to_vec()));
+        assert!(db.execute("COUNT FROM 'a\0b'").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+pub fn test_storage_snapshots() {
+    for db_type in [DbType::Memory, DbType::Disk] {
+        let dir = temp_dir("snapshots");
+        let db = Db::new(db_type, dir.to_str().unwrap()).unwrap();
+        db.put("balance:alice", b"10".to_vec()).unwrap();
+        db.put("balance:bob", b"5".to_vec()).unwrap();
+
+        let snapshot = db.snapshot().unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put("balance:alice", b"7".to_vec())
+            .put("balance:bob", b"8".to_vec())
+            .put("balance:carol", b"1".to_vec());
+        db.write(batch).unwrap();
+        db.delete("balance:bob").unwrap();
+
+        assert_eq!(snapshot.get("balance:alice").unwrap(), Some(b"10".to_vec()));
+        assert_eq!(snapshot.get("balance:bob").unwrap(), Some(b"5".to_vec()));
+        assert_eq!(snapshot.get("balance:carol").unwrap(), None);
+        let entries: Vec<(String, Vec<u8>)> = snapshot.prefix_iter("balance:").map(|entry| entry.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("balance:alice".to_string(), b"10".to_vec()),
+                ("balance:bob".to_string(), b"5".to_vec()),
+            ]
+        );
+        let rows = snapshot.execute("COUNT PREFIX balance:").unwrap();
+        assert_eq!(rows[0].columns, vec![b"2".to_vec()]);
+
+        assert_eq!(db.get("balance:alice").unwrap(), Some(b"7".to_vec()));
+        assert_eq!(db.get("balance:bob").unwrap(), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+pub fn test_storage_compaction() {
+    let dir = temp_dir("compaction");
+    let path = dir.to_str().unwrap().to_string();
+    let db = Arc::new(Db::new(DbType::Disk, &path).unwrap());
+    for round in 0..20u8 {
+        for key in 0..50 {
+            db.put(&format!("account:{:04}", key), vec![round; 100]).unwrap();
+        }
+    }
+    for key in 25..50 {
+        db.delete(&format!("account:{:04}", key)).unwrap();
+    }
+    let snapshot = db.snapshot().unwrap();
+    assert!(db.garbage_ratio().unwrap() > 0.9);
+
+    // Keep writing while the compaction runs.
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || {
+            for key in 0..200 {
+                db.put(&format!("block:{:04}", key), vec![1; 10]).unwrap();
+            }
+            db.delete("account:0000").unwrap();
+        })
+    };
+    let stats = db.compact().unwrap();
+    writer.join().unwrap();
+    assert!(stats.reclaimed_bytes() > 0);
+
+    assert_eq!(db.get("account:0000").unwrap(), None);
+    assert_eq!(db.get("account:0001").unwrap(), Some(vec![19; 100]));
+    assert_eq!(db.get("account:0030").unwrap(), None);
+    assert_eq!(db.prefix_iter("block:").count(), 200);
+    assert_eq!(snapshot.get("account:0000").unwrap(), Some(vec![19; 100]));
+    assert_eq!(snapshot.get("account:0030").unwrap(), None);
+    drop(snapshot);
+    drop(db);
+
+    let db = Db::new(DbType::Disk, &path).unwrap();
+    assert_eq!(db.get("account:0024").unwrap(), Some(vec![19; 100]));
+    assert_eq!(db.get("account:0000").unwrap(), None);
+    assert_eq!(db.prefix_iter("account:").count(), 24);
+    assert_eq!(db.prefix_iter("block:").count(), 200);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+pub fn test_storage_strict_durability() {
+    for (durability, survives) in [(Durability::Strict, true), (Durability::Buffered, false)] {
+        let dir = temp_dir("durability");
+        let config = StorageConfig {
+            path: dir.to_str().unwrap().to_string(),
+            durability,
+            ..StorageConfig::default()
+        };
+
+        let db = Db::open(DbType::Disk, &config).unwrap();
+        for height in 0..10u32 {
+            db.put(&format!("block:{}", height), vec![height as u8; 32]).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(&format!("receipt:{}", height), vec![1; 8]);
+            db.write(batch).unwrap();
+            if survives {
+                assert_eq!(db.unsynced_bytes().unwrap(), 0);
+            }
+        }
+
+        // Simulate a power loss: throw away every byte that was not fsynced
+        // yet. Closing the database flushes them, so cut the log back after.
+        let log_len = fs::metadata(dir.join("data.log")).unwrap().len();
+        let synced_len = log_len - db.unsynced_bytes().unwrap();
+        drop(db);
+        OpenOptions::new()
+            .write(true)
+            .open(dir.join("data.log"))
+            .unwrap()
+            .set_len(synced_len)
+            .unwrap();
+
+        let db = Db::open(DbType::Disk, &config).unwrap();
+        let recovered = db.prefix_iter("block:").count() + db.prefix_iter("receipt:").count();
+        if survives {
+            assert_eq!(recovered, 20);
+            assert_eq!(db.get("block:9").unwrap(), Some(vec![9; 32]));
+        } else {
+            assert!(recovered < 20);
+        }
+        drop(db);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // Syncing every 0 ms would spin a core; such a config is refused.
+    let dir = temp_dir("durability");
+    let config = StorageConfig {
+        path: dir.to_str().unwrap().to_string(),
+        durability: Durability::GroupCommit { interval_ms: 0 },
+        ..StorageConfig::default()
+    };
+    assert!(matches!(Db::open(DbType::Disk, &config), Err(DbError::ConnectionError(_))));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+pub fn test_storage_encryption() {
+    let dir = temp_dir("encryption");
+    let key_file = temp_dir("encryption-key");
+    fs::write(&key_file, "correct horse battery staple\n").unwrap();
+    let mut config = StorageConfig {
+        path: dir.to_str().unwrap().to_string(),
+        encryption: Some(EncryptionKey::Passphrase("hunter2".to_string())),
+        ..StorageConfig::default()
+    };
+
+    let db = Db::open(DbType::Disk, &config).unwrap();
+    db.put("block:1", b"secret block".to_vec()).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put_cf("votes", "block:1", b"secret vote".to_vec());
+    db.write(batch).unwrap();
+    assert_eq!(db.cf("votes").unwrap().size().unwrap(), 11);
+    drop(db);
+
+    let log = fs::read(dir.join("data.log")).unwrap();
+    assert!(!log.windows(6).any(|window| window == b"secret"));
+
+    // Reopening with the right passphrase reads the values back.
+    let db = Db::open(DbType::Disk, &config).unwrap();
+    assert_eq!(db.get("block:1").unwrap(), Some(b"secret block".to_vec()));
+    db.compact().unwrap();
+    assert_eq!(db.cf("votes").unwrap().get("block:1").unwrap(), Some(b"secret vote".to_vec()));
+    drop(db);
+
+    // A wrong key is rejected up front rather than surfacing as corruption.
+    let mut wrong = config.clone();
+    wrong.encryption = Some(EncryptionKey::Passphrase("hunter3".to_string()));
+    match Db::open(DbType::Disk, &wrong) {
+        Err(DbError::EncryptionError(message)) => assert!(message.contains("wrong encryption key")),
+        _ => panic!("opened with the wrong key"),
+    }
+    let mut missing = config.clone();
+    missing.encryption = None;
+    assert!(matches!(Db::open(DbType::Disk, &missing), Err(DbError::EncryptionError(_))));
+
+    // Rotate to a key file; the old passphrase stops working.
+    let db = Db::open(DbType::Disk, &config).unwrap();
+    let rotated = EncryptionKey::KeyFile(key_file.to_str().unwrap().to_string());
+    db.rotate_key(Some(&rotated)).unwrap();
+    db.put("block:2", b"after rotation".to_vec()).unwrap();
+    drop(db);
+    assert!(Db::open(DbType::Disk, &config).is_err());
+    config.encryption = Some(rotated);
+    let db = Db::open(DbType::Disk, &config).unwrap();
+    assert_eq!(db.get("block:1").unwrap(), Some(b"secret block".to_vec()));
+    assert_eq!(db.get("block:2").unwrap(), Some(b"after rotation".to_vec()));
+
+    // Rotating to no key decrypts the database in place.
+    db.rotate_key(None).unwrap();
+    drop(db);
+    let db = Db::open(DbType::Disk, &missing).unwrap();
+    assert_eq!(db.cf("votes").unwrap().get("block:1").unwrap(), Some(b"secret vote".to_vec()));
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_file(&key_file);
+}
+
+pub fn test_storage_backup_restore() {
+    let dir = temp_dir("backup");
+    let restored_dir = temp_dir("backup-restored");
+    let archive = temp_dir("backup-archive");
+    let config = StorageConfig {
+        path: dir.to_str().unwrap().to_string(),
+        ..StorageConfig::default()
+    };
+
+    let db = Db::open(DbType::Disk, &config).unwrap();
+    let mut batch = WriteBatch::new();
+    for height in 0..100u32 {
+        batch.put_cf("blocks", &height.to_string(), vec![height as u8; 16]);
+    }
+    batch.put_cf("votes", "0", b"yes".to_vec());
+    db.write(batch).unwrap();
+    db.put("head", b"99".to_vec()).unwrap();
+
+    let info = db.backup(&archive, None).unwrap();
+    assert_eq!(info.entries, 102);
+    assert!(!info.encrypted);
+    let mut partial = archive.clone().into_os_string();
+    partial.push(".partial");
+    assert!(!PathBuf::from(partial).exists());
+    // Later writes are not part of the backup.
+    db.put("head", b"100".to_vec()).unwrap();
+    drop(db);
+
+    let mut target = config.clone();
+    target.path = restored_dir.to_str().unwrap().to_string();
+    Db::new(DbType::Disk, &target.path).unwrap().put("stale", vec![1]).unwrap();
+    let restored = Db::restore(&archive, &target).unwrap();
+    assert_eq!(restored.checksum, info.checksum);
+
+    let db = Db::open(DbType::Disk, &target).unwrap();
+    assert_eq!(db.get("head").unwrap(), Some(b"99".to_vec()));
+    assert_eq!(db.get("stale").unwrap(), None);
+    assert_eq!(db.cf("blocks").unwrap().len().unwrap(), 100);
+    assert_eq!(db.cf("votes").unwrap().get("0").unwrap(), Some(b"yes".to_vec()));
+    drop(db);
+
+    // A corrupted archive is rejected and the existing data is left alone.
+    let mut bytes = fs::read(&archive).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    fs::write(&archive, &bytes).unwrap();
+    assert!(Db::restore(&archive, &target).is_err());
+    let db = Db::open(DbType::Disk, &target).unwrap();
+    assert_eq!(db.cf("blocks").unwrap().len().unwrap(), 100);
+    drop(db);
+
+    // Encrypted archives keep values sealed and need the key to restore.
+    let key = EncryptionKey::Passphrase("hunter2".to_string());
+    let db = Db::open(DbType::Disk, &target).unwrap();
+    let info = db.backup(&archive, Some(&key)).unwrap();
+    assert!(info.encrypted);
+    assert_eq!(info.entries, 102);
+    drop(db);
+    let bytes = fs::read(&archive).unwrap();
+    assert!(!bytes.windows(3).any(|window| window == b"yes"));
+    assert!(Db::restore(&archive, &target).is_err());
+    let mut wrong = target.clone();
+    wrong.encryption = Some(EncryptionKey::Passphrase("hunter3".to_string()));
+    assert!(Db::restore(&archive, &wrong).is_err());
+    let mut encrypted = target.clone();
+    encrypted.encryption = Some(key);
+    assert!(Db::restore(&archive, &encrypted).unwrap().encrypted);
+    let db = Db::open(DbType::Disk, &encrypted).unwrap();
+    assert_eq!(db.cf("votes").unwrap().get("0").unwrap(), Some(b"yes".to_vec()));
+    drop(db);
+
+    // The offline commands read the database and key from a config file.
+    let config_file = temp_dir("backup-config");
+    let mut node_config = Config::new();
+    node_config.storage = encrypted.clone();
+    node_config.save_to_file(&config_file).unwrap();
+    let args = |command: &str| {
+        vec![
+            command.to_string(),
+            config_file.to_str().unwrap().to_string(),
+            archive.to_str().unwrap().to_string(),
+        ]
+    };
+    assert!(admin::run(&args("backup")).unwrap().contains("102 entries"));
+    assert!(admin::run(&args("verify")).unwrap().contains("encrypted"));
+    assert!(admin::run(&args("restore")).is_ok());
+    assert!(admin::run(&args("unknown")).is_err());
+    assert!(admin::run(&args("verify")[..2]).is_err());
+
+    // RPC callers can only name a file inside the backup directory.
+    assert_eq!(backup::archive_in(&dir, "daily.bak"), Some(dir.join("daily.bak")));
+    for name in ["", ".", "..", "../daily.bak", "/tmp/daily.bak", "a\\b"] {
+        assert_eq!(backup::archive_in(&dir, name), None);
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&restored_dir);
+    let _ = fs::remove_file(&archive);
+    let _ = fs::remove_file(&config_file);
+}
+
+pub fn test_state_trie() {
+    let db = Db::new(DbType::Memory, "").unwrap();
+    let mut genesis = Genesis::new();
+    for i in 0..50u64 {
+        genesis.add_account(&format!("0x{:040x}", i), 1000 + i);
+    }
+    let mut state = StateTrie::from_genesis(&db, &genesis).unwrap();
+    let genesis_root = state.root();
+    assert_ne!(genesis_root, EMPTY_ROOT);
+
+    let mut chain = Blockchain::new();
+    let genesis_block = Block::genesis(&genesis, genesis_root);
+    chain.add_block(genesis_block.clone()).unwrap();
+
+    // The root depends only on the contents, not on insertion order.
+    let mut reversed = StateTrie::new(&db);
+    for i in (0..50u64).rev() {
+        reversed
+            .set_account(&format!("0x{:040x}", i), &Account { balance: 1000 + i, nonce: 0 })
+            .unwrap();
+    }
+    assert_eq!(reversed.root(), genesis_root);
+
+    // Move funds, touch contract storage and remove an account.
+    let from = format!("0x{:040x}", 1);
+    state.set_account(&from, &Account { balance: 900, nonce: 1 }).unwrap();
+    state.set_storage("0xc0ffee", "owner", from.clone().into_bytes()).unwrap();
+    state.remove(&trie::account_key(&format!("0x{:040x}", 2))).unwrap();
+    let root = state.commit().unwrap();
+    let block = Block::new(1, &genesis_block.hash, genesis.timestamp + 10, root);
+    chain.add_block(block.clone()).unwrap();
+
+    let account = state.account(&from).unwrap().unwrap();
+    assert_eq!((account.balance, account.nonce), (900, 1));
+    assert_eq!(state.storage("0xc0ffee", "owner").unwrap(), Some(from.clone().into_bytes()));
+
+    // Historical state stays readable under its root.
+    let old = StateTrie::at(&db, genesis_root);
+    assert_eq!(old.account(&from).unwrap().unwrap().balance, 1001);
+    assert!(old.storage("0xc0ffee", "owner").unwrap().is_none());
+
+    // Inclusion and exclusion proofs verify against the block's state root.
+    let included = state.prove(&trie::account_key(&from)).unwrap();
+    let value = trie::verify_proof(&block.state_root, &included).unwrap().unwrap();
+    assert_eq!(trie::decode_account(&value).unwrap().balance, 900);
+    let removed = state.prove(&trie::account_key(&format!("0x{:040x}", 2))).unwrap();
+    assert_eq!(trie::verify_proof(&block.state_root, &removed), Ok(None));
+    let missing = state.prove(&trie::account_key("0xnobody")).unwrap();
+    assert_eq!(trie::verify_proof(&block.state_root, &missing), Ok(None));
+
+    // A proof is only good for its own root and contents.
+    assert_eq!(
+        trie::verify_proof(&genesis_root, &included),
+        Err(ProofError::HashMismatch)
+    );
+    let mut forged = included.clone();
+    let last = forged.nodes.last_mut().unwrap();
+    *last.last_mut().unwrap() ^= 1;
+    assert_eq!(trie::verify_proof(&block.state_root, &forged), Err(ProofError::HashMismatch));
+    let mut truncated = included;
+    truncated.nodes.pop();
+    assert_eq!(trie::verify_proof(&block.state_root, &truncated), Err(ProofError::WrongLength));
+
+    // Removing everything returns to the empty root.
+    let mut emptied = StateTrie::at(&db, root);
+    for i in 0..50u64 {
+        emptied.remove(&trie::account_key(&format!("0x{:040x}", i))).unwrap();
+    }
+    emptied.remove(&trie::storage_key("0xc0ffee", "owner")).unwrap();
+    assert_eq!(emptied.root(), EMPTY_ROOT);
+}
+
+pub fn test_storage_pruning() {
+    let mut state_nodes = Vec::new();
+    for pruning in [Pruning::Archive, Pruning::Pruned { keep_blocks: 10 }] {
+        let chain = ChainStore::new(Arc::new(Db::new(DbType::Memory, "").unwrap()), pruning);
+        let mut genesis = Genesis::new();
+        genesis.add_account("0xa11ce", 1_000_000);
+        let mut state = StateTrie::from_genesis(chain.db(), &genesis).unwrap();
+        let mut block = Block::genesis(&genesis, state.root());
+        chain.put_block(&block).unwrap();
+
+        for height in 1..=100u64 {
+            state.set_account("0xa11ce", &Account { balance: 1_000_000 - height, nonce: height }).unwrap();
+            state.set_account(&format!("0x{:x}", height), &Account { balance: height, nonce: 0 }).unwrap();
+            let root = chain.commit_state(&mut state).unwrap();
+            block = Block::new(height, &block.hash, genesis.timestamp + height, root);
+            chain.put_block(&block).unwrap();
+        }
+        assert_eq!(chain.head().unwrap(), Some(100));
+        assert_eq!(chain.state_at(100).unwrap().account("0xa11ce").unwrap().unwrap().nonce, 100);
+
+        match pruning {
+            Pruning::Archive => {
+                assert_eq!(chain.oldest().unwrap(), 0);
+                assert_eq!(chain.state_at(0).unwrap().account("0xa11ce").unwrap().unwrap().balance, 1_000_000);
+                assert_eq!(chain.sweep().unwrap().removed_nodes, 0);
+            }
+            Pruning::Pruned { .. } => {
+                assert_eq!(chain.oldest().unwrap(), 91);
+                assert_eq!(chain.block(91).unwrap().unwrap().height, 91);
+                assert_eq!(chain.state_at(91).unwrap().account("0xa11ce").unwrap().unwrap().nonce, 91);
+                match chain.state_at(90) {
+                    Err(DbError::StatePruned { height, oldest }) => assert_eq!((height, oldest), (90, 91)),
+                    _ => panic!("expected a state pruned error"),
+                }
+                assert!(matches!(chain.block(0), Err(DbError::StatePruned { .. })));
+
+                // Everything the retained states need survives a full sweep.
+                chain.sweep().unwrap();
+                assert_eq!(chain.sweep().unwrap().removed_nodes, 0);
+                for height in 91..=100 {
+                    let state = chain.state_at(height).unwrap();
+                    assert_eq!(state.account("0xa11ce").unwrap().unwrap().nonce, height);
+                    assert_eq!(state.account("0x1").unwrap().unwrap().balance, 1);
+                }
+                assert_eq!(chain.db().cf("blocks").unwrap().len().unwrap(), 10);
+
+                // State committed for a block not stored yet survives sweeps.
+                state.set_account("0xb0b", &Account { balance: 7, nonce: 0 }).unwrap();
+                let root = chain.commit_state(&mut state).unwrap();
+                assert_eq!(chain.sweep().unwrap().removed_nodes, 0);
+                block = Block::new(101, &block.hash, genesis.timestamp + 101, root);
+                chain.put_block(&block).unwrap();
+                assert_eq!(chain.state_at(101).unwrap().account("0xb0b").unwrap().unwrap().balance, 7);
+            }
+        }
+        state_nodes.push(chain.db().cf("state").unwrap().len().unwrap());
+    }
+    // Nodes only the pruned states used are gone.
+    assert!(state_nodes[1] < state_nodes[0]);
+}
+
+pub fn test_storage_indexes() {
+    let transfer = |from: &str, to: &str, nonce: u64| Transaction {
+        from: from.to_string(),
+        to: to.to_string(),
+        amount: 10,
+        nonce,
+        signature: String::new(),
+    };
+    let chain = ChainStore::new(Arc::new(Db::new(DbType::Memory, "").unwrap()), Pruning::Archive);
+    let genesis = Block::new(0, "", 0, EMPTY_ROOT);
+    let one = Block::with_transactions(1, &genesis.hash, 1, EMPTY_ROOT, vec![transfer("alice", "bob", 0)]);
+    let two = Block::with_transactions(
+        2,
+        &one.hash,
+        2,
+        EMPTY_ROOT,
+        vec![transfer("bob", "carol", 0), transfer("alice", "alice", 1)],
+    );
+    chain.put_blocks(&[genesis.clone(), one.clone(), two.clone()]).unwrap();
+
+    assert_eq!(chain.block(2).unwrap(), Some(two.clone()));
+    let (location, tx) = chain.transaction(&two.transactions[1].hash()).unwrap().unwrap();
+    assert_eq!(location, TxLocation { block_hash: two.hash.clone(), height: 2, index: 1 });
+    assert_eq!(tx, two.transactions[1]);
+    let alice = chain.address_transactions("alice", Direction::Forward, 10).unwrap();
+    assert_eq!(alice, vec![one.transactions[0].hash(), two.transactions[1].hash()]);
+    let bob = chain.address_transactions("bob", Direction::Reverse, 1).unwrap();
+    assert_eq!(bob, vec![two.transactions[0].hash()]);
+
+    // A block that does not connect leaves the chain untouched.
+    let orphan = Block::new(3, "unknown", 3, EMPTY_ROOT);
+    assert!(chain.put_blocks(&[orphan]).is_err());
+    assert_eq!(chain.head().unwrap(), Some(2));
+
+    // Replacing block 2 rolls back its index entries.
+    let fork = Block::with_transactions(2, &one.hash, 20, EMPTY_ROOT, vec![transfer("dave", "bob", 0)]);
+    let fork_child = Block::new(3, &fork.hash, 21, EMPTY_ROOT);
+    chain.put_blocks(&[fork.clone(), fork_child.clone()]).unwrap();
+    assert_eq!(chain.head_block().unwrap(), Some(fork_child.clone()));
+    assert_eq!(chain.block(2).unwrap(), Some(fork.clone()));
+    assert_eq!(chain.block_by_hash(&two.hash).unwrap(), Some(two.clone()));
+    assert!(chain.transaction(&two.transactions[0].hash()).unwrap().is_none());
+    assert_eq!(chain.address_transactions("carol", Direction::Forward, 10).unwrap(), Vec::<String>::new());
+    let bob = chain.address_transactions("bob", Direction::Forward, 10).unwrap();
+    assert_eq!(bob, vec![one.transactions[0].hash(), fork.transactions[0].hash()]);
+
+    // Rebuilding the indexes from scratch gives the same answers.
+    let entries = |cf: &str| {
+        chain
+            .db()
+            .cf(cf).unwrap()
+            .iter(KeyRange::All, Direction::Forward)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+    let before: Vec<_> = ["index_height", "index_tx", "index_address"].iter().map(|cf| entries(cf)).collect();
+    chain.db().cf("index_tx").unwrap().clear().unwrap();
+    assert!(chain.transaction(&fork.transactions[0].hash()).unwrap().is_none());
+    let stats = chain.reindex().unwrap();
+    assert_eq!((stats.blocks, stats.transactions), (4, 2));
+    let after: Vec<_> = ["index_height", "index_tx", "index_address"].iter().map(|cf| entries(cf)).collect();
+    assert_eq!(before, after);
+
+    // A transaction repeated in a later block keeps its first entry when the
+    // later block is rolled back, and addresses containing ':' stay apart.
+    let repeat = Block::with_transactions(
+        4,
+        &fork_child.hash,
+        22,
+        EMPTY_ROOT,
+        vec![fork.transactions[0].clone(), transfer("a:1", "b", 0)],
+    );
+    chain.put_block(&repeat).unwrap();
+    assert_eq!(chain.address_transactions("a:1", Direction::Forward, 10).unwrap().len(), 1);
+    assert!(chain.address_transactions("a", Direction::Forward, 10).unwrap().is_empty());
+    chain.put_block(&Block::new(4, &fork_child.hash, 23, EMPTY_ROOT)).unwrap();
+    let (location, _) = chain.transaction(&fork.transactions[0].hash()).unwrap().unwrap();
+    assert_eq!(location.block_hash, fork.hash);
+}
+
+pub fn test_storage_backend_conformance() {
+    conformance::check_backend(|| Box::new(MemoryConnection::new()));
+    let opened = std::cell::Cell::new(0);
+    conformance::check_backend(|| {
+        opened.set(opened.get() + 1);
+        let dir = temp_dir(&format!("conformance-{}", opened.get()));
+        let config = StorageConfig {
+            path: dir.to_str().unwrap().to_string(),
+            ..StorageConfig::default()
+        };
+        Box::new(DiskConnection::open(&config).unwrap())
+    });
+}
+
+pub fn test_storage_shared_layer() {
+    let backend: Box<dyn Backend> = Box::new(MemoryConnection::new());
+    let storage = Storage::with_backend(backend, Pruning::Archive).unwrap();
+    let transfer = Transaction {
+        from: "alice".to_string(),
+        to: "bob".to_string(),
+        amount: 5,
+        nonce: 0,
+        signature: String::new(),
+    };
+    storage.add_transaction(transfer.clone()).unwrap();
+    assert_eq!(storage.pending_transactions(10).unwrap(), vec![transfer.clone()]);
+
+    let genesis = Block::new(0, "", 0, EMPTY_ROOT);
+    let block = Block::with_transactions(1, &genesis.hash, 1, EMPTY_ROOT, vec![transfer.clone()]);
+    storage.add_block(genesis).unwrap();
+    storage.add_block(block.clone()).unwrap();
+    assert_eq!(storage.latest_block().unwrap(), Some(block.clone()));
+    assert_eq!(storage.get_block(&block.hash).unwrap(), Some(block.clone()));
+    assert!(storage.pending_transactions(10).unwrap().is_empty());
+    // Already on the chain.
+    storage.add_transaction(transfer).unwrap();
+    assert!(storage.pending_transactions(10).unwrap().is_empty());
+
+    assert_eq!(storage.get_vote("validator-1", &block).unwrap(), None);
+    storage.put_vote("validator-1", &block.hash, true).unwrap();
+    storage.put_vote("validator-2", &block.hash, false).unwrap();
+    assert_eq!(storage.get_vote("validator-1", &block).unwrap(), Some(true));
+    assert_eq!(storage.get_vote("validator-2", &block).unwrap(), Some(false));
+
+    assert!(!storage.has_contract("token").unwrap());
+    storage.store_contract("token").unwrap();
+    assert!(storage.has_contract("token").unwrap());
+}
+
+pub fn test_storage_async() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let storage = Arc::new(Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Archive).unwrap());
+    let async_storage = AsyncStorage::new(storage.clone());
+
+    runtime.block_on(async {
+        let genesis = Block::new(0, "", 0, EMPTY_ROOT);
+        async_storage.add_block(genesis.clone()).await.unwrap();
+
+        // Readers run alongside the writer and only ever see the chain grow.
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let reader = async_storage.clone();
+                tokio::spawn(async move {
+                    let mut seen = 0;
+                    for _ in 0..50 {
+                        let head = reader.latest_block().await.unwrap().unwrap();
+                        assert!(head.height >= seen);
+                        seen = head.height;
+                    }
+                })
+            })
+            .collect();
+        let mut parent = genesis;
+        for height in 1..=100 {
+            let block = Block::new(height, &parent.hash, height, EMPTY_ROOT);
+            async_storage.add_block(block.clone()).await.unwrap();
+            parent = block;
+        }
+        for reader in readers {
+            reader.await.unwrap();
+        }
+
+        assert_eq!(async_storage.block_at(100).await.unwrap(), Some(parent.clone()));
+        let rows = async_storage.query("COUNT FROM blocks").await.unwrap();
+        assert_eq!(rows[0].columns[0], b"101".to_vec());
+        // Write errors reach the caller and leave the writer running.
+        let orphan = Block::new(5, "unknown", 5, EMPTY_ROOT);
+        assert!(async_storage.add_block(orphan).await.is_err());
+        async_storage.put_vote("validator", &parent.hash, true).await.unwrap();
+        assert_eq!(async_storage.get_vote("validator", &parent).await.unwrap(), Some(true));
+    });
+    // The blocking interface sees the same data.
+    assert_eq!(storage.chain.head().unwrap(), Some(100));
+
+    // Threads outside the runtime write through the same queue, and a write
+    // that panics fails on its own.
+    let head = storage.latest_block().unwrap().unwrap();
+    let voted = head.clone();
+    async_storage.write_blocking(move |storage| storage.put_vote("other", &voted.hash, false)).unwrap();
+    assert_eq!(storage.get_vote("other", &head).unwrap(), Some(false));
+    let failed: Result<(), StorageError> = async_storage.write_blocking(|_| panic!("write failed"));
+    assert!(matches!(failed, Err(StorageError::Unavailable(_))));
+    assert_eq!(async_storage.write_blocking(|storage| Ok(storage.chain.head()?)).unwrap(), Some(100));
+}
+
+pub fn test_storage_migrations() {
+    let dir = temp_dir("migrations");
+    let config = StorageConfig {
+        path: dir.to_str().unwrap().to_string(),
+        ..StorageConfig::default()
+    };
+    let genesis = Block::new(0, "", 0, EMPTY_ROOT);
+    let child = Block::new(1, &genesis.hash, 1, EMPTY_ROOT);
+
+    // Version 1 layout, with no recorded version: blocks keyed by height.
+    {
+        let db = Db::open(DbType::Disk, &config).unwrap();
+        for block in [&genesis, &child] {
+            let key = format!("{:020}", block.height);
+            db.cf("blocks").unwrap().put(&key, serde_json::to_vec(block).unwrap()).unwrap();
+        }
+        db.cf("chain").unwrap().put("head", b"1".to_vec()).unwrap();
+        assert_eq!(migration::schema_version(&db).unwrap(), Some(1));
+
+        // A dry run reports the upgrade and leaves the data alone.
+        let report = migration::migrate(&db, true).unwrap();
+        assert!(report.dry_run);
+        assert_eq!((report.from, report.to), (Some(1), SCHEMA_VERSION));
+        assert_eq!(report.steps.len(), 4);
+        // The last step only moves substrate blocks, which this data has none of.
+        assert!(report.steps[..3].iter().all(|step| step.writes > 0));
+        assert_eq!(report.steps[3].writes, 0);
+        assert_eq!(migration::schema_version(&db).unwrap(), Some(1));
+        assert!(db.cf("blocks").unwrap().get(&format!("{:020}", 1)).unwrap().is_some());
+    }
+    // Planning reads the log without repairing it, so a torn tail stays
+    // until the storage is opened.
+    let log = dir.join("data.log");
+    OpenOptions::new().append(true).open(&log).unwrap().write_all(&[1, 2, 3]).unwrap();
+    let torn_len = fs::metadata(&log).unwrap().len();
+    let planned = Storage::plan_migrations(&config).unwrap();
+    assert_eq!(planned.steps.len(), 4);
+    assert_eq!(fs::metadata(&log).unwrap().len(), torn_len);
+
+    // Opening the storage runs the migration, making the writes the plan
+    // reported.
+    {
+        let storage = Storage::open(&config).unwrap();
+        assert_eq!(migration::schema_version(&storage.db).unwrap(), Some(SCHEMA_VERSION));
+        assert_eq!(storage.latest_block().unwrap(), Some(child.clone()));
+        assert_eq!(storage.chain.block(0).unwrap(), Some(genesis.clone()));
+        assert!(storage.db.cf("blocks").unwrap().get(&format!("{:020}", 0)).unwrap().is_none());
+        assert_eq!(storage.tips().unwrap(), vec![child.clone()]);
+        assert!(migration::migrate(&storage.db, false).unwrap().steps.is_empty());
+    }
+
+    // Data from a newer release is refused.
+    {
+        let db = Db::open(DbType::Disk, &config).unwrap();
+        db.cf("meta").unwrap().put("schema_version", (SCHEMA_VERSION + 1).to_string().into_bytes()).unwrap();
+    }
+    match Storage::open(&config) {
+        Err(StorageError::DbError(DbError::SchemaTooNew { found, supported })) => {
+            assert_eq!((found, supported), (SCHEMA_VERSION + 1, SCHEMA_VERSION))
+        }
+        _ => panic!("expected a schema too new error"),
+    }
+
+    // Keys written before column families move into the default family.
+    let legacy = Db::new(DbType::Memory, "").unwrap();
+    legacy.backend().put("balance:alice", b"10".to_vec()).unwrap();
+    assert_eq!(migration::schema_version(&legacy).unwrap(), Some(0));
+    let planned = migration::migrate(&legacy, true).unwrap();
+    assert_eq!(planned.steps[0].writes, 2);
+    assert_eq!(legacy.backend().get("balance:alice").unwrap(), Some(b"10".to_vec()));
+    let report = migration::migrate(&legacy, false).unwrap();
+    assert_eq!(report.steps[0].writes, 2);
+    assert_eq!(legacy.get("balance:alice").unwrap(), Some(b"10".to_vec()));
+    assert_eq!(legacy.column_families().unwrap(), vec!["default", "meta"]);
+
+    // Blocks of the substrate storage join the shared chain layout.
+    let substrate = Db::new(DbType::Memory, "").unwrap();
+    substrate.cf("meta").unwrap().put("schema_version", b"4".to_vec()).unwrap();
+    for block in [&genesis, &child] {
+        let value = serde_json::to_vec(block).unwrap();
+        substrate.cf("blocks_by_hash").unwrap().put(&block.hash, value).unwrap();
+        let height = format!("{:020}", block.height);
+        substrate.cf("canonical").unwrap().put(&height, block.hash.clone().into_bytes()).unwrap();
+    }
+    migration::migrate(&substrate, false).unwrap();
+    let chain = ChainStore::new(Arc::new(substrate), Pruning::Archive);
+    assert_eq!(chain.head_block().unwrap(), Some(child.clone()));
+    assert_eq!(chain.block(0).unwrap(), Some(genesis.clone()));
+    assert_eq!(chain.tips().unwrap(), vec![child.clone()]);
+    assert!(chain.db().cf("canonical").unwrap().is_empty().unwrap());
+
+    // A damaged version is reported rather than guessed at.
+    let corrupt = Db::new(DbType::Memory, "").unwrap();
+    corrupt.cf("meta").unwrap().put("schema_version", b"four".to_vec()).unwrap();
+    assert!(matches!(migration::migrate(&corrupt, false), Err(DbError::CorruptSchemaVersion(_))));
+
+    // A database that was never created plans as empty, and stays absent.
+    let missing = StorageConfig {
+        path: dir.join("missing").to_str().unwrap().to_string(),
+        ..StorageConfig::default()
+    };
+    assert_eq!(Storage::plan_migrations(&missing).unwrap().from, None);
+    assert!(!dir.join("missing").exists());
+
+    // New databases start at the current version.
+    let fresh = Db::new(DbType::Memory, "").unwrap();
+    assert_eq!(migration::schema_version(&fresh).unwrap(), None);
+    assert!(migration::migrate(&fresh, false).unwrap().steps.is_empty());
+    assert_eq!(migration::schema_version(&fresh).unwrap(), Some(SCHEMA_VERSION));
+}
+
+pub fn test_storage_incremental_persistence() {
+    let dir = temp_dir("substrate-storage");
+    let path = dir.to_str().unwrap();
+    let genesis = Block::new(0, "", 0, EMPTY_ROOT);
+    let mut chain = Blockchain::new();
+    chain.add_block(genesis.clone()).unwrap();
+
+    let storage = PiSentinelStorage::new(path).unwrap();
+    for height in 1..=50u64 {
+        let parent = chain.latest_block().unwrap().hash.clone();
+        let block = Block::new(height, &parent, height, EMPTY_ROOT);
+        storage.store_block(block.clone()).unwrap();
+        chain.add_block(block).unwrap();
+    }
+    storage.store_blockchain(chain.clone()).unwrap();
+    let log_len = fs::metadata(dir.join("data.log")).unwrap().len();
+
+    // Storing one more block appends roughly one block's worth of data,
+    // however long the chain already is.
+    let parent = chain.latest_block().unwrap().hash.clone();
+    let block = Block::new(51, &parent, 51, EMPTY_ROOT);
+    storage.store_block(block.clone()).unwrap();
+    chain.add_block(block.clone()).unwrap();
+    storage.store_blockchain(chain.clone()).unwrap();
+    let grown = fs::metadata(dir.join("data.log")).unwrap().len() - log_len;
+    assert!(grown < 1024, "appended {} bytes for one block", grown);
+    drop(storage);
+
+    // Everything comes back on restart.
+    let storage = PiSentinelStorage::new(path).unwrap();
+    assert_eq!(storage.get_block(&block.hash).unwrap(), Some(block));
+    assert_eq!(storage.get_block(&genesis.hash).unwrap(), Some(genesis.clone()));
+    let reloaded = storage.get_blockchain().unwrap();
+    assert_eq!(reloaded.blocks(), chain.blocks());
+
+    // Contract code and state are stored too.
+    storage.store_contract("token", b"code".to_vec()).unwrap();
+    storage.store_contract_state("token", "supply", b"100".to_vec()).unwrap();
+    storage.store_contract_state("to", "kensupply", b"1".to_vec()).unwrap();
+    drop(storage);
+    let storage = PiSentinelStorage::new(path).unwrap();
+    assert_eq!(storage.get_contract("token").unwrap(), Some(b"code".to_vec()));
+    assert_eq!(storage.get_contract("other").unwrap(), None);
+    assert_eq!(storage.get_contract_state("token", "supply").unwrap(), Some(b"100".to_vec()));
+    assert_eq!(storage.get_contract_state("to", "kensupply").unwrap(), Some(b"1".to_vec()));
+    storage.store_contract_state("token", "supply", Vec::new()).unwrap();
+    assert_eq!(storage.get_contract_state("token", "supply").unwrap(), None);
+
+    // Replacing the tail with a shorter fork drops the old heights.
+    let mut fork = Blockchain::new();
+    for block in &chain.blocks()[..=40] {
+        fork.add_block(block.clone()).unwrap();
+    }
+    let parent = fork.latest_block().unwrap().hash.clone();
+    fork.add_block(Block::new(41, &parent, 1000, EMPTY_ROOT)).unwrap();
+    storage.store_blockchain(fork.clone()).unwrap();
+    drop(storage);
+    let storage = PiSentinelStorage::new(path).unwrap();
+    assert_eq!(storage.get_blockchain().unwrap().blocks(), fork.blocks());
+    drop(storage);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+pub fn test_storage_read_cache() {
+    let dir = temp_dir("read_cache");
+    let mut config = StorageConfig {
+        path: dir.to_str().unwrap().to_string(),
+        // Room for two of the values below, not three.
+        read_cache_bytes: 200,
+        ..StorageConfig::default()
+    };
+
+    let db = Db::open(DbType::Disk, &config).unwrap();
+    db.put("a", vec![1; 80]).unwrap();
+    db.put("b", vec![2; 80]).unwrap();
+    assert_eq!(db.get("a").unwrap(), Some(vec![1; 80]));
+    assert_eq!(db.get("a").unwrap(), Some(vec![1; 80]));
+    let stats = db.read_stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+
+    // Writes replace cached values rather than leaving them stale.
+    db.put("a", vec![3; 80]).unwrap();
+    assert_eq!(db.get("a").unwrap(), Some(vec![3; 80]));
+    let mut batch = WriteBatch::new();
+    batch.put("a", vec![4; 80]).delete("b");
+    db.write(batch).unwrap();
+    assert_eq!(db.get("a").unwrap(), Some(vec![4; 80]));
+    assert_eq!(db.get("b").unwrap(), None);
+    db.delete("a").unwrap();
+    assert_eq!(db.get("a").unwrap(), None);
+
+    // The least recently used value is evicted first.
+    for key in ["x", "y", "z"] {
+        db.put(key, vec![0; 80]).unwrap();
+    }
+    for key in ["x", "y", "x", "z", "x", "y"] {
+        db.get(key).unwrap();
+    }
+    let stats = db.read_stats();
+    // x, y, hit x, z evicts y, hit x, y evicts z.
+    assert_eq!((stats.cache_hits, stats.cache_misses), (3, 7));
+
+    // Missing keys are mostly answered by the bloom filter, including after
+    // its first segment has filled up.
+    let mut batch = WriteBatch::new();
+    for n in 0..5000u32 {
+        batch.put(&format!("tx:{}", n), n.to_le_bytes().to_vec());
+    }
+    db.write(batch).unwrap();
+    for n in (0..5000u32).step_by(7) {
+        assert_eq!(db.get(&format!("tx:{}", n)).unwrap(), Some(n.to_le_bytes().to_vec()));
+    }
+    let before = db.read_stats();
+    for n in 0..1000u32 {
+        assert_eq!(db.get(&format!("missing:{}", n)).unwrap(), None);
+    }
+    let stats = db.read_stats();
+    let negatives = stats.bloom_negatives - before.bloom_negatives;
+    let false_positives = stats.bloom_false_positives - before.bloom_false_positives;
+    assert_eq!(negatives + false_positives, 1000);
+    assert!(false_positives < 50, "{} false positives", false_positives);
+    assert_eq!(stats.bloom_segments, 2);
+
+    // Compaction drops deleted keys from the filter without losing live ones.
+    let mut batch = WriteBatch::new();
+    for n in 0..2500u32 {
+        batch.delete(&format!("tx:{}", n));
+    }
+    db.write(batch).unwrap();
+    db.compact().unwrap();
+    assert_eq!(db.get("tx:10").unwrap(), None);
+    assert_eq!(db.get("tx:4999").unwrap(), Some(4999u32.to_le_bytes().to_vec()));
+    assert_eq!(db.read_stats().bloom_segments, 1);
+    drop(db);
+
+    // Reopened with both disabled, reads still work and count nothing.
+    config.read_cache_bytes = 0;
+    config.bloom_filter = false;
+    let db = Db::open(DbType::Disk, &config).unwrap();
+    assert_eq!(db.get("x").unwrap(), Some(vec![0; 80]));
+    assert_eq!(db.get("missing").unwrap(), None);
+    assert_eq!(db.read_stats().cache_hits, 0);
+    assert_eq!(db.read_stats().bloom_negatives, 0);
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+pub fn test_proof_of_work() {
+    assert_eq!(pow::target(1), [0xff; 32]);
+    let mut half = [0xff; 32];
+    half[0] = 0x7f;
+    assert_eq!(pow::target(2), half);
+    assert!(pow::target(1000) < pow::target(999));
+
+    let parent = Block::new(0, "", 0, EMPTY_ROOT);
+    let transfer = Transaction {
+        from: "alice".to_string(),
+        to: "bob".to_string(),
+        amount: 5,
+        nonce: 0,
+        signature: String::new(),
+    };
+    let mut template = Block::with_transactions(1, &parent.hash, 10, EMPTY_ROOT, vec![transfer]);
+    template.difficulty = 1000;
+
+    let miner = Miner::new(4);
+    let block = miner.mine(&template, &AtomicBool::new(false)).unwrap();
+    assert_eq!(pow::verify(&block, 1000), Ok(()));
+    assert_eq!(block.hash, block.compute_hash());
+    assert_ne!(block.hash, template.hash);
+    let mut chain = Blockchain::new();
+    chain.add_block(parent.clone()).unwrap();
+    chain.add_block(block.clone()).unwrap();
+
+    // A block must carry the expected difficulty, match its hash and meet
+    // its target.
+    assert_eq!(
+        pow::verify(&block, 2000),
+        Err(PowError::WrongDifficulty {
+            expected: 2000,
+            found: 1000
+        })
+    );
+    let mut tampered = block.clone();
+    tampered.timestamp += 1;
+    assert_eq!(pow::verify(&tampered, 1000), Err(PowError::InvalidHash));
+    let mut unmined = template.clone();
+    unmined.nonce = (0..).find(|&nonce| pow::work_hash(&template, nonce) > pow::target(1000)).unwrap();
+    unmined.hash = unmined.compute_hash();
+    assert_eq!(pow::verify(&unmined, 1000), Err(PowError::InsufficientWork));
+
+    // A search that cannot finish in time stops once cancelled.
+    let cancel = AtomicBool::new(false);
+    let mut hopeless = template.clone();
+    hopeless.difficulty = u64::MAX;
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            thread::sleep(std::time::Duration::from_millis(50));
+            cancel.store(true, Ordering::SeqCst);
+        });
+        assert_eq!(miner.mine(&hopeless, &cancel), None);
+    });
+
+    // A search watching the chain stops when any writer moves the head,
+    // but not when the head is written again unchanged.
+    let storage = Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Archive).unwrap();
+    storage.chain.put_block(&parent).unwrap();
+    let head_changed = storage.chain.watch_head();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            thread::sleep(std::time::Duration::from_millis(50));
+            storage.chain.put_block(&block).unwrap();
+        });
+        assert_eq!(miner.mine(&hopeless, &head_changed), None);
+    });
+    head_changed.store(false, Ordering::SeqCst);
+    storage.chain.put_block(&block).unwrap();
+    assert!(!head_changed.load(Ordering::SeqCst));
+
+    // Blocks that were not mined hash as they did before proof of work.
+    assert_eq!(
+        Block::new(0, "", 0, EMPTY_ROOT).hash,
+        "17b0761f87b081d5cf10757ccc89f12be355c70e2e29df288b65b30710dcbcd1"
+    );
+}
+
+pub fn test_difficulty_retargeting() {
+    let block_time = 10;
+    let retarget = Retarget::new(block_time, 1000);
+    let genesis = Block::new(0, "", 0, EMPTY_ROOT);
+    assert_eq!(retarget.next_difficulty(&[]), 1000);
+    assert_eq!(retarget.next_difficulty(std::slice::from_ref(&genesis)), 1000);
+
+    // Hashrate swings: steady, ten times more, fifty times less, back.
+    // Each phase must settle at hashrate * block_time within two windows,
+    // and average close to the block time once settled.
+    let mut mining = MiningSimulation::new(genesis.clone(), 0x5eed);
+    for hashrate in [100, 1000, 20, 100] {
+        let mined = mining.mine(&retarget, hashrate, 600, |now, _, _| now);
+        let expected = (hashrate * block_time) as f64;
+        let settled = mean_difficulty(&mined[150..]);
+        assert!((settled / expected - 1.0).abs() < 0.15, "difficulty {} for {}", settled, expected);
+        let spacing = (mined[599].1 - mined[149].1) as f64 / 450.0;
+        assert!((spacing / block_time as f64 - 1.0).abs() < 0.15, "block time {}", spacing);
+        let caught_up = mined
+            .iter()
+            .position(|(block, _)| (block.difficulty as f64 / expected - 1.0).abs() < 0.3)
+            .unwrap();
+        assert!(caught_up < 120, "took {} blocks to adjust", caught_up);
+    }
+    let chain = mining.chain;
+    assert_eq!(retarget.next_difficulty(&chain), retarget.next_difficulty(&chain.clone()));
+
+    // A third of the blocks come from a miner who reports the earliest
+    // timestamp allowed, or the latest. Neither moves the difficulty far.
+    for backdate in [true, false] {
+        let mut mining = MiningSimulation::new(genesis.clone(), 0xbad);
+        let mined = mining.mine(&retarget, 100, 900, |now, earliest, latest| match (now % 3, backdate) {
+            (0, true) => earliest,
+            (0, false) => latest,
+            _ => now,
+        });
+        let settled = mean_difficulty(&mined[300..]);
+        assert!((settled / 1000.0 - 1.0).abs() < 0.2, "difficulty {} with manipulated timestamps", settled);
+    }
+
+    // Timestamps must be after the median of the last 11 blocks and at most
+    // six block times ahead of the local clock.
+    let history: Vec<Block> = (0..11).map(|n| Block::new(n, "", n * 10, EMPTY_ROOT)).collect();
+    let at = |timestamp| Block::new(11, "", timestamp, EMPTY_ROOT);
+    assert_eq!(
+        retarget.check_timestamp(&at(50), &history, 110),
+        Err(PowError::TimestampTooOld { median: 50 })
+    );
+    assert_eq!(retarget.check_timestamp(&at(51), &history, 110), Ok(()));
+    assert_eq!(retarget.check_timestamp(&at(170), &history, 110), Ok(()));
+    assert_eq!(
+        retarget.check_timestamp(&at(171), &history, 110),
+        Err(PowError::TimestampTooNew { limit: 170 })
+    );
+
+    // Pruning must keep the blocks retargeting and proposer selection read,
+    // which a config file is checked for when it is loaded.
+    let mut config = Config::new();
+    config.storage.pruning = Pruning::Pruned { keep_blocks: 60 };
+    assert!(config.validate().unwrap_err().contains("retargeting"));
+    config.storage.pruning = Pruning::Pruned { keep_blocks: 2 * EPOCH_LENGTH - 1 };
+    assert!(config.validate().unwrap_err().contains("proposer"));
+    let dir = temp_dir("retarget_config");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.json");
+    config.save_to_file(&path).unwrap();
+    let err = Config::load_from_file(&path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    config.storage.pruning = Pruning::Pruned { keep_blocks: 2 * EPOCH_LENGTH };
+    assert_eq!(config.validate(), Ok(()));
+    config.save_to_file(&path).unwrap();
+    assert!(Config::load_from_file(&path).is_ok());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+pub fn test_proof_of_stake() {
+    let [alice, bob, carol, dave] = [1, 2, 3, 4].map(|seed| Keypair::from_seed([seed; 32]));
+    let mut genesis = Genesis::new();
+    genesis.add_validator(&alice.address(), 100);
+    genesis.add_validator(&bob.address(), 200);
+    genesis.add_validator("idle", 0);
+    let mut stakes = StakeRegistry::from_genesis(&genesis);
+    assert_eq!(stakes.total(), 300);
+    let mut validators = vec![(alice.address(), 100), (bob.address(), 200)];
+    validators.sort();
+    let listed = |stakes: &StakeRegistry| -> Vec<(String, u64)> {
+        stakes.validators().map(|(validator, stake)| (validator.to_string(), stake)).collect()
+    };
+    assert_eq!(listed(&stakes), validators);
+
+    // Signed staking transactions bond and release stake; other transfers
+    // don't.
+    let transfer = |from: &Keypair, to: &str, amount, nonce| {
+        let mut transaction = Transaction {
+            from: from.address(),
+            to: to.to_string(),
+            amount,
+            nonce,
+            signature: String::new(),
+        };
+        transaction.sign(from);
+        transaction
+    };
+    let balances = |address: &str| Ok::<_, ()>(if address == carol.address() { 1000 } else { 0 });
+    let block = Block::with_transactions(
+        1,
+        "",
+        0,
+        EMPTY_ROOT,
+        vec![
+            transfer(&carol, STAKE_ADDRESS, 700, 0),
+            transfer(&alice, UNSTAKE_ADDRESS, 100, 0),
+            transfer(&bob, UNSTAKE_ADDRESS, 50, 0),
+            transfer(&bob, &carol.address(), 1000, 0),
+            transfer(&dave, UNSTAKE_ADDRESS, 10, 0),
+        ],
+    );
+    assert_eq!(stakes.apply_block(&block, balances), Ok(Vec::new()));
+    let mut validators = vec![(bob.address(), 150), (carol.address(), 700)];
+    validators.sort();
+    assert_eq!(listed(&stakes), validators);
+
+    // Unsigned or forged staking transactions, and bonds the balance cannot
+    // cover, change nothing and are reported.
+    let mut unsigned = transfer(&bob, UNSTAKE_ADDRESS, 150, 1);
+    unsigned.signature.clear();
+    let mut forged = transfer(&dave, UNSTAKE_ADDRESS, 150, 1);
+    forged.from = bob.address();
+    let rejected = Block::with_transactions(
+        2,
+        &block.hash,
+        0,
+        EMPTY_ROOT,
+        vec![unsigned, forged, transfer(&carol, STAKE_ADDRESS, 301, 1), transfer(&dave, STAKE_ADDRESS, 1, 1)],
+    );
+    let before = stakes.clone();
+    assert_eq!(stakes.apply_block(&rejected, balances).unwrap().len(), 4);
+    assert_eq!(stakes, before);
+
+    // Each signed staking transaction takes effect once: a replay in a
+    // later block, or a repeat within one, is rejected by its nonce.
+    assert_eq!((stakes.next_nonce(&carol.address()), stakes.next_nonce(&dave.address())), (1, 1));
+    let unbond = transfer(&carol, UNSTAKE_ADDRESS, 100, 1);
+    let replayed = Block::with_transactions(
+        2,
+        &block.hash,
+        0,
+        EMPTY_ROOT,
+        vec![block.transactions[0].clone(), unbond.clone(), unbond.clone(), transfer(&carol, UNSTAKE_ADDRESS, 100, 3)],
+    );
+    assert_eq!(stakes.apply_block(&replayed, balances).unwrap().len(), 3);
+    assert_eq!((stakes.stake(&carol.address()), stakes.next_nonce(&carol.address())), (600, 2));
+    let copy: StakeRegistry = serde_json::from_str(&serde_json::to_string(&stakes).unwrap()).unwrap();
+    assert_eq!(copy, stakes);
+    stakes = before;
+    // The block hash covers signatures, which decide what a block does.
+    let mut stripped = block.clone();
+    stripped.transactions[0].signature.clear();
+    assert_ne!(stripped.compute_hash(), block.hash);
+
+    // Proposers are drawn in proportion to stake, and the schedule depends
+    // only on the seed and the stakes, so every node computes the same one.
+    let seed = Block::new(0, "", genesis.timestamp, EMPTY_ROOT).hash;
+    let mut counts = std::collections::HashMap::new();
+    for slot in 0..20_000 {
+        *counts.entry(stakes.proposer(&seed, slot).unwrap()).or_insert(0) += 1;
+    }
+    let share = counts[carol.address().as_str()] as f64 / 20_000.0;
+    assert!((share - 700.0 / 850.0).abs() < 0.02, "carol proposed {} of slots", share);
+    let copy: StakeRegistry = serde_json::from_str(&serde_json::to_string(&stakes).unwrap()).unwrap();
+    assert!((0..1000).all(|slot| copy.proposer(&seed, slot) == stakes.proposer(&seed, slot)));
+    assert_ne!(
+        (0..100).map(|slot| stakes.proposer(&seed, slot)).collect::<Vec<_>>(),
+        (0..100).map(|slot| stakes.proposer("another seed", slot)).collect::<Vec<_>>()
+    );
+    assert_eq!(StakeRegistry::default().proposer(&seed, 0), None);
+
+    // Slots count from genesis; draws use the first block of the previous
+    // epoch.
+    assert_eq!(stake::slot(1000, 10, 1000), 0);
+    assert_eq!(stake::slot(1000, 10, 1019), 1);
+    assert_eq!(stake::seed_height(1), 0);
+    assert_eq!(stake::seed_height(2 * EPOCH_LENGTH - 1), 0);
+    assert_eq!(stake::seed_height(2 * EPOCH_LENGTH), EPOCH_LENGTH);
+    assert_eq!(stake::seed_height(3 * EPOCH_LENGTH + 5), 2 * EPOCH_LENGTH);
+
+    // Registries are kept per block, so each fork has its own. They can be
+    // written with their block, and are pruned with it.
+    let storage = Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Pruned { keep_blocks: 2 }).unwrap();
+    storage.put_stakes(&block.hash, &stakes).unwrap();
+    assert_eq!(storage.stakes(&block.hash).unwrap(), Some(stakes.clone()));
+    assert_eq!(storage.stakes(&seed).unwrap(), None);
+    let mut chain = vec![Block::new(0, "", 0, EMPTY_ROOT)];
+    for height in 1..4 {
+        chain.push(Block::new(height, &chain[height as usize - 1].hash, height, EMPTY_ROOT));
+    }
+    storage.add_block(chain[0].clone()).unwrap();
+    storage.store_block_with_stakes(&chain[1], &stakes).unwrap();
+    assert_eq!(storage.get_block(&chain[1].hash).unwrap(), Some(chain[1].clone()));
+    assert_eq!(storage.stakes(&chain[1].hash).unwrap(), Some(stakes.clone()));
+    for block in &chain[1..3] {
+        storage.add_block(block.clone()).unwrap();
+    }
+    storage.store_block_with_stakes(&chain[3], &stakes).unwrap();
+    storage.add_block(chain[3].clone()).unwrap();
+    assert_eq!(storage.stakes(&chain[1].hash).unwrap(), None);
+    assert_eq!(storage.stakes(&chain[3].hash).unwrap(), Some(stakes));
+
+    // A block counts only if the slot's proposer signed it; writing its
+    // address into `proposer` is not enough.
+    let storage = Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Archive).unwrap();
+    let root = Block::new(0, "", genesis.timestamp, EMPTY_ROOT);
+    storage.add_block(root.clone()).unwrap();
+    let (block_time, slot, now) = (10, 1, genesis.timestamp + 100);
+    let expected = rules::slot_proposer(&storage, &genesis, &root, slot).unwrap().unwrap();
+    let (owner, other) = if expected == alice.address() { (&alice, &bob) } else { (&bob, &alice) };
+    let proposal = |proposer: &str, key: &Keypair| {
+        let mut block = Block::new(1, &root.hash, genesis.timestamp + slot * block_time, EMPTY_ROOT);
+        block.proposer = proposer.to_string();
+        block.hash = block.compute_hash();
+        block.sign_proposal(key);
+        block
+    };
+    let check = |block: &Block| rules::check_proposal(&storage, &genesis, block_time, block, now);
+    assert_eq!(check(&proposal(&expected, owner)).unwrap().total(), 300);
+    let mut unsigned = proposal(&expected, owner);
+    unsigned.proposer_signature.clear();
+    for bad in [proposal(&expected, other), unsigned, proposal(&other.address(), other)] {
+        assert!(matches!(check(&bad), Err(ConsensusError::InvalidBlock(_))));
+    }
+}
+
+pub fn test_hybrid_consensus() {
+    let [alice, bob, carol, mallory, nobody] = [1, 2, 3, 4, 5].map(|seed| Keypair::from_seed([seed; 32]));
+    let mut genesis = Genesis::new();
+    genesis.difficulty = 64;
+    for (validator, stake) in [(&alice, 30), (&bob, 30), (&carol, 20), (&mallory, 20)] {
+        genesis.add_validator(&validator.address(), stake);
+    }
+    let genesis = Arc::new(genesis);
+    let genesis_block = Block::new(0, "", genesis.timestamp, EMPTY_ROOT);
+    let honest_validators = [&alice, &bob, &carol];
+    let vote = |chain: &HybridChain, key: &Keypair, block: &Block, approve| {
+        chain.vote(&key.address(), &block.hash, approve, &hybrid::sign_vote(key, &block.hash, approve))
+    };
+    let new_chain = |pow_weight, ratification_threshold| {
+        let storage = Arc::new(Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Archive).unwrap());
+        storage.add_block(genesis_block.clone()).unwrap();
+        let config = HybridConfig {
+            pow_weight,
+            ratification_threshold,
+        };
+        let chain = HybridChain::new(storage.clone(), genesis.clone(), Retarget::new(10, genesis.difficulty), config);
+        (storage, chain)
+    };
+    let miner = Miner::new(2);
+    let seal = |chain: &HybridChain, parent: &Block, timestamp: u64| {
+        let template = chain.template(parent, Vec::new(), timestamp).unwrap();
+        miner.mine(&template, &AtomicBool::new(false)).unwrap()
+    };
+    let head = |storage: &Storage| storage.latest_block().unwrap().unwrap();
+
+    // Mined blocks wait for two thirds of the stake before they count.
+    let (storage, chain) = new_chain(50, 67);
+    let first = seal(&chain, &genesis_block, genesis.timestamp + 15);
+    assert!(!chain.import(&first, first.timestamp).unwrap());
+    assert_eq!(head(&storage), genesis_block);
+    assert!(chain.should_approve(&first).unwrap());
+    assert!(!vote(&chain, &alice, &first, true).unwrap());
+    assert!(!vote(&chain, &bob, &first, true).unwrap());
+    assert!(matches!(vote(&chain, &nobody, &first, true), Err(ConsensusError::InvalidVote(_))));
+    assert_eq!(chain.tally(&first).unwrap(), (60, 100));
+
+    // Votes must be signed by the validator they claim to be from, for the
+    // same block and choice.
+    let forged = hybrid::sign_vote(&mallory, &first.hash, true);
+    assert!(matches!(chain.vote(&carol.address(), &first.hash, true, &forged), Err(ConsensusError::InvalidVote(_))));
+    let rejection = hybrid::sign_vote(&carol, &first.hash, false);
+    assert!(chain.vote(&carol.address(), &first.hash, true, &rejection).is_err());
+    assert!(chain.vote(&carol.address(), &first.hash, true, "").is_err());
+    assert_eq!(chain.tally(&first).unwrap(), (60, 100));
+    assert!(vote(&chain, &carol, &first, true).unwrap());
+    assert_eq!(head(&storage), first);
+    assert_eq!(chain.weight(&first).unwrap(), first.difficulty as u128 * 90);
+
+    // The honest miners hold 40% of the hashrate and all stake but
+    // mallory's; the rest of the hashrate mines a private branch.
+    let mut honest = vec![genesis_block.clone(), first];
+    for i in 2..=20 {
+        let block = seal(&chain, &honest[i - 1], genesis.timestamp + 15 * i as u64);
+        chain.import(&block, block.timestamp).unwrap();
+        for validator in honest_validators {
+            assert!(chain.should_approve(&block).unwrap());
+            vote(&chain, validator, &block, true).unwrap();
+        }
+        assert_eq!(head(&storage), block);
+        honest.push(block);
+    }
+    let (_, private) = new_chain(100, 0);
+    let mut attack = vec![genesis_block.clone()];
+    for i in 1..=30 {
+        let block = seal(&private, &attack[i - 1], genesis.timestamp + 10 * i as u64);
+        assert!(private.import(&block, block.timestamp).unwrap());
+        attack.push(block);
+    }
+    let work = |blocks: &[Block]| blocks.iter().map(|block| block.difficulty).sum::<u64>();
+    assert!(work(&attack) > work(&honest), "attack {} honest {}", work(&attack), work(&honest));
+
+    // Published, the heavier branch is valid work but gets no honest
+    // votes, and mallory's stake alone cannot ratify it.
+    let now = genesis.timestamp + 300;
+    for block in &attack[1..] {
+        assert!(!chain.import(block, now).unwrap());
+        assert!(!chain.should_approve(block).unwrap());
+        for validator in honest_validators {
+            vote(&chain, validator, block, false).unwrap();
+        }
+        assert!(!vote(&chain, &mallory, block, true).unwrap());
+        assert!(vote(&chain, &nobody, block, true).is_err());
+    }
+    assert_eq!(chain.tally(&attack[30]).unwrap(), (20, 100));
+    assert!(!chain.is_ratified(&attack[1]).unwrap());
+    assert_eq!(head(&storage), honest[20]);
+    assert!(storage.is_canonical(&honest[1]).unwrap());
+    assert_eq!(storage.get_block(&attack[30].hash).unwrap(), Some(attack[30].clone()));
+
+    // Without ratification the same branch takes over on work alone.
+    let (storage, chain) = new_chain(100, 0);
+    for block in &honest[1..] {
+        assert!(chain.import(block, block.timestamp).unwrap());
+    }
+    for block in &attack[1..] {
+        chain.import(block, now).unwrap();
+    }
+    assert_eq!(head(&storage), attack[30]);
+    assert!(!storage.is_canonical(&honest[1]).unwrap());
+
+    // Between ratified branches, `pow_weight` sets how much approval
+    // counts: with equal work, the more approved block wins only when the
+    // weight is below 100.
+    let sibling = seal(&chain, &genesis_block, genesis.timestamp + 20);
+    assert_eq!(sibling.difficulty, honest[1].difficulty);
+    for pow_weight in [100, 0] {
+        let (storage, chain) = new_chain(pow_weight, 50);
+        chain.import(&honest[1], now).unwrap();
+        chain.import(&sibling, now).unwrap();
+        for validator in honest_validators {
+            vote(&chain, validator, &honest[1], true).unwrap();
+        }
+        assert_eq!(head(&storage), honest[1]);
+        for validator in [&alice, &bob, &carol, &mallory] {
+            vote(&chain, validator, &sibling, true).unwrap();
+        }
+        let expected = if pow_weight == 100 { &honest[1] } else { &sibling };
+        assert_eq!(&head(&storage), expected);
+    }
+
+    // Votes may arrive before their block, a bounded number from each
+    // validator, and count once it is imported.
+    let (storage, chain) = new_chain(50, 67);
+    let early = seal(&chain, &genesis_block, genesis.timestamp + 15);
+    for validator in [&alice, &bob, &carol] {
+        assert!(!vote(&chain, validator, &early, true).unwrap());
+    }
+    assert!(matches!(vote(&chain, &nobody, &early, true), Err(ConsensusError::InvalidVote(_))));
+    for timestamp in 0..16 {
+        vote(&chain, &mallory, &Block::new(1, "", timestamp, EMPTY_ROOT), true).unwrap();
+    }
+    assert!(matches!(vote(&chain, &mallory, &early, true), Err(ConsensusError::InvalidVote(_))));
+    assert_eq!(storage.get_vote(&alice.address(), &early).unwrap(), None);
+    assert!(chain.import(&early, early.timestamp).unwrap());
+    assert_eq!(chain.tally(&early).unwrap(), (80, 100));
+
+    // Votes are deleted with their block.
+    chain.import(&sibling, now).unwrap();
+    vote(&chain, &alice, &sibling, false).unwrap();
+    assert_eq!(storage.get_vote(&alice.address(), &sibling).unwrap(), Some(false));
+    assert_eq!(storage.prune_branch(&sibling).unwrap(), 1);
+    assert_eq!(storage.get_vote(&alice.address(), &sibling).unwrap(), None);
+
+    // A chain without stake ratifies nothing, whatever the threshold.
+    let mut unstaked = Genesis::new();
+    unstaked.difficulty = genesis.difficulty;
+    let storage = Arc::new(Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Archive).unwrap());
+    storage.add_block(genesis_block.clone()).unwrap();
+    let config = HybridConfig {
+        pow_weight: 100,
+        ratification_threshold: 0,
+    };
+    let chain = HybridChain::new(storage.clone(), Arc::new(unstaked), Retarget::new(10, genesis.difficulty), config);
+    assert!(!chain.import(&honest[1], now).unwrap());
+    assert!(!chain.is_ratified(&honest[1]).unwrap());
+
+    // The node votes with the key in its config, a hex seed checked when
+    // the config is loaded.
+    let mut config = Config::new();
+    config.node.validator_key = Some("01".repeat(32));
+    assert_eq!(config.validate(), Ok(()));
+    assert_eq!(Keypair::from_hex(&"01".repeat(32)).unwrap().address(), alice.address());
+    config.node.validator_key = Some("not a key".to_string());
+    assert!(config.validate().unwrap_err().contains("validator_key"));
+}
+
+pub fn test_bft_voting() {
+    // Safety and progress with f Byzantine validators out of 3f + 1, on a
+    // fast network and on one slow enough to time rounds out.
+    for (validators, faulty, max_delay, seed) in [(4, 1, 50, 1), (7, 2, 50, 2), (7, 2, 2500, 3)] {
+        let mut network = BftNetwork::new(validators, faulty, Fault::Equivocate, max_delay, seed);
+        network.run(5, 600_000);
+        let honest = &network.decisions[..validators - faulty];
+        for decisions in honest {
+            assert!(decisions.len() >= 5, "{} of {} validators faulty: {} decisions", faulty, validators, decisions.len());
+        }
+        for height in 0..5 {
+            let (block, certificate) = &honest[0][height];
+            assert_eq!(block.height, height as u64 + 1);
+            assert_eq!(certificate.block_hash, block.hash);
+            for decisions in honest {
+                assert_eq!(decisions[height].0, *block);
+                assert!(decisions[height].1.verify(&network.validators));
+            }
+        }
+        assert!(network.equivocations > 0);
+    }
+
+    // With more than f validators silent there is never a quorum, so no
+    // block is decided at all.
+    let mut network = BftNetwork::new(4, 2, Fault::Silent, 50, 4);
+    network.run(1, 120_000);
+    assert!(network.decisions.iter().all(Vec::is_empty));
+
+    // A certificate needs precommits for its block from two thirds of the
+    // stake, each counted once.
+    let mut network = BftNetwork::new(4, 0, Fault::Silent, 50, 5);
+    network.run(1, 60_000);
+    let (_, certificate) = network.decisions[0][0].clone();
+    assert!(certificate.verify(&network.validators));
+    let mut short = certificate.clone();
+    short.precommits.truncate(2);
+    assert!(!short.verify(&network.validators));
+    let mut padded = short.clone();
+    padded.precommits.push(padded.precommits[0].clone());
+    assert!(!padded.verify(&network.validators));
+    let mut other = certificate.clone();
+    other.block_hash = "another block".to_string();
+    assert!(!other.verify(&network.validators));
+    let mut nil = certificate.clone();
+    nil.precommits[0].block_hash = None;
+    assert!(!nil.verify(&network.validators));
+
+    // Each precommit must carry its validator's signature, so neither a
+    // certificate nor a peer can vote for another validator.
+    let mut forged = certificate.clone();
+    forged.precommits[0].signature = forged.precommits[1].signature.clone();
+    assert!(!forged.verify(&network.validators));
+    let mut unsigned = certificate.clone();
+    unsigned.precommits[0].signature.clear();
+    assert!(!unsigned.verify(&network.validators));
+    let block = network.decisions[0][0].0.clone();
+    let node = network.nodes[0].as_mut().unwrap();
+    let mut vote = Vote::signed(&network.keys[1], VoteType::Prevote, node.height(), 5, None);
+    vote.validator = network.names[2].clone();
+    assert!(node.handle_message(Message::Vote(vote)).is_empty());
+    let impostor = Keypair::from_seed([99; 32]);
+    let mut proposal = Proposal::signed(&impostor, node.height(), 0, block, None);
+    proposal.proposer = bft::proposer(&network.validators, node.height(), 0).unwrap().to_string();
+    assert!(node.handle_message(Message::Proposal(proposal)).is_empty());
+
+    // Votes more than eight rounds ahead are dropped, so they cannot make a
+    // validator skip there; votes nearer by can.
+    let (height, round) = (node.height(), node.round());
+    for key in &network.keys[1..3] {
+        node.handle_message(Message::Vote(Vote::signed(key, VoteType::Prevote, height, round + 9, None)));
+    }
+    assert_eq!(node.round(), round);
+    for key in &network.keys[1..3] {
+        node.handle_message(Message::Vote(Vote::signed(key, VoteType::Prevote, height, round + 8, None)));
+    }
+    assert_eq!(node.round(), round + 8);
+
+    // Messages for later heights are only kept if a validator signed them,
+    // for one of the first rounds, and each validator's share is bounded.
+    let mut vote = Vote::signed(&network.keys[1], VoteType::Prevote, height + 1, 0, None);
+    vote.validator = network.names[2].clone();
+    node.handle_message(Message::Vote(vote));
+    node.handle_message(Message::Vote(Vote::signed(&impostor, VoteType::Prevote, height + 1, 0, None)));
+    node.handle_message(Message::Vote(Vote::signed(&network.keys[1], VoteType::Prevote, height + 1, 9, None)));
+    assert_eq!(node.buffered(), 0);
+    for i in 0..100 {
+        let vote = Vote::signed(&network.keys[1], VoteType::Prevote, height + 1, i % 9, Some(i.to_string()));
+        node.handle_message(Message::Vote(vote.clone()));
+        node.handle_message(Message::Vote(vote));
+    }
+    assert_eq!(node.buffered(), 27);
+    node.handle_message(Message::Vote(Vote::signed(&network.keys[2], VoteType::Prevote, height + 1, 0, None)));
+    assert_eq!(node.buffered(), 28);
+
+    // The validators are looked up for every height, from the block decided
+    // before it. Once the silent validator holds most of the stake, the
+    // others can no longer decide.
+    let mut network = BftNetwork::new(4, 1, Fault::Silent, 50, 6);
+    let mut genesis = Genesis::new();
+    for (i, name) in network.names.iter().enumerate() {
+        genesis.add_validator(name, if i == 3 { 100 } else { 10 });
+    }
+    let validators = StakeRegistry::from_genesis(&genesis);
+    network.change_validators(3, validators.clone());
+    network.run(5, 120_000);
+    for decisions in &network.decisions[..3] {
+        assert_eq!(decisions.len(), 2);
+        assert!(!decisions[1].1.verify(&validators));
+        assert!(decisions[1].1.verify(&network.validators));
+    }
+}
+
+pub fn test_fork_choice() {
+    let storage = Arc::new(Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Archive).unwrap());
+    let genesis = Block::new(0, "", 0, EMPTY_ROOT);
+    storage.add_block(genesis.clone()).unwrap();
+    let tree = BlockTree::new(storage.clone(), HeaviestWork);
+    let payment = |from: &str, nonce| Transaction {
+        from: from.to_string(),
+        to: "merchant".to_string(),
+        amount: 10,
+        nonce,
+        signature: String::new(),
+    };
+    let block = |parent: &Block, difficulty, transactions| {
+        let mut block = Block::with_transactions(parent.height + 1, &parent.hash, parent.timestamp + 1, EMPTY_ROOT, transactions);
+        block.difficulty = difficulty;
+        block.hash = block.compute_hash();
+        block
+    };
+    let head = |storage: &Storage| storage.latest_block().unwrap().unwrap();
+    let shared = payment("carol", 0);
+
+    // Extending the head moves it.
+    let a1 = block(&genesis, 10, vec![payment("alice", 0)]);
+    let a2 = block(&a1, 10, vec![payment("alice", 1)]);
+    let a3 = block(&a2, 10, vec![payment("alice", 2), shared.clone()]);
+    for block in [&a1, &a2, &a3] {
+        assert!(tree.insert(block).unwrap().is_some());
+    }
+    assert_eq!(head(&storage), a3);
+
+    // A lighter fork is kept but not followed, and so is an equal one.
+    let b2 = block(&a1, 5, vec![payment("bob", 0)]);
+    let b3 = block(&b2, 5, vec![shared.clone()]);
+    assert!(tree.insert(&b2).unwrap().is_none());
+    assert!(tree.insert(&b3).unwrap().is_none());
+    let c2 = block(&a1, 10, Vec::new());
+    let c3 = block(&c2, 10, Vec::new());
+    assert!(tree.insert(&c2).unwrap().is_none());
+    assert!(tree.insert(&c3).unwrap().is_none());
+    assert_eq!(head(&storage), a3);
+    let mut tips: Vec<_> = storage.tips().unwrap().into_iter().map(|tip| tip.hash).collect();
+    tips.sort();
+    let mut expected = vec![a3.hash.clone(), b3.hash.clone(), c3.hash.clone()];
+    expected.sort();
+    assert_eq!(tips, expected);
+
+    // Once it has more work, the head switches to it. The unwound blocks'
+    // transactions go back to the pending pool, except those the new branch
+    // includes as well.
+    let b4 = block(&b3, 15, vec![payment("bob", 1)]);
+    let reorg = tree.insert(&b4).unwrap().unwrap();
+    assert_eq!(reorg.common_ancestor, a1.hash);
+    assert_eq!(reorg.unwound, vec![a2.clone(), a3.clone()]);
+    assert_eq!(reorg.applied, vec![b2.clone(), b3.clone(), b4.clone()]);
+    assert_eq!(reorg.requeued, 2);
+    assert_eq!(head(&storage), b4);
+    assert_eq!(storage.chain.block(2).unwrap(), Some(b2.clone()));
+    assert!(storage.is_canonical(&a1).unwrap());
+    assert!(!storage.is_canonical(&a3).unwrap());
+    let pending: HashSet<_> = storage.pending_transactions(10).unwrap().iter().map(Transaction::hash).collect();
+    let requeued: HashSet<_> = [payment("alice", 1).hash(), payment("alice", 2).hash()].into_iter().collect();
+    assert_eq!(pending, requeued);
+    assert!(storage.chain.transaction(&payment("alice", 2).hash()).unwrap().is_none());
+    let (location, _) = storage.chain.transaction(&shared.hash()).unwrap().unwrap();
+    assert_eq!(location.block_hash, b3.hash);
+
+    // Switching back takes the transactions out of the pool again.
+    let a4 = block(&a3, 10, Vec::new());
+    let reorg = tree.insert(&a4).unwrap().unwrap();
+    assert_eq!(reorg.unwound, vec![b2.clone(), b3.clone(), b4.clone()]);
+    assert_eq!(reorg.requeued, 2);
+    assert_eq!(head(&storage), a4);
+    let pending: HashSet<_> = storage.pending_transactions(10).unwrap().iter().map(Transaction::hash).collect();
+    let requeued: HashSet<_> = [payment("bob", 0).hash(), payment("bob", 1).hash()].into_iter().collect();
+    assert_eq!(pending, requeued);
+
+    // Blocks need a known parent.
+    let orphan = block(&Block::new(7, "unknown", 0, EMPTY_ROOT), 100, Vec::new());
+    assert!(matches!(tree.insert(&orphan), Err(ConsensusError::InvalidBlock(_))));
+
+    // Under proof of stake, the branch more stake attested wins; with no
+    // votes either way, the current chain stays.
+    let mut stakes = Genesis::new();
+    stakes.add_validator("alice", 30);
+    stakes.add_validator("bob", 70);
+    let storage = Arc::new(Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Archive).unwrap());
+    storage.add_block(genesis.clone()).unwrap();
+    let tree = BlockTree::new(storage.clone(), MostAttested::new(storage.clone(), Arc::new(stakes)));
+    let x1 = block(&genesis, 0, vec![payment("alice", 0)]);
+    let y1 = block(&genesis, 0, vec![payment("bob", 0)]);
+    assert!(tree.insert(&x1).unwrap().is_some());
+    assert!(tree.insert(&y1).unwrap().is_none());
+    storage.put_vote("alice", &x1.hash, true).unwrap();
+    storage.put_vote("bob", &y1.hash, true).unwrap();
+    assert_eq!(tree.fork_choice().weight(&y1).unwrap(), 70);
+    assert_eq!(tree.update_head().unwrap().unwrap().applied, vec![y1.clone()]);
+    assert_eq!(head(&storage), y1);
+    assert!(tree.update_head().unwrap().is_none());
+
+    // A fork from below the oldest kept block can never win, so it is
+    // deleted with its stakes once the window passes it.
+    let storage = Arc::new(Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Pruned { keep_blocks: 3 }).unwrap());
+    storage.add_block(genesis.clone()).unwrap();
+    let tree = BlockTree::new(storage.clone(), HeaviestWork);
+    let mut chain = vec![genesis.clone()];
+    let fork = block(&genesis, 5, Vec::new());
+    tree.insert(&block(&genesis, 10, Vec::new())).unwrap();
+    chain.push(head(&storage));
+    tree.insert_with_stakes(&fork, &StakeRegistry::default()).unwrap();
+    assert_eq!(storage.tips().unwrap().len(), 2);
+    for height in 2..=5 {
+        let next = block(&chain[height - 1], 10, Vec::new());
+        tree.insert(&next).unwrap();
+        chain.push(next);
+    }
+    assert_eq!(storage.chain.oldest().unwrap(), 3);
+    assert_eq!(storage.tips().unwrap(), vec![chain[5].clone()]);
+    assert_eq!(storage.get_block(&fork.hash).unwrap(), None);
+    assert_eq!(storage.stakes(&fork.hash).unwrap(), None);
+
+    // Without pruning or finality, a fork more than MAX_FORK_DEPTH blocks
+    // below the head is deleted all the same, and blocks on it are refused.
+    let storage = Arc::new(Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Archive).unwrap());
+    storage.add_block(genesis.clone()).unwrap();
+    let tree = BlockTree::new(storage.clone(), HeaviestWork);
+    let mut chain = vec![genesis.clone()];
+    let fork = block(&genesis, 5, Vec::new());
+    tree.insert(&fork).unwrap();
+    for height in 1..=MAX_FORK_DEPTH as usize + 2 {
+        let next = block(&chain[height - 1], 10, Vec::new());
+        tree.insert(&next).unwrap();
+        chain.push(next);
+        if height == MAX_FORK_DEPTH as usize {
+            assert_eq!(storage.tips().unwrap().len(), 2);
+        }
+    }
+    assert_eq!(storage.tips().unwrap(), vec![chain[chain.len() - 1].clone()]);
+    assert_eq!(storage.get_block(&fork.hash).unwrap(), None);
+    assert!(matches!(tree.insert(&block(&genesis, 10_000, Vec::new())), Err(ConsensusError::InvalidBlock(_))));
+    let reorg = tree.insert(&block(&chain[2], 10_000, Vec::new())).unwrap().unwrap();
+    assert_eq!(reorg.unwound.len() as u64, MAX_FORK_DEPTH);
+}
+
+pub fn test_finality() {
+    let [alice, bob, carol, dave, nobody] = [1, 2, 3, 4, 5].map(|seed| Keypair::from_seed([seed; 32]));
+    let mut genesis = Genesis::new();
+    for (validator, stake) in [(&alice, 40), (&bob, 30), (&carol, 20), (&dave, 10)] {
+        genesis.add_validator(&validator.address(), stake);
+    }
+    let genesis = Arc::new(genesis);
+    let config = FinalityConfig { checkpoint_interval: 4 };
+    let new_storage = || Arc::new(Storage::with_backend(Box::new(MemoryConnection::new()), Pruning::Archive).unwrap());
+    let block = |parent: &Block, difficulty| {
+        let mut block = Block::new(parent.height + 1, &parent.hash, parent.timestamp + 1, EMPTY_ROOT);
+        block.difficulty = difficulty;
+        block.hash = block.compute_hash();
+        block
+    };
+    let vote = |block: &Block, key: &Keypair| CheckpointVote::signed(key, block.height, &block.hash);
+    let storage = new_storage();
+    let tree = BlockTree::new(storage.clone(), HeaviestWork);
+    let gadget = FinalityGadget::new(storage.clone(), genesis.clone(), config);
+    let mut chain = vec![Block::new(0, "", genesis.timestamp, EMPTY_ROOT)];
+    storage.add_block(chain[0].clone()).unwrap();
+    for height in 1..=8 {
+        let next = block(&chain[height - 1], 10);
+        tree.insert(&next).unwrap();
+        chain.push(next);
+    }
+    assert!(gadget.is_checkpoint(4) && gadget.is_checkpoint(8));
+    assert!(!gadget.is_checkpoint(0) && !gadget.is_checkpoint(3));
+    assert!(!FinalityGadget::new(storage.clone(), genesis.clone(), FinalityConfig { checkpoint_interval: 0 }).is_checkpoint(4));
+    assert!(matches!(gadget.vote(vote(&chain[3], &alice)), Err(ConsensusError::InvalidBlock(_))));
+
+    // Only signed votes from staked validators count.
+    let mut forged = vote(&chain[4], &dave);
+    forged.validator = alice.address();
+    for bad in [forged, vote(&chain[4], &nobody)] {
+        assert!(matches!(gadget.vote(bad), Err(ConsensusError::InvalidVote(_))));
+    }
+
+    // A validator signs each checkpoint height once, even across restarts.
+    assert_eq!(gadget.sign(&alice, &chain[4]).unwrap(), Some(vote(&chain[4], &alice)));
+    assert_eq!(gadget.sign(&alice, &chain[4]).unwrap(), None);
+    assert_eq!(gadget.sign(&nobody, &chain[4]).unwrap(), None);
+    assert_eq!(gadget.sign(&dave, &chain[8]).unwrap(), Some(vote(&chain[8], &dave)));
+    assert_eq!(storage.signed_checkpoint(&dave.address(), 8).unwrap(), Some(chain[8].hash.clone()));
+
+    // A checkpoint is final once more than two thirds of the stake signs it.
+    assert!(gadget.should_sign(&chain[4]).unwrap());
+    assert_eq!(gadget.vote(vote(&chain[4], &carol)).unwrap(), None);
+    let proof = gadget.vote(vote(&chain[4], &bob)).unwrap().unwrap();
+    assert_eq!(proof.votes.len(), 3);
+    assert!(proof.verify(&gadget.validators(&chain[4]).unwrap()));
+    assert_eq!(gadget.proof(&chain[4].hash).unwrap(), Some(proof.clone()));
+    assert_eq!(
+        storage.finalized().unwrap(),
+        Some(Checkpoint {
+            height: 4,
+            hash: chain[4].hash.clone()
+        })
+    );
+    assert!(gadget.is_final(&chain[3]).unwrap() && !gadget.is_final(&chain[5]).unwrap());
+    assert!(!gadget.should_sign(&chain[4]).unwrap());
+    assert!(matches!(gadget.vote(vote(&chain[4], &dave)), Err(ConsensusError::InvalidVote(_))));
+    assert!(storage.checkpoint_votes(&chain[4].hash).unwrap().is_empty());
+    assert_eq!(storage.signed_checkpoint(&alice.address(), 4).unwrap(), None);
+    let mut short = proof.clone();
+    short.votes.retain(|vote| vote.validator != bob.address());
+    assert!(!short.verify(&gadget.validators(&chain[4]).unwrap()));
+    let mut mixed = proof.clone();
+    mixed.votes[0].block_hash = chain[3].hash.clone();
+    assert!(!mixed.verify(&gadget.validators(&chain[4]).unwrap()));
+    let mut unsigned = proof.clone();
+    unsigned.votes[0].signature = unsigned.votes[1].signature.clone();
+    assert!(!unsigned.verify(&gadget.validators(&chain[4]).unwrap()));
+
+    // No branch may replace a final block, however heavy.
+    let forked = block(&chain[2], 1000);
+    assert!(matches!(tree.insert(&forked), Err(ConsensusError::InvalidBlock(_))));
+    let deep = [forked.clone(), block(&forked, 1000)];
+    match storage.reorg(&deep) {
+        Err(StorageError::DbError(DbError::Finalized { height })) => assert_eq!(height, 4),
+        _ => panic!("expected a finalized error"),
+    }
+    assert!(storage.add_block(chain[4].clone()).is_err());
+    assert_eq!(storage.latest_block().unwrap(), Some(chain[8].clone()));
+
+    // Above the checkpoint, forks are still resolved as usual.
+    let mut fork = vec![block(&chain[4], 100)];
+    assert_eq!(tree.insert(&fork[0]).unwrap().unwrap().unwound, chain[5..].to_vec());
+    for _ in 6..=8 {
+        let next = block(fork.last().unwrap(), 100);
+        tree.insert(&next).unwrap();
+        fork.push(next);
+    }
+
+    // Having signed the checkpoint at height 8 before the reorg, dave does
+    // not sign the one that replaced it, nor after a restart.
+    assert!(gadget.should_sign(&fork[3]).unwrap());
+    assert_eq!(gadget.sign(&dave, &fork[3]).unwrap(), None);
+    let restarted = FinalityGadget::new(storage.clone(), genesis.clone(), config);
+    assert_eq!(restarted.sign(&dave, &fork[3]).unwrap(), None);
+
+    // Votes for a checkpoint this node has reorged away from are kept, and
+    // count once it is canonical again. `update` has already examined the
+    // checkpoint that replaced it, so it looks again from the fork point.
+    for validator in [&alice, &bob, &carol] {
+        assert_eq!(gadget.vote(vote(&chain[8], validator)).unwrap(), None);
+    }
+    assert!(!gadget.should_sign(&chain[8]).unwrap());
+    assert_eq!(gadget.update().unwrap(), None);
+    let heavier = block(&chain[8], 1000);
+    tree.insert(&heavier).unwrap();
+    let proof = gadget.update().unwrap().unwrap();
+    assert_eq!((proof.height, proof.block_hash.clone()), (8, chain[8].hash.clone()));
+    assert!(gadget.is_final(&chain[8]).unwrap());
+    assert_eq!(gadget.update().unwrap(), None);
+    assert_eq!(storage.signed_checkpoint(&dave.address(), 8).unwrap(), None);
+
+    // Another node checks the proof against its own stake registry.
+    let peer = new_storage();
+    for block in &chain {
+        peer.add_block(block.clone()).unwrap();
+    }
+    let peer_gadget = FinalityGadget::new(peer.clone(), genesis.clone(), config);
+    assert!(matches!(peer_gadget.import_proof(&short), Err(ConsensusError::InvalidBlock(_))));
+    assert!(peer_gadget.import_proof(&proof).unwrap());
+    assert_eq!(peer.finalized().unwrap().map(|checkpoint| checkpoint.height), Some(8));
+    assert!(!peer_gadget.import_proof(&proof).unwrap());
+    let mut unknown = proof.clone();
+    unknown.block_hash = heavier.hash.clone();
+    assert!(matches!(peer_gadget.import_proof(&unknown), Err(ConsensusError::MissingBlock(_))));
+
+    // Votes may arrive before their checkpoint, within one interval of the
+    // head and from staked validators, and count once it is stored.
+    let mut ahead = vec![heavier.clone()];
+    for _ in 10..=16 {
+        let next = block(ahead.last().unwrap(), 10);
+        ahead.push(next);
+    }
+    for validator in [&alice, &bob, &carol] {
+        assert_eq!(gadget.vote(vote(&ahead[3], validator)).unwrap(), None);
+    }
+    for early in [vote(&ahead[3], &nobody), vote(&ahead[7], &alice)] {
+        assert!(matches!(gadget.vote(early), Err(ConsensusError::InvalidVote(_))));
+    }
+    assert!(storage.checkpoint_votes(&ahead[3].hash).unwrap().is_empty());
+    for next in &ahead[1..=3] {
+        tree.insert(next).unwrap();
+    }
+    let proof = gadget.update().unwrap().unwrap();
+    assert_eq!((proof.height, proof.block_hash), (12, ahead[3].hash.clone()));
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Fault {
+    // Sends nothing.
+    Silent,
+    // Proposes two blocks and votes for both the proposal and nil, each to
+    // a different half of the network.
+    Equivocate,
+}
+
+struct BftTestApp {
+    validator: String,
+    validators: StakeRegistry,
+    // Validators from the given height on, replacing `validators`.
+    later: Option<(u64, StakeRegistry)>,
+}
+
+impl Application for BftTestApp {
+    fn propose(&mut self, height: u64) -> Option<Block> {
+        let mut block = Block::new(height, "", height, EMPTY_ROOT);
+        block.proposer = self.validator.clone();
+        block.hash = block.compute_hash();
+        Some(block)
+    }
+
+    fn is_valid(&self, _block: &Block) -> bool {
+        true
+    }
+
+    fn validators(&mut self, parent: &Block) -> StakeRegistry {
+        match &self.later {
+            Some((height, validators)) if parent.height + 1 >= *height => validators.clone(),
+            _ => self.validators.clone(),
+        }
+    }
+}
+
+enum BftEvent {
+    Deliver(usize, Box<Message>),
+    Timeout(usize, Timeout),
+}
+
+// Validators exchanging BFT messages over a simulated network. Every
+// message arrives after a random delay of up to `max_delay` milliseconds,
+// and timeouts fire on the simulated clock. The last validators are faulty.
+struct BftNetwork {
+    keys: Vec<Keypair>,
+    names: Vec<String>,
+    validators: StakeRegistry,
+    // `None` for faulty validators.
+    nodes: Vec<Option<Tendermint<BftTestApp>>>,
+    fault: Fault,
+    // Pending events by (time, sequence number).
+    events: BTreeMap<(u64, u64), BftEvent>,
+    clock: u64,
+    sequence: u64,
+    seed: u64,
+    max_delay: u64,
+    decisions: Vec<Vec<(Block, QuorumCertificate)>>,
+    equivocations: usize,
+    // (validator, height, round) a faulty validator has attacked.
+    attacked: HashSet<(usize, u64, u32)>,
+}
+
+impl BftNetwork {
+    fn new(size: usize, faulty: usize, fault: Fault, max_delay: u64, seed: u64) -> Self {
+        let key = |i: usize| Keypair::from_seed([i as u8 + 1; 32]);
+        let keys: Vec<Keypair> = (0..size).map(key).collect();
+        let names: Vec<String> = keys.iter().map(Keypair::address).collect();
+        let mut genesis = Genesis::new();
+        for name in &names {
+            genesis.add_validator(name, 10);
+        }
+        let validators = StakeRegistry::from_genesis(&genesis);
+        let nodes = (0..size)
+            .map(|i| {
+                let app = BftTestApp {
+                    validator: names[i].clone(),
+                    validators: validators.clone(),
+                    later: None,
+                };
+                (i < size - faulty).then(|| Tendermint::new(app, Some(key(i)), validators.clone(), BftConfig::default(), 1))
+            })
+            .collect();
+        BftNetwork {
+            keys,
+            names,
+            validators,
+            nodes,
+            fault,
+            events: BTreeMap::new(),
+            clock: 0,
+            sequence: 0,
+            seed,
+            max_delay,
+            decisions: vec![Vec::new(); size],
+            equivocations: 0,
+            attacked: HashSet::new(),
+        }
+    }
+
+    // Has every honest validator's application switch to `validators` from
+    // `height` on.
+    fn change_validators(&mut self, height: u64, validators: StakeRegistry) {
+        for node in self.nodes.iter_mut().flatten() {
+            node.app_mut().later = Some((height, validators.clone()));
+        }
+    }
+
+    // Runs until every honest validator has decided `heights` blocks or
+    // `limit` milliseconds have passed.
+    fn run(&mut self, heights: usize, limit: u64) {
+        for i in 0..self.nodes.len() {
+            if let Some(node) = &mut self.nodes[i] {
+                let outputs = node.start();
+                self.dispatch(i, outputs);
+            }
+        }
+        while let Some(((time, _), event)) = self.events.pop_first() {
+            let done = (0..self.nodes.len()).all(|i| self.nodes[i].is_none() || self.decisions[i].len() >= heights);
+            if time > limit || done {
+                return;
+            }
+            self.clock = time;
+            let (to, outputs) = match event {
+                BftEvent::Deliver(to, message) => match &mut self.nodes[to] {
+                    Some(node) => (to, node.handle_message(*message)),
+                    None => {
+                        self.attack(to, *message);
+                        continue;
+                    }
+                },
+                BftEvent::Timeout(to, timeout) => match &mut self.nodes[to] {
+                    Some(node) => (to, node.handle_timeout(timeout)),
+                    None => continue,
+                },
+            };
+            self.dispatch(to, outputs);
+        }
+    }
+
+    fn dispatch(&mut self, from: usize, outputs: Vec<Output>) {
+        for output in outputs {
+            match output {
+                Output::Broadcast(message) => {
+                    for to in (0..self.nodes.len()).filter(|to| *to != from) {
+                        self.send(to, message.clone());
+                    }
+                }
+                Output::ScheduleTimeout(timeout, after) => {
+                    self.schedule(self.clock + after.as_millis() as u64, BftEvent::Timeout(from, timeout));
+                }
+                Output::Decide(block, certificate) => self.decisions[from].push((block, certificate)),
+                Output::Equivocation(..) => self.equivocations += 1,
+            }
+        }
+    }
+
+    // A faulty validator reacting to `message`.
+    fn attack(&mut self, me: usize, message: Message) {
+        if self.fault == Fault::Silent {
+            return;
+        }
+        let (height, round) = (message.height(), message.round());
+        let peers: Vec<usize> = (0..self.nodes.len()).filter(|peer| *peer != me).collect();
+        if bft::proposer(&self.validators, height, round) == Some(self.names[me].as_str())
+            && self.attacked.insert((me, height, round))
+        {
+            let blocks: Vec<Block> = (0..2)
+                .map(|variant| {
+                    let mut block = Block::new(height, "", variant, EMPTY_ROOT);
+                    block.proposer = self.names[me].clone();
+                    block.hash = block.compute_hash();
+                    block
+                })
+                .collect();
+            for &peer in &peers {
+                let proposal = Proposal::signed(&self.keys[me], height, round, blocks[peer % 2].clone(), None);
+                self.send(peer, Message::Proposal(proposal));
+                self.equivocate(me, peer, height, round, Some(blocks[peer % 2].hash.clone()), None);
+            }
+        }
+        if let Message::Proposal(proposal) = message {
+            for &peer in &peers {
+                self.equivocate(me, peer, height, round, Some(proposal.block.hash.clone()), None);
+            }
+        }
+    }
+
+    // Sends `peer` a prevote and precommit for `first` or `second`,
+    // depending on which half of the network it is in; the first peer
+    // gets both.
+    fn equivocate(&mut self, me: usize, peer: usize, height: u64, round: u32, first: Option<String>, second: Option<String>) {
+        let choices = match peer {
+            0 => vec![first, second],
+            peer if peer % 2 == 0 => vec![first],
+            _ => vec![second],
+        };
+        for block_hash in choices {
+            for vote_type in [VoteType::Prevote, VoteType::Precommit] {
+                let vote = Vote::signed(&self.keys[me], vote_type, height, round, block_hash.clone());
+                self.send(peer, Message::Vote(vote));
+            }
+        }
+    }
+
+    fn send(&mut self, to: usize, message: Message) {
+        // xorshift64*
+        self.seed ^= self.seed >> 12;
+        self.seed ^= self.seed << 25;
+        self.seed ^= self.seed >> 27;
+        let delay = 1 + self.seed.wrapping_mul(0x2545f4914f6cdd1d) % self.max_delay;
+        self.schedule(self.clock + delay, BftEvent::Deliver(to, Box::new(message)));
+    }
+
+    fn schedule(&mut self, time: u64, event: BftEvent) {
+        self.sequence += 1;
+        self.events.insert((time, self.sequence), event);
+    }
+}
+
+// A chain mined by simulated hashrate, with exponentially distributed solve
+// times drawn from a seeded generator.
+struct MiningSimulation {
+    chain: Vec<Block>,
+    // True time in seconds.
+    clock: u64,
+    seed: u64,
+}
+
+impl MiningSimulation {
+    fn new(genesis: Block, seed: u64) -> Self {
+        MiningSimulation {
+            clock: genesis.timestamp,
+            chain: vec![genesis],
+            seed,
+        }
+    }
+
+    // Mines `count` blocks at `hashrate` hashes a second. `timestamp` picks
+    // each reported timestamp from the true time and the earliest and
+    // latest that `check_timestamp` allows. Returns the blocks with their
+    // true times.
+    fn mine(
+        &mut self,
+        retarget: &Retarget,
+        hashrate: u64,
+        count: usize,
+        timestamp: impl Fn(u64, u64, u64) -> u64,
+    ) -> Vec<(Block, u64)> {
+        let mut mined = Vec::with_capacity(count);
+        for _ in 0..count {
+            let history = &self.chain[self.chain.len().saturating_sub(retarget.history_len())..];
+            let difficulty = retarget.next_difficulty(history);
+            // xorshift64*, then inverse transform sampling.
+            self.seed ^= self.seed >> 12;
+            self.seed ^= self.seed << 25;
+            self.seed ^= self.seed >> 27;
+            let uniform = (self.seed.wrapping_mul(0x2545f4914f6cdd1d) >> 11) as f64 / (1u64 << 53) as f64;
+            let solve_time = -(1.0 - uniform).ln() * difficulty as f64 / hashrate as f64;
+            self.clock += solve_time.round() as u64;
+
+            let earliest = difficulty::median_time_past(history).map_or(0, |median| median + 1);
+            // Six block times of 10 seconds.
+            let latest = self.clock + 60;
+            let parent = &self.chain[self.chain.len() - 1];
+            let reported = timestamp(self.clock, earliest, latest).max(earliest);
+            let mut block = Block::new(parent.height + 1, &parent.hash, reported, EMPTY_ROOT);
+            block.difficulty = difficulty;
+            assert_eq!(retarget.check_timestamp(&block, history, self.clock), Ok(()));
+            mined.push((block.clone(), self.clock));
+            self.chain.push(block);
+        }
+        mined
+    }
+}
+
+fn mean_difficulty(mined: &[(Block, u64)]) -> f64 {
+    mined.iter().map(|(block, _)| block.difficulty as f64).sum::<f64>() / mined.len() as f64
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pi-sentinel-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}